@@ -0,0 +1,437 @@
+use super::{
+    a64, call, load_address,
+    runtime::{abort, exit, print_reg, syscall, write},
+};
+use crate::{
+    freestanding::Port,
+    intrinsics::Syscall,
+    repr::{
+        self, HEAP_ALIGN, STRING_ALIGN, STRING_BYTES, STRING_LENGTH, STRING_TAG, TUPLE_ELEMENTS,
+        TUPLE_LENGTH,
+    },
+    rom, Instrument, Memory, Options, Os,
+};
+use dynasmrt::{aarch64::Assembler, DynasmApi, DynasmLabelApi};
+
+// The intrinsics of `crate::intrinsics` for AArch64, with the same calling
+// convention and value representation. Three-operand instructions and
+// conditional selects make most of them shorter than on x86_64.
+
+pub(super) fn intrinsic(
+    ops: &mut Assembler,
+    options: &Options,
+    rom: &rom::Layout,
+    ram_start: usize,
+    name: &str,
+) {
+    match name {
+        "exit" if options.instrument == Instrument::Counts => {
+            a64!(ops
+                ; b ->exit_with_counts
+            );
+        }
+        "exit" => sys_exit(ops, options.os),
+        "print" => sys_print(ops, options.os),
+        "add" => add(ops),
+        "sub" => sub(ops),
+        "mul" => mul(ops),
+        "divmod" => divmod(ops),
+        "isZero" => is_zero(ops),
+        "addChecked" => add_checked(ops),
+        "subChecked" => sub_checked(ops),
+        "mulChecked" => mul_checked(ops),
+        "neg" => neg(ops),
+        "isNegative" => is_negative(ops),
+        "lessSigned" => less_signed(ops),
+        "compilerVersion" => compiler_version(ops, rom),
+        "hasBuiltin" => has_builtin(ops, rom),
+        "abort" => abort(ops, options.os),
+        "panic" => panic(ops, options.os),
+        "charAt" => char_at(ops, ram_start, &options.memory),
+        "substring" => substring(ops, ram_start, &options.memory),
+        "newTuple" => new_tuple(ops, ram_start, &options.memory),
+        "tupleGet" => tuple_get(ops, options.os),
+        "tupleSet" => tuple_set(ops, options.os),
+        // TODO:
+        "input" => is_zero(ops),
+        "parseInt" => is_zero(ops),
+        _ => panic!("Unknown intrinsic {}", name),
+    }
+}
+
+/// Emit the exit builtin
+/// `exit code`
+pub(super) fn sys_exit(ops: &mut Assembler, os: Os) {
+    a64!(ops
+        ; lsr x0, x1, 1
+    );
+    if let Os::Freestanding { mmio } = os {
+        exit(ops, mmio);
+    } else {
+        syscall(ops, os, Syscall::Exit);
+    }
+}
+
+/// Emit the print builtin
+/// `print str ret`
+fn sys_print(ops: &mut Assembler, os: Os) {
+    a64!(ops
+        // Back up ret to x15
+        ; mov x15, x2
+        ; sub x9, x1, STRING_TAG as u32
+    );
+    if let Os::Freestanding { mmio } = os {
+        a64!(ops
+            ; mov x6, x9
+        );
+        write(ops, mmio, Port::Output);
+    } else {
+        // write(fd, buffer, length)
+        a64!(ops
+            ; movz x0, 1
+            ; add x1, x9, STRING_BYTES as u32
+            ; ldr w2, [x9, STRING_LENGTH as u32]
+        );
+        syscall(ops, os, Syscall::Write);
+    }
+    a64!(ops
+        ; mov x0, x15
+    );
+    call(ops);
+}
+
+/// Emit the panic builtin, which writes the message to stderr and aborts
+/// `panic message`
+fn panic(ops: &mut Assembler, os: Os) {
+    a64!(ops
+        ; sub x12, x1, STRING_TAG as u32
+    );
+    print_reg(ops, os, 12);
+    abort(ops, os);
+}
+
+/// Emit the add builtin
+/// `add a b ret`
+fn add(ops: &mut Assembler) {
+    a64!(ops
+        ; add x1, x1, x2
+        ; sub x1, x1, 1
+        ; mov x0, x3
+    );
+    call(ops);
+}
+
+/// Emit the sub builtin
+/// `sub a b ret`
+fn sub(ops: &mut Assembler) {
+    a64!(ops
+        ; sub x1, x1, x2
+        ; orr x1, x1, 1
+        ; mov x0, x3
+    );
+    call(ops);
+}
+
+/// Emit the mul builtin
+/// `mul a b ret`
+fn mul(ops: &mut Assembler) {
+    a64!(ops
+        ; sub x1, x1, 1
+        ; lsr x2, x2, 1
+        ; mul x1, x1, x2
+        ; orr x1, x1, 1
+        ; mov x0, x3
+    );
+    call(ops);
+}
+
+/// Emit the div builtin
+/// `divmod a b ret`
+fn divmod(ops: &mut Assembler) {
+    // Unlike `div` on x86_64, `udiv` does not trap on division by zero but
+    // gives a quotient of zero, and with it a remainder of `a`.
+    a64!(ops
+        ; lsr x4, x2, 1
+        ; lsr x5, x1, 1
+        ; udiv x1, x5, x4
+        ; msub x2, x1, x4, x5
+        ; lsl x1, x1, 1
+        ; orr x1, x1, 1
+        ; lsl x2, x2, 1
+        ; orr x2, x2, 1
+        ; mov x0, x3
+    );
+    call(ops);
+}
+
+/// Emit the isZero builtin
+/// `isZero n true false`
+fn is_zero(ops: &mut Assembler) {
+    a64!(ops
+        ; cmp x1, 1
+        ; csel x0, x2, x3, eq
+    );
+    call(ops);
+}
+
+/// Emit the checked add builtin
+/// `addChecked a b ok overflow`
+fn add_checked(ops: &mut Assembler) {
+    // Carries out of 64 bits exactly when the sum exceeds 63 bits.
+    a64!(ops
+        ; sub x1, x1, 1
+        ; adds x1, x1, x2
+        ; csel x0, x4, x3, cs
+    );
+    call(ops);
+}
+
+/// Emit the checked sub builtin
+/// `subChecked a b ok overflow`
+fn sub_checked(ops: &mut Assembler) {
+    // The carry flag is clear on borrow, and `add` leaves it alone.
+    a64!(ops
+        ; subs x1, x1, x2
+        ; add x1, x1, 1
+        ; csel x0, x4, x3, lo
+    );
+    call(ops);
+}
+
+/// Emit the checked mul builtin
+/// `mulChecked a b ok overflow`
+fn mul_checked(ops: &mut Assembler) {
+    // `a (2 b)` with `a` and `b` untagged overflows 64 bits exactly when
+    // `a b` overflows 63 bits, which is when the high half is non-zero.
+    a64!(ops
+        ; lsr x1, x1, 1
+        ; sub x2, x2, 1
+        ; umulh x17, x1, x2
+        ; mul x1, x1, x2
+        ; add x1, x1, 1
+        ; cmp x17, 0
+        ; csel x0, x4, x3, ne
+    );
+    call(ops);
+}
+
+/// Emit the neg builtin
+/// `neg a ret`
+fn neg(ops: &mut Assembler) {
+    a64!(ops
+        ; neg x1, x1
+        ; add x1, x1, 2
+        ; mov x0, x2
+    );
+    call(ops);
+}
+
+/// Emit the isNegative builtin
+/// `isNegative n true false`
+fn is_negative(ops: &mut Assembler) {
+    a64!(ops
+        ; cmp x1, 0
+        ; csel x0, x2, x3, lt
+    );
+    call(ops);
+}
+
+/// Emit the lessSigned builtin
+/// `lessSigned a b true false`
+fn less_signed(ops: &mut Assembler) {
+    a64!(ops
+        ; cmp x1, x2
+        ; csel x0, x3, x4, lt
+    );
+    call(ops);
+}
+
+/// Emit the compilerVersion builtin
+/// `compilerVersion ret`
+fn compiler_version(ops: &mut Assembler, rom: &rom::Layout) {
+    let version = rom.version.expect("Version string in ROM");
+    a64!(ops
+        ; mov x0, x1
+    );
+    load_address(ops, 1, repr::string(version) as usize);
+    call(ops);
+}
+
+/// Emit the hasBuiltin builtin
+/// `hasBuiltin name true false`
+fn has_builtin(ops: &mut Assembler, rom: &rom::Layout) {
+    // The ROM table is a sequence of strings terminated by an empty one.
+    let table = rom.builtins.expect("Builtin table in ROM");
+    a64!(ops
+        ; sub x9, x1, STRING_TAG as u32
+    );
+    load_address(ops, 10, table);
+    a64!(ops
+        ; next:
+        ; ldr w1, [x10, STRING_LENGTH as u32]
+        ; cbz w1, >not_found
+        ; ldr w11, [x9, STRING_LENGTH as u32]
+        ; cmp w1, w11
+        ; b.ne >skip
+        ; add x6, x10, STRING_BYTES as u32
+        ; add x7, x9, STRING_BYTES as u32
+        ; compare:
+        ; ldrb w11, [x6], 1
+        ; ldrb w12, [x7], 1
+        ; cmp w11, w12
+        ; b.ne >skip
+        ; subs w1, w1, 1
+        ; b.ne <compare
+        ; mov x0, x2
+        ; b >found
+        ; skip:
+        // Next record, see `x64::intrinsics::assemble_next_string`
+        ; ldr w11, [x10, STRING_LENGTH as u32]
+        ; add x10, x10, x11
+        ; add x10, x10, (STRING_BYTES as usize + STRING_ALIGN - 1) as u32
+        ; and x10, x10, !(STRING_ALIGN as u64 - 1)
+        ; b <next
+        ; not_found:
+        ; mov x0, x3
+        ; found:
+    );
+    call(ops);
+}
+
+/// Emit the charAt builtin
+/// `charAt string index ret`
+fn char_at(ops: &mut Assembler, ram_start: usize, memory: &Memory) {
+    a64!(ops
+        ; mov x4, x3
+        ; movz x3, repr::number(1) as u32
+    );
+    substring(ops, ram_start, memory);
+}
+
+/// Emit the substring builtin
+/// `substring string start length ret`
+fn substring(ops: &mut Assembler, ram_start: usize, memory: &Memory) {
+    a64!(ops
+        ; sub x9, x1, STRING_TAG as u32
+        ; ldr w8, [x9, STRING_LENGTH as u32]
+        ; lsr x2, x2, 1
+        ; lsr x3, x3, 1
+        ; cmp x2, x8
+        ; csel x2, x2, x8, lo
+        ; sub x8, x8, x2
+        ; cmp x3, x8
+        ; csel x3, x3, x8, lo
+        ; add x6, x9, x2
+        ; add x6, x6, STRING_BYTES as u32
+    );
+    // Allocate the record, keeping the heap aligned
+    load_address(ops, 10, ram_start);
+    a64!(ops
+        ; ldr x7, [x10]
+        ; add x1, x7, STRING_TAG as u32
+        ; add x11, x7, x3
+        ; add x11, x11, (STRING_BYTES as usize + HEAP_ALIGN - 1) as u32
+        ; and x11, x11, !(HEAP_ALIGN as u64 - 1)
+    );
+    if memory.heap_checks {
+        load_address(ops, 12, ram_start + memory.heap_end());
+        a64!(ops
+            ; cmp x11, x12
+            ; b.hi ->heap_overflow
+        );
+    }
+    a64!(ops
+        ; str x11, [x10]
+        ; str w3, [x7, STRING_LENGTH as u32]
+        ; add x7, x7, STRING_BYTES as u32
+        ; cbz x3, >done
+        ; copy:
+        ; ldrb w12, [x6], 1
+        ; strb w12, [x7], 1
+        ; subs x3, x3, 1
+        ; b.ne <copy
+        ; done:
+        ; mov x0, x4
+    );
+    call(ops);
+}
+
+/// Emit the newTuple builtin
+/// `newTuple length ret`
+fn new_tuple(ops: &mut Assembler, ram_start: usize, memory: &Memory) {
+    load_address(ops, 10, ram_start);
+    a64!(ops
+        ; ldr x7, [x10]
+    );
+    // A length beyond the heap would overflow the size
+    if memory.heap_checks {
+        load_address(ops, 12, ram_start + memory.heap_end());
+        a64!(ops
+            ; cmp x1, x12
+            ; b.hi ->heap_overflow
+        );
+    }
+    a64!(ops
+        ; add x11, x7, x1, lsl 2
+        ; add x11, x11, (TUPLE_ELEMENTS - 4) as u32
+    );
+    if memory.heap_checks {
+        a64!(ops
+            ; cmp x11, x12
+            ; b.hi ->heap_overflow
+        );
+    }
+    a64!(ops
+        ; str x11, [x10]
+        ; str x1, [x7, TUPLE_LENGTH as u32]
+        ; add x6, x7, TUPLE_ELEMENTS as u32
+        ; movz x12, repr::number(0) as u32
+        ; b >check
+        ; fill:
+        ; str x12, [x6], 8
+        ; check:
+        ; cmp x6, x11
+        ; b.lo <fill
+        ; mov x1, x7
+        ; mov x0, x2
+    );
+    call(ops);
+}
+
+/// Emit the tupleGet builtin
+/// `tupleGet tuple index ret`
+fn tuple_get(ops: &mut Assembler, os: Os) {
+    a64!(ops
+        ; ldr x9, [x1, TUPLE_LENGTH as u32]
+        ; cmp x2, x9
+        ; b.hs >out_of_bounds
+        ; lsr x9, x2, 1
+        ; add x9, x1, x9, lsl 3
+        ; ldr x1, [x9, TUPLE_ELEMENTS as u32]
+        ; mov x0, x3
+    );
+    call(ops);
+    a64!(ops
+        ; out_of_bounds:
+    );
+    abort(ops, os);
+}
+
+/// Emit the tupleSet builtin
+/// `tupleSet tuple index value ret`
+fn tuple_set(ops: &mut Assembler, os: Os) {
+    a64!(ops
+        ; ldr x9, [x1, TUPLE_LENGTH as u32]
+        ; cmp x2, x9
+        ; b.hs >out_of_bounds
+        ; lsr x9, x2, 1
+        ; add x9, x1, x9, lsl 3
+        ; str x3, [x9, TUPLE_ELEMENTS as u32]
+        ; mov x0, x4
+    );
+    call(ops);
+    a64!(ops
+        ; out_of_bounds:
+    );
+    abort(ops, os);
+}
@@ -0,0 +1,332 @@
+mod intrinsics;
+mod runtime;
+
+use crate::{arch::Isa, machine::Transition, rom, Memory, Options};
+use dynasmrt::{
+    aarch64::{Aarch64Relocation, Assembler},
+    DynamicLabel, DynasmApi, DynasmLabelApi,
+};
+
+// AArch64, for ARM Linux and Apple Silicon.
+// See <https://censoredusername.github.io/dynasm-rs/language/langref_aarch64.html>
+//
+// Machine registers r0..r15 are x0..x15. The intra-procedure-call registers
+// x16 and x17 are scratch, x18 is reserved by some platforms and left alone,
+// x30 links outlined stubs. The stack pointer is not a general purpose
+// register here, so unlike on x86_64 it does not double as r4.
+//
+// Instructions are four bytes and immediates short, so constants are built
+// with `movz` and `movk`. Addresses always take two instructions, which keeps
+// the code size independent of the layout, as the two compilation passes
+// require.
+
+/// `dynasm!` for AArch64, every invocation starts out in x64 mode.
+macro_rules! a64 {
+    ($ops:ident $($t:tt)*) => {
+        dynasm::dynasm!($ops ; .arch aarch64 $($t)*)
+    };
+}
+pub(crate) use a64;
+
+/// Scratch registers, never part of the machine state.
+const SCRATCH0: u32 = 16;
+const SCRATCH1: u32 = 17;
+
+/// The AArch64 instruction set.
+pub(crate) struct A64;
+
+/// Emit `reg = value`. The sequence is one instruction for zero, two below
+/// 2^32 and four otherwise, like the `Set` encodings on x86_64.
+pub(crate) fn load(ops: &mut Assembler, reg: u32, value: u64) {
+    let chunk = |shift: u32| (value >> shift) as u32 & 0xffff;
+    if value == 0 {
+        a64!(ops
+            ; movz X(reg), 0
+        );
+        return;
+    }
+    a64!(ops
+        ; movz X(reg), chunk(0)
+        ; movk X(reg), chunk(16), lsl 16
+    );
+    if value > u64::from(u32::max_value()) {
+        a64!(ops
+            ; movk X(reg), chunk(32), lsl 32
+            ; movk X(reg), chunk(48), lsl 48
+        );
+    }
+}
+
+/// Emit `reg = address` in exactly two instructions.
+pub(crate) fn load_address(ops: &mut Assembler, reg: u32, address: usize) {
+    assert!(address <= u32::max_value() as usize);
+    let address = address as u32;
+    a64!(ops
+        ; movz X(reg), address & 0xffff
+        ; movk X(reg), address >> 16, lsl 16
+    );
+}
+
+/// Emit a jump to the closure in x0.
+pub(crate) fn call(ops: &mut Assembler) {
+    a64!(ops
+        ; ldr X(SCRATCH1), [x0]
+        ; br X(SCRATCH1)
+    );
+}
+
+fn transition(ops: &mut Assembler, transition: &Transition, ram_start: usize) {
+    use Transition::*;
+    let reg = |register: crate::machine::Register| u32::from(register.as_u8());
+    match *transition {
+        Set { dest, value } => load(ops, reg(dest), value),
+        Copy { dest, source } => {
+            if dest != source {
+                a64!(ops
+                    ; mov X(reg(dest)), X(reg(source))
+                );
+            }
+        }
+        Swap { dest, source } => {
+            if dest != source {
+                a64!(ops
+                    ; mov X(SCRATCH1), X(reg(dest))
+                    ; mov X(reg(dest)), X(reg(source))
+                    ; mov X(reg(source)), X(SCRATCH1)
+                );
+            }
+        }
+        Read {
+            dest,
+            source,
+            offset,
+        } => {
+            // The scaled unsigned offset reaches 4095 quad words
+            if (0..4096).contains(&offset) {
+                a64!(ops
+                    ; ldr X(reg(dest)), [X(reg(source)), 8 * offset as u32]
+                );
+            } else {
+                load(ops, SCRATCH1, (8 * offset) as u64);
+                a64!(ops
+                    ; ldr X(reg(dest)), [X(reg(source)), X(SCRATCH1)]
+                );
+            }
+        }
+        Write {
+            dest,
+            offset,
+            source,
+        } => {
+            if (0..4096).contains(&offset) {
+                a64!(ops
+                    ; str X(reg(source)), [X(reg(dest)), 8 * offset as u32]
+                );
+            } else {
+                load(ops, SCRATCH1, (8 * offset) as u64);
+                a64!(ops
+                    ; str X(reg(source)), [X(reg(dest)), X(SCRATCH1)]
+                );
+            }
+        }
+        Alloc { dest, size } => {
+            // Bump allocation, see `allocator::Bump`
+            let size = 8 * size;
+            load_address(ops, SCRATCH0, ram_start);
+            a64!(ops
+                ; ldr X(reg(dest)), [X(SCRATCH0)]
+            );
+            if size < 4096 {
+                a64!(ops
+                    ; add X(SCRATCH1), X(reg(dest)), size as u32
+                );
+            } else {
+                load(ops, SCRATCH1, size as u64);
+                a64!(ops
+                    ; add X(SCRATCH1), X(reg(dest)), X(SCRATCH1)
+                );
+            }
+            a64!(ops
+                ; str X(SCRATCH1), [X(SCRATCH0)]
+            );
+        }
+        Drop { .. } => {}
+        Test { source } => {
+            // Zero is represented as one, see `repr::number`
+            a64!(ops
+                ; cmp X(reg(source)), 1
+            );
+        }
+    }
+}
+
+impl Isa for A64 {
+    type Relocation = Aarch64Relocation;
+
+    /// A `bl`, the return address goes in x30.
+    const CALL_SIZE: usize = 4;
+
+    fn prelude(ops: &mut Assembler, ram_end: usize, main: usize, fallthrough: bool) {
+        a64!(ops
+            ; mov X(SCRATCH1), sp
+        );
+        load_address(ops, SCRATCH0, ram_end - 8);
+        a64!(ops
+            ; str X(SCRATCH1), [X(SCRATCH0)]
+        );
+        load_address(ops, 0, main);
+        if !fallthrough {
+            call(ops);
+        }
+    }
+
+    fn transition(ops: &mut Assembler, transition: &Transition, ram_start: usize) {
+        self::transition(ops, transition, ram_start);
+    }
+
+    fn heap_check(ops: &mut Assembler, ram_start: usize, memory: &Memory, size: usize) {
+        let limit = (ram_start + memory.heap_end()).saturating_sub(size);
+        load_address(ops, SCRATCH0, ram_start);
+        a64!(ops
+            ; ldr X(SCRATCH1), [X(SCRATCH0)]
+        );
+        load_address(ops, SCRATCH0, limit);
+        a64!(ops
+            ; cmp X(SCRATCH1), X(SCRATCH0)
+            ; b.hi ->heap_overflow
+        );
+    }
+
+    fn call_stub(
+        ops: &mut Assembler,
+        _ram_start: usize,
+        stub: DynamicLabel,
+        _return_address: usize,
+    ) {
+        let start = ops.offset().0;
+        a64!(ops
+            ; bl =>stub
+        );
+        assert_eq!(ops.offset().0 - start, Self::CALL_SIZE);
+    }
+
+    fn stub_return(ops: &mut Assembler, _ram_start: usize) {
+        a64!(ops
+            ; ret
+        );
+    }
+
+    fn enter_declaration(ops: &mut Assembler, ram_start: usize, index: usize) {
+        load_address(ops, SCRATCH0, ram_start + crate::runtime::DECLARATION_SLOT);
+        load(ops, SCRATCH1, index as u64);
+        a64!(ops
+            ; str X(SCRATCH1), [X(SCRATCH0)]
+        );
+    }
+
+    fn call_trace(ops: &mut Assembler, _ram_start: usize, _return_address: usize) {
+        let start = ops.offset().0;
+        a64!(ops
+            ; bl ->trace
+        );
+        assert_eq!(ops.offset().0 - start, Self::CALL_SIZE);
+    }
+
+    fn increment(ops: &mut Assembler, address: usize) {
+        load_address(ops, SCRATCH0, address);
+        a64!(ops
+            ; ldr X(SCRATCH1), [X(SCRATCH0)]
+            ; add X(SCRATCH1), X(SCRATCH1), 1
+            ; str X(SCRATCH1), [X(SCRATCH0)]
+        );
+    }
+
+    fn select_on_zero(ops: &mut Assembler) {
+        a64!(ops
+            ; csel x0, x0, x1, eq
+        );
+    }
+
+    fn call(ops: &mut Assembler) {
+        call(ops);
+    }
+
+    fn jump(ops: &mut Assembler, label: DynamicLabel) {
+        a64!(ops
+            ; b =>label
+        );
+    }
+
+    fn check_closure(ops: &mut Assembler) {
+        runtime::check_closure(ops);
+    }
+
+    fn check_arity(ops: &mut Assembler, argc: usize) {
+        runtime::check_arity(ops, argc);
+    }
+
+    fn intrinsic(
+        ops: &mut Assembler,
+        options: &Options,
+        rom: &rom::Layout,
+        ram_start: usize,
+        name: &str,
+    ) {
+        intrinsics::intrinsic(ops, options, rom, ram_start, name);
+    }
+
+    fn runtime(ops: &mut Assembler, options: &Options, rom: &rom::Layout, ram_start: usize) {
+        runtime::runtime(ops, options, rom, ram_start);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::machine::Register;
+
+    fn assemble(transition: Transition) -> Vec<u8> {
+        let mut ops = Assembler::new().unwrap();
+        self::transition(&mut ops, &transition, 0x1000);
+        ops.finalize().unwrap().to_vec()
+    }
+
+    /// Sets are as long for every address, whatever the layout pass.
+    #[test]
+    fn test_set_size() {
+        let set = |value| {
+            assemble(Transition::Set {
+                dest: Register(3),
+                value,
+            })
+            .len()
+        };
+        assert_eq!(set(0), 4);
+        assert_eq!(set(1), 8);
+        assert_eq!(set(0x1_0000), 8);
+        assert_eq!(set(u64::from(u32::max_value())), 8);
+        assert_eq!(set(u64::max_value()), 16);
+    }
+
+    #[test]
+    fn test_encoding() {
+        // mov x3, x5
+        let copy = Transition::Copy {
+            dest:   Register(3),
+            source: Register(5),
+        };
+        assert_eq!(assemble(copy), vec![0xe3, 0x03, 0x05, 0xaa]);
+        // ldr x3, [x5, #16]
+        let read = Transition::Read {
+            dest:   Register(3),
+            source: Register(5),
+            offset: 2,
+        };
+        assert_eq!(assemble(read), vec![0xa3, 0x08, 0x40, 0xf9]);
+        // cmp x5, #1
+        let test = Transition::Test {
+            source: Register(5),
+        };
+        assert_eq!(assemble(test), vec![0xbf, 0x04, 0x00, 0xf1]);
+    }
+}
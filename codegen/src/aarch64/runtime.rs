@@ -0,0 +1,242 @@
+use super::{a64, intrinsics::sys_exit, load, load_address, SCRATCH0, SCRATCH1};
+use crate::{
+    freestanding::Port,
+    intrinsics::Syscall,
+    repr::{CLOSURE_INFO, STRING_BYTES, STRING_LENGTH, TAG_MASK},
+    rom,
+    runtime::{Message, COUNTS_BUFFER, DECLARATION_SLOT, PANIC_EXIT_CODE, TRACE_SAVED},
+    Instrument, Options, Os,
+};
+use dynasmrt::{aarch64::Assembler, DynasmApi, DynasmLabelApi};
+
+// The run-time support routines, system calls and freestanding ports of
+// `crate::runtime` and `crate::freestanding` for AArch64.
+//
+// System call arguments are in x0..x5. Linux takes the number in x8 and
+// traps with `svc #0`, Darwin takes it in x16 and traps with `svc #0x80`.
+// See <https://github.com/torvalds/linux/blob/master/include/uapi/asm-generic/unistd.h>
+
+/// Emit the system call `syscall`, with its arguments in x0...
+pub(super) fn syscall(ops: &mut Assembler, os: Os, syscall: Syscall) {
+    match (os, syscall) {
+        (Os::Darwin, Syscall::Exit) => a64!(ops; movz x16, 1; svc 0x80),
+        (Os::Darwin, Syscall::Write) => a64!(ops; movz x16, 4; svc 0x80),
+        // exit_group, see `Syscall::number`
+        (Os::Linux, Syscall::Exit) => a64!(ops; movz x8, 94; svc 0),
+        (Os::Linux, Syscall::Write) => a64!(ops; movz x8, 64; svc 0),
+        (Os::Freestanding { .. }, _) => panic!("No system calls in freestanding mode"),
+    }
+}
+
+/// Emit the check that x0 holds a closure and not a number or string.
+pub(super) fn check_closure(ops: &mut Assembler) {
+    a64!(ops
+        ; tst x0, TAG_MASK
+        ; b.ne ->not_closure
+    );
+}
+
+/// Emit the check that the closure in x0 takes `argc` arguments.
+pub(super) fn check_arity(ops: &mut Assembler, argc: usize) {
+    assert!(argc <= u8::max_value() as usize);
+    a64!(ops
+        ; ldrb W(SCRATCH1), [x0, CLOSURE_INFO as u32]
+        ; cmp W(SCRATCH1), argc as u32
+        ; b.ne ->arity_mismatch
+    );
+}
+
+/// Emit the runtime support routines.
+pub(super) fn runtime(ops: &mut Assembler, options: &Options, rom: &rom::Layout, ram_start: usize) {
+    let os = options.os;
+    // Arity mismatch: x0 holds the closure that was about to be called.
+    a64!(ops
+        ; ->arity_mismatch:
+        ; ldr x12, [x0, CLOSURE_INFO as u32]
+        ; lsr x12, x12, 8
+    );
+    print_const(ops, os, rom.messages[Message::ArityMismatch as usize]);
+    print_reg(ops, os, 12);
+    print_const(ops, os, rom.messages[Message::Newline as usize]);
+    abort(ops, os);
+
+    // Not a closure: x0 holds a tagged number or string.
+    a64!(ops
+        ; ->not_closure:
+    );
+    print_const(ops, os, rom.messages[Message::NotClosure as usize]);
+    abort(ops, os);
+
+    // Heap overflow: the allocations of a declaration do not fit in RAM.
+    a64!(ops
+        ; ->heap_overflow:
+    );
+    print_const(ops, os, rom.messages[Message::HeapOverflow as usize]);
+    abort(ops, os);
+
+    match options.instrument {
+        Instrument::None => {}
+        Instrument::Trace => trace(ops, options, rom, ram_start),
+        Instrument::Counts => exit_with_counts(ops, options, rom, ram_start),
+    }
+}
+
+/// The trace routine, called with `bl`: write the name of the declaration in
+/// the declaration slot to stderr. All registers are preserved, x0, x1, x2,
+/// x6, x8 and x9 are saved at the heap offset.
+fn trace(ops: &mut Assembler, options: &Options, rom: &rom::Layout, ram_start: usize) {
+    let save = ram_start + options.memory.heap_offset;
+    let names = rom.ranges.expect("Tracing needs the table of code ranges") + 16;
+    assert_eq!(TRACE_SAVED, 6);
+    a64!(ops
+        ; ->trace:
+    );
+    load_address(ops, SCRATCH0, save);
+    a64!(ops
+        ; stp x0, x1, [X(SCRATCH0)]
+        ; stp x2, x6, [X(SCRATCH0), 16]
+        ; stp x8, x9, [X(SCRATCH0), 32]
+    );
+    // The name is the third quad word of the declaration's entry in the table
+    load_address(ops, 9, ram_start + DECLARATION_SLOT);
+    load_address(ops, 6, names);
+    a64!(ops
+        ; ldr x9, [x9]
+        ; add x9, x9, x9, lsl 1
+        ; lsl x9, x9, 3
+        ; ldr x9, [x6, x9]
+    );
+    print_reg(ops, options.os, 9);
+    print_const(ops, options.os, rom.messages[Message::Newline as usize]);
+    load_address(ops, SCRATCH0, save);
+    a64!(ops
+        ; ldp x0, x1, [X(SCRATCH0)]
+        ; ldp x2, x6, [X(SCRATCH0), 16]
+        ; ldp x8, x9, [X(SCRATCH0), 32]
+        ; ret
+    );
+}
+
+/// The routine the `exit` intrinsic branches to when counting: write the name
+/// and count of every declaration to stderr, a line each, and exit with the
+/// code in x1. The exit code and the index survive in x15 and x12.
+fn exit_with_counts(ops: &mut Assembler, options: &Options, rom: &rom::Layout, ram_start: usize) {
+    let declarations = rom.closures.len();
+    let counters = ram_start + options.memory.heap_offset;
+    let end = counters + 8 * declarations + COUNTS_BUFFER;
+    let names = rom.ranges.expect("Counting needs the table of code ranges") + 16;
+    a64!(ops
+        ; ->exit_with_counts:
+        ; mov x15, x1
+        ; movz x12, 0
+        ; counts_next:
+    );
+    load(ops, 13, declarations as u64);
+    load_address(ops, 9, names);
+    a64!(ops
+        ; cmp x12, x13
+        ; b.eq >counts_done
+        ; add x13, x12, x12, lsl 1
+        ; lsl x13, x13, 3
+        ; ldr x9, [x9, x13]
+    );
+    print_reg(ops, options.os, 9);
+    // Format ` <count>\n` backwards from the end of the buffer, and prefix
+    // the length to make it a string record
+    load_address(ops, 9, counters);
+    load_address(ops, 13, end);
+    a64!(ops
+        ; ldr x10, [x9, x12, lsl 3]
+        ; movz x11, 10
+        ; movz w14, u32::from(b'\n')
+        ; strb w14, [x13, -1]!
+        ; counts_digit:
+        ; udiv x14, x10, x11
+        ; msub x9, x14, x11, x10
+        ; add x9, x9, u32::from(b'0')
+        ; strb w9, [x13, -1]!
+        ; mov x10, x14
+        ; cbnz x10, <counts_digit
+        ; movz w14, u32::from(b' ')
+        ; strb w14, [x13, -1]!
+    );
+    load_address(ops, 9, end);
+    a64!(ops
+        ; sub x14, x9, x13
+        ; sub x9, x13, STRING_BYTES as u32
+        ; str w14, [x9, STRING_LENGTH as u32]
+    );
+    print_reg(ops, options.os, 9);
+    a64!(ops
+        ; add x12, x12, 1
+        ; b <counts_next
+        ; counts_done:
+        ; mov x1, x15
+    );
+    sys_exit(ops, options.os);
+}
+
+/// Write the length-prefixed string at `address` to stderr.
+fn print_const(ops: &mut Assembler, os: Os, address: usize) {
+    load_address(ops, 9, address);
+    print_reg(ops, os, 9);
+}
+
+/// Write the length-prefixed string pointed to by `reg` to stderr. The
+/// register must survive the syscall, so it can not be x0, x1, x2, x6, x8 or
+/// x16.
+pub(super) fn print_reg(ops: &mut Assembler, os: Os, reg: u32) {
+    if let Os::Freestanding { mmio } = os {
+        a64!(ops
+            ; mov x6, X(reg)
+        );
+        write(ops, mmio, Port::Diagnostics);
+        return;
+    }
+    a64!(ops
+        ; movz x0, 2
+        ; add x1, X(reg), STRING_BYTES as u32
+        ; ldr w2, [X(reg), STRING_LENGTH as u32]
+    );
+    syscall(ops, os, Syscall::Write);
+}
+
+/// Exit with `PANIC_EXIT_CODE`.
+pub(super) fn abort(ops: &mut Assembler, os: Os) {
+    a64!(ops
+        ; movz x0, u32::from(PANIC_EXIT_CODE)
+    );
+    if let Os::Freestanding { mmio } = os {
+        exit(ops, mmio);
+    } else {
+        syscall(ops, os, Syscall::Exit);
+    }
+}
+
+/// Write the length-prefixed string at x6 to the freestanding `port` one byte
+/// at a time. Clobbers x0, x2, x6 and x17.
+pub(super) fn write(ops: &mut Assembler, mmio: usize, port: Port) {
+    load(ops, SCRATCH1, port.address(mmio) as u64);
+    a64!(ops
+        ; ldr w2, [x6, STRING_LENGTH as u32]
+        ; add x6, x6, STRING_BYTES as u32
+        ; cbz w2, >done
+        ; next:
+        ; ldrb w0, [x6], 1
+        ; strb w0, [X(SCRATCH1)]
+        ; subs w2, w2, 1
+        ; b.ne <next
+        ; done:
+    );
+}
+
+/// Write the exit code in x0 to the freestanding exit port and halt.
+pub(super) fn exit(ops: &mut Assembler, mmio: usize) {
+    load(ops, SCRATCH1, Port::Exit.address(mmio) as u64);
+    a64!(ops
+        ; str x0, [X(SCRATCH1)]
+        ; halt:
+        ; wfi
+        ; b <halt
+    );
+}
@@ -0,0 +1,70 @@
+use crate::machine::{Allocation, Register, State, Value};
+
+// The calling convention of native code. A call is a closure followed by its
+// arguments, which the caller puts in place before jumping to the code pointer
+// of the closure:
+//
+// * r0 holds the closure, see `repr` for its record.
+// * r1..r15 hold the arguments, if there are at most fifteen.
+// * With more arguments r1..r14 hold the first fourteen and r15 the address
+//   of an argument block with the rest. The block is a heap allocated record
+//   of one word per argument, in order and without header. The caller
+//   allocates it like a closure, and as nothing else refers to it the callee
+//   reads what it needs from it and may write its own call into it.
+//
+// Registers past the last argument hold nothing. Intrinsics take at most four
+// arguments, so they never get a block. The C and WebAssembly backends have as
+// many registers as the largest call needs and pass every argument in them.
+
+/// Number of registers a call passes values in, the closure included.
+pub(crate) const REGISTERS: usize = 16;
+
+/// The register holding the argument block of a call with more arguments
+/// than fit in registers.
+pub(crate) const BLOCK: Register = Register(15);
+
+/// Put `values`, a closure and its arguments, in the registers of `state` the
+/// way a call passes them. If there are too many, those from `BLOCK` on go in
+/// a new allocation of `state` that `BLOCK` refers to.
+pub(crate) fn pass(state: &mut State, mut values: Vec<Value>) {
+    if values.len() > REGISTERS {
+        let block = values.split_off(BLOCK.as_u8() as usize);
+        let index = state.allocations.len();
+        state.allocations.push(Allocation::new(block));
+        values.push(Value::Reference { index, offset: 0 });
+    }
+    for (register, value) in state.registers.iter_mut().zip(values) {
+        *register = value;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::intrinsics::{arity, NAMES};
+
+    #[test]
+    fn test_pass() {
+        let symbols = |count: usize| (0..count).map(Value::Symbol).collect::<Vec<_>>();
+        let mut state = State::default();
+        pass(&mut state, symbols(16));
+        assert_eq!(state.registers.to_vec(), symbols(16));
+        assert!(state.allocations().is_empty());
+
+        // The closure is in r0 already, the block comes after it
+        let mut state = State::default();
+        state.allocations.push(Allocation::new(vec![Value::Literal(0)]));
+        pass(&mut state, symbols(20));
+        assert_eq!(state.registers[..15], symbols(15)[..]);
+        assert_eq!(state.registers[15], Value::Reference {
+            index:  1,
+            offset: 0,
+        });
+        assert_eq!(state.allocations[1].values, symbols(20)[15..]);
+    }
+
+    #[test]
+    fn test_intrinsics() {
+        assert!(NAMES.iter().all(|name| arity(name) < REGISTERS));
+    }
+}
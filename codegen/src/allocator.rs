@@ -1,17 +1,33 @@
+use crate::{
+    error::{finalize, new_assembler, Phase, Result},
+    machine::{Instruction, Register},
+    runtime, Memory, Options,
+};
 use dynasm::dynasm;
-use dynasmrt::{x64::Assembler, DynasmApi};
+use dynasmrt::{x64::Assembler, DynasmApi, DynasmLabelApi};
+use parser::mir::Module;
 
-pub(crate) fn initial_ram(ram_start: usize) -> Vec<u8> {
-    let mut ram = Assembler::new().unwrap();
+/// The reserved words. The first allocation follows the data of the
+/// instrumentation, if any, at the heap offset.
+pub(crate) fn initial_ram(ram_start: usize, module: &Module, options: &Options) -> Result<Vec<u8>> {
+    let instrumentation = runtime::instrumentation_size(module, options);
+    let heap = ram_start + options.memory.heap_offset + instrumentation;
+    let mut ram: Assembler = new_assembler(Phase::Ram)?;
     dynasm!(ram
         // First 4 bytes are free memory pointer
-        ; .qword (ram_start + 4) as i64
+        ; .qword heap as i64
+        // Return address slot for outlined code
+        ; .qword 0
+        // Running declaration, none before the prelude calls the entry
+        ; .qword -1
     );
-    let ram = ram.finalize().expect("Finalize after commit.");
-    ram.to_vec()
+    finalize(ram, Phase::Ram)
 }
 
 pub(crate) trait Allocator {
+    /// Check that `size` more bytes can be allocated, jump to the
+    /// `heap_overflow` runtime routine otherwise.
+    fn check(code: &mut Assembler, ram_start: usize, memory: &Memory, size: usize);
     fn alloc<A: DynasmApi>(code: &mut A, ram_start: usize, reg: usize, size: usize);
     fn drop<A: DynasmApi>(code: &mut A, reg: usize);
 }
@@ -19,23 +35,30 @@ pub(crate) trait Allocator {
 pub(crate) struct Bump();
 
 impl Allocator for Bump {
+    fn check(asm: &mut Assembler, ram_start: usize, memory: &Memory, size: usize) {
+        // Compare the free memory pointer with the last address it can have
+        // before the allocations. Free memory pointers are below 2GB, so the
+        // low double word suffices and no register is needed.
+        let limit = (ram_start + memory.heap_end()).saturating_sub(size);
+        assert!(limit <= i32::max_value() as usize);
+        dynasm!(asm
+            ; cmp DWORD [ram_start as i32], DWORD limit as i32
+            ; ja ->heap_overflow
+        );
+    }
+
     /// Allocate `size` bytes and store the pointer in register `reg`
     fn alloc<A: DynasmApi>(asm: &mut A, ram_start: usize, reg: usize, size: usize) {
         // Read current free memory pointer
         // Add size to free memory pointer
-        if size <= 127 {
-            // TODO: Avoid REX when reg < 8.
-            // TODO: BYTE operand for ADD
-            dynasm!(asm
-                ; mov Rd(reg as u8), DWORD [ram_start as i32]
-                ; add DWORD [ram_start as i32], BYTE size as i32); // ?
-        } else if size <= (u32::max_value() as usize) {
-            dynasm!(asm
-                ; mov Rd(reg as u8), DWORD [ram_start as i32]
-                ; add DWORD [ram_start as i32], DWORD size as i32);
-        } else {
-            panic!("Can not allocate more than 4GB.");
-        }
+        // TODO: Avoid REX when reg < 8.
+        // TODO: BYTE operand for ADD
+        assert!(
+            size <= (i32::max_value() as usize),
+            "Can not allocate more than 2GB."
+        );
+        Instruction::LoadAbsolute32(Register(reg as u8), ram_start as i32).encode(asm);
+        Instruction::AddAbsolute32(ram_start as i32, size as i32).encode(asm);
     }
 
     /// Deallocate bytes pointed to by register `reg`
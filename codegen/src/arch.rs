@@ -0,0 +1,136 @@
+#[cfg(feature = "native")]
+use crate::{machine::Transition, rom, Memory, Options};
+#[cfg(feature = "native")]
+use dynasmrt::{relocations::Relocation, Assembler, DynamicLabel};
+use std::fmt::{self, Display};
+
+// Instruction sets. Planning is shared: the machine model, its transitions and
+// their costs are those of x86_64, which are a fair estimate elsewhere too.
+// What differs is how the transitions, the calling convention, the intrinsics
+// and the runtime are encoded, which is what `Isa` abstracts. It is
+// implemented in `x64` and `aarch64`, which need the `native` feature.
+//
+// All instruction sets have at least the 16 registers of the machine model,
+// r0 holds the closure and r1... the arguments, see `abi`. Addresses fit in
+// 31 bits.
+
+/// Instruction set architectures code can be generated for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+}
+
+impl Default for Arch {
+    fn default() -> Self {
+        Arch::X86_64
+    }
+}
+
+impl Arch {
+    /// The architecture running the compiler, if supported.
+    #[must_use]
+    pub fn host() -> Option<Self> {
+        if cfg!(target_arch = "x86_64") {
+            Some(Arch::X86_64)
+        } else if cfg!(target_arch = "aarch64") {
+            Some(Arch::Aarch64)
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Encoding of the generated code for an instruction set.
+#[cfg(feature = "native")]
+pub(crate) trait Isa {
+    type Relocation: Relocation;
+
+    /// Bytes taken by `call_stub`.
+    const CALL_SIZE: usize;
+
+    /// Store the stack pointer the program was started with at `ram_end - 8`
+    /// and load the `main` closure in r0, calling it unless `fallthrough`.
+    fn prelude(
+        asm: &mut Assembler<Self::Relocation>,
+        ram_end: usize,
+        main: usize,
+        fallthrough: bool,
+    );
+
+    fn transition(asm: &mut Assembler<Self::Relocation>, transition: &Transition, ram_start: usize);
+
+    /// Jump to the `heap_overflow` runtime routine unless `size` more bytes
+    /// can be allocated.
+    fn heap_check(
+        asm: &mut Assembler<Self::Relocation>,
+        ram_start: usize,
+        memory: &Memory,
+        size: usize,
+    );
+
+    /// Call an outlined stub, `return_address` is the address right after
+    /// the call.
+    fn call_stub(
+        asm: &mut Assembler<Self::Relocation>,
+        ram_start: usize,
+        stub: DynamicLabel,
+        return_address: usize,
+    );
+
+    /// Return from an outlined stub.
+    fn stub_return(asm: &mut Assembler<Self::Relocation>, ram_start: usize);
+
+    /// Store `index` in the declaration slot, see `runtime::DECLARATION_SLOT`.
+    fn enter_declaration(asm: &mut Assembler<Self::Relocation>, ram_start: usize, index: usize);
+
+    /// Call the `trace` runtime routine, `return_address` is the address
+    /// right after the call, which takes `CALL_SIZE` bytes too.
+    fn call_trace(asm: &mut Assembler<Self::Relocation>, ram_start: usize, return_address: usize);
+
+    /// Add one to the quad word at `address`, clobbering the flags.
+    fn increment(asm: &mut Assembler<Self::Relocation>, address: usize);
+
+    /// Replace r0 by r1 unless the flags are set by a `Transition::Test` of
+    /// zero.
+    fn select_on_zero(asm: &mut Assembler<Self::Relocation>);
+
+    /// Call the closure in r0.
+    fn call(asm: &mut Assembler<Self::Relocation>);
+
+    /// Call the closure in r0 whose code is known to start at `label`.
+    fn jump(asm: &mut Assembler<Self::Relocation>, label: DynamicLabel);
+
+    /// Abort unless r0 holds a closure.
+    fn check_closure(asm: &mut Assembler<Self::Relocation>);
+
+    /// Abort unless the closure in r0 takes `argc` arguments.
+    fn check_arity(asm: &mut Assembler<Self::Relocation>, argc: usize);
+
+    /// The code of intrinsic `name`, called like a closure.
+    fn intrinsic(
+        asm: &mut Assembler<Self::Relocation>,
+        options: &Options,
+        rom: &rom::Layout,
+        ram_start: usize,
+        name: &str,
+    );
+
+    /// The run-time support routines, see `runtime`.
+    fn runtime(
+        asm: &mut Assembler<Self::Relocation>,
+        options: &Options,
+        rom: &rom::Layout,
+        ram_start: usize,
+    );
+}
@@ -0,0 +1,63 @@
+use crate::{
+    error::{Error, Result},
+    utils::Timer,
+    Options,
+};
+use parser::{mir::Module, timings};
+
+// The steps every backend goes through, driven by `compile_with`: decide
+// where declarations and intrinsics go, emit each of them, and put the result
+// in the backend's container. Native code for each instruction set and
+// executable format is one backend, see `native`, WebAssembly and C are the
+// others. A backend is made for one module and compiles it once.
+
+pub(crate) trait Backend {
+    /// What the backend produces.
+    type Output;
+
+    fn module(&self) -> &Module;
+
+    fn options(&self) -> &Options;
+
+    /// Decide where the declarations and intrinsics go. Returns the order to
+    /// emit the declarations in.
+    fn layout(&mut self) -> Result<Vec<usize>>;
+
+    /// Emit declaration `index`.
+    fn emit_declaration(&mut self, index: usize);
+
+    /// Emit the intrinsic of import `index`. Imports are emitted in order,
+    /// after the declarations.
+    fn emit_intrinsic(&mut self, index: usize);
+
+    /// Put what was emitted in the container.
+    fn finalize(self) -> Result<Self::Output>;
+}
+
+/// Compile the module of `backend` with it, after checking the module and
+/// options are valid.
+pub(crate) fn compile_with<B: Backend>(mut backend: B) -> Result<B::Output> {
+    let module = backend.module();
+    module.validate().map_err(Error::InvalidMir)?;
+    if !backend.options().memory.is_valid() {
+        return Err(Error::Memory(backend.options().memory));
+    }
+    let imports = module.imports.len();
+    let order = {
+        let _timer = Timer::new("Layout".to_string());
+        backend.layout()?
+    };
+    {
+        let _timer = Timer::new("Emit".to_string());
+        timings::count("declarations", order.len() as u64);
+        timings::count("intrinsics", imports as u64);
+        for index in order {
+            backend.emit_declaration(index);
+        }
+        for index in 0..imports {
+            backend.emit_intrinsic(index);
+        }
+    }
+    let _timer = Timer::new("Finalize".to_string());
+    backend.finalize()
+}
@@ -0,0 +1,620 @@
+use crate::{
+    backend::{compile_with, Backend},
+    code, intrinsics, repr,
+    rom::VERSION,
+    runtime::PANIC_EXIT_CODE,
+    Options, Result,
+};
+use parser::mir::{Declaration, Expression, Module};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+};
+
+// C source output, for platforms without a native backend and as a reference
+// to test the native backends against.
+//
+// Like the WebAssembly backend, continuation passing is done with a
+// trampoline: every declaration and intrinsic is a function in `table` that
+// sets up the registers for its call and returns, and `main` calls the closure
+// in r0 until one returns an exit code instead of `CONTINUE`.
+//
+// Values are represented as in native code, see `repr`, and so are closure
+// records, holding a table index instead of a code address. Constant closures
+// and strings are static records, closures with captures, tuples and the
+// strings of `charAt` and `substring` are allocated from a static heap. Only
+// the C99 standard library is used.
+
+/// The C source for `module`.
+pub fn compile_c(module: &Module, options: &Options) -> Result<String> {
+    compile_with(C {
+        module,
+        options,
+        main: 0,
+        head: String::new(),
+        intrinsics: String::new(),
+        declarations: String::new(),
+    })
+}
+
+/// The C backend. Intrinsics come before the declarations in the source, so
+/// each part is written on its own and they are joined at the end.
+struct C<'a> {
+    module:       &'a Module,
+    options:      &'a Options,
+    main:         usize,
+    /// Everything before the intrinsics: registers, heap, records and helpers
+    head:         String,
+    intrinsics:   String,
+    declarations: String,
+}
+
+impl Backend for C<'_> {
+    type Output = String;
+
+    fn module(&self) -> &Module {
+        self.module
+    }
+
+    fn options(&self) -> &Options {
+        self.options
+    }
+
+    fn layout(&mut self) -> Result<Vec<usize>> {
+        self.main = code::find_entry(self.module, self.options)?;
+        write_head(&mut self.head, self.module, self.options).unwrap();
+        Ok((0..self.module.declarations.len()).collect())
+    }
+
+    fn emit_declaration(&mut self, index: usize) {
+        let (module, out) = (self.module, &mut self.declarations);
+        writeln!(out).unwrap();
+        write_declaration(out, module, self.options, index, &module.declarations[index]).unwrap();
+    }
+
+    fn emit_intrinsic(&mut self, index: usize) {
+        let out = &mut self.intrinsics;
+        let name = &self.module.imports[index];
+        writeln!(out).unwrap();
+        writeln!(out, "static int import_{}_{}(void) {{", index, name).unwrap();
+        for line in intrinsic(name) {
+            writeln!(out, "    {}", line).unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+    }
+
+    fn finalize(self) -> Result<String> {
+        let mut out = self.head + &self.intrinsics + &self.declarations;
+        write_table(&mut out, self.module, self.main).unwrap();
+        Ok(out)
+    }
+}
+
+const PRELUDE: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef uint64_t value;
+
+/* Returned by a declaration to have `main` call the closure in r0 */
+#define CONTINUE (-1)
+
+#define STRING(record) ((value)(uintptr_t)&(record) + 2)
+#define CLOSURE(record) ((value)(uintptr_t)(record))
+#define RECORD(v) ((const value *)(uintptr_t)(v))
+"#;
+
+const WRITE_STRING: &str = r#"
+static void write_string(FILE *file, value string) {
+    const unsigned char *record = (const unsigned char *)(uintptr_t)(string - 2);
+    uint32_t length;
+    memcpy(&length, record, sizeof length);
+    fwrite(record + 4, 1, length, file);
+}
+"#;
+
+/// Lines of the body of the intrinsic `name`, with the same calling
+/// convention as the native ones in `x64::intrinsics`.
+fn intrinsic(name: &str) -> Vec<String> {
+    let lines = |lines: &[&str]| lines.iter().map(|line| (*line).to_string()).collect();
+    // Continue with the closure in register `ret`
+    let call = |mut body: Vec<String>, ret: usize| {
+        body.push(format!("r[0] = r[{}];", ret));
+        body.push("return CONTINUE;".to_string());
+        body
+    };
+    let abort = format!("return {};", PANIC_EXIT_CODE);
+    // Tagged indices compare like the indices
+    let in_bounds = vec![
+        "if (r[2] >= RECORD(r[1])[0]) {".to_string(),
+        format!("    {}", abort),
+        "}".to_string(),
+    ];
+    match name {
+        "exit" => lines(&["return (int)((r[1] >> 1) & 0xff);"]),
+        "print" => call(lines(&["write_string(stdout, r[1]);"]), 2),
+        "add" => call(lines(&["r[1] = r[1] + r[2] - 1;"]), 3),
+        "sub" => call(lines(&["r[1] = (r[1] - r[2]) | 1;"]), 3),
+        "mul" => call(lines(&["r[1] = ((r[1] - 1) * (r[2] >> 1)) | 1;"]), 3),
+        // Division by zero is undefined in C, abort like `div` traps
+        "divmod" => {
+            call(
+                lines(&[
+                    "value a = r[1] >> 1, b = r[2] >> 1;",
+                    "if (b == 0) {",
+                    "    abort();",
+                    "}",
+                    "r[1] = (a / b) << 1 | 1;",
+                    "r[2] = (a % b) << 1 | 1;",
+                ]),
+                3,
+            )
+        }
+        // TODO: input and parseInt are placeholders, as in native code
+        "isZero" | "input" | "parseInt" => {
+            lines(&["r[0] = r[1] == 1 ? r[2] : r[3];", "return CONTINUE;"])
+        }
+        "addChecked" => {
+            lines(&[
+                "value sum = r[1] - 1 + r[2];",
+                "r[0] = sum < r[2] ? r[4] : r[3];",
+                "r[1] = sum;",
+                "return CONTINUE;",
+            ])
+        }
+        "subChecked" => {
+            lines(&[
+                "int borrow = r[1] < r[2];",
+                "r[1] = r[1] - r[2] + 1;",
+                "r[0] = borrow ? r[4] : r[3];",
+                "return CONTINUE;",
+            ])
+        }
+        "mulChecked" => {
+            lines(&[
+                "value a = r[1] >> 1, b = r[2] - 1, product = a * b;",
+                "r[0] = a != 0 && product / a != b ? r[4] : r[3];",
+                "r[1] = product + 1;",
+                "return CONTINUE;",
+            ])
+        }
+        "neg" => call(lines(&["r[1] = 2 - r[1];"]), 2),
+        "isNegative" => {
+            lines(&[
+                "r[0] = (int64_t)r[1] < 0 ? r[2] : r[3];",
+                "return CONTINUE;",
+            ])
+        }
+        "lessSigned" => {
+            lines(&[
+                "r[0] = (int64_t)r[1] < (int64_t)r[2] ? r[3] : r[4];",
+                "return CONTINUE;",
+            ])
+        }
+        "compilerVersion" => {
+            lines(&[
+                "r[0] = r[1];",
+                "r[1] = STRING(string_version);",
+                "return CONTINUE;",
+            ])
+        }
+        "hasBuiltin" => {
+            lines(&[
+                "r[0] = has_builtin(r[1]) ? r[2] : r[3];",
+                "return CONTINUE;",
+            ])
+        }
+        "newTuple" => call(lines(&["r[1] = new_tuple(r[1]);"]), 2),
+        "tupleGet" => {
+            let mut body = in_bounds;
+            body.push("r[1] = RECORD(r[1])[1 + (r[2] >> 1)];".to_string());
+            call(body, 3)
+        }
+        "tupleSet" => {
+            let mut body = in_bounds;
+            body.push("((value *)(uintptr_t)r[1])[1 + (r[2] >> 1)] = r[3];".to_string());
+            call(body, 4)
+        }
+        "charAt" => call(lines(&["r[1] = substring(r[1], r[2], 3);"]), 3),
+        "substring" => call(lines(&["r[1] = substring(r[1], r[2], r[3]);"]), 4),
+        "abort" => vec![abort],
+        "panic" => vec!["write_string(stderr, r[1]);".to_string(), abort],
+        _ => panic!("Unknown intrinsic {}", name),
+    }
+}
+
+/// A C string literal with the bytes of `string`.
+fn literal(string: &str) -> String {
+    let mut result = String::from("\"");
+    for byte in string.bytes() {
+        if byte.is_ascii_alphanumeric() || b" .,:;!-_'".contains(&byte) {
+            result.push(byte as char);
+        } else {
+            // Octal escapes end after three digits, unlike hex escapes
+            result.push_str(&format!("\\{:03o}", byte));
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Declaration names for comments, anonymous ones named after their symbol.
+fn comment(module: &Module, symbol: usize) -> String {
+    let name = &module.symbols[symbol];
+    let name = if name.is_empty() {
+        format!("λ{}", symbol)
+    } else {
+        name.clone()
+    };
+    format!("/* {} */", name.replace("*/", "* /"))
+}
+
+fn write_record<W: Write>(out: &mut W, name: &str, string: &str) -> fmt::Result {
+    writeln!(
+        out,
+        "static const struct {{ uint32_t length; char bytes[{}]; }} {} = {{ {}, {} }};",
+        string.len() + 1,
+        name,
+        string.len(),
+        literal(string)
+    )
+}
+
+/// The `substring` helper, which allocates the record of the bytes `start` to
+/// `start + length` of `string`, clamped to it.
+fn write_substring<W: Write>(out: &mut W, options: &Options) -> fmt::Result {
+    writeln!(out)?;
+    writeln!(
+        out,
+        "static value substring(value string, value start, value length) {{"
+    )?;
+    writeln!(
+        out,
+        "    const unsigned char *record = (const unsigned char *)(uintptr_t)(string - 2);"
+    )?;
+    writeln!(out, "    unsigned char *result;")?;
+    writeln!(out, "    uint32_t size;")?;
+    writeln!(out, "    size_t words;")?;
+    writeln!(out, "    memcpy(&size, record, sizeof size);")?;
+    writeln!(out, "    start >>= 1;")?;
+    writeln!(out, "    length >>= 1;")?;
+    writeln!(out, "    if (start > size) {{")?;
+    writeln!(out, "        start = size;")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    if (length > size - start) {{")?;
+    writeln!(out, "        length = size - start;")?;
+    writeln!(out, "    }}")?;
+    writeln!(
+        out,
+        "    words = (4 + length + sizeof *heap - 1) / sizeof *heap;"
+    )?;
+    if options.memory.heap_checks {
+        writeln!(
+            out,
+            "    if (sizeof heap / sizeof *heap - heap_used < words) {{"
+        )?;
+        writeln!(out, "        fputs(\"Out of memory\\n\", stderr);")?;
+        writeln!(out, "        exit({});", PANIC_EXIT_CODE)?;
+        writeln!(out, "    }}")?;
+    }
+    writeln!(out, "    result = (unsigned char *)(heap + heap_used);")?;
+    writeln!(out, "    heap_used += words;")?;
+    writeln!(out, "    size = (uint32_t)length;")?;
+    writeln!(out, "    memcpy(result, &size, sizeof size);")?;
+    writeln!(out, "    memcpy(result + 4, record + 4 + start, length);")?;
+    writeln!(out, "    return STRING(*result);")?;
+    writeln!(out, "}}")
+}
+
+/// The `new_tuple` helper, which allocates a tuple of `length` zeros.
+fn write_new_tuple<W: Write>(out: &mut W, options: &Options) -> fmt::Result {
+    writeln!(out)?;
+    writeln!(out, "static value new_tuple(value length) {{")?;
+    writeln!(out, "    value *tuple = heap + heap_used;")?;
+    writeln!(out, "    size_t i;")?;
+    if options.memory.heap_checks {
+        writeln!(
+            out,
+            "    if (sizeof heap / sizeof *heap - heap_used <= length >> 1) {{"
+        )?;
+        writeln!(out, "        fputs(\"Out of memory\\n\", stderr);")?;
+        writeln!(out, "        exit({});", PANIC_EXIT_CODE)?;
+        writeln!(out, "    }}")?;
+    }
+    writeln!(out, "    heap_used += 1 + (length >> 1);")?;
+    writeln!(out, "    tuple[0] = length;")?;
+    writeln!(out, "    for (i = 1; i <= length >> 1; i++) {{")?;
+    writeln!(out, "        tuple[i] = 1;")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    return CLOSURE(tuple);")?;
+    writeln!(out, "}}")
+}
+
+fn write_head<W: Write>(out: &mut W, module: &Module, options: &Options) -> fmt::Result {
+    let registers = module
+        .declarations
+        .iter()
+        .map(|decl| decl.procedure.len().max(decl.call.len()))
+        .chain(
+            module
+                .imports
+                .iter()
+                .map(|name| intrinsics::arity(name) + 1),
+        )
+        .max()
+        .unwrap_or(1);
+    let memory = &options.memory;
+    let imports = |name: &str| module.imports.iter().any(|import| import == name);
+
+    writeln!(out, "/* Generated by Oluś {} */", VERSION)?;
+    write!(out, "{}", PRELUDE)?;
+    writeln!(out)?;
+    writeln!(out, "static value r[{}];", registers)?;
+    // Only closures with captures, tuples and substrings are allocated
+    let substrings = imports("charAt") || imports("substring");
+    if substrings
+        || imports("newTuple")
+        || module
+            .declarations
+            .iter()
+            .any(|decl| !decl.closure.is_empty())
+    {
+        writeln!(
+            out,
+            "static value heap[{}];",
+            (memory.heap_end() - memory.heap_offset) / 8
+        )?;
+        writeln!(out, "static size_t heap_used;")?;
+    }
+
+    // Static records, closures refer to table indices
+    writeln!(out)?;
+    for (index, string) in module.strings.iter().enumerate() {
+        write_record(out, &format!("string_{}", index), string)?;
+    }
+    if imports("compilerVersion") {
+        write_record(out, "string_version", VERSION)?;
+    }
+    for (index, decl) in module.declarations.iter().enumerate() {
+        if decl.closure.is_empty() {
+            writeln!(
+                out,
+                "static const value closure_{}[] = {{ {} }};",
+                index, index
+            )?;
+        }
+    }
+    for index in 0..module.imports.len() {
+        let entry = module.declarations.len() + index;
+        writeln!(
+            out,
+            "static const value import_{}[] = {{ {} }};",
+            index, entry
+        )?;
+    }
+
+    // Helpers and intrinsics
+    if imports("print") || imports("panic") {
+        write!(out, "{}", WRITE_STRING)?;
+    }
+    if substrings {
+        write_substring(out, options)?;
+    }
+    if imports("newTuple") {
+        write_new_tuple(out, options)?;
+    }
+    if imports("hasBuiltin") {
+        writeln!(out)?;
+        writeln!(out, "static int has_builtin(value string) {{")?;
+        write!(out, "    static const char *const names[] = {{")?;
+        for name in &intrinsics::NAMES {
+            write!(out, " {},", literal(name))?;
+        }
+        writeln!(out, " }};")?;
+        writeln!(
+            out,
+            "    const unsigned char *record = (const unsigned char *)(uintptr_t)(string - 2);"
+        )?;
+        writeln!(out, "    uint32_t length;")?;
+        writeln!(out, "    size_t i;")?;
+        writeln!(out, "    memcpy(&length, record, sizeof length);")?;
+        writeln!(
+            out,
+            "    for (i = 0; i < sizeof names / sizeof *names; i++) {{"
+        )?;
+        writeln!(
+            out,
+            "        if (strlen(names[i]) == length && memcmp(names[i], record + 4, length) == \
+             0) {{"
+        )?;
+        writeln!(out, "            return 1;")?;
+        writeln!(out, "        }}")?;
+        writeln!(out, "    }}")?;
+        writeln!(out, "    return 0;")?;
+        writeln!(out, "}}")?;
+    }
+    Ok(())
+}
+
+/// The table of declarations and intrinsics and the trampoline.
+fn write_table<W: Write>(out: &mut W, module: &Module, main: usize) -> fmt::Result {
+    writeln!(out)?;
+    writeln!(out, "static int (*const table[])(void) = {{")?;
+    for (index, decl) in module.declarations.iter().enumerate() {
+        writeln!(
+            out,
+            "    decl_{}, {}",
+            index,
+            comment(module, decl.procedure[0])
+        )?;
+    }
+    for (index, name) in module.imports.iter().enumerate() {
+        writeln!(out, "    import_{}_{},", index, name)?;
+    }
+    writeln!(out, "}};")?;
+    writeln!(out)?;
+    writeln!(out, "int main(void) {{")?;
+    writeln!(out, "    r[0] = CLOSURE(closure_{});", main)?;
+    writeln!(out, "    for (;;) {{")?;
+    writeln!(out, "        int code = table[RECORD(r[0])[0]]();")?;
+    writeln!(out, "        if (code != CONTINUE) {{")?;
+    writeln!(out, "            return code;")?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")
+}
+
+/// Closures a declaration allocates, each with its offset in words from the
+/// start of the allocation, its declaration and the values of its captures.
+struct Allocations {
+    closures: BTreeMap<usize, (usize, usize, Vec<String>)>,
+    size:     usize,
+}
+
+/// Value of `symbol` at the call, like `code::emit::goal_value`: a local if it
+/// is available or allocated, or the static record of a constant closure. New
+/// closures are added to `allocations`, each allocated once. Available symbols
+/// that are used are marked in `used`.
+fn symbol_value(
+    module: &Module,
+    available: &[usize],
+    used: &mut [bool],
+    allocations: &mut Allocations,
+    symbol: usize,
+) -> String {
+    if let Some(position) = available.iter().position(|&s| s == symbol) {
+        used[position] = true;
+        return format!("s{}", symbol);
+    }
+    if allocations.closures.contains_key(&symbol) {
+        return format!("s{}", symbol);
+    }
+    let (index, decl) = module
+        .declarations
+        .iter()
+        .enumerate()
+        .find(|decl| decl.1.procedure[0] == symbol)
+        .expect("Expected closure symbol");
+    if decl.closure.is_empty() {
+        return format!("CLOSURE(closure_{})", index);
+    }
+    // Captures are never declaration names, so they are available
+    let captures = decl
+        .closure
+        .iter()
+        .map(|&capture| symbol_value(module, available, used, allocations, capture))
+        .collect();
+    let offset = allocations.size;
+    allocations.size += 1 + decl.closure.len();
+    let _ = allocations
+        .closures
+        .insert(symbol, (offset, index, captures));
+    format!("s{}", symbol)
+}
+
+fn write_declaration<W: Write>(
+    out: &mut W,
+    module: &Module,
+    options: &Options,
+    index: usize,
+    decl: &Declaration,
+) -> fmt::Result {
+    // Parameters, including the closure itself in r0, then captures
+    let available = decl
+        .procedure
+        .iter()
+        .chain(decl.closure.iter())
+        .copied()
+        .collect::<Vec<_>>();
+    let mut used = vec![false; available.len()];
+    let mut allocations = Allocations {
+        closures: BTreeMap::new(),
+        size:     0,
+    };
+    let values = decl
+        .call
+        .iter()
+        .map(|expr| {
+            match *expr {
+                Expression::Literal(i) => format!("STRING(string_{})", i),
+                Expression::Number(n) => format!("{}u", repr::number(module.numbers[n])),
+                Expression::Import(i) => format!("CLOSURE(import_{})", i),
+                Expression::Symbol(s) => {
+                    symbol_value(module, &available, &mut used, &mut allocations, s)
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    writeln!(out, "{}", comment(module, decl.procedure[0]))?;
+    writeln!(out, "static int decl_{}(void) {{", index)?;
+    for (position, &symbol) in available.iter().enumerate() {
+        if !used[position] {
+            continue;
+        }
+        if position < decl.procedure.len() {
+            writeln!(out, "    value s{} = r[{}];", symbol, position)?;
+        } else {
+            let capture = 1 + position - decl.procedure.len();
+            writeln!(out, "    value s{} = RECORD(r[0])[{}];", symbol, capture)?;
+        }
+    }
+
+    // Bump allocate all closures at once, checking the whole size up front
+    if allocations.size > 0 {
+        let size = allocations.size;
+        writeln!(out, "    value *alloc = heap + heap_used;")?;
+        if options.memory.heap_checks {
+            writeln!(
+                out,
+                "    if (sizeof heap / sizeof *heap - heap_used < {}) {{",
+                size
+            )?;
+            writeln!(out, "        fputs(\"Out of memory\\n\", stderr);")?;
+            writeln!(out, "        return {};", PANIC_EXIT_CODE)?;
+            writeln!(out, "    }}")?;
+        }
+        writeln!(out, "    heap_used += {};", size)?;
+        for (symbol, (offset, _, _)) in &allocations.closures {
+            writeln!(out, "    value s{} = CLOSURE(alloc + {});", symbol, offset)?;
+        }
+        for (offset, closure, captures) in allocations.closures.values() {
+            writeln!(out, "    alloc[{}] = {};", offset, closure)?;
+            for (j, capture) in captures.iter().enumerate() {
+                writeln!(out, "    alloc[{}] = {};", offset + 1 + j, capture)?;
+            }
+        }
+    }
+
+    // The values only refer to locals, so registers can be set in any order
+    for (i, value) in values.iter().enumerate() {
+        writeln!(out, "    r[{}] = {};", i, value)?;
+    }
+    writeln!(out, "    return CONTINUE;")?;
+    writeln!(out, "}}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parser::parse_str;
+
+    #[test]
+    fn test_literal() {
+        assert_eq!(literal("Hi, 7"), "\"Hi, 7\"");
+        assert_eq!(literal("a\"\\\n?"), "\"a\\042\\134\\012\\077\"");
+        assert_eq!(literal("λ"), "\"\\316\\273\"");
+    }
+
+    #[test]
+    fn test_declaration() {
+        let module = parse_str("main ↦\n    add 1 2 (x ↦ print “Hi” (↦ exit x))\n").unwrap();
+        let source = compile_c(&module, &Options::default()).unwrap();
+        assert!(source.contains("    r[1] = 3u;\n"));
+        assert!(source.contains("static int import_0_add(void) {\n"));
+        // The continuation of print captures x
+        assert!(source.contains("    heap_used += 2;\n"));
+        assert!(source.contains("    value s1 = RECORD(r[0])[1];\n"));
+    }
+}
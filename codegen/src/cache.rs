@@ -0,0 +1,235 @@
+use crate::{code::Layout, codesign::sha256, Assembly, Compiled, MemoryMap, Options, Target};
+use log::{debug, warn};
+use parser::mir::Module;
+use std::{convert::TryInto, fs, path::Path, process};
+
+// On-disk cache of compiled programs, so an unchanged program does not go
+// through the transition search again. Entries hold the code layout and the
+// code, ROM and initial RAM segments, and are named by a hash of everything
+// they depend on: the module, the options, the target and the compiler
+// version. A corrupt or unreadable entry is compiled again. Entries are never
+// removed.
+
+/// The file name of the entry for compiling `module` with `options`, which
+/// already have the target's instruction set and operating system.
+fn key(module: &Module, options: &Options, target: Target) -> String {
+    let options = Options {
+        cache: None,
+        ..options.clone()
+    };
+    let description = format!(
+        "{} {}\n{:?}\n{:?}",
+        env!("CARGO_PKG_VERSION"),
+        target,
+        options,
+        module
+    );
+    sha256(description.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The layout as little endian words: the start of the run-time support, the
+/// number of declarations and imports, and then the start and end of each
+/// declaration and the start of each import.
+fn encode_layout(layout: &Layout) -> Vec<u8> {
+    let words = [
+        layout.runtime,
+        layout.declarations.len(),
+        layout.imports.len(),
+    ];
+    words
+        .iter()
+        .chain(&layout.declarations)
+        .chain(&layout.declaration_ends)
+        .chain(&layout.imports)
+        .flat_map(|&word| (word as u64).to_le_bytes().to_vec())
+        .collect()
+}
+
+fn decode_layout(bytes: &[u8]) -> Option<Layout> {
+    if bytes.len() % 8 != 0 || bytes.len() < 24 {
+        return None;
+    }
+    let words = bytes
+        .chunks(8)
+        .map(|word| u64::from_le_bytes(word.try_into().unwrap()) as usize)
+        .collect::<Vec<_>>();
+    let (declarations, imports) = (words[1], words[2]);
+    let count = declarations.checked_mul(2)?.checked_add(imports)?;
+    if words.len() != 3 + count {
+        return None;
+    }
+    let (declaration_starts, rest) = words[3..].split_at(declarations);
+    let (declaration_ends, imports) = rest.split_at(declarations);
+    Some(Layout {
+        declarations:     declaration_starts.to_vec(),
+        imports:          imports.to_vec(),
+        declaration_ends: declaration_ends.to_vec(),
+        runtime:          words[0],
+    })
+}
+
+/// The cached entry, if any, with its segments loaded from `start`.
+pub(crate) fn load(
+    dir: &Path,
+    module: &Module,
+    options: &Options,
+    target: Target,
+    start: usize,
+) -> Option<Compiled> {
+    let path = dir.join(key(module, options, target));
+    let bytes = fs::read(&path).ok()?;
+    let mut segments = Vec::new();
+    let mut rest = &bytes[..];
+    for _ in 0..4 {
+        if rest.len() < 8 {
+            break;
+        }
+        let (length, tail) = rest.split_at(8);
+        let length = u64::from_le_bytes(length.try_into().unwrap()) as usize;
+        if tail.len() < length {
+            break;
+        }
+        segments.push(tail[..length].to_vec());
+        rest = &tail[length..];
+    }
+    let layout = segments.first().and_then(|layout| decode_layout(layout));
+    let layout = match layout {
+        Some(layout) if segments.len() == 4 && rest.is_empty() => layout,
+        _ => {
+            debug!("Ignoring corrupt cache entry {}", path.display());
+            return None;
+        }
+    };
+    debug!("Using cached {}", path.display());
+    let ram = segments.pop().unwrap();
+    let rom = segments.pop().unwrap();
+    let code = segments.pop().unwrap();
+    let assembly = Assembly {
+        arch: options.arch,
+        map: MemoryMap::new(options.segment_order, start, code.len(), rom.len()),
+        memory: options.memory,
+        startup: options.startup,
+        code,
+        rom,
+        ram,
+    };
+    Some(Compiled {
+        target,
+        assembly,
+        layout,
+    })
+}
+
+/// Add the entry for `compiled`. Failing to is only logged.
+pub(crate) fn store(
+    dir: &Path,
+    module: &Module,
+    options: &Options,
+    target: Target,
+    compiled: &Compiled,
+) {
+    let path = dir.join(key(module, options, target));
+    let assembly = &compiled.assembly;
+    let layout = encode_layout(&compiled.layout);
+    let mut bytes = Vec::new();
+    for segment in &[&layout, &assembly.code, &assembly.rom, &assembly.ram] {
+        bytes.extend(&(segment.len() as u64).to_le_bytes());
+        bytes.extend(segment.iter());
+    }
+    // Write under a temporary name, so concurrent builds see whole entries
+    let temporary = path.with_extension(process::id().to_string());
+    let result = fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&temporary, bytes))
+        .and_then(|_| fs::rename(&temporary, &path));
+    if let Err(error) = result {
+        let _ = fs::remove_file(&temporary);
+        warn!("Could not cache {}: {}", path.display(), error);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{compile, compile_to_bytes};
+    use parser::parse_str;
+    use std::{env, path::PathBuf};
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("olus-cache-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = cache_dir("hit");
+        let module = parse_str("main ↦ exit 3\n").unwrap();
+        let options = Options {
+            cache: Some(dir.clone()),
+            ..Options::default()
+        };
+        let target = Target::X86_64UnknownLinuxGnu;
+        let uncached = compile_to_bytes(&module, &Options::default(), target).unwrap();
+        assert_eq!(
+            compile_to_bytes(&module, &options, target).unwrap(),
+            uncached
+        );
+        let entries = fs::read_dir(&dir).unwrap().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        let entry = entries[0].as_ref().unwrap().path();
+
+        // The entry is used instead of compiling
+        let mut bytes = fs::read(&entry).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&entry, &bytes).unwrap();
+        let cached = compile_to_bytes(&module, &options, target).unwrap();
+        assert_ne!(cached, uncached);
+        assert_eq!(cached.code, uncached.code);
+        assert_eq!(cached.map, uncached.map);
+
+        // A corrupt entry is replaced
+        fs::write(&entry, &bytes[..10]).unwrap();
+        assert_eq!(
+            compile_to_bytes(&module, &options, target).unwrap(),
+            uncached
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // The layout is cached too
+        let compiled = compile(&module, &Options::default(), target).unwrap();
+        assert_eq!(compile(&module, &options, target).unwrap(), compiled);
+        assert_eq!(decode_layout(&encode_layout(&compiled.layout)), Some(compiled.layout));
+        assert_eq!(decode_layout(&[0; 16]), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_key() {
+        let module = parse_str("main ↦ exit 3\n").unwrap();
+        let other = parse_str("main ↦ exit 4\n").unwrap();
+        let options = Options::default();
+        let target = Target::X86_64AppleDarwin;
+        let key = key(&module, &options, target);
+        assert_eq!(key.len(), 64);
+        assert_ne!(super::key(&other, &options, target), key);
+        assert_ne!(
+            super::key(&module, &options, Target::X86_64UnknownLinuxGnu),
+            key
+        );
+        let outlined = Options {
+            outline: true,
+            ..Options::default()
+        };
+        assert_ne!(super::key(&module, &outlined, target), key);
+        // The cache location is not part of the key
+        let cached = Options {
+            cache: Some(cache_dir("key")),
+            ..Options::default()
+        };
+        assert_eq!(super::key(&module, &cached, target), key);
+    }
+}
@@ -0,0 +1,255 @@
+#![allow(unsafe_code)]
+use crate::{
+    cost_model::{CostTable, Timing},
+    jit::LOAD_ADDRESS,
+    machine::{Register, Transition},
+};
+use dynasm::dynasm;
+use dynasmrt::{x64::Assembler, AssemblyOffset, DynasmApi, DynasmLabelApi};
+use std::{
+    error::Error,
+    io,
+    time::{Duration, Instant},
+};
+
+// Measure a cost table on the processor running the compiler.
+//
+// Each kind of transition is assembled into a loop of copies of itself and
+// timed. A chain of dependent additions, one cycle each on any
+// x86_64, converts the times to clock cycles.
+
+/// Copies of the transition in the body of the loop.
+const UNROLL: usize = 100;
+
+/// Times the body of the loop runs.
+const ITERATIONS: usize = 20_000;
+
+/// Best of this many runs is taken, to skip interrupts and frequency changes.
+const RUNS: usize = 7;
+
+/// Size of the scratch memory in bytes. It holds the allocator state and the
+/// slots read and written.
+const SCRATCH: usize = 4096;
+
+/// Registers the transitions rotate through. Register 4 is the stack pointer
+/// and 6 points to the scratch memory.
+const DESTINATIONS: [Register; 4] = [Register(0), Register(1), Register(2), Register(3)];
+const SCRATCH_REGISTER: Register = Register(6);
+
+/// Measure the cost of each kind of transition. Takes a fraction of a second.
+pub fn measure() -> Result<CostTable, Box<dyn Error>> {
+    let scratch = Scratch::new()?;
+    let empty = time(&scratch, &|_| {})?;
+    let cycle = time(&scratch, &|asm| {
+        for _ in 0..UNROLL {
+            dynasm!(asm; add rax, rcx);
+        }
+    })?
+    .saturating_sub(empty);
+    let cost = |kernel: &dyn Fn(usize) -> Transition| -> Result<usize, Box<dyn Error>> {
+        let elapsed = time(&scratch, &|asm| {
+            for i in 0..UNROLL {
+                kernel(i).assemble(asm, scratch.allocator());
+            }
+        })?
+        .saturating_sub(empty);
+        Ok((12.0 * elapsed.as_secs_f64() / cycle.as_secs_f64()).round() as usize)
+    };
+
+    // Throughput is measured with independent transitions, latency with a
+    // chain of dependent ones. Transitions that write no registers or read
+    // none have their throughput or a single cycle as latency.
+    let dest = |i: usize| DESTINATIONS[i % DESTINATIONS.len()];
+    let offset = |i: usize| (i % 8) as isize;
+    let alloc = cost(&|i| {
+        Transition::Alloc {
+            dest: dest(i),
+            size: 2,
+        }
+    })?;
+    let write = cost(&|i| {
+        Transition::Write {
+            dest:   SCRATCH_REGISTER,
+            offset: offset(i),
+            source: dest(i),
+        }
+    })?;
+    Ok(CostTable {
+        set:   Timing {
+            throughput: cost(&|i| {
+                Transition::Set {
+                    dest:  dest(i),
+                    value: 0x1234,
+                }
+            })?,
+            latency:    12,
+        },
+        copy:  Timing {
+            throughput: cost(&|i| {
+                Transition::Copy {
+                    dest:   dest(i),
+                    source: SCRATCH_REGISTER,
+                }
+            })?,
+            latency:    cost(&|i| {
+                Transition::Copy {
+                    dest:   dest(i + 1),
+                    source: dest(i),
+                }
+            })?,
+        },
+        // Two independent pairs, and one pair
+        swap:  Timing {
+            throughput: cost(&|i| {
+                Transition::Swap {
+                    dest:   dest(2 * i),
+                    source: dest(2 * i + 1),
+                }
+            })?,
+            latency:    cost(&|_| {
+                Transition::Swap {
+                    dest:   dest(0),
+                    source: dest(1),
+                }
+            })?,
+        },
+        // Scratch memory holds its own address, so reads can chase it.
+        read:  Timing {
+            throughput: cost(&|i| {
+                Transition::Read {
+                    dest:   dest(i),
+                    source: SCRATCH_REGISTER,
+                    offset: offset(i),
+                }
+            })?,
+            latency:    cost(&|_| {
+                Transition::Read {
+                    dest:   dest(0),
+                    source: dest(0),
+                    offset: 0,
+                }
+            })?,
+        },
+        write: Timing {
+            throughput: write,
+            latency:    write,
+        },
+        // Allocations form a chain through the free memory pointer already.
+        alloc: Timing {
+            throughput: alloc,
+            latency:    alloc,
+        },
+        drop:  Timing {
+            throughput: cost(&|i| Transition::Drop { dest: dest(i) })?,
+            latency:    0,
+        },
+        test:  Timing {
+            throughput: cost(&|i| Transition::Test { source: dest(i) })?,
+            latency:    12,
+        },
+    })
+}
+
+/// A page of memory below 2GB, where the allocator state can be addressed
+/// absolutely. Reads and writes go to its start, the free memory pointer is at
+/// its end.
+struct Scratch {
+    address: usize,
+}
+
+impl Scratch {
+    fn new() -> Result<Self, Box<dyn Error>> {
+        let memory = unsafe {
+            libc::mmap(
+                LOAD_ADDRESS as *mut libc::c_void,
+                SCRATCH,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if memory == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        let scratch = Self {
+            address: memory as usize,
+        };
+        if scratch.allocator() + 8 > i32::max_value() as usize {
+            return Err(format!("Could not map memory at {:#x}", LOAD_ADDRESS).into());
+        }
+        Ok(scratch)
+    }
+
+    fn allocator(&self) -> usize {
+        self.address + SCRATCH - 8
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.address as *mut libc::c_void, SCRATCH) };
+    }
+}
+
+/// Best run time of a function looping `ITERATIONS` times over `body`.
+fn time(scratch: &Scratch, body: &dyn Fn(&mut Assembler)) -> Result<Duration, Box<dyn Error>> {
+    let mut asm = Assembler::new()?;
+    prologue(&mut asm);
+    dynasm!(asm
+        ; mov r15d, DWORD ITERATIONS as i32
+        ; body:
+    );
+    body(&mut asm);
+    dynasm!(asm
+        ; dec r15
+        ; jnz <body
+    );
+    epilogue(&mut asm);
+    let code = asm
+        .finalize()
+        .map_err(|_| "Could not finalize the calibration code")?;
+    let entry: extern "sysv64" fn(usize) =
+        unsafe { std::mem::transmute(code.ptr(AssemblyOffset(0))) };
+    Ok((0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            entry(scratch.address);
+            start.elapsed()
+        })
+        .min()
+        .unwrap())
+}
+
+/// Save the callee-saved registers and point all registers the kernels use at
+/// the scratch memory in rdi.
+fn prologue(asm: &mut Assembler) {
+    dynasm!(asm
+        ; push rbx
+        ; push rbp
+        ; push r12
+        ; push r13
+        ; push r14
+        ; push r15
+        ; mov rax, rdi
+        ; mov rcx, rdi
+        ; mov rdx, rdi
+        ; mov rbx, rdi
+        ; mov rsi, rdi
+    );
+    for i in 0..8 {
+        dynasm!(asm; mov QWORD [rdi + 8 * i], rdi);
+    }
+}
+
+fn epilogue(asm: &mut Assembler) {
+    dynasm!(asm
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rbp
+        ; pop rbx
+        ; ret
+    );
+}
@@ -0,0 +1,700 @@
+use super::{find_entry, Layout};
+use crate::{
+    abi,
+    arch::Isa,
+    cost_model::Costs,
+    error::{finalize, new_assembler, Error, Phase, Result},
+    machine::{Allocation, State, Transition, Value},
+    outline::{outline, Outlined, Step},
+    placement::Placement,
+    repr, rom, runtime,
+    utils::Timer,
+    Instrument, Options, Set,
+};
+use dynasmrt::{Assembler, DynamicLabel, DynasmApi, DynasmLabelApi};
+use log::debug;
+use parser::mir::{Declaration, Expression, Module};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Where to find a particular expression
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
+enum Source {
+    Constant(u64),
+    Register(usize),
+    Closure(usize), // Value from current closure
+    Alloc(usize),   // New closure for decl
+    None,
+}
+
+struct Context<'a, I: Isa> {
+    module:     &'a Module,
+    options:    &'a Options,
+    code_start: usize,
+    code:       &'a Layout,
+    rom:        &'a rom::Layout,
+    ram_start:  usize,
+    /// For every declaration, the one whose code it shares
+    shared:     &'a [usize],
+    asm:        &'a mut Assembler<I::Relocation>,
+}
+
+impl<'a, I: Isa> Context<'a, I> {
+    fn find_decl(&self, symbol: usize) -> Option<(usize, &'a Declaration)> {
+        self.module
+            .declarations
+            .iter()
+            .enumerate()
+            .find(|decl| decl.1.procedure[0] == symbol)
+    }
+}
+
+fn closure_val<I: Isa>(ctx: &Context<'_, I>, symbol: usize) -> Vec<Value> {
+    let (index, decl) = ctx.find_decl(symbol).expect("Expected closure symbol");
+    let mut result = vec![Value::Literal(ctx.code.declarations[index] as u64)];
+    if ctx.options.debug_runtime {
+        let arity = decl.procedure.len() - 1;
+        let name = ctx.rom.names[index];
+        result.push(Value::Literal(repr::closure_info(arity, name)));
+    }
+    for symbol in &decl.closure {
+        result.push(Value::Symbol(*symbol));
+    }
+    result
+}
+
+/// Value of `symbol` in the goal state: the symbol itself if it is available,
+/// the ROM record of a constant closure, or a new allocation. Closures capture
+/// no declaration names, see `Module::validate`, so the captures of a new
+/// closure are always available and never new closures themselves.
+/// Allocations are keyed by the declaration whose code they share, so a
+/// closure used more than once, or closures of declarations sharing code, are
+/// allocated once and referred to from every place they are used.
+fn goal_value<I: Isa>(
+    ctx: &Context<'_, I>,
+    available: &Set<usize>,
+    allocated: &mut BTreeMap<usize, Value>,
+    goal: &mut State,
+    symbol: usize,
+) -> Value {
+    if available.contains(&symbol) {
+        return Value::Symbol(symbol);
+    }
+    let (index, decl) = ctx.find_decl(symbol).expect("Expected closure symbol");
+    if decl.closure.is_empty() {
+        // The address of the record and not of the code: a closure is a
+        // value like any other, callees that receive it call it through
+        // `jmp QWORD [r0]` and the debug runtime reads its arity from the
+        // record, see `repr`. Calls to a known declaration already jump to
+        // its code directly, see `direct_callee`.
+        return Value::Literal(ctx.rom.closures[index] as u64);
+    }
+    let shared = ctx.shared[index];
+    if let Some(&value) = allocated.get(&shared) {
+        return value;
+    }
+    let value = Value::Reference {
+        index:  goal.allocations.len(),
+        offset: 0,
+    };
+    let _ = allocated.insert(shared, value);
+    goal.allocations.push(Allocation::new(closure_val(ctx, symbol)));
+    value
+}
+
+/// Whether the call is `isZero n z nz` on a parameter or capture `n`, which is
+/// branched on inline: the flags are set by testing `n` and a conditional
+/// move picks `z` in r0 or `nz` in r1 to call, without going through the
+/// intrinsic.
+fn is_inline_branch(module: &Module, decl: &Declaration) -> bool {
+    match decl.call[..] {
+        [Expression::Import(import), Expression::Symbol(n), _, _] => {
+            module.imports[import] == "isZero" && !module.names[n]
+        }
+        _ => false,
+    }
+}
+
+/// Whether the declaration calls itself with as many arguments as it takes.
+/// The current closure then stays in r0, and with the direct jump of
+/// [`direct_callee`] a recursive procedure is a loop.
+fn is_self_call(decl: &Declaration) -> bool {
+    decl.call.first() == Some(&Expression::Symbol(decl.procedure[0]))
+        && decl.call.len() == decl.procedure.len()
+}
+
+/// The declaration called, if it is known statically and takes as many
+/// arguments as it is given. Its code is then jumped to directly, without
+/// loading the address from the closure or checking it at run time.
+fn direct_callee(module: &Module, decl: &Declaration) -> Option<usize> {
+    match decl.call.first() {
+        Some(&Expression::Symbol(symbol)) => module.declarations.iter().position(|callee| {
+            callee.procedure[0] == symbol && callee.procedure.len() == decl.call.len()
+        }),
+        _ => None,
+    }
+}
+
+/// The state the declaration's code starts in: the procedure passed as
+/// [`abi::pass`] does, with the current closure expanded unless it is
+/// constant.
+fn initial_state<I: Isa>(ctx: &Context<'_, I>, decl: &Declaration) -> State {
+    let mut initial = State::default();
+    let mut procedure = decl
+        .procedure
+        .iter()
+        .map(|&symbol| Value::Symbol(symbol))
+        .collect::<Vec<_>>();
+    if !decl.closure.is_empty() {
+        initial
+            .allocations
+            .push(Allocation::new(closure_val(ctx, decl.procedure[0])));
+        procedure[0] = Value::Reference {
+            index:  0,
+            offset: 0,
+        };
+    }
+    abi::pass(&mut initial, procedure);
+    initial.count_references();
+    initial
+}
+
+/// The state the declaration's code ends in: the call with closures expanded
+/// as needed, passed as [`abi::pass`] does. Constant closures are referred to
+/// in ROM instead. Inline branches only need the flags and the two
+/// continuations.
+fn goal_state<I: Isa>(ctx: &Context<'_, I>, decl: &Declaration, initial: &State) -> State {
+    let available = initial.symbols();
+    let mut goal = State::default();
+    let mut allocated = BTreeMap::new();
+    if is_self_call(decl) && !decl.closure.is_empty() {
+        // Keep the current closure instead of allocating a copy
+        let (index, _) = ctx.find_decl(decl.procedure[0]).unwrap();
+        let _ = allocated.insert(ctx.shared[index], initial.registers[0]);
+        goal.allocations
+            .push(Allocation::new(closure_val(ctx, decl.procedure[0])));
+    }
+    let call = if is_inline_branch(ctx.module, decl) {
+        if let Expression::Symbol(n) = decl.call[1] {
+            goal.flags = Value::Symbol(n);
+        }
+        &decl.call[2..]
+    } else {
+        &decl.call[..]
+    };
+    let values = call
+        .iter()
+        .map(|expr| {
+            match *expr {
+                Expression::Literal(i) => Value::Literal(repr::string(ctx.rom.strings[i])),
+                Expression::Number(n) => Value::Literal(repr::number(ctx.module.numbers[n])),
+                Expression::Import(i) => Value::Literal(ctx.rom.imports[i] as u64),
+                Expression::Symbol(s) => goal_value(ctx, &available, &mut allocated, &mut goal, s),
+            }
+        })
+        .collect::<Vec<_>>();
+    abi::pass(&mut goal, values);
+    goal
+}
+
+/// Find the transitions from the declaration's entry state to its call.
+fn plan_decl<I: Isa>(ctx: &mut Context<'_, I>, decl: &Declaration) -> Result<Vec<Transition>> {
+    let name = &ctx.module.symbols[decl.procedure[0]];
+    let name = if name.is_empty() {
+        format!("λ{}", decl.procedure[0])
+    } else {
+        name.clone()
+    };
+    let _timer = Timer::new(format!("Planning {}", name));
+    let initial = initial_state(ctx, decl);
+    debug!("Initial:\n{}", initial);
+    let goal = goal_state(ctx, decl, &initial);
+    debug!("Goal:\n{}", goal);
+
+    // Transition into the correct machine state. The greedy plan falls back
+    // to the search for the few goals it does not find a way to. The search
+    // does not finish for goals with more values than fit in registers, like
+    // calls passing an argument block, so those are planned greedily either
+    // way and fail if that does.
+    let costs = Costs::new(ctx.options);
+    let values = goal.symbols().len() + goal.literals().len();
+    let searchable = values <= abi::REGISTERS;
+    let path = if ctx.options.optimize && searchable {
+        initial.transition_to(&goal, &costs)
+    } else if let Some(path) = initial.greedy_transition_to(&goal, &costs) {
+        path
+    } else if searchable {
+        initial.transition_to(&goal, &costs)
+    } else {
+        return Err(Error::Plan(name));
+    };
+    debug!("Path: {:?}", path);
+    Ok(path)
+}
+
+/// Bytes allocated on `path`, including by the stubs it calls.
+fn allocation_size(path: &[Step], stubs: &[Vec<Transition>]) -> usize {
+    let size = |transition: &Transition| {
+        match *transition {
+            Transition::Alloc { size, .. } => 8 * size,
+            _ => 0,
+        }
+    };
+    path.iter()
+        .map(|step| {
+            match *step {
+                Step::Transition(ref transition) => size(transition),
+                Step::Stub(stub) => stubs[stub].iter().map(size).sum(),
+            }
+        })
+        .sum()
+}
+
+/// Assemble declaration `index`. With `fallthrough` the callee is the
+/// declaration emitted next and the final jump is left out. `heap` is the
+/// number of bytes the path allocates, checked up front so no allocation is
+/// written to before the check. `entries` label the code of every
+/// declaration.
+fn assemble_decl<I: Isa>(
+    ctx: &mut Context<'_, I>,
+    index: usize,
+    path: &[Step],
+    stubs: &[DynamicLabel],
+    entries: &[DynamicLabel],
+    heap: usize,
+    fallthrough: bool,
+) {
+    let decl = &ctx.module.declarations[index];
+    if runtime::records_declaration(ctx.options) {
+        I::enter_declaration(ctx.asm, ctx.ram_start, index);
+    }
+    match ctx.options.instrument {
+        Instrument::None => {}
+        Instrument::Trace => {
+            let return_address = ctx.code_start + ctx.asm.offset().0 + I::CALL_SIZE;
+            I::call_trace(ctx.asm, ctx.ram_start, return_address);
+        }
+        Instrument::Counts => {
+            let counters = ctx.ram_start + ctx.options.memory.heap_offset;
+            I::increment(ctx.asm, counters + 8 * index);
+        }
+    }
+    if ctx.options.memory.heap_checks && heap > 0 {
+        I::heap_check(ctx.asm, ctx.ram_start, &ctx.options.memory, heap);
+    }
+    for step in path {
+        match *step {
+            Step::Transition(transition) => I::transition(ctx.asm, &transition, ctx.ram_start),
+            Step::Stub(stub) => {
+                let return_address = ctx.code_start + ctx.asm.offset().0 + I::CALL_SIZE;
+                I::call_stub(ctx.asm, ctx.ram_start, stubs[stub], return_address);
+            }
+        }
+    }
+
+    // Call the closure, a known callee needs no checks
+    if let Some(callee) = direct_callee(ctx.module, decl) {
+        if !fallthrough {
+            I::jump(ctx.asm, entries[callee]);
+        }
+        return;
+    }
+    let arity = if is_inline_branch(ctx.module, decl) {
+        I::select_on_zero(ctx.asm);
+        0
+    } else {
+        decl.call.len() - 1
+    };
+    if ctx.options.debug_runtime {
+        I::check_closure(ctx.asm);
+        I::check_arity(ctx.asm, arity);
+    }
+    if !fallthrough {
+        I::call(ctx.asm);
+    }
+}
+
+/// Assembles the code of a module one part at a time: the prelude when
+/// created, then each declaration in the placement order and each intrinsic,
+/// and the run-time support and outlined stubs when finished. Declarations are
+/// planned and outlined up front, since stubs are shared between them.
+pub(crate) struct Emitter<'a, I: Isa> {
+    module:     &'a Module,
+    options:    Options,
+    code_start: usize,
+    code:       Layout,
+    rom:        rom::Layout,
+    ram_start:  usize,
+    placement:  Placement,
+    phase:      Phase,
+    asm:        Assembler<I::Relocation>,
+    outlined:   Outlined,
+    stubs:      Vec<DynamicLabel>,
+    entries:    Vec<DynamicLabel>,
+    /// Where the emitted code went
+    layout:     Layout,
+}
+
+impl<'a, I: Isa> Emitter<'a, I> {
+    /// Plan the declarations and assemble the prelude, for code at
+    /// `code_start` referring to the `code` and `rom` layouts.
+    pub(crate) fn new(
+        module: &'a Module,
+        options: &Options,
+        code_start: usize,
+        code: &Layout,
+        rom: &rom::Layout,
+        ram_start: usize,
+        placement: &Placement,
+        phase: Phase,
+    ) -> Result<Self> {
+        assert_eq!(rom.closures.len(), module.declarations.len());
+        assert_eq!(rom.imports.len(), module.imports.len());
+        assert_eq!(rom.strings.len(), module.strings.len());
+        assert_eq!(code.declarations.len(), module.declarations.len());
+        assert_eq!(code.imports.len(), module.imports.len());
+        assert_eq!(placement.shared.len(), module.declarations.len());
+
+        let mut asm = new_assembler::<I::Relocation>(phase)?;
+        let main_index = find_entry(module, options)?;
+        let main = &module.declarations[main_index];
+        assert_eq!(main.closure.len(), 0);
+
+        // The address is always encoded in 32 bits, so the code size does not
+        // depend on `ram_start`.
+        let ram_end = ram_start + options.memory.ram_size();
+        if ram_end > (i32::max_value() as usize) {
+            return Err(Error::Memory(options.memory));
+        }
+        // Prelude, write the stack pointer to RAM[END-8]. End of ram is
+        // initialized with the OS provided stack frame. Then jump to main.
+        let fallthrough = placement.order.first() == Some(&main_index);
+        I::prelude(&mut asm, ram_end, rom.closures[main_index], fallthrough);
+
+        let mut ctx = Context::<I> {
+            module,
+            options,
+            code_start,
+            code,
+            rom,
+            ram_start,
+            shared: &placement.shared,
+            asm: &mut asm,
+        };
+        // Declarations, those sharing code with another one are not planned
+        let paths = module
+            .declarations
+            .iter()
+            .enumerate()
+            .map(|(index, decl)| {
+                if placement.shared[index] == index {
+                    plan_decl(&mut ctx, decl)
+                } else {
+                    Ok(Vec::new())
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let outlined = if options.outline {
+            outline(&paths)
+        } else {
+            Outlined::new(&paths)
+        };
+        let stubs = outlined
+            .stubs
+            .iter()
+            .map(|_| asm.new_dynamic_label())
+            .collect::<Vec<_>>();
+        let labels = module
+            .declarations
+            .iter()
+            .map(|_| asm.new_dynamic_label())
+            .collect::<Vec<_>>();
+        let entries = placement
+            .shared
+            .iter()
+            .map(|&shared| labels[shared])
+            .collect::<Vec<_>>();
+        Ok(Self {
+            module,
+            options: options.clone(),
+            code_start,
+            code: code.clone(),
+            rom: rom.clone(),
+            ram_start,
+            placement: placement.clone(),
+            phase,
+            asm,
+            outlined,
+            stubs,
+            entries,
+            layout: Layout {
+                declarations:     vec![0; module.declarations.len()],
+                imports:          Vec::new(),
+                declaration_ends: vec![0; module.declarations.len()],
+                runtime:          0,
+            },
+        })
+    }
+
+    /// Assemble declaration `index`, which is the next one in the placement
+    /// order.
+    pub(crate) fn declaration(&mut self, index: usize) {
+        self.layout.declarations[index] = self.code_start + self.asm.offset().0;
+        self.asm.dynamic_label(self.entries[index]);
+        let path = &self.outlined.paths[index];
+        let heap = allocation_size(path, &self.outlined.stubs);
+        let fallthrough = self.placement.fallthrough[index];
+        let mut ctx = Context::<I> {
+            module:     self.module,
+            options:    &self.options,
+            code_start: self.code_start,
+            code:       &self.code,
+            rom:        &self.rom,
+            ram_start:  self.ram_start,
+            shared:     &self.placement.shared,
+            asm:        &mut self.asm,
+        };
+        assemble_decl(&mut ctx, index, path, &self.stubs, &self.entries, heap, fallthrough);
+        self.layout.declaration_ends[index] = self.code_start + self.asm.offset().0;
+    }
+
+    /// Assemble the intrinsic of import `index`, after the declarations and
+    /// the intrinsics of the imports before it.
+    pub(crate) fn intrinsic(&mut self, index: usize) {
+        assert_eq!(self.layout.imports.len(), index);
+        self.layout.imports.push(self.code_start + self.asm.offset().0);
+        let name = &self.module.imports[index];
+        I::intrinsic(&mut self.asm, &self.options, &self.rom, self.ram_start, name);
+    }
+
+    /// Assemble the run-time support and the outlined stubs, returning the
+    /// code and where everything in it is.
+    pub(crate) fn finish(mut self) -> Result<(Vec<u8>, Layout)> {
+        for (index, &shared) in self.placement.shared.iter().enumerate() {
+            self.layout.declarations[index] = self.layout.declarations[shared];
+            self.layout.declaration_ends[index] = self.layout.declaration_ends[shared];
+        }
+        self.layout.runtime = self.code_start + self.asm.offset().0;
+        if runtime::is_needed(&self.options) {
+            I::runtime(&mut self.asm, &self.options, &self.rom, self.ram_start);
+        }
+        // Outlined stubs
+        for (&label, stub) in self.stubs.iter().zip(self.outlined.stubs.iter()) {
+            self.asm.dynamic_label(label);
+            for transition in stub {
+                I::transition(&mut self.asm, transition, self.ram_start);
+            }
+            I::stub_return(&mut self.asm, self.ram_start);
+        }
+        Ok((finalize(self.asm, self.phase)?, self.layout))
+    }
+}
+
+pub(crate) fn compile<I: Isa>(
+    module: &Module,
+    options: &Options,
+    code_start: usize,
+    code: &Layout,
+    rom: &rom::Layout,
+    ram_start: usize,
+    placement: &Placement,
+    phase: Phase,
+) -> Result<(Vec<u8>, Layout)> {
+    let _timer = Timer::new(format!("{:?} pass", phase));
+    let mut emitter =
+        Emitter::<I>::new(module, options, code_start, code, rom, ram_start, placement, phase)?;
+    for &index in &placement.order {
+        emitter.declaration(index);
+    }
+    for index in 0..module.imports.len() {
+        emitter.intrinsic(index);
+    }
+    emitter.finish()
+}
+
+/// The goal state of every declaration, for the final `code` and `rom`
+/// layouts.
+pub(crate) fn goals<I: Isa>(
+    module: &Module,
+    options: &Options,
+    code_start: usize,
+    code: &Layout,
+    rom: &rom::Layout,
+    ram_start: usize,
+) -> Result<Vec<State>> {
+    // Nothing is assembled, the context just needs one
+    let mut asm = new_assembler::<I::Relocation>(Phase::Code)?;
+    let shared = rom::canonical(module, options);
+    let ctx = Context::<I> {
+        module,
+        options,
+        code_start,
+        code,
+        rom,
+        ram_start,
+        shared: &shared,
+        asm: &mut asm,
+    };
+    Ok(module
+        .declarations
+        .iter()
+        .map(|decl| {
+            let mut goal = goal_state(&ctx, decl, &initial_state(&ctx, decl));
+            goal.count_references();
+            goal
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{macho::CODE_START, x64::X64};
+    use parser::{parse_mir, parse_str};
+    use std::{env, fmt::Write, fs, path::Path};
+
+    /// The transitions the search plans for every declaration, against dummy
+    /// layouts so they do not depend on code size.
+    fn render_transitions(module: &Module) -> String {
+        let options = Options {
+            optimize: true,
+            ..Options::default()
+        };
+        let code = Layout::dummy(module, CODE_START);
+        let rom = rom::Layout::dummy(module, &options);
+        let mut asm = new_assembler(Phase::Layout).unwrap();
+        let shared = rom::canonical(module, &options);
+        let mut ctx = Context::<X64> {
+            module,
+            options: &options,
+            code_start: CODE_START,
+            code: &code,
+            rom: &rom,
+            ram_start: 0,
+            shared: &shared,
+            asm: &mut asm,
+        };
+        let mut out = String::new();
+        for decl in &module.declarations {
+            let name = &module.symbols[decl.procedure[0]];
+            writeln!(out, "declaration {:?} {:?}", decl.procedure, name).unwrap();
+            for transition in plan_decl(&mut ctx, decl).unwrap() {
+                writeln!(out, "    {:?}", transition).unwrap();
+            }
+        }
+        out
+    }
+
+    /// Plan the end-to-end test programs and compare the transitions with the
+    /// checked in `tests/e2e/<name>.transitions` files. Run with `BLESS=1` to
+    /// update them.
+    #[test]
+    fn golden() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/e2e");
+        let mut paths = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .map_or(false, |ext| ext == "olus" || ext == "mir")
+            })
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert!(!paths.is_empty());
+        let mut ok = true;
+        for path in paths {
+            let source = fs::read_to_string(&path).unwrap();
+            let module = if path.extension().unwrap() == "mir" {
+                parse_mir(&source).unwrap()
+            } else {
+                parse_str(&source).unwrap()
+            };
+            let actual = render_transitions(&module);
+            let expected = path.with_extension("transitions");
+            if env::var_os("BLESS").is_some() {
+                fs::write(&expected, actual).unwrap();
+            } else if fs::read_to_string(&expected).unwrap_or_default() != actual {
+                eprintln!("Mismatch in {}:\n{}", expected.display(), actual);
+                ok = false;
+            }
+        }
+        assert!(
+            ok,
+            "Golden files differ, rerun with BLESS=1 to update them."
+        );
+    }
+
+    #[test]
+    fn test_constant_closure() {
+        let module = parse_mir("main ↦ print “a” λ0\nλ0 ↦ exit 0\n").unwrap();
+        let transitions = render_transitions(&module);
+        let rom = rom::Layout::dummy(&module, &Options::default());
+        assert!(!transitions.contains("Alloc"));
+        assert!(transitions.contains(&format!("value: {} }}", rom.closures[1])));
+    }
+
+    #[test]
+    fn test_inline_branch() {
+        let module = parse_mir("main n ↦ isZero n λ0 λ1\nλ0 ↦ exit 0\nλ1 ↦ exit n\n").unwrap();
+        assert!(is_inline_branch(&module, &module.declarations[0]));
+        let transitions = render_transitions(&module);
+        // Allocating clobbers the flags, so the test comes after it
+        let main = transitions
+            .lines()
+            .skip(1)
+            .take_while(|line| line.starts_with(' '))
+            .collect::<Vec<_>>();
+        let test = main.iter().position(|line| line.contains("Test { source: Register(1) }"));
+        let alloc = main.iter().position(|line| line.contains("Alloc"));
+        assert!(alloc.is_some() && test > alloc);
+        let module = parse_mir("main ↦ isZero λ0 λ0 λ0\nλ0 ↦ exit 0\n").unwrap();
+        assert!(!is_inline_branch(&module, &module.declarations[0]));
+    }
+
+    #[test]
+    fn test_self_call() {
+        let module = parse_mir("main x ↦ λ0 1\nλ0 a ↦ λ0 x\nf ↦ f 1\n").unwrap();
+        assert!(is_self_call(&module.declarations[1]));
+        // Wrong arity is left to the run-time checks
+        assert!(!is_self_call(&module.declarations[2]));
+        // The loop keeps the closure capturing `x` in r0
+        let transitions = render_transitions(&module);
+        let lambda = transitions
+            .lines()
+            .skip_while(|line| !line.starts_with("declaration [2, 3]"))
+            .skip(1)
+            .take_while(|line| line.starts_with(' '))
+            .collect::<Vec<_>>();
+        assert_eq!(lambda, vec![
+            "    Read { dest: Register(1), source: Register(0), offset: 1 }"
+        ]);
+    }
+
+    #[test]
+    fn test_direct_callee() {
+        let module = parse_mir("f a ↦ exit a
+main x ↦ f x
+g ↦ f
+h k ↦ k 1
+").unwrap();
+        let callees = module
+            .declarations
+            .iter()
+            .map(|decl| direct_callee(&module, decl))
+            .collect::<Vec<_>>();
+        // Intrinsics, wrong arities and parameters are called through r0
+        assert_eq!(callees, vec![None, Some(0), None, None]);
+    }
+
+    #[test]
+    fn test_shared_allocation() {
+        let module = parse_mir("main x ↦ f λ0 λ0\nλ0 ↦ exit x\n").unwrap();
+        let transitions = render_transitions(&module);
+        assert_eq!(transitions.matches("Alloc").count(), 1);
+
+        // Closures of declarations sharing code, with the same captures
+        let module = parse_mir("main x ↦ f λ0 λ1\nλ0 a ↦ exit x\nλ1 b ↦ exit x\n").unwrap();
+        let transitions = render_transitions(&module);
+        assert_eq!(transitions.matches("Alloc").count(), 1);
+    }
+}
@@ -0,0 +1,57 @@
+#[cfg(feature = "native")]
+mod emit;
+
+#[cfg(feature = "native")]
+pub(crate) use emit::{compile, goals, Emitter};
+
+use crate::{
+    error::{Error, Result},
+    Options,
+};
+use parser::mir::{Module, DEFAULT_ENTRY};
+use serde::{Deserialize, Serialize};
+
+// Where the code of each declaration goes and where the program starts, which
+// all backends need. Emitting native code is in `emit`.
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Layout {
+    pub(crate) declarations:     Vec<usize>,
+    pub(crate) imports:          Vec<usize>,
+    /// End of the code of each declaration. Declarations sharing code share
+    /// the end too.
+    pub(crate) declaration_ends: Vec<usize>,
+    /// Start of the run-time support and outlined stubs following the
+    /// intrinsics.
+    pub(crate) runtime:          usize,
+}
+
+#[cfg(feature = "native")]
+impl Layout {
+    pub(crate) fn dummy(module: &Module, code_start: usize) -> Layout {
+        const DUMMY_SIZE: usize = 1 << 10; // ~ 1kiB of code
+        let declarations: Vec<usize> = (0..module.declarations.len())
+            .map(|i| code_start + i * DUMMY_SIZE)
+            .collect();
+        let imports: Vec<usize> = (0..module.imports.len())
+            .map(|i| declarations.last().unwrap() + (i + 1) * DUMMY_SIZE)
+            .collect();
+        let declaration_ends = declarations
+            .iter()
+            .map(|start| start + DUMMY_SIZE)
+            .collect();
+        let runtime = declarations.last().unwrap() + (imports.len() + 1) * DUMMY_SIZE;
+        Layout {
+            declarations,
+            imports,
+            declaration_ends,
+            runtime,
+        }
+    }
+}
+
+/// Index of the declaration the program starts at.
+pub(crate) fn find_entry(module: &Module, options: &Options) -> Result<usize> {
+    let name = options.entry.as_deref().unwrap_or(DEFAULT_ENTRY);
+    module.entry(name).map_err(Error::Entry)
+}
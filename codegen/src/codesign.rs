@@ -0,0 +1,224 @@
+use std::convert::TryInto;
+
+// Ad-hoc code signatures for Mach-O executables, like the ones `ld` adds. Such
+// a signature names no signer, it only holds a hash of every page of the file
+// before it. XNU refuses to run unsigned code on Apple Silicon and with a
+// hardened runtime. The blobs are big endian, unlike the rest of the file.
+// See <https://github.com/apple-oss-distributions/xnu/blob/main/osfmk/kern/cs_blobs.h>
+
+/// Identifier of the signed code.
+const IDENTIFIER: &[u8] = b"olus\0";
+
+/// Pages are hashed in 4 KiB units, regardless of the page size.
+const PAGE_SHIFT: u8 = 12;
+
+const HASH_SIZE: usize = 32;
+const HASH_TYPE_SHA256: u8 = 2;
+
+const MAGIC_EMBEDDED_SIGNATURE: u32 = 0xfade_0cc0;
+const MAGIC_CODE_DIRECTORY: u32 = 0xfade_0c02;
+const SLOT_CODE_DIRECTORY: u32 = 0;
+
+/// Code directory version with the executable segment fields.
+const VERSION: u32 = 0x0002_0400;
+const FLAG_ADHOC: u32 = 0x0000_0002;
+const FLAG_LINKER_SIGNED: u32 = 0x0002_0000;
+const EXEC_SEGMENT_MAIN_BINARY: u64 = 1;
+
+/// Super blob header and its one index entry.
+const SUPER_BLOB_SIZE: usize = 12 + 8;
+
+/// Code directory header, up to version `VERSION`.
+const CODE_DIRECTORY_SIZE: usize = 88;
+
+fn num_pages(code_limit: usize) -> usize {
+    (code_limit + (1 << PAGE_SHIFT) - 1) >> PAGE_SHIFT
+}
+
+/// Size in bytes of the signature of the first `code_limit` bytes of a file.
+pub(crate) fn size(code_limit: usize) -> usize {
+    SUPER_BLOB_SIZE + CODE_DIRECTORY_SIZE + IDENTIFIER.len() + HASH_SIZE * num_pages(code_limit)
+}
+
+/// The signature of `file`, to be appended to it. `exec_segment` is the file
+/// range of the executable segment holding the headers.
+pub(crate) fn sign(file: &[u8], exec_segment: (usize, usize)) -> Vec<u8> {
+    let length = size(file.len());
+    let directory_length = length - SUPER_BLOB_SIZE;
+    let hash_offset = CODE_DIRECTORY_SIZE + IDENTIFIER.len();
+    let mut blob = Vec::with_capacity(length);
+    let word = |blob: &mut Vec<u8>, value: usize| {
+        blob.extend(&(value as u32).to_be_bytes());
+    };
+
+    // Super blob
+    word(&mut blob, MAGIC_EMBEDDED_SIGNATURE as usize);
+    word(&mut blob, length);
+    word(&mut blob, 1);
+    word(&mut blob, SLOT_CODE_DIRECTORY as usize);
+    word(&mut blob, SUPER_BLOB_SIZE);
+
+    // Code directory
+    word(&mut blob, MAGIC_CODE_DIRECTORY as usize);
+    word(&mut blob, directory_length);
+    word(&mut blob, VERSION as usize);
+    word(&mut blob, (FLAG_ADHOC | FLAG_LINKER_SIGNED) as usize);
+    word(&mut blob, hash_offset);
+    word(&mut blob, CODE_DIRECTORY_SIZE);
+    word(&mut blob, 0); // Special slots
+    word(&mut blob, num_pages(file.len()));
+    word(&mut blob, file.len()); // Code limit
+    blob.extend(&[HASH_SIZE as u8, HASH_TYPE_SHA256, 0, PAGE_SHIFT]);
+    word(&mut blob, 0); // Spare
+    word(&mut blob, 0); // Scatter offset
+    word(&mut blob, 0); // Team offset
+    word(&mut blob, 0); // Spare
+    blob.extend(&0_u64.to_be_bytes()); // 64 bit code limit
+    blob.extend(&(exec_segment.0 as u64).to_be_bytes());
+    blob.extend(&(exec_segment.1 as u64).to_be_bytes());
+    blob.extend(&EXEC_SEGMENT_MAIN_BINARY.to_be_bytes());
+    assert_eq!(blob.len(), SUPER_BLOB_SIZE + CODE_DIRECTORY_SIZE);
+    blob.extend(IDENTIFIER);
+    for page in file.chunks(1 << PAGE_SHIFT) {
+        blob.extend(&sha256(page));
+    }
+    assert_eq!(blob.len(), length);
+    blob
+}
+
+/// SHA-256 of `data`.
+/// See <https://nvlpubs.nist.gov/nistpubs/FIPS/NIST.FIPS.180-4.pdf>
+pub(crate) fn sha256(data: &[u8]) -> [u8; HASH_SIZE] {
+    #[rustfmt::skip]
+    const K: [u32; 64] = [
+        0x428a_2f98, 0x7137_4491, 0xb5c0_fbcf, 0xe9b5_dba5,
+        0x3956_c25b, 0x59f1_11f1, 0x923f_82a4, 0xab1c_5ed5,
+        0xd807_aa98, 0x1283_5b01, 0x2431_85be, 0x550c_7dc3,
+        0x72be_5d74, 0x80de_b1fe, 0x9bdc_06a7, 0xc19b_f174,
+        0xe49b_69c1, 0xefbe_4786, 0x0fc1_9dc6, 0x240c_a1cc,
+        0x2de9_2c6f, 0x4a74_84aa, 0x5cb0_a9dc, 0x76f9_88da,
+        0x983e_5152, 0xa831_c66d, 0xb003_27c8, 0xbf59_7fc7,
+        0xc6e0_0bf3, 0xd5a7_9147, 0x06ca_6351, 0x1429_2967,
+        0x27b7_0a85, 0x2e1b_2138, 0x4d2c_6dfc, 0x5338_0d13,
+        0x650a_7354, 0x766a_0abb, 0x81c2_c92e, 0x9272_2c85,
+        0xa2bf_e8a1, 0xa81a_664b, 0xc24b_8b70, 0xc76c_51a3,
+        0xd192_e819, 0xd699_0624, 0xf40e_3585, 0x106a_a070,
+        0x19a4_c116, 0x1e37_6c08, 0x2748_774c, 0x34b0_bcb5,
+        0x391c_0cb3, 0x4ed8_aa4a, 0x5b9c_ca4f, 0x682e_6ff3,
+        0x748f_82ee, 0x78a5_636f, 0x84c8_7814, 0x8cc7_0208,
+        0x90be_fffa, 0xa450_6ceb, 0xbef9_a3f7, 0xc671_78f2,
+    ];
+    let mut state: [u32; 8] = [
+        0x6a09_e667,
+        0xbb67_ae85,
+        0x3c6e_f372,
+        0xa54f_f53a,
+        0x510e_527f,
+        0x9b05_688c,
+        0x1f83_d9ab,
+        0x5be0_cd19,
+    ];
+
+    // Pad with a one bit, zeros and the length in bits to whole blocks
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend(&(8 * data.len() as u64).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0_u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for (k, w) in K.iter().zip(w.iter()) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(*k)
+                .wrapping_add(*w);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(majority);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in state.iter_mut().zip(&[a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(*value);
+        }
+    }
+
+    let mut hash = [0; HASH_SIZE];
+    for (bytes, word) in hash.chunks_mut(4).zip(state.iter()) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // Spans several blocks
+        assert_eq!(
+            hex(&sha256(&[b'a'; 1000])),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        let file = (0..10_000).map(|i| i as u8).collect::<Vec<_>>();
+        let signature = sign(&file, (0, 8192));
+        assert_eq!(signature.len(), size(file.len()));
+        let word =
+            |offset: usize| u32::from_be_bytes(signature[offset..offset + 4].try_into().unwrap());
+        assert_eq!(word(0), MAGIC_EMBEDDED_SIGNATURE);
+        assert_eq!(word(4) as usize, signature.len());
+        assert_eq!(word(SUPER_BLOB_SIZE), MAGIC_CODE_DIRECTORY);
+        // Three pages, the last one partial
+        assert_eq!(word(SUPER_BLOB_SIZE + 28), 3);
+        assert_eq!(word(SUPER_BLOB_SIZE + 32) as usize, file.len());
+        let hashes = SUPER_BLOB_SIZE + word(SUPER_BLOB_SIZE + 16) as usize;
+        assert_eq!(
+            &signature[hashes..hashes + HASH_SIZE],
+            &sha256(&file[..4096])
+        );
+        assert_eq!(
+            &signature[signature.len() - HASH_SIZE..],
+            &sha256(&file[8192..])
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use crate::Options;
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+// The optimizer minimizes size first and uses run time to break ties, so the
+// tables only need to rank the transitions right. Timings are clock cycles ⨉ 12
+// to keep fractional throughputs integral.
+// See <https://www.agner.org/optimize/instruction_tables.pdf>
+
+/// Reciprocal throughput and latency of a transition in clock cycles ⨉ 12.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Timing {
+    /// Time it takes from the execution resources, the cost of a transition
+    /// that nothing waits for.
+    pub throughput: usize,
+    /// Time until the registers it writes can be used.
+    pub latency:    usize,
+}
+
+const fn timing(throughput: usize, latency: usize) -> Timing {
+    Timing {
+        throughput,
+        latency,
+    }
+}
+
+/// Timing of each kind of transition. Copies and swaps of a register with
+/// itself are free regardless.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CostTable {
+    pub set:   Timing,
+    pub copy:  Timing,
+    pub swap:  Timing,
+    pub read:  Timing,
+    pub write: Timing,
+    pub alloc: Timing,
+    pub drop:  Timing,
+    pub test:  Timing,
+}
+
+impl CostTable {
+    /// A rough estimate for any recent x86_64.
+    pub const GENERIC: Self = Self {
+        set:   timing(3, 12),
+        copy:  timing(3, 12),
+        swap:  timing(6, 24),
+        read:  timing(6, 60),
+        write: timing(12, 12),
+        alloc: timing(24, 72),
+        drop:  timing(24, 0),
+        test:  timing(3, 12),
+    };
+    /// From Fog's Skylake table. `xchg` is three micro-ops there.
+    /// See <https://stackoverflow.com/questions/45766444/why-is-xchg-reg-reg-a-3-micro-op-instruction-on-modern-intel-architectures>
+    pub const SKYLAKE: Self = Self {
+        set:   timing(3, 12),
+        copy:  timing(3, 12),
+        swap:  timing(12, 24),
+        read:  timing(6, 48),
+        write: timing(12, 12),
+        // A load and a read-modify-write of the free memory pointer
+        alloc: timing(30, 72),
+        drop:  timing(0, 0),
+        test:  timing(3, 12),
+    };
+    /// From Fog's Zen table. `xchg` is two micro-ops and moves are
+    /// eliminated.
+    pub const ZEN: Self = Self {
+        set:   timing(3, 12),
+        copy:  timing(0, 0),
+        swap:  timing(6, 12),
+        read:  timing(6, 48),
+        write: timing(12, 12),
+        alloc: timing(24, 60),
+        drop:  timing(0, 0),
+        test:  timing(3, 12),
+    };
+
+    const NAMES: [&'static str; 8] = [
+        "set", "copy", "swap", "read", "write", "alloc", "drop", "test",
+    ];
+
+    fn entries(&self) -> [Timing; 8] {
+        [
+            self.set, self.copy, self.swap, self.read, self.write, self.alloc, self.drop, self.test,
+        ]
+    }
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        Self::GENERIC
+    }
+}
+
+/// One `name throughput latency` triple per line, as written by
+/// `olus calibrate`.
+impl Display for CostTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, timing) in Self::NAMES.iter().zip(self.entries().iter()) {
+            writeln!(f, "{} {} {}", name, timing.throughput, timing.latency)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CostTable {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut entries = [None; 8];
+        for line in s.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let (name, throughput, latency) = match words[..] {
+                [name, throughput, latency] => (name, throughput, latency),
+                _ => {
+                    return Err(format!(
+                        "Expected a name, a throughput and a latency, found '{}'",
+                        line
+                    ))
+                }
+            };
+            let index = Self::NAMES
+                .iter()
+                .position(|known| *known == name)
+                .ok_or_else(|| format!("Unknown transition '{}'", name))?;
+            let number = |word: &str| {
+                word.parse()
+                    .map_err(|_| format!("Invalid cost '{}' for {}", word, name))
+            };
+            entries[index] = Some(timing(number(throughput)?, number(latency)?));
+        }
+        let entry = |index: usize| {
+            entries[index].ok_or_else(|| format!("Missing cost for {}", Self::NAMES[index]))
+        };
+        Ok(Self {
+            set:   entry(0)?,
+            copy:  entry(1)?,
+            swap:  entry(2)?,
+            read:  entry(3)?,
+            write: entry(4)?,
+            alloc: entry(5)?,
+            drop:  entry(6)?,
+            test:  entry(7)?,
+        })
+    }
+}
+
+/// Processor the optimizer weighs run time for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CostModel {
+    Generic,
+    Skylake,
+    Zen,
+    /// Measured on the processor, see `calibrate::measure`.
+    Calibrated(CostTable),
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel::Generic
+    }
+}
+
+impl CostModel {
+    #[must_use]
+    pub const fn table(&self) -> CostTable {
+        match self {
+            CostModel::Generic => CostTable::GENERIC,
+            CostModel::Skylake => CostTable::SKYLAKE,
+            CostModel::Zen => CostTable::ZEN,
+            CostModel::Calibrated(table) => *table,
+        }
+    }
+}
+
+/// Only the named models, calibrated tables are read with `CostTable`.
+impl FromStr for CostModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "generic" => Ok(CostModel::Generic),
+            "skylake" => Ok(CostModel::Skylake),
+            "zen" => Ok(CostModel::Zen),
+            _ => {
+                Err(format!(
+                    "Unknown cost model '{}', expected 'generic', 'skylake' or 'zen'",
+                    s
+                ))
+            }
+        }
+    }
+}
+
+/// What the optimizer minimizes first.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Objective {
+    /// Code size, with run time breaking ties.
+    Size,
+    /// The sum of both, one byte of code weighing as much as a clock cycle.
+    Balanced,
+    /// Run time, with code size breaking ties. Microcoded instructions like
+    /// `xchg` are not considered.
+    Speed,
+}
+
+impl Default for Objective {
+    fn default() -> Self {
+        Objective::Size
+    }
+}
+
+impl FromStr for Objective {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "size" => Ok(Objective::Size),
+            "balanced" => Ok(Objective::Balanced),
+            "speed" => Ok(Objective::Speed),
+            _ => {
+                Err(format!(
+                    "Unknown objective '{}', expected 'size', 'balanced' or 'speed'",
+                    s
+                ))
+            }
+        }
+    }
+}
+
+/// What the optimizer weighs transitions by.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Costs {
+    pub table:     CostTable,
+    pub objective: Objective,
+}
+
+impl Costs {
+    #[must_use]
+    pub fn new(options: &Options) -> Self {
+        Self {
+            table:     options.cost_model.table(),
+            objective: options.objective,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_table_roundtrip() {
+        for table in &[CostTable::GENERIC, CostTable::SKYLAKE, CostTable::ZEN] {
+            assert_eq!(table.to_string().parse::<CostTable>(), Ok(*table));
+        }
+        assert!("set 3 12".parse::<CostTable>().is_err());
+        assert!(CostTable::GENERIC
+            .to_string()
+            .replace("set 3 12", "set 3")
+            .parse::<CostTable>()
+            .is_err());
+        assert!(CostTable::GENERIC
+            .to_string()
+            .replace("swap", "xchg")
+            .parse::<CostTable>()
+            .is_err());
+    }
+}
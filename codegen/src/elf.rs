@@ -0,0 +1,211 @@
+use crate::{
+    error::{finalize, new_assembler, Error, Phase, Result},
+    memory_map::{page_align, PAGE},
+    Arch, Assembly, SegmentOrder,
+};
+use dynasm::dynasm;
+use dynasmrt::DynasmApi;
+
+// ELF executables for Linux. The file is the memory image from `BASE`: the
+// headers followed by the segments as laid out by `to_flat`, so file offsets
+// and addresses differ by `BASE` and no segment needs relocating. Linux always
+// allocates a stack, so unlike Mach-O there is no choice to make there.
+// See <https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html>
+// See <https://refspecs.linuxfoundation.org/elf/gabi4+/ch5.pheader.html>
+
+/// Address the file is mapped at. Linux refuses to map the lowest pages, see
+/// `vm.mmap_min_addr`, so this is the traditional start of executables.
+const BASE: usize = 0x40_0000;
+
+/// Number of program headers: code, ROM, RAM and the stack permissions.
+const NUM_HEADERS: usize = 4;
+
+/// Size of the ELF header and the program headers.
+const HEADER_SIZE: usize = 64 + 56 * NUM_HEADERS;
+
+/// Address of the first segment, right after the headers.
+pub(crate) const CODE_START: usize = BASE + HEADER_SIZE;
+
+/// Segment permissions
+const EXECUTE: u32 = 1;
+const WRITE: u32 = 2;
+const READ: u32 = 4;
+
+impl Assembly {
+    /// The ELF executable. The assembly needs to be compiled for Linux with
+    /// its first segment at the fixed start address.
+    pub fn to_elf(&self) -> Result<Vec<u8>> {
+        if self.map.start != CODE_START {
+            return Err(Error::CodeStart(self.map.start));
+        }
+        let image = self.to_flat();
+        let ram_offset = self.map.ram_start - BASE;
+        let ram_size = std::cmp::max(self.memory.ram_size(), page_align(self.ram.len()));
+        let (first_start, second_start) = match self.map.order {
+            SegmentOrder::CodeFirst => (self.map.code_start, self.map.rom_start),
+            SegmentOrder::RomFirst => (self.map.rom_start, self.map.code_start),
+        };
+        let (first_flags, second_flags) = match self.map.order {
+            SegmentOrder::CodeFirst => (READ | EXECUTE, READ),
+            SegmentOrder::RomFirst => (READ, READ | EXECUTE),
+        };
+        // The first segment includes the headers, from the start of its page
+        let first_offset = first_start - HEADER_SIZE - BASE;
+        let machine = match self.arch {
+            Arch::X86_64 => 0x3e,
+            Arch::Aarch64 => 0xb7,
+        };
+        let second_offset = second_start - BASE;
+        assert_eq!(first_offset, 0);
+
+        let mut ops = new_assembler(Phase::Executable)?;
+
+        // ELF header (64 bytes)
+        dynasm!(ops
+            ; .bytes b"\x7fELF"
+            ; .byte 2           // 64 bit
+            ; .byte 1           // Little endian
+            ; .byte 1           // Version
+            ; .byte 0           // System V ABI
+            ; .qword 0          // Padding
+            ; .word 2           // Type: executable
+            ; .word machine     // Machine
+            ; .dword 1          // Version
+            ; .qword self.map.code_start as i64 // Entry point
+            ; .qword 64         // Program headers offset
+            ; .qword 0          // Section headers offset
+            ; .dword 0          // Flags
+            ; .word 64          // ELF header size
+            ; .word 56          // Program header size
+            ; .word NUM_HEADERS as i16 // Number of program headers
+            ; .word 64          // Section header size
+            ; .word 0           // Number of section headers
+            ; .word 0           // Section name table index
+        );
+
+        // Program headers (56 bytes each)
+        fn load(
+            ops: &mut dynasmrt::x64::Assembler,
+            offset: usize,
+            file_size: usize,
+            vm_size: usize,
+            flags: u32,
+        ) {
+            dynasm!(ops
+                ; .dword 1      // Loadable segment
+                ; .dword flags as i32
+                ; .qword offset as i64  // File offset
+                ; .qword (BASE + offset) as i64 // Virtual address
+                ; .qword (BASE + offset) as i64 // Physical address
+                ; .qword file_size as i64
+                ; .qword vm_size as i64
+                ; .qword PAGE as i64   // Alignment
+            );
+        }
+        let first_size = second_offset - first_offset;
+        let second_size = ram_offset - second_offset;
+        load(&mut ops, first_offset, first_size, first_size, first_flags);
+        load(&mut ops, second_offset, second_size, second_size, second_flags);
+        load(&mut ops, ram_offset, self.ram.len(), ram_size, READ | WRITE);
+        // Non-executable stack
+        dynasm!(ops
+            ; .dword 0x6474_e551 // GNU stack
+            ; .dword (READ | WRITE) as i32
+            ; .qword 0, 0, 0, 0, 0, 0
+        );
+
+        let mut result = finalize(ops, Phase::Executable)?;
+        assert_eq!(result.len(), HEADER_SIZE);
+        result.extend(image);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{macho::test::write_and_exit, MemoryMap, Os};
+
+    /// Flags, file offset, file size and memory size of the loadable
+    /// segments.
+    fn segments(exe: &[u8]) -> Vec<(u32, u64, u64, u64)> {
+        let dword = |offset: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&exe[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        let qword = |offset: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&exe[offset..offset + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        (0..3)
+            .map(|index| 64 + 56 * index)
+            .map(|header| {
+                assert_eq!(dword(header), 1);
+                (dword(header + 4), qword(header + 8), qword(header + 32), qword(header + 40))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_segments() {
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, CODE_START, 1, 1);
+        let assembly = write_and_exit(Os::Linux, map, 0);
+        let exe = assembly.to_elf().unwrap();
+        assert_eq!(&exe[..4], b"\x7fELF");
+        let ram_size = assembly.memory.ram_size() as u64;
+        let page = PAGE as u64;
+        assert_eq!(segments(&exe), vec![
+            (READ | EXECUTE, 0, page, page),
+            (READ, page, page, page),
+            (READ | WRITE, 2 * page, 8, ram_size),
+        ]);
+        assert_eq!(exe.len(), 2 * PAGE + 8);
+
+        let map = MemoryMap::new(SegmentOrder::RomFirst, CODE_START, 1, 1);
+        let exe = write_and_exit(Os::Linux, map, 0).to_elf().unwrap();
+        assert_eq!(segments(&exe)[0].0, READ);
+        assert_eq!(segments(&exe)[1].0, READ | EXECUTE);
+    }
+
+    #[test]
+    fn test_machine() {
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, CODE_START, 1, 1);
+        let mut assembly = write_and_exit(Os::Linux, map, 0);
+        assert_eq!(&assembly.to_elf().unwrap()[18..20], &[0x3e, 0]);
+        assembly.arch = Arch::Aarch64;
+        assert_eq!(&assembly.to_elf().unwrap()[18..20], &[0xb7, 0]);
+    }
+
+    #[test]
+    fn test_start_address() {
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, crate::macho::CODE_START, 1, 1);
+        let result = write_and_exit(Os::Linux, map, 0).to_elf();
+        assert!(matches!(result, Err(Error::CodeStart(_))));
+    }
+
+    /// Run the executable, writing to code and ROM must fault.
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn test_write_protection() {
+        use crate::Target;
+        use std::{env, fs, os::unix::process::ExitStatusExt, process::Command};
+
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, CODE_START, 1, 1);
+        for &(case, address, faults) in &[
+            ("code", map.code_start, true),
+            ("rom", map.rom_start, true),
+            ("ram", map.ram_start, false),
+        ] {
+            let executable = env::temp_dir().join(format!("olus-elf-protection-{}", case));
+            write_and_exit(Os::Linux, map, address)
+                .save(Target::X86_64UnknownLinuxGnu, &executable)
+                .unwrap();
+            let status = Command::new(&executable).status().unwrap();
+            fs::remove_file(&executable).unwrap();
+            assert_eq!(status.signal().is_some(), faults, "writing to {}", case);
+            assert_eq!(status.success(), !faults, "writing to {}", case);
+        }
+    }
+}
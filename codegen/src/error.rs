@@ -0,0 +1,112 @@
+use crate::{Arch, Memory};
+#[cfg(feature = "native")]
+use dynasmrt::{relocations::Relocation, Assembler};
+use parser::{EntryError, ValidationError};
+use std::{fmt, io};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Stage of code generation, used to give errors context.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Phase {
+    /// First pass over the code to determine its layout.
+    Layout,
+    /// Final pass over the code.
+    Code,
+    Ram,
+    /// Writing the executable headers.
+    Executable,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The assembler could not allocate its buffer.
+    Assembler { phase: Phase, source: io::Error },
+    /// The assembler could not be finalized.
+    Finalize { phase: Phase },
+    /// The entry point is missing or not a declaration that can be started.
+    Entry(EntryError),
+    /// The module violates an invariant of MIR.
+    InvalidMir(ValidationError),
+    /// Executables need the first segment at a fixed address, but it was
+    /// compiled for the given one.
+    CodeStart(usize),
+    /// The heap does not fit in RAM, or RAM does not fit below 2GB.
+    Memory(Memory),
+    /// The executable format can not hold code for the instruction set.
+    Unsupported { format: &'static str, arch: Arch },
+    /// No way into the call of the named declaration was found. Calls with
+    /// more values than registers are only planned greedily, which can fail.
+    Plan(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::Layout => "code layout",
+            Phase::Code => "code",
+            Phase::Ram => "ram",
+            Phase::Executable => "executable",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Assembler { phase, source } => {
+                write!(f, "Could not create assembler for {}: {}", phase, source)
+            }
+            Error::Finalize { phase } => write!(f, "Could not finalize {}", phase),
+            Error::Entry(error) => write!(f, "{}", error),
+            Error::InvalidMir(error) => write!(f, "Invalid MIR: {}", error),
+            Error::CodeStart(start) => {
+                write!(f, "Executables can not have segments starting at {:#x}", start)
+            }
+            Error::Memory(memory) => {
+                write!(
+                    f,
+                    "Invalid RAM of {} pages with the heap at offset {:#x}",
+                    memory.ram_pages, memory.heap_offset
+                )
+            }
+            Error::Unsupported { format, arch } => {
+                write!(f, "{} executables for {} are not supported", format, arch)
+            }
+            Error::Plan(name) => write!(f, "Could not plan the call in {}", name),
+            Error::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Assembler { source, .. } | Error::Io(source) => Some(source),
+            Error::InvalidMir(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// Create an assembler, failures are attributed to `phase`.
+#[cfg(feature = "native")]
+pub(crate) fn new_assembler<R: Relocation>(phase: Phase) -> Result<Assembler<R>> {
+    Assembler::new().map_err(|source| Error::Assembler { phase, source })
+}
+
+/// Finalize an assembler into its bytes, failures are attributed to `phase`.
+#[cfg(feature = "native")]
+pub(crate) fn finalize<R: Relocation>(asm: Assembler<R>, phase: Phase) -> Result<Vec<u8>> {
+    asm.finalize()
+        .map(|buffer| buffer.to_vec())
+        .map_err(|_| Error::Finalize { phase })
+}
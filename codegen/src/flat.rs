@@ -0,0 +1,154 @@
+use crate::{assemble, Assembly, Options, Result};
+use parser::mir::Module;
+
+// Flat binary output: the segments as they appear in memory, without any
+// headers. Meant for freestanding code, see `Os::Freestanding`.
+
+/// Compile for a flat image loaded at `load_address`.
+pub fn compile_flat(module: &Module, options: &Options, load_address: usize) -> Result<Vec<u8>> {
+    Ok(assemble(module, options, load_address)?.to_flat())
+}
+
+impl Assembly {
+    /// The memory image starting at `map.start`. The entry point is
+    /// `map.code_start`, which is the start of the image unless the ROM comes
+    /// first. Segments are padded to page boundaries. The image ends with the
+    /// initialized part of RAM, the loader needs to provide the remainder of
+    /// `memory.ram_size()` zero initialized.
+    #[must_use]
+    pub fn to_flat(&self) -> Vec<u8> {
+        let map = &self.map;
+        let mut image = vec![0; map.ram_start - map.start];
+        let mut place = |start: usize, segment: &[u8]| {
+            image[start - map.start..start - map.start + segment.len()].copy_from_slice(segment);
+        };
+        place(map.code_start, &self.code);
+        place(map.rom_start, &self.rom);
+        image.extend(&self.ram);
+        image
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{memory_map::PAGE, Arch, Error, Memory, MemoryMap, Os, SegmentOrder, Startup};
+    use parser::{mir::Expression, parse_str, ValidationError};
+
+    #[test]
+    fn test_flat_layout() {
+        let mut assembly = Assembly {
+            arch:    Arch::X86_64,
+            map:     MemoryMap::new(SegmentOrder::CodeFirst, 0x1000, 10, 4),
+            memory:  Memory::default(),
+            startup: Startup::default(),
+            code:    vec![1; 10],
+            rom:     vec![2; 4],
+            ram:     vec![3; 4],
+        };
+        let image = assembly.to_flat();
+        assert_eq!(image.len(), 2 * PAGE + 4);
+        assert_eq!(&image[..11], &[1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0]);
+        assert_eq!(&image[PAGE..PAGE + 5], &[2, 2, 2, 2, 0]);
+        assert_eq!(&image[2 * PAGE..], &[3, 3, 3, 3]);
+
+        assembly.map = MemoryMap::new(SegmentOrder::RomFirst, 0x1000, 10, 4);
+        let image = assembly.to_flat();
+        assert_eq!(image.len(), 2 * PAGE + 4);
+        assert_eq!(&image[..5], &[2, 2, 2, 2, 0]);
+        assert_eq!(&image[PAGE..PAGE + 11], &[1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0]);
+        assert_eq!(&image[2 * PAGE..], &[3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn test_freestanding() {
+        let module = parse_str("main ↦\n    print “Hi” (↦ exit 0)\n").unwrap();
+        let mmio: usize = 0x00de_ad00_0000;
+        let options = Options {
+            os: Os::Freestanding { mmio },
+            ..Options::default()
+        };
+        let image = compile_flat(&module, &options, 0x10_0000).unwrap();
+        let contains = |needle: &[u8]| image.windows(needle.len()).any(|w| w == needle);
+        // Both ports are loaded as 64 bit immediates
+        assert!(contains(&(mmio as u64).to_le_bytes()));
+        assert!(contains(&(mmio as u64 + 8).to_le_bytes()));
+        // `hlt; jmp` back to it
+        assert!(contains(&[0xf4, 0xe9, 0xfa, 0xff, 0xff, 0xff]));
+    }
+
+    #[test]
+    fn test_freestanding_aarch64() {
+        let module = parse_str("main ↦\n    print “Hi” (↦ exit 0)\n").unwrap();
+        let options = Options {
+            arch: Arch::Aarch64,
+            os: Os::Freestanding { mmio: 0x00de_ad00_0000 },
+            ..Options::default()
+        };
+        let image = compile_flat(&module, &options, 0x10_0000).unwrap();
+        let contains = |needle: &[u8]| image.windows(needle.len()).any(|w| w == needle);
+        // `wfi; b` back to it
+        assert!(contains(&[0x7f, 0x20, 0x03, 0xd5, 0xff, 0xff, 0xff, 0x17]));
+        assert!(!contains(&[0xf4, 0xe9, 0xfa, 0xff, 0xff, 0xff]));
+    }
+
+    #[test]
+    fn test_rom_first() {
+        let module = parse_str("main ↦\n    print “Hi” (↦ exit 0)\n").unwrap();
+        let options = Options {
+            os: Os::Freestanding { mmio: 0x00de_ad00_0000 },
+            segment_order: SegmentOrder::RomFirst,
+            ..Options::default()
+        };
+        let assembly = assemble(&module, &options, 0x10_0000).unwrap();
+        assert_eq!(assembly.map.rom_start, 0x10_0000);
+        assert_eq!(assembly.map.code_start, 0x10_0000 + PAGE);
+        let image = assembly.to_flat();
+        // The string is in the first page, code in the second
+        let hi = image.windows(6).position(|w| w == b"\x02\0\0\0Hi").unwrap();
+        assert!(hi < PAGE);
+        assert_eq!(&image[PAGE..PAGE + assembly.code.len()], &assembly.code[..]);
+    }
+
+    #[test]
+    fn test_memory() {
+        let module = parse_str("main ↦ exit 0\n").unwrap();
+        let memory = Memory {
+            ram_pages: 2,
+            heap_offset: 64,
+            ..Memory::default()
+        };
+        let options = Options {
+            memory,
+            ..Options::default()
+        };
+        let assembly = assemble(&module, &options, 0x10_0000).unwrap();
+        assert_eq!(assembly.memory, memory);
+        let heap = (assembly.map.ram_start + 64) as u64;
+        assert_eq!(&assembly.ram[..8], &heap.to_le_bytes());
+
+        let options = Options {
+            memory: Memory {
+                heap_offset: 2 * PAGE,
+                ..memory
+            },
+            ..Options::default()
+        };
+        let result = compile_flat(&module, &options, 0x10_0000);
+        assert!(matches!(result, Err(Error::Memory(_))));
+    }
+
+    #[test]
+    fn test_invalid_mir() {
+        let mut module = parse_str("main ↦ exit 0\n").unwrap();
+        module.declarations[0].call.push(Expression::Number(1));
+        let result = compile_flat(&module, &Options::default(), 0x10_0000);
+        assert!(matches!(
+            result,
+            Err(Error::InvalidMir(ValidationError::Number {
+                declaration: 0,
+                number:      1,
+            }))
+        ));
+    }
+}
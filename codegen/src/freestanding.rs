@@ -0,0 +1,55 @@
+use crate::repr::{STRING_BYTES, STRING_LENGTH};
+use dynasm::dynasm;
+use dynasmrt::{x64::Assembler, DynasmApi, DynasmLabelApi};
+
+// Freestanding mode, for emulators and bare metal. There are no system calls,
+// instead I/O goes through memory mapped ports at a configurable base address.
+// After writing the exit code the processor halts in a loop, `hlt` resumes
+// after interrupts.
+
+/// Memory mapped ports, as offsets from the base address.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Port {
+    /// Byte port receiving everything `print` writes
+    Output      = 0,
+    /// Byte port receiving run-time error messages
+    Diagnostics = 1,
+    /// Quad word port receiving the exit code
+    Exit        = 8,
+}
+
+impl Port {
+    pub(crate) const fn address(self, mmio: usize) -> i64 {
+        (mmio + self as usize) as i64
+    }
+}
+
+/// Write the length-prefixed string at r6 to `port` one byte at a time.
+/// Clobbers r0, r2, r6 and r11.
+pub(crate) fn write(ops: &mut Assembler, mmio: usize, port: Port) {
+    dynasm!(ops
+        ; mov r11, QWORD port.address(mmio)
+        ; mov r2d, [r6 + STRING_LENGTH]
+        ; add r6, STRING_BYTES
+        ; test r2d, r2d
+        ; jz >done
+        ; next:
+        ; mov r0b, BYTE [r6]
+        ; mov BYTE [r11], r0b
+        ; inc r6
+        ; dec r2d
+        ; jnz <next
+        ; done:
+    );
+}
+
+/// Write the exit code in r7 and halt.
+pub(crate) fn exit(ops: &mut Assembler, mmio: usize) {
+    dynasm!(ops
+        ; mov r11, QWORD Port::Exit.address(mmio)
+        ; mov QWORD [r11], r7
+        ; halt:
+        ; hlt
+        ; jmp <halt
+    );
+}
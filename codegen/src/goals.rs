@@ -0,0 +1,209 @@
+use crate::{
+    aarch64::A64,
+    code,
+    listing::label,
+    machine::{Register, State, Value},
+    rom,
+    x64::X64,
+    Arch, Compiled, Options, Result,
+};
+use parser::mir::Module;
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+// Goal states of declarations, for co-simulating generated code with the
+// interpreter. The goal of a declaration is the machine state its code ends
+// in, right before it makes its call: which register holds which symbol or
+// constant, and the closures allocated for the call. The interpreter makes the
+// same call in one step, so a debugger can show the registers generated code
+// should have after each step. Goals are written next to the executable as
+// text, see `olus --emit goals`.
+
+/// The goal state of each declaration, by index in the module.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Goals {
+    pub states: Vec<State>,
+}
+
+impl Compiled {
+    /// The goal state of every declaration of `module`, compiled with
+    /// `options`.
+    pub fn goals(&self, module: &Module, options: &Options) -> Result<Goals> {
+        let options = Options {
+            arch: self.target.arch(),
+            os: self.target.os(),
+            ..options.clone()
+        };
+        let map = &self.assembly.map;
+        let (_, rom) = rom::compile(module, &options, &self.layout, map.rom_start);
+        let goals = match options.arch {
+            Arch::X86_64 => code::goals::<X64>,
+            Arch::Aarch64 => code::goals::<A64>,
+        };
+        let states = goals(
+            module,
+            &options,
+            map.code_start,
+            &self.layout,
+            &rom,
+            map.ram_start,
+        )?;
+        Ok(Goals { states })
+    }
+}
+
+impl Goals {
+    /// The goals with the names of the declarations, which [`Goals`]'
+    /// `FromStr` ignores.
+    #[must_use]
+    pub fn display<'a>(&'a self, module: &'a Module) -> impl Display + 'a {
+        Named {
+            goals: self,
+            module,
+        }
+    }
+}
+
+struct Named<'a> {
+    goals:  &'a Goals,
+    module: &'a Module,
+}
+
+impl Display for Named<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, state) in self.goals.states.iter().enumerate() {
+            writeln!(f, "declaration {} {}", index, label(self.module, index))?;
+            write_state(f, state)?;
+        }
+        Ok(())
+    }
+}
+
+/// A `declaration` line per declaration, followed by the specified registers
+/// and flags as `r1 = #4` and the allocations as `0: 0x… #5`, indented.
+impl Display for Goals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, state) in self.states.iter().enumerate() {
+            writeln!(f, "declaration {}", index)?;
+            write_state(f, state)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_state(f: &mut fmt::Formatter<'_>, state: &State) -> fmt::Result {
+    for index in 0..16 {
+        let value = state.get_register(Register::new(index));
+        if value.is_specified() {
+            writeln!(f, "    r{} = {}", index, value)?;
+        }
+    }
+    if state.flags().is_specified() {
+        writeln!(f, "    flags = {}", state.flags())?;
+    }
+    for (index, allocation) in state.allocations().iter().enumerate() {
+        write!(f, "    {}:", index)?;
+        for value in allocation {
+            write!(f, " {}", value)?;
+        }
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+impl FromStr for Goals {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut builders = Vec::new();
+        for line in s.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            if words[0] == "declaration" {
+                if words.get(1) != Some(&builders.len().to_string().as_str()) {
+                    return Err(format!("Expected declaration {}", builders.len()));
+                }
+                builders.push(State::builder());
+                continue;
+            }
+            let builder = builders
+                .pop()
+                .ok_or_else(|| format!("Expected a declaration, found '{}'", line))?;
+            let values = words
+                .iter()
+                .skip(1)
+                .filter(|word| **word != "=")
+                .map(|word| word.parse())
+                .collect::<std::result::Result<Vec<Value>, _>>()?;
+            let builder = match (words[0], &values[..]) {
+                ("flags", &[value]) => builder.flags(value),
+                (register, &[value]) if register.starts_with('r') => {
+                    let index = register[1..]
+                        .parse()
+                        .ok()
+                        .filter(|index| *index < 16)
+                        .ok_or_else(|| format!("Unknown register '{}'", register))?;
+                    builder.register(Register::new(index), value)
+                }
+                (allocation, _) if allocation.ends_with(':') => builder.allocation(values),
+                _ => return Err(format!("Expected a register or allocation, found '{}'", line)),
+            };
+            builders.push(builder);
+        }
+        let states = builders
+            .into_iter()
+            .enumerate()
+            .map(|(index, builder)| {
+                builder
+                    .build()
+                    .ok_or_else(|| format!("Invalid goal for declaration {}", index))
+            })
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(Self { states })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{compile, Target};
+    use parser::parse_str;
+
+    #[test]
+    fn test_goals() {
+        let source = "f a k ↦ sub a 1 (b ↦ k a b)\nmain ↦ f 5 (_ r ↦ exit r)\n";
+        let module = parse_str(source).unwrap();
+        let options = Options::default();
+        let compiled = compile(&module, &options, Target::X86_64UnknownLinuxGnu).unwrap();
+        let goals = compiled.goals(&module, &options).unwrap();
+        assert_eq!(goals.states.len(), module.declarations.len());
+        let text = goals.to_string();
+        assert_eq!(text.parse::<Goals>(), Ok(goals.clone()));
+        // Names are only for reading
+        let named = goals.display(&module).to_string();
+        assert!(named.starts_with("declaration 0 f\n"));
+        assert_eq!(named.parse::<Goals>(), Ok(goals));
+
+        // `f` calls `sub a 1` with a new closure capturing `a` and `k`
+        let f = text.split("declaration 1").next().unwrap();
+        assert!(f.contains("    r1 = #1\n"));
+        assert!(f.contains("    r3 = 0[0]\n"));
+        assert!(f.ends_with(" #1 #2\n"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!("declaration 1\n".parse::<Goals>().is_err());
+        assert!("r1 = #4\n".parse::<Goals>().is_err());
+        assert!("declaration 0\n    r16 = #4\n".parse::<Goals>().is_err());
+        assert!("declaration 0\n    r1 = 0[0]\n".parse::<Goals>().is_err());
+        assert!("declaration 0\n    r1 = x\n".parse::<Goals>().is_err());
+        let goals = "declaration 0\n    r1 = 0[0]\n    flags = #3\n    0: 0x10 ? \
+                     0[-1]\n";
+        assert_eq!(
+            goals.parse::<Goals>().unwrap().to_string(),
+            goals.replace("0x10", "0x0000000000000010")
+        );
+    }
+}
@@ -1,113 +1,78 @@
-use dynasm::dynasm;
-use dynasmrt::{x64::Assembler, DynasmApi};
+#[cfg(feature = "native")]
+use crate::Os;
 
-// Syscalls are in r0, r7, r6, r2, r10, r8, r9, returns in r0, r1 clobbers r11
-// See <https://github.com/hjl-tools/x86-psABI/wiki/X86-psABI> A.2.1
-// See <https://github.com/apple/darwin-xnu/blob/master/bsd/kern/syscalls.master>
+// The intrinsics, implemented in assembly for each instruction set, see
+// `x64::intrinsics` and `aarch64::intrinsics`, and in the WebAssembly and C
+// backends.
 
-// TODO: These intrinsics don't need a closure to be passed. They can have a
-// more optimized calling convention.
-
-pub(crate) fn intrinsic(ops: &mut Assembler, name: &str) {
-    match name {
-        "exit" => sys_exit(ops),
-        "print" => sys_print(ops),
-        "add" => add(ops),
-        "sub" => sub(ops),
-        "mul" => mul(ops),
-        "divmod" => divmod(ops),
-        "isZero" => is_zero(ops),
-        // TODO:
-        "input" => is_zero(ops),
-        "parseInt" => is_zero(ops),
-        _ => panic!("Unknown intrinsic {}", name),
-    }
-}
-
-/// Emit the exit builtin
-/// `exit code`
-fn sys_exit(ops: &mut Assembler) {
-    dynasm!(ops
-        // sys_exit(code)
-        ; mov r0d, WORD 0x0200_0001
-        ; mov r7, r1
-        ; syscall
-    );
-}
-
-/// Emit the print builtin
-/// `print str ret`
-fn sys_print(ops: &mut Assembler) {
-    dynasm!(ops
-        // Back up ret to r15
-        ; mov r15, r2
-        // sys_write(fd, buffer, length)
-        ; mov r0d, WORD 0x0200_0004
-        ; mov r7d, BYTE 1
-        ; lea r6, [r1 + 4]
-        ; mov r2d, [r1]
-        ; syscall
-        // call ret from r15
-        ; mov r0, r15
-        ; jmp QWORD [r0]
-    );
-}
-
-/// Emit the add builtin
-/// `add a b ret`
-fn add(ops: &mut Assembler) {
-    dynasm!(ops
-        ; add r1, r2
-        ; mov r0, r3
-        ; jmp QWORD [r0]
-    );
+/// System calls used by the intrinsics and runtime.
+#[cfg(feature = "native")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Syscall {
+    Exit,
+    Write,
 }
 
-/// Emit the add builtin
-/// `sub a b ret`
-fn sub(ops: &mut Assembler) {
-    dynasm!(ops
-        ; sub r1, r2
-        ; mov r0, r3
-        ; jmp QWORD [r0]
-    );
+#[cfg(feature = "native")]
+impl Syscall {
+    /// Call number to load in r0.
+    // See <https://github.com/torvalds/linux/blob/master/arch/x86/entry/syscalls/syscall_64.tbl>
+    pub(crate) fn number(self, os: Os) -> i32 {
+        match (os, self) {
+            (Os::Darwin, Syscall::Exit) => 0x0200_0001,
+            (Os::Darwin, Syscall::Write) => 0x0200_0004,
+            // exit_group, plain exit only ends the thread in a multi-threaded
+            // host process
+            (Os::Linux, Syscall::Exit) => 231,
+            (Os::Linux, Syscall::Write) => 1,
+            (Os::Freestanding { .. }, _) => panic!("No system calls in freestanding mode"),
+        }
+    }
 }
 
-/// Emit the mul builtin
-/// `mul a b ret`
-fn mul(ops: &mut Assembler) {
-    dynasm!(ops
-        ; mulx r0, r1, r1 // r0:r1 = r1 * r2
-        ; mov r0, r3
-        ; jmp QWORD [r0]
-    );
-}
+/// Names of the implemented intrinsics, as reported by `hasBuiltin`.
+pub(crate) const NAMES: [&str; 22] = [
+    "exit",
+    "print",
+    "add",
+    "sub",
+    "mul",
+    "divmod",
+    "isZero",
+    "addChecked",
+    "subChecked",
+    "mulChecked",
+    "neg",
+    "isNegative",
+    "lessSigned",
+    "compilerVersion",
+    "hasBuiltin",
+    "abort",
+    "panic",
+    "charAt",
+    "substring",
+    "newTuple",
+    "tupleGet",
+    "tupleSet",
+];
 
-/// Emit the div builtin
-/// `divmod a b ret`
-fn divmod(ops: &mut Assembler) {
-    // TODO: Expose high bits
-    // See <https://www.felixcloutier.com/x86/div>
-    // TODO: Capture #DE event
-    dynasm!(ops
-        ; mov r4, r2
-        ; mov r0, r1
-        ; xor r2, r2
-        ; div r4  // r0 = r2:r0 / r4
-                  // r2 = r2:r0 % r4
-        ; mov r1, r0
-        ; mov r0, r3
-        ; jmp QWORD [r0]
-    );
+/// Number of arguments the intrinsic takes, excluding the closure.
+pub(crate) fn arity(name: &str) -> usize {
+    known_arity(name).unwrap_or_else(|| panic!("Unknown intrinsic {}", name))
 }
 
-/// Emit the isZero builtin
-/// `isZero n true false`
-fn is_zero(ops: &mut Assembler) {
-    dynasm!(ops
-        ; test r1, r1
-        ; mov r0, r2
-        ; cmovnz r0, r3
-        ; jmp QWORD [r0]
-    );
+/// Like [`arity`], `None` if there is no intrinsic `name`.
+pub(crate) fn known_arity(name: &str) -> Option<usize> {
+    Some(match name {
+        "abort" => 0,
+        "exit" | "compilerVersion" | "panic" => 1,
+        "print" | "neg" | "newTuple" => 2,
+        "add" | "sub" | "mul" | "divmod" | "isZero" | "isNegative" => 3,
+        "addChecked" | "subChecked" | "mulChecked" | "lessSigned" => 4,
+        "hasBuiltin" | "charAt" | "tupleGet" => 3,
+        "substring" | "tupleSet" => 4,
+        // TODO: Placeholders emitted as isZero
+        "input" | "parseInt" => 3,
+        _ => return None,
+    })
 }
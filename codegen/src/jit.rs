@@ -0,0 +1,129 @@
+#![allow(unsafe_code)]
+use crate::{
+    assemble,
+    macho::Assembly,
+    Options, SegmentOrder,
+};
+use parser::mir::Module;
+use std::{error::Error, io, ptr};
+
+// Run generated code in the current process.
+//
+// The segments are mapped at the same fixed addresses the code was compiled
+// for, so the generated code runs unmodified. The program ends with an `exit`
+// system call, taking the whole process with it.
+
+/// Address the code segment is mapped at. The generated code uses 32-bit
+/// absolute addresses, so everything needs to be mapped below 2GB.
+pub(crate) const LOAD_ADDRESS: usize = 0x1000_0000;
+
+/// Compile `module` and jump to it. Only returns if the memory could not be
+/// mapped.
+pub fn run(module: &Module, options: &Options) -> Result<(), Box<dyn Error>> {
+    let assembly = assemble(module, options, LOAD_ADDRESS)?;
+    enter(&assembly)
+}
+
+/// Map the segments of code assembled for `LOAD_ADDRESS` and jump to it.
+pub(crate) fn enter(assembly: &Assembly) -> Result<(), Box<dyn Error>> {
+    let map = &assembly.map;
+    assert_eq!(map.start, LOAD_ADDRESS);
+    let ram_end = map.ram_start + assembly.memory.ram_size();
+    assert!(assembly.ram.len() <= assembly.memory.ram_size());
+
+    // Map read-write, copy the segments in and then restrict permissions.
+    // The address is only a hint, MAP_FIXED would silently replace existing
+    // mappings.
+    let size = ram_end - LOAD_ADDRESS;
+    let memory = unsafe {
+        libc::mmap(
+            LOAD_ADDRESS as *mut libc::c_void,
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if memory == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error().into());
+    }
+    if memory as usize != LOAD_ADDRESS {
+        unsafe { libc::munmap(memory, size) };
+        return Err(format!("Could not map memory at {:#x}", LOAD_ADDRESS).into());
+    }
+    unsafe {
+        copy(map.code_start, &assembly.code);
+        copy(map.rom_start, &assembly.rom);
+        copy(map.ram_start, &assembly.ram);
+        let (code_end, rom_end) = match map.order {
+            SegmentOrder::CodeFirst => (map.rom_start, map.ram_start),
+            SegmentOrder::RomFirst => (map.ram_start, map.code_start),
+        };
+        protect(map.code_start, code_end, libc::PROT_READ | libc::PROT_EXEC)?;
+        protect(map.rom_start, rom_end, libc::PROT_READ)?;
+    }
+
+    // The generated code ends with an exit system call and never returns.
+    let entry: extern "C" fn() -> ! = unsafe { std::mem::transmute(map.code_start) };
+    entry()
+}
+
+unsafe fn copy(address: usize, data: &[u8]) {
+    ptr::copy_nonoverlapping(data.as_ptr(), address as *mut u8, data.len());
+}
+
+unsafe fn protect(start: usize, end: usize, protection: libc::c_int) -> io::Result<()> {
+    if libc::mprotect(start as *mut libc::c_void, end - start, protection) == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{macho::test::write_and_exit, MemoryMap, Os};
+    use std::{env, os::unix::process::ExitStatusExt, process::Command};
+
+    /// Environment variable selecting the case to run in the child process.
+    const CHILD: &str = "OLUS_JIT_PROTECTION_CASE";
+
+    /// Generated code ends the process, so every case runs in a child process
+    /// running only this test.
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn test_protections() {
+        if let Ok(case) = env::var(CHILD) {
+            let (order, segment) = case.split_at(case.find(' ').unwrap());
+            let order = match order {
+                "code-first" => SegmentOrder::CodeFirst,
+                "rom-first" => SegmentOrder::RomFirst,
+                _ => panic!("Unknown order {}", order),
+            };
+            let map = MemoryMap::new(order, LOAD_ADDRESS, 1, 1);
+            let target = match segment.trim() {
+                "code" => map.code_start,
+                "rom" => map.rom_start,
+                "ram" => map.ram_start,
+                _ => panic!("Unknown case {}", case),
+            };
+            enter(&write_and_exit(Os::Linux, map, target)).unwrap();
+            unreachable!();
+        }
+        for order in &["code-first", "rom-first"] {
+            for &(segment, faults) in &[("code", true), ("rom", true), ("ram", false)] {
+                let case = format!("{} {}", order, segment);
+                let status = Command::new(env::current_exe().unwrap())
+                    .args(&["--exact", "jit::test::test_protections", "--test-threads=1"])
+                    .env(CHILD, &case)
+                    .output()
+                    .unwrap()
+                    .status;
+                assert_eq!(status.signal().is_some(), faults, "writing to {}", case);
+                assert_eq!(status.success(), !faults, "writing to {}", case);
+            }
+        }
+    }
+}
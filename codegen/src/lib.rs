@@ -1,59 +1,457 @@
-#![forbid(unsafe_code)]
+#![cfg_attr(not(feature = "jit"), forbid(unsafe_code))]
+#![cfg_attr(feature = "jit", deny(unsafe_code))]
 #![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
 // Required for dynasm!
-#![feature(proc_macro_hygiene)]
+#![cfg_attr(feature = "native", feature(proc_macro_hygiene))]
 
+#[cfg(feature = "native")]
+mod aarch64;
+#[cfg(feature = "native")]
+mod abi;
+#[cfg(feature = "native")]
 mod allocator;
+mod arch;
+mod backend;
+mod c;
+#[cfg(feature = "native")]
+mod cache;
+#[cfg(all(feature = "jit", target_arch = "x86_64"))]
+pub mod calibrate;
 mod code;
+#[cfg(feature = "native")]
+mod codesign;
+mod cost_model;
+#[cfg(feature = "native")]
+mod elf;
+mod error;
+#[cfg(feature = "native")]
+mod flat;
+#[cfg(feature = "native")]
+mod freestanding;
+#[cfg(feature = "native")]
+mod goals;
 mod intrinsics;
-mod machine;
+#[cfg(feature = "jit")]
+pub mod jit;
+mod lint;
+#[cfg(feature = "native")]
+mod listing;
+pub mod machine;
+#[cfg(feature = "native")]
 mod macho;
+mod memory_map;
+#[cfg(feature = "native")]
+mod native;
+#[cfg(feature = "native")]
 mod offset_assembler;
+#[cfg(feature = "native")]
+mod outline;
+#[cfg(feature = "native")]
+mod placement;
+mod repr;
+#[cfg(all(feature = "jit", target_os = "linux", target_arch = "x86_64"))]
+pub mod ptrace;
 mod rom;
+mod runtime;
 mod utils;
+mod wasm;
+#[cfg(feature = "native")]
+mod x64;
 
-use crate::{
-    intrinsics::intrinsic,
-    macho::{ram_start, rom_start, Assembly},
-};
+#[cfg(feature = "native")]
+use crate::{backend::compile_with, native::Native};
 use bitvec;
+#[cfg(feature = "native")]
 use parser::mir::Module;
-use std::{collections::HashSet, error::Error, path::PathBuf};
+use std::{
+    collections::BTreeSet,
+    fmt::{self, Display},
+    path::PathBuf,
+    str::FromStr,
+};
 
-type Set<T> = HashSet<T>;
+// Ordered, so iteration order and with it the generated code is deterministic.
+type Set<T> = BTreeSet<T>;
 type BitVec = bitvec::vec::BitVec<bitvec::order::Lsb0, u64>;
 
+pub use arch::Arch;
+pub use c::compile_c;
+pub use cost_model::{CostModel, CostTable, Costs, Objective, Timing};
+pub use error::{Error, Phase, Result};
+#[cfg(feature = "native")]
+pub use flat::compile_flat;
+#[cfg(feature = "native")]
+pub use goals::Goals;
+pub use lint::lint;
+#[cfg(feature = "native")]
+pub use listing::compile_listing;
+#[cfg(feature = "native")]
+pub use macho::Assembly;
+pub use memory_map::{Memory, MemoryMap, SegmentOrder, Startup};
+#[cfg(feature = "native")]
 pub use offset_assembler::OffsetAssembler;
-
-// For Dynasm syntax see
-// <https://censoredusername.github.io/dynasm-rs/language/langref_x64.html#register>
+pub use wasm::compile_wasm;
 
 // Oluś default calling convention:
 // r0: current closure pointer
-// r1..r15: arguments
-
-pub fn codegen(module: &Module, destination: &PathBuf) -> Result<(), Box<dyn Error>> {
-    let dummy_code_layout = code::Layout::dummy(module);
-    let dummy_rom_layout = rom::Layout::dummy(module);
-    // TODO: ram_start and ram_layout
-
-    // First pass with dummy layout
-    let (code, code_layout) = code::compile(module, &dummy_code_layout, &dummy_rom_layout, 0);
-
-    // Compile final rom
-    let rom_start = rom_start(code.len());
-    println!("ROM start: {:08x}", rom_start);
-    let (rom, rom_layout) = rom::compile(module, &code_layout, rom_start);
-    assert!(rom.len() < 4096);
-
-    // Second pass compile
-    let ram_start = ram_start(rom_start, rom.len());
-    println!("RAM start: {:08x}", ram_start);
-    let (code, code_layout_final) = code::compile(module, &code_layout, &rom_layout, ram_start);
-    // Layout should not change between passes
-    assert_eq!(code_layout, code_layout_final);
-
-    let ram = allocator::initial_ram(ram_start);
-    let assembly = Assembly { code, rom, ram };
-    assembly.save(destination)
+// r1..r15: arguments, more than fifteen are passed in memory, see `abi`
+// See `arch` for how the registers map to other instruction sets.
+
+/// Code generation options.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Options {
+    /// Emit run-time checks: closure records carry arity and name and every
+    /// call verifies the arity before jumping. Declarations store their index
+    /// in RAM when they start, and the ROM maps it to their code and name.
+    pub debug_runtime: bool,
+
+    /// Instruction set to generate code for. Executables take it from their
+    /// [`Target`].
+    pub arch: Arch,
+
+    /// Instruction set extensions available on the target.
+    pub features: Features,
+
+    /// Processor whose instruction timings estimate run time.
+    pub cost_model: CostModel,
+
+    /// Whether to minimize code size or run time.
+    pub objective: Objective,
+
+    /// Search for the cheapest transitions into each call, see
+    /// [`machine::State::transition_to`], instead of picking them greedily.
+    /// Compiling takes much longer.
+    pub optimize: bool,
+
+    /// Operating system whose system calls the intrinsics use. Executables
+    /// take it from their [`Target`].
+    pub os: Os,
+
+    /// Share transition sequences that are repeated across declarations,
+    /// trading a jump in and out for smaller code.
+    pub outline: bool,
+
+    /// Order of the code and ROM segments in the address space.
+    pub segment_order: SegmentOrder,
+
+    /// Size and layout of RAM.
+    pub memory: Memory,
+
+    /// How Mach-O executables are started.
+    pub startup: Startup,
+
+    /// Directory to keep compiled programs in, so compiling them again only
+    /// takes a lookup. Not used for WebAssembly, C and flat images.
+    pub cache: Option<PathBuf>,
+
+    /// Declaration to start at, [`parser::mir::DEFAULT_ENTRY`] if `None`.
+    pub entry: Option<String>,
+
+    /// Code added to every declaration to observe the program as it runs.
+    /// Not supported for WebAssembly and C.
+    pub instrument: Instrument,
+}
+
+/// Ways to instrument the generated code.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Instrument {
+    None,
+    /// Write the name of each declaration to stderr when it starts, like the
+    /// interpreter traces calls.
+    Trace,
+    /// Count how often each declaration starts, and write the counts with
+    /// the names to stderr when the program calls `exit`.
+    Counts,
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Instrument::None
+    }
+}
+
+impl FromStr for Instrument {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Instrument::None),
+            "trace" => Ok(Instrument::Trace),
+            "counts" => Ok(Instrument::Counts),
+            _ => {
+                Err(format!(
+                    "Unknown instrumentation '{}', expected 'none', 'trace' or 'counts'",
+                    s
+                ))
+            }
+        }
+    }
+}
+
+/// Operating systems with a known system call table.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Os {
+    Darwin,
+    Linux,
+    /// No operating system. Output is written byte by byte to `mmio`, run-time
+    /// errors to `mmio + 1`, and `exit` writes the exit code as a quad word to
+    /// `mmio + 8` and halts. Use with [`Assembly::to_flat`].
+    Freestanding { mmio: usize },
+}
+
+impl Default for Os {
+    fn default() -> Self {
+        Os::Darwin
+    }
+}
+
+impl Os {
+    /// The operating system running the compiler, if supported.
+    #[must_use]
+    pub fn host() -> Option<Self> {
+        if cfg!(target_os = "macos") {
+            Some(Os::Darwin)
+        } else if cfg!(target_os = "linux") {
+            Some(Os::Linux)
+        } else {
+            None
+        }
+    }
+}
+
+/// Platforms executables can be written for: an instruction set and an
+/// operating system with its executable format.
+///
+/// There is no `aarch64-apple-darwin` yet: Apple Silicon only runs signed
+/// executables with 16 KiB aligned segments. Code for it can be generated
+/// with [`Arch::Aarch64`] and [`Os::Darwin`] all the same.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Target {
+    /// macOS, Mach-O executables.
+    X86_64AppleDarwin,
+    /// Linux, ELF executables.
+    X86_64UnknownLinuxGnu,
+    /// ARM Linux, ELF executables.
+    Aarch64UnknownLinuxGnu,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::X86_64AppleDarwin
+    }
+}
+
+impl Target {
+    /// All supported targets.
+    pub const ALL: [Self; 3] = [
+        Target::X86_64AppleDarwin,
+        Target::X86_64UnknownLinuxGnu,
+        Target::Aarch64UnknownLinuxGnu,
+    ];
+
+    /// The platform running the compiler, if executables for it can be
+    /// written.
+    #[must_use]
+    pub fn host() -> Option<Self> {
+        if cfg!(all(target_os = "macos", target_arch = "x86_64")) {
+            Some(Target::X86_64AppleDarwin)
+        } else if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+            Some(Target::X86_64UnknownLinuxGnu)
+        } else if cfg!(all(target_os = "linux", target_arch = "aarch64")) {
+            Some(Target::Aarch64UnknownLinuxGnu)
+        } else {
+            None
+        }
+    }
+
+    /// The instruction set of the code.
+    #[must_use]
+    pub const fn arch(self) -> Arch {
+        match self {
+            Target::X86_64AppleDarwin | Target::X86_64UnknownLinuxGnu => Arch::X86_64,
+            Target::Aarch64UnknownLinuxGnu => Arch::Aarch64,
+        }
+    }
+
+    /// The operating system whose system calls the code uses.
+    #[must_use]
+    pub const fn os(self) -> Os {
+        match self {
+            Target::X86_64AppleDarwin => Os::Darwin,
+            Target::X86_64UnknownLinuxGnu | Target::Aarch64UnknownLinuxGnu => Os::Linux,
+        }
+    }
+
+    /// Address of the first segment, following the executable headers.
+    #[cfg(feature = "native")]
+    pub(crate) const fn start(self, startup: Startup) -> usize {
+        match self {
+            Target::X86_64AppleDarwin => startup.code_start(),
+            Target::X86_64UnknownLinuxGnu | Target::Aarch64UnknownLinuxGnu => elf::CODE_START,
+        }
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Target::X86_64AppleDarwin => "x86_64-apple-darwin",
+            Target::X86_64UnknownLinuxGnu => "x86_64-unknown-linux-gnu",
+            Target::Aarch64UnknownLinuxGnu => "aarch64-unknown-linux-gnu",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|target| target.to_string() == s)
+            .ok_or_else(|| {
+                let names = Self::ALL.iter().map(ToString::to_string).collect::<Vec<_>>();
+                format!("Unknown target '{}', expected one of {}", s, names.join(", "))
+            })
+    }
+}
+
+/// Instruction set extensions beyond baseline x86_64 that instruction
+/// selection may use. The default assumes none are available. There are none
+/// to choose from on AArch64.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Features {
+    /// `mulx` and other BMI2 instructions (Haswell and later).
+    pub bmi2: bool,
+}
+
+impl Features {
+    /// The extensions supported by the processor running the compiler.
+    #[must_use]
+    pub fn host() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        return Self {
+            bmi2: is_x86_feature_detected!("bmi2"),
+        };
+        #[cfg(not(target_arch = "x86_64"))]
+        return Self::default();
+    }
+}
+
+/// Code compiled for a target, with where the code of each declaration
+/// starts and ends. Executables, listings and linker maps are all made from
+/// it, so producing several of them takes a single compile.
+#[cfg(feature = "native")]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Compiled {
+    pub target:   Target,
+    pub assembly: Assembly,
+    layout:       code::Layout,
+}
+
+#[cfg(feature = "native")]
+impl Compiled {
+    /// Write the executable to `destination`.
+    pub fn save(&self, destination: &PathBuf) -> Result<()> {
+        self.assembly.save(self.target, destination)
+    }
+}
+
+/// Compile to an executable file for `target` at `destination`.
+#[cfg(feature = "native")]
+pub fn codegen(
+    module: &Module,
+    options: &Options,
+    target: Target,
+    destination: &PathBuf,
+) -> Result<()> {
+    compile(module, options, target)?.save(destination)
+}
+
+/// Compile to segments laid out for an executable for `target`, without
+/// writing anything. The `options` select the processor features, the
+/// instruction set and operating system are the target's.
+#[cfg(feature = "native")]
+pub fn compile(module: &Module, options: &Options, target: Target) -> Result<Compiled> {
+    let options = Options {
+        arch: target.arch(),
+        os: target.os(),
+        ..options.clone()
+    };
+    let start = target.start(options.startup);
+    if let Some(dir) = &options.cache {
+        if let Some(compiled) = cache::load(dir, module, &options, target, start) {
+            return Ok(compiled);
+        }
+    }
+    let (assembly, layout) = assemble_with_layout(module, &options, start)?;
+    let compiled = Compiled {
+        target,
+        assembly,
+        layout,
+    };
+    if let Some(dir) = &options.cache {
+        cache::store(dir, module, &options, target, &compiled);
+    }
+    Ok(compiled)
+}
+
+/// Like [`compile`], only returning the segments.
+#[cfg(feature = "native")]
+pub fn compile_to_bytes(module: &Module, options: &Options, target: Target) -> Result<Assembly> {
+    Ok(compile(module, options, target)?.assembly)
+}
+
+/// Compile the code, rom and initial ram segments for segments loaded from
+/// `start`. The segments are placed on consecutive pages in the order given by
+/// the options.
+#[cfg(feature = "native")]
+pub(crate) fn assemble(module: &Module, options: &Options, start: usize) -> Result<Assembly> {
+    Ok(assemble_with_layout(module, options, start)?.0)
+}
+
+/// Like [`assemble`], also returning where the code of each declaration and
+/// intrinsic is.
+#[cfg(feature = "native")]
+pub(crate) fn assemble_with_layout(
+    module: &Module,
+    options: &Options,
+    start: usize,
+) -> Result<(Assembly, code::Layout)> {
+    compile_with(Native::new(module, options, start))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parser::parse_str;
+
+    /// Builds are reproducible: ties in the optimizer are broken the same way
+    /// every time, so compiling twice gives the same bytes.
+    #[test]
+    fn test_deterministic() {
+        let module = parse_str(
+            "g n ↦\n    mul n n (m ↦ exit m)\nf a b c k ↦\n    add a b (s ↦ add s c (t ↦ k \
+             t))\nmain ↦\n    f 1 2 3 (x ↦ print “a” (↦ g x))\n",
+        )
+        .unwrap();
+        let options = Options::default();
+        #[cfg(feature = "native")]
+        for &target in &Target::ALL {
+            let first = compile_to_bytes(&module, &options, target).unwrap();
+            let second = compile_to_bytes(&module, &options, target).unwrap();
+            assert_eq!(first.code, second.code, "{}", target);
+            assert_eq!(first.rom, second.rom, "{}", target);
+        }
+        assert_eq!(
+            compile_wasm(&module, &options).unwrap(),
+            compile_wasm(&module, &options).unwrap()
+        );
+        assert_eq!(
+            compile_c(&module, &options).unwrap(),
+            compile_c(&module, &options).unwrap()
+        );
+    }
 }
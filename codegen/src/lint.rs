@@ -0,0 +1,156 @@
+use crate::{code::find_entry, intrinsics::known_arity, Options};
+use parser::{
+    mir::{Expression, Module},
+    Sink, Warning,
+};
+use std::collections::HashSet;
+
+// Warnings that need the program as code generation sees it: the entry point,
+// the declarations left after lowering and the builtins. They are reported on
+// the source spans the parser kept, so modules without them get no warnings.
+
+/// Report what is wrong with `module` to `sink`, short of failing to compile:
+/// calls with the wrong number of arguments, names that are neither declared
+/// nor builtins, and declarations the entry point of `options` never gets to.
+pub fn lint(module: &Module, options: &Options, sink: &mut dyn Sink) {
+    calls(module, sink);
+    unreachable(module, options, sink);
+}
+
+/// Report calls of declarations and builtins with the wrong number of
+/// arguments and builtins that do not exist. Closures desugared from a
+/// declaration share its span, the same mistake in several of them is
+/// reported once.
+fn calls(module: &Module, sink: &mut dyn Sink) {
+    let mut reported = Vec::new();
+    for declaration in &module.declarations {
+        let span = match module.span(declaration.procedure[0]) {
+            Some(span) => span,
+            None => continue,
+        };
+        let arguments = declaration.call.len() - 1;
+        let warning = match declaration.call[0] {
+            Expression::Symbol(symbol) => {
+                module.declaration(symbol).and_then(|callee| {
+                    let parameters = callee.procedure.len() - 1;
+                    (parameters != arguments).then(|| {
+                        Warning::Arity(module.symbols[symbol].clone(), parameters, arguments)
+                    })
+                })
+            }
+            Expression::Import(import) => {
+                let name = &module.imports[import];
+                match known_arity(name) {
+                    Some(parameters) if parameters != arguments => {
+                        Some(Warning::Arity(name.clone(), parameters, arguments))
+                    }
+                    Some(_) => None,
+                    None => Some(Warning::Undeclared(name.clone())),
+                }
+            }
+            _ => None,
+        };
+        // Builtins passed along are called elsewhere, but must exist
+        let undeclared = declaration.call[1..].iter().filter_map(|expression| {
+            match expression {
+                Expression::Import(import) if known_arity(&module.imports[*import]).is_none() => {
+                    Some(Warning::Undeclared(module.imports[*import].clone()))
+                }
+                _ => None,
+            }
+        });
+        for warning in warning.into_iter().chain(undeclared) {
+            if !reported.contains(&(warning.clone(), span.start)) {
+                reported.push((warning.clone(), span.start));
+                sink.warn(warning, span.clone());
+            }
+        }
+    }
+}
+
+/// Report the declarations the entry point never calls or makes a closure of.
+/// Closures desugared from a declaration share its span and are reported once
+/// with it. A module without the entry point gets no warnings, compiling it
+/// reports that.
+fn unreachable(module: &Module, options: &Options, sink: &mut dyn Sink) {
+    let entry = match find_entry(module, options) {
+        Ok(entry) => entry,
+        Err(_) => return,
+    };
+    let mut reached = HashSet::new();
+    let mut todo = vec![entry];
+    while let Some(index) = todo.pop() {
+        if !reached.insert(index) {
+            continue;
+        }
+        for expression in &module.declarations[index].call {
+            if let Expression::Symbol(symbol) = expression {
+                todo.extend(
+                    module
+                        .declarations
+                        .iter()
+                        .position(|declaration| declaration.procedure[0] == *symbol),
+                );
+            }
+        }
+    }
+    let mut unreached = (0..module.declarations.len())
+        .filter(|index| !reached.contains(index))
+        .map(|index| module.declarations[index].procedure[0])
+        .collect::<Vec<_>>();
+    unreached.sort_unstable();
+    let mut reported = HashSet::new();
+    for symbol in unreached {
+        if let Some(span) = module.span(symbol) {
+            if reported.insert(span.start) {
+                let warning = Warning::Unreachable(module.symbols[symbol].clone());
+                sink.warn(warning, span);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parser::parse_str;
+
+    #[test]
+    fn test_unreachable() {
+        let source = "g a k ↦ k a\nf a ↦ g a (b ↦ exit b)\nh ↦ f (x ↦ exit x)\nmain ↦ f 3\n";
+        let module = parse_str(source).unwrap();
+        let mut warnings = Vec::new();
+        lint(&module, &Options::default(), &mut warnings);
+        assert_eq!(warnings, vec![(Warning::Unreachable("h".to_string()), 41..42)]);
+        assert_eq!(
+            warnings[0].0.to_string(),
+            "h is never reached from the entry point"
+        );
+
+        // Without the entry point there is nothing to reach from
+        let options = Options {
+            entry: Some("start".to_string()),
+            ..Options::default()
+        };
+        let mut warnings = Vec::new();
+        lint(&module, &options, &mut warnings);
+        assert_eq!(warnings, vec![]);
+    }
+
+    #[test]
+    fn test_calls() {
+        let source = "f a b ↦ exit a\nmain ↦ f 1\ng ↦ add 2 (x ↦ frobnicate x)\n";
+        let module = parse_str(source).unwrap();
+        let mut warnings = Vec::new();
+        calls(&module, &mut warnings);
+        assert_eq!(warnings, vec![
+            (Warning::Arity("f".to_string(), 2, 1), 17..21),
+            (Warning::Arity("add".to_string(), 3, 2), 30..31),
+            (Warning::Undeclared("frobnicate".to_string()), 30..31),
+        ]);
+        assert_eq!(
+            warnings[0].0.to_string(),
+            "f takes 2 arguments, but is called with 1"
+        );
+    }
+}
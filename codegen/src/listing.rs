@@ -0,0 +1,297 @@
+use crate::{compile, Compiled, Options, Result, Target};
+use parser::mir::Module;
+use std::{collections::BTreeMap, fmt::Write};
+
+// Annotated listings, like `objdump -S`: every source line followed by the
+// code of the declarations bound on it. Declarations introduced by desugaring
+// are listed under the line of the closure they come from. There is no
+// disassembler, the code is shown as bytes. Linker maps give the same
+// addresses without the code.
+
+/// Bytes per line of the listing.
+const ROW: usize = 16;
+
+/// Compile for `target` and list the code under the lines of `source`, which
+/// `module` was parsed from.
+pub fn compile_listing(
+    module: &Module,
+    options: &Options,
+    target: Target,
+    source: &str,
+) -> Result<String> {
+    Ok(compile(module, options, target)?.listing(module, source))
+}
+
+/// Anonymous declarations are named after their symbol, like the interpreter
+/// does.
+pub(crate) fn label(module: &Module, index: usize) -> String {
+    let symbol = module.declarations[index].procedure[0];
+    let name = &module.symbols[symbol];
+    if name.is_empty() {
+        format!("λ{}", symbol)
+    } else {
+        name.clone()
+    }
+}
+
+impl Compiled {
+    /// List the code under the lines of `source`, which `module` was parsed
+    /// from.
+    #[must_use]
+    pub fn listing(&self, module: &Module, source: &str) -> String {
+        write_listing(self, module, source)
+    }
+
+    /// The address range of each segment and of the code of the prelude, each
+    /// declaration, each intrinsic and the run-time support, in address order.
+    #[must_use]
+    pub fn linker_map(&self, module: &Module) -> String {
+        let assembly = &self.assembly;
+        let layout = &self.layout;
+        let map = &assembly.map;
+        let mut out = String::new();
+        let code_end = map.code_start + assembly.code.len();
+        let ram_end = map.ram_start + assembly.memory.ram_size();
+        let segments = [
+            ("code", map.code_start, code_end),
+            ("rom", map.rom_start, map.rom_start + assembly.rom.len()),
+            ("ram", map.ram_start, ram_end),
+        ];
+        for (name, start, end) in &segments {
+            writeln!(out, "{:08x} {:08x} segment {}", start, end, name).unwrap();
+        }
+        let mut ranges = (0..module.declarations.len())
+            .map(|index| {
+                let range = (layout.declarations[index], layout.declaration_ends[index]);
+                (range, label(module, index))
+            })
+            .collect::<Vec<_>>();
+        let first = ranges
+            .iter()
+            .map(|((start, _), _)| *start)
+            .chain(layout.imports.iter().copied())
+            .fold(layout.runtime, usize::min);
+        ranges.push(((map.code_start, first), "prelude".to_string()));
+        for (index, import) in module.imports.iter().enumerate() {
+            let end = layout
+                .imports
+                .get(index + 1)
+                .copied()
+                .unwrap_or(layout.runtime);
+            ranges.push(((layout.imports[index], end), import.clone()));
+        }
+        ranges.push(((layout.runtime, code_end), "runtime".to_string()));
+        ranges.sort();
+        for ((start, end), name) in ranges {
+            writeln!(out, "{:08x} {:08x} {}", start, end, name).unwrap();
+        }
+        out
+    }
+}
+
+fn write_listing(compiled: &Compiled, module: &Module, source: &str) -> String {
+    let Compiled {
+        assembly, layout, ..
+    } = compiled;
+    let code_start = assembly.map.code_start;
+    let mut listing = Listing {
+        code: &assembly.code,
+        code_start,
+        listed: BTreeMap::new(),
+        out: String::new(),
+    };
+
+    // Declarations by the line they are bound on, in code order
+    let lines = lines(source);
+    let mut by_line = vec![Vec::new(); lines.len()];
+    let mut unlocated = Vec::new();
+    for (index, decl) in module.declarations.iter().enumerate() {
+        let line = module.span(decl.procedure[0]).map(|span| {
+            let starts = lines.iter().map(|(start, _)| *start).collect::<Vec<_>>();
+            match starts.binary_search(&span.start) {
+                Ok(line) => line,
+                Err(line) => line - 1,
+            }
+        });
+        match line {
+            Some(line) => by_line[line].push(index),
+            None => unlocated.push(index),
+        }
+    }
+    let decl_range = |index: usize| (layout.declarations[index], layout.declaration_ends[index]);
+
+    let first = layout
+        .declarations
+        .iter()
+        .chain(&layout.imports)
+        .copied()
+        .fold(layout.runtime, usize::min);
+    listing.code("prelude", code_start, first);
+    for (number, (_, text)) in lines.iter().enumerate() {
+        writeln!(listing.out, "{:>5}  {}", number + 1, text).unwrap();
+        by_line[number].sort_by_key(|&index| decl_range(index));
+        for &index in &by_line[number] {
+            let (start, end) = decl_range(index);
+            listing.code(&label(module, index), start, end);
+        }
+    }
+    for index in unlocated {
+        let (start, end) = decl_range(index);
+        listing.code(&label(module, index), start, end);
+    }
+    for (index, import) in module.imports.iter().enumerate() {
+        let end = layout
+            .imports
+            .get(index + 1)
+            .copied()
+            .unwrap_or(layout.runtime);
+        listing.code(import, layout.imports[index], end);
+    }
+    listing.code("runtime", layout.runtime, code_start + assembly.code.len());
+    listing.out
+}
+
+/// The start offset and text of each line, without the line break.
+fn lines(source: &str) -> Vec<(usize, &str)> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut chars = source.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        if c == '\n' || c == '\r' {
+            result.push((start, &source[start..index]));
+            if c == '\r' && chars.peek().map(|&(_, next)| next) == Some('\n') {
+                let _ = chars.next();
+            }
+            start = chars.peek().map_or(source.len(), |&(next, _)| next);
+        }
+    }
+    if start < source.len() {
+        result.push((start, &source[start..]));
+    }
+    result
+}
+
+struct Listing<'a> {
+    code:       &'a [u8],
+    code_start: usize,
+    /// Label of the code listed at each address, to refer to shared code.
+    listed:     BTreeMap<usize, String>,
+    out:        String,
+}
+
+impl Listing<'_> {
+    /// List the code from `start` to `end` under `label`.
+    fn code(&mut self, label: &str, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+        writeln!(self.out, "{}:", label).unwrap();
+        if let Some(shared) = self.listed.get(&start) {
+            writeln!(self.out, "    {:08x}: same code as {}", start, shared).unwrap();
+            return;
+        }
+        let _ = self.listed.insert(start, label.to_string());
+        let bytes = &self.code[start - self.code_start..end - self.code_start];
+        for (row, chunk) in bytes.chunks(ROW).enumerate() {
+            let hex = chunk
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>();
+            writeln!(self.out, "    {:08x}: {}", start + row * ROW, hex.join(" ")).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compile_to_bytes;
+    use parser::parse_str;
+
+    #[test]
+    fn test_lines() {
+        assert_eq!(
+            lines("a\nb\r\nc\rd"),
+            vec![(0, "a"), (2, "b"), (5, "c"), (7, "d")]
+        );
+        assert_eq!(lines("a\n\n"), vec![(0, "a"), (2, "")]);
+    }
+
+    #[test]
+    fn test_listing() {
+        let source = "f a k ↦ add a a k\nmain ↦\n    f 1 (x ↦ print “a” (↦ exit x))\n";
+        let module = parse_str(source).unwrap();
+        let options = Options::default();
+        let target = Target::X86_64UnknownLinuxGnu;
+        let listing = compile_listing(&module, &options, target, source).unwrap();
+        let lines = listing.lines().collect::<Vec<_>>();
+        let position = |line: &str| lines.iter().position(|l| *l == line).unwrap();
+
+        // Declarations follow the line they are bound on, the anonymous ones
+        // included, and intrinsics come after the source.
+        assert!(position("    1  f a k ↦ add a a k") < position("f:"));
+        assert!(position("f:") < position("    2  main ↦"));
+        assert!(position("    2  main ↦") < position("main:"));
+        assert!(position("main:") < position("    3      f 1 (x ↦ print “a” (↦ exit x))"));
+        assert!(position("    3      f 1 (x ↦ print “a” (↦ exit x))") < position("add:"));
+        assert!(lines.iter().any(|line| line.starts_with("main.λ")));
+
+        // Every byte of code is listed once
+        let assembly = compile_to_bytes(&module, &options, target).unwrap();
+        let mut rows = lines
+            .iter()
+            .filter_map(|line| {
+                let mut parts = line.trim().splitn(2, ": ");
+                let address = usize::from_str_radix(parts.next()?, 16).ok()?;
+                let bytes = parts
+                    .next()?
+                    .split(' ')
+                    .map(|byte| u8::from_str_radix(byte, 16).ok())
+                    .collect::<Option<Vec<_>>>()?;
+                Some((address, bytes))
+            })
+            .collect::<Vec<_>>();
+        rows.sort();
+        assert_eq!(rows[0].0, assembly.map.code_start);
+        let listed = rows
+            .into_iter()
+            .flat_map(|(_, bytes)| bytes)
+            .collect::<Vec<_>>();
+        assert_eq!(listed, assembly.code);
+    }
+
+    #[test]
+    fn test_linker_map() {
+        let module = parse_str("main ↦\n    print “Hi” (↦ exit 0)\n").unwrap();
+        let target = Target::X86_64UnknownLinuxGnu;
+        let compiled = compile(&module, &Options::default(), target).unwrap();
+        let map = compiled.linker_map(&module);
+        let lines = map.lines().collect::<Vec<_>>();
+        let code_start = compiled.assembly.map.code_start;
+        let code_end = code_start + compiled.assembly.code.len();
+        assert_eq!(
+            lines[0],
+            format!("{:08x} {:08x} segment code", code_start, code_end)
+        );
+        assert!(lines[1].ends_with(" segment rom"));
+        assert!(lines[2].ends_with(" segment ram"));
+        assert!(lines[3].ends_with(" prelude"));
+        assert!(lines.iter().any(|line| line.ends_with(" main")));
+
+        // The code ranges cover the code segment in order, shared code is
+        // listed for each declaration
+        let ranges = lines[3..]
+            .iter()
+            .map(|line| {
+                let mut parts = line.split(' ');
+                let mut address = || usize::from_str_radix(parts.next().unwrap(), 16).unwrap();
+                (address(), address())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ranges[0].0, code_start);
+        assert_eq!(ranges.last().unwrap().1, code_end);
+        assert!(ranges
+            .windows(2)
+            .all(|pair| pair[0].1 == pair[1].0 || pair[0] == pair[1]));
+    }
+}
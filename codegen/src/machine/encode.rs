@@ -0,0 +1,225 @@
+use super::Register;
+use std::convert::TryFrom;
+
+// Binary encoding of the x86_64 instructions transitions are made of, so the
+// machine model needs no `dynasm!`. The encodings are the ones `dynasm!`
+// picks, including its REX prefixes and 32 bit immediates where shorter forms
+// exist, so code is the same either way.
+// See <https://www.felixcloutier.com/x86/>
+
+/// Instruction forms. Registers are in `dest, source` order.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Instruction {
+    /// `xor r32, r32`, zeroes the register and clears the flags.
+    Zero(Register),
+    /// `mov r32, imm32`, zero extending.
+    MoveImmediate32(Register, u32),
+    /// `mov r64, imm64`
+    MoveImmediate64(Register, u64),
+    /// `mov r64, r64`
+    Move(Register, Register),
+    /// `xchg r64, r64`, with the short form if one of them is rax.
+    Exchange(Register, Register),
+    /// `mov r64, [base + displacement]`
+    Load(Register, Register, i32),
+    /// `mov [base + displacement], r64`
+    Store(Register, i32, Register),
+    /// `mov r32, [address]`
+    LoadAbsolute32(Register, i32),
+    /// `add dword [address], imm32`
+    AddAbsolute32(i32, i32),
+    /// `cmp r64, imm32`
+    CompareImmediate(Register, i32),
+}
+
+const REX: u8 = 0x40;
+const REX_W: u8 = 0x48;
+
+/// REX bit extending the ModRM reg field.
+fn rex_r(register: Register) -> u8 {
+    (register.as_u8() >> 3) << 2
+}
+
+/// REX bit extending the ModRM r/m or SIB base field.
+fn rex_b(register: Register) -> u8 {
+    register.as_u8() >> 3
+}
+
+fn modrm(mode: u8, reg: u8, rm: u8) -> u8 {
+    mode << 6 | (reg & 7) << 3 | rm & 7
+}
+
+/// ModRM and SIB for `[base + displacement]`, then the displacement. The SIB
+/// byte is needed for rsp and r12, `dynasm!` always emits it.
+fn memory<E: Extend<u8>>(sink: &mut E, reg: Register, base: Register, displacement: i32) {
+    if let Ok(displacement) = i8::try_from(displacement) {
+        sink.extend(vec![
+            modrm(0b01, reg.as_u8(), 0b100),
+            0x20 | base.as_u8() & 7,
+            displacement as u8,
+        ]);
+    } else {
+        sink.extend(vec![
+            modrm(0b10, reg.as_u8(), 0b100),
+            0x20 | base.as_u8() & 7,
+        ]);
+        sink.extend(displacement.to_le_bytes().iter().copied());
+    }
+}
+
+/// ModRM and SIB for an absolute `[address]`, then the address.
+fn absolute<E: Extend<u8>>(sink: &mut E, reg: u8, address: i32) {
+    sink.extend(vec![modrm(0b00, reg, 0b100), 0x25]);
+    sink.extend(address.to_le_bytes().iter().copied());
+}
+
+impl Instruction {
+    pub(crate) fn encode<E: Extend<u8>>(self, sink: &mut E) {
+        use Instruction::*;
+        match self {
+            Zero(reg) => {
+                if reg.as_u8() >= 8 {
+                    sink.extend(Some(REX | rex_r(reg) | rex_b(reg)));
+                }
+                sink.extend(vec![0x31, modrm(0b11, reg.as_u8(), reg.as_u8())]);
+            }
+            MoveImmediate32(reg, value) => {
+                if reg.as_u8() >= 8 {
+                    sink.extend(Some(REX | rex_b(reg)));
+                }
+                sink.extend(Some(0xb8 | reg.as_u8() & 7));
+                sink.extend(value.to_le_bytes().iter().copied());
+            }
+            MoveImmediate64(reg, value) => {
+                sink.extend(vec![REX_W | rex_b(reg), 0xb8 | reg.as_u8() & 7]);
+                sink.extend(value.to_le_bytes().iter().copied());
+            }
+            Move(dest, source) => {
+                sink.extend(vec![
+                    REX_W | rex_r(source) | rex_b(dest),
+                    0x89,
+                    modrm(0b11, source.as_u8(), dest.as_u8()),
+                ]);
+            }
+            Exchange(dest, source) if dest.as_u8() == 0 || source.as_u8() == 0 => {
+                let other = if dest.as_u8() == 0 { source } else { dest };
+                sink.extend(vec![REX_W | rex_b(other), 0x90 | other.as_u8() & 7]);
+            }
+            Exchange(dest, source) => {
+                sink.extend(vec![
+                    REX_W | rex_r(dest) | rex_b(source),
+                    0x87,
+                    modrm(0b11, dest.as_u8(), source.as_u8()),
+                ]);
+            }
+            Load(dest, base, displacement) => {
+                sink.extend(vec![REX_W | rex_r(dest) | rex_b(base), 0x8b]);
+                memory(sink, dest, base, displacement);
+            }
+            Store(base, displacement, source) => {
+                sink.extend(vec![REX_W | rex_r(source) | rex_b(base), 0x89]);
+                memory(sink, source, base, displacement);
+            }
+            LoadAbsolute32(dest, address) => {
+                sink.extend(vec![REX | rex_r(dest), 0x8b]);
+                absolute(sink, dest.as_u8(), address);
+            }
+            AddAbsolute32(address, value) => {
+                sink.extend(Some(0x81));
+                absolute(sink, 0, address);
+                sink.extend(value.to_le_bytes().iter().copied());
+            }
+            CompareImmediate(reg, value) => {
+                sink.extend(vec![REX_W | rex_b(reg), 0x81, modrm(0b11, 7, reg.as_u8())]);
+                sink.extend(value.to_le_bytes().iter().copied());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dynasm::dynasm;
+    use dynasmrt::{x64::Assembler, DynasmApi};
+
+    fn encode(instruction: Instruction) -> Vec<u8> {
+        let mut sink = Vec::new();
+        instruction.encode(&mut sink);
+        sink
+    }
+
+    fn assemble(f: &dyn Fn(&mut Assembler)) -> Vec<u8> {
+        let mut asm = Assembler::new().unwrap();
+        f(&mut asm);
+        asm.finalize().unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_registers() {
+        use Instruction::*;
+        let registers = || (0_u8..16).map(Register);
+        for a in registers() {
+            let r = a.as_u8();
+            assert_eq!(
+                encode(MoveImmediate64(a, 1 << 40)),
+                assemble(&|asm| dynasm!(asm; mov Rq(r), QWORD 1 << 40))
+            );
+            assert_eq!(
+                encode(CompareImmediate(a, 1)),
+                assemble(&|asm| dynasm!(asm; cmp Rq(r), BYTE 1))
+            );
+            assert_eq!(
+                encode(LoadAbsolute32(a, 0x1000)),
+                assemble(&|asm| dynasm!(asm; mov Rd(r), DWORD [0x1000]))
+            );
+            for b in registers() {
+                let s = b.as_u8();
+                assert_eq!(
+                    encode(Move(a, b)),
+                    assemble(&|asm| dynasm!(asm; mov Rq(r), Rq(s)))
+                );
+                for &offset in &[-8_i8, 0, 120, -128] {
+                    assert_eq!(
+                        encode(Load(a, b, offset.into())),
+                        assemble(&|asm| dynasm!(asm; mov Rq(r), QWORD [BYTE Rq(s) + offset]))
+                    );
+                    assert_eq!(
+                        encode(Store(a, offset.into(), b)),
+                        assemble(&|asm| dynasm!(asm; mov QWORD [BYTE Rq(r) + offset], Rq(s)))
+                    );
+                }
+                for &offset in &[128, -136, 1 << 20] {
+                    assert_eq!(
+                        encode(Load(a, b, offset)),
+                        assemble(&|asm| dynasm!(asm; mov Rq(r), QWORD [DWORD Rq(s) + offset]))
+                    );
+                    assert_eq!(
+                        encode(Store(a, offset, b)),
+                        assemble(&|asm| dynasm!(asm; mov QWORD [DWORD Rq(r) + offset], Rq(s)))
+                    );
+                }
+            }
+        }
+        assert_eq!(
+            encode(AddAbsolute32(0x1000, 24)),
+            assemble(&|asm| dynasm!(asm; add DWORD [0x1000], DWORD 24))
+        );
+    }
+
+    #[test]
+    fn test_short_forms() {
+        use Instruction::*;
+        let r = Register;
+        assert_eq!(encode(Zero(r(3))), vec![0x31, 0xdb]);
+        assert_eq!(encode(Zero(r(12))), vec![0x45, 0x31, 0xe4]);
+        assert_eq!(encode(MoveImmediate32(r(2), 5)), vec![0xba, 5, 0, 0, 0]);
+        assert_eq!(
+            encode(MoveImmediate32(r(13), 5)),
+            vec![0x41, 0xbd, 5, 0, 0, 0]
+        );
+        assert_eq!(encode(Exchange(r(0), r(10))), vec![0x49, 0x92]);
+        assert_eq!(encode(Exchange(r(3), r(0))), vec![0x48, 0x93]);
+        assert_eq!(encode(Exchange(r(9), r(5))), vec![0x4c, 0x87, 0xcd]);
+    }
+}
@@ -0,0 +1,657 @@
+use super::{Register, State, Transition, TransitionCost, Value};
+use crate::Set;
+use std::{cmp::Reverse, collections::VecDeque};
+
+// Greedy planning: the transitions into a goal state are picked in a fixed
+// order instead of searched for. Goal allocations the state already has are
+// kept, the others are allocated, or changed from allocations the goal has no
+// use for, and filled first. Then every register gets
+// its value as soon as nothing else needs the value it holds. Registers that
+// wait on each other are swapped, or one of them is moved out of the way to a
+// free register. The flags are tested after allocating, which clobbers them,
+// and before the registers unless one of those is set to zero.
+// Values come from a register already holding them, a `Set` for literals or
+// reads through the allocations.
+
+/// Give up on goals taking more transitions than this.
+const MAX_TRANSITIONS: usize = 256;
+
+struct Greedy<'a> {
+    state:   State,
+    goal:    &'a State,
+    /// The allocation of the state standing for each goal allocation, once
+    /// there is one
+    targets: Vec<Option<usize>>,
+    /// The registers, cheapest class first
+    order:   Vec<Register>,
+    swaps:   bool,
+    path:    Vec<Transition>,
+}
+
+impl State {
+    /// A sequence of transitions from this state to one that satisfies
+    /// `goal`, picked greedily. It is found quickly, but is not always the
+    /// cheapest one, see [`transition_to`](Self::transition_to). Like that,
+    /// it ends with `Drop`s of the allocations the goal has no use for.
+    /// Returns `None` if the goal is not [reachable](Self::reachable) or if
+    /// it runs out of free registers.
+    #[must_use]
+    pub fn greedy_transition_to<C: TransitionCost + ?Sized>(
+        &self,
+        goal: &Self,
+        costs: &C,
+    ) -> Option<Vec<Transition>> {
+        if !self.reachable(goal) {
+            return None;
+        }
+        let mut order = (0..=15).map(Register).collect::<Vec<_>>();
+        order.sort_by_key(|&register| costs.register_class(register));
+        let mut greedy = Greedy {
+            state: self.clone(),
+            goal,
+            targets: vec![None; goal.allocations.len()],
+            order,
+            swaps: costs.swaps(),
+            path: Vec::new(),
+        };
+        greedy.keep_allocations();
+        greedy.allocate()?;
+        if goal.registers.contains(&Value::Literal(0)) {
+            greedy.place()?;
+            greedy.test()?;
+        } else {
+            greedy.test()?;
+            greedy.place()?;
+        }
+        let drops = greedy.state.drop_dead(goal);
+        greedy.path.extend(drops);
+        if greedy.state.satisfies(goal) {
+            Some(greedy.path)
+        } else {
+            None
+        }
+    }
+}
+
+impl Greedy<'_> {
+    /// The value in the state for `goal`, `None` for a reference to a goal
+    /// allocation that has no target yet.
+    fn value(&self, goal: Value) -> Option<Value> {
+        match goal {
+            Value::Reference { index, offset } => {
+                self.targets[index].map(|index| Value::Reference { index, offset })
+            }
+            _ => Some(goal),
+        }
+    }
+
+    fn satisfied(&self, register: Register) -> bool {
+        let goal = self.goal.get_register(register);
+        !goal.is_specified() || self.value(goal) == Some(self.state.get_register(register))
+    }
+
+    /// The first register holding `value`.
+    fn holder(&self, value: Value) -> Option<Register> {
+        (0..=15)
+            .map(Register)
+            .find(|&register| self.state.get_register(register) == value)
+    }
+
+    /// Use allocations of the state that already are what goal allocations
+    /// ask for, like the current closure of a loop.
+    fn keep_allocations(&mut self) {
+        loop {
+            let mut changed = false;
+            for index in 0..self.goal.allocations.len() {
+                if self.targets[index].is_some() {
+                    continue;
+                }
+                let found = (0..self.state.allocations.len()).find(|&ours| {
+                    !self.targets.contains(&Some(ours)) && self.is_kept(index, ours)
+                });
+                if found.is_some() {
+                    self.targets[index] = found;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return;
+            }
+        }
+    }
+
+    /// Whether allocation `ours` of the state holds what goal allocation
+    /// `index` asks for.
+    fn is_kept(&self, index: usize, ours: usize) -> bool {
+        let theirs = &self.goal.allocations[index];
+        let ours_values = &self.state.allocations[ours];
+        theirs.len() == ours_values.len()
+            && theirs.iter().zip(ours_values.iter()).all(|(goal, value)| {
+                let expected = match *goal {
+                    Value::Unspecified => return true,
+                    Value::Reference { index: other, offset } if other == index => {
+                        Some(Value::Reference {
+                            index: ours,
+                            offset,
+                        })
+                    }
+                    _ => self.value(*goal),
+                };
+                expected == Some(*value)
+            })
+    }
+
+    /// The values the goal still needs somewhere: in a register, the flags or
+    /// an allocation.
+    fn pending(&self) -> Vec<Value> {
+        let mut pending = (0..=15)
+            .map(Register)
+            .filter(|&register| !self.satisfied(register))
+            .filter_map(|register| self.value(self.goal.get_register(register)))
+            .collect::<Vec<_>>();
+        if self.goal.flags.is_specified() && self.state.flags != self.goal.flags {
+            pending.push(self.goal.flags);
+        }
+        for (allocation, target) in self.goal.allocations.iter().zip(&self.targets) {
+            for (word, goal) in allocation.iter().enumerate() {
+                if let Some(value) = self.value(*goal).filter(Value::is_specified) {
+                    let ours = target.map(|target| self.state.allocations[target].values[word]);
+                    if ours != Some(value) {
+                        pending.push(value);
+                    }
+                }
+            }
+        }
+        pending
+    }
+
+    /// Which allocations are still needed: the targets and those leading to
+    /// pending values no register holds.
+    fn live(&self, pending: &[Value]) -> Vec<bool> {
+        let missing = pending
+            .iter()
+            .copied()
+            .filter(|&value| self.holder(value).is_none())
+            .collect::<Set<_>>();
+        let mut live = self.state.leads_to(&missing);
+        for &target in self.targets.iter().flatten() {
+            live[target] = true;
+        }
+        live
+    }
+
+    /// Whether `register` can be overwritten without losing anything, and
+    /// whether that takes dropping the allocation it references first.
+    fn freeable(&self, register: Register) -> Option<bool> {
+        if self.goal.get_register(register).is_specified() && self.satisfied(register) {
+            return None;
+        }
+        let value = self.state.get_register(register);
+        let others = (0..=15)
+            .map(Register)
+            .filter(|&other| other != register && self.state.get_register(other) == value)
+            .count();
+        match value {
+            Value::Symbol(_) if others == 0 && self.pending().contains(&value) => None,
+            Value::Reference { index, .. } if others == 0 => {
+                if self.live(&self.pending())[index] {
+                    None
+                } else if self.state.reference_count(index) > 1 {
+                    Some(false)
+                } else if (Transition::Drop { dest: register }).applies(&self.state) {
+                    Some(true)
+                } else {
+                    None
+                }
+            }
+            _ => Some(false),
+        }
+    }
+
+    fn make_free(&mut self, register: Register) -> Option<()> {
+        if self.freeable(register)? {
+            self.push(Transition::Drop { dest: register })?;
+        }
+        Some(())
+    }
+
+    /// A register that can be freed, preferring one the goal wants a value
+    /// `wants` holds for, then one it has no use for.
+    fn pick(&self, wants: impl Fn(Value) -> bool) -> Option<Register> {
+        self.order
+            .iter()
+            .copied()
+            .filter(|&register| self.freeable(register).is_some())
+            .min_by_key(|&register| {
+                let goal = self.goal.get_register(register);
+                if !goal.is_specified() {
+                    1
+                } else if wants(goal) {
+                    0
+                } else {
+                    2
+                }
+            })
+    }
+
+    fn push(&mut self, transition: Transition) -> Option<()> {
+        if self.path.len() >= MAX_TRANSITIONS || !transition.applies(&self.state) {
+            return None;
+        }
+        if let Transition::Drop { dest } = transition {
+            // The last allocation takes the place of the dropped one
+            if let Value::Reference { index, .. } = self.state.get_register(dest) {
+                let last = self.state.allocations.len() - 1;
+                for target in &mut self.targets {
+                    if *target == Some(last) {
+                        *target = Some(index);
+                    }
+                }
+            }
+        }
+        transition.apply(&mut self.state);
+        self.path.push(transition);
+        Some(())
+    }
+
+    /// The reads that get `value` into `dest`, following references from the
+    /// registers through the allocations.
+    fn reads(&self, dest: Register, value: Value) -> Option<Vec<Transition>> {
+        let mut visited = vec![false; self.state.allocations.len()];
+        let mut queue = VecDeque::new();
+        for source in (0..=15).map(Register) {
+            if let Value::Reference { index, offset } = self.state.get_register(source) {
+                queue.push_back((index, offset, source, Vec::new()));
+            }
+        }
+        while let Some((index, base, source, reads)) = queue.pop_front() {
+            if visited[index] {
+                continue;
+            }
+            visited[index] = true;
+            for (word, content) in self.state.allocations[index].iter().enumerate() {
+                let mut reads = reads.clone();
+                reads.push(Transition::Read {
+                    dest,
+                    source,
+                    offset: word as isize - base,
+                });
+                if *content == value {
+                    return Some(reads);
+                }
+                if let Value::Reference { index, offset } = *content {
+                    queue.push_back((index, offset, dest, reads));
+                }
+            }
+        }
+        None
+    }
+
+    /// Put `value` in `dest`, which has to be free.
+    fn fetch_into(&mut self, dest: Register, value: Value) -> Option<()> {
+        if let Value::Literal(value) = value {
+            return self.push(Transition::Set { dest, value });
+        }
+        if let Some(source) = self.holder(value) {
+            return self.push(Transition::Copy { dest, source });
+        }
+        for read in self.reads(dest, value)? {
+            self.push(read)?;
+        }
+        Some(())
+    }
+
+    /// A register holding `value`, putting it in one if there is none.
+    fn fetch(&mut self, value: Value) -> Option<Register> {
+        if let Some(register) = self.holder(value) {
+            return Some(register);
+        }
+        let register = self.pick(|goal| self.value(goal) == Some(value))?;
+        self.make_free(register)?;
+        self.fetch_into(register, value)?;
+        Some(register)
+    }
+
+    /// An allocation of the state the goal has no use for that can be changed
+    /// into goal allocation `index` instead of allocating it: of the same
+    /// size, only referenced from a register and holding no references. Of
+    /// those, the one that takes the fewest writes.
+    fn reusable(&self, index: usize) -> Option<usize> {
+        let theirs = &self.goal.allocations[index];
+        (0..self.state.allocations.len())
+            .filter(|&ours| {
+                let allocation = &self.state.allocations[ours];
+                !self.targets.contains(&Some(ours))
+                    && allocation.len() == theirs.len()
+                    && allocation.references == 1
+                    && self.state.registers.iter().any(|value| value.references(ours))
+                    && !allocation
+                        .iter()
+                        .any(|value| matches!(value, Value::Reference { .. }))
+            })
+            .max_by_key(|&ours| {
+                let ours = &self.state.allocations[ours];
+                ours.iter().zip(theirs.iter()).filter(|(a, b)| a == b).count()
+            })
+    }
+
+    /// Make the goal allocations the state does not have and fill them.
+    /// Allocations the goal has no use for are changed instead where
+    /// possible, see `reusable`.
+    ///
+    /// Filling an allocation frees the registers holding values only it
+    /// needs, so each new one is filled right after allocating it, those
+    /// taking the most values out of registers first. Those referring to
+    /// allocations that are not there yet wait for them. Reused allocations
+    /// are filled last, when the new ones took the values they held.
+    fn allocate(&mut self) -> Option<()> {
+        let created = (0..self.goal.allocations.len())
+            .filter(|&index| self.targets[index].is_none())
+            .collect::<Vec<_>>();
+        let mut reused = Vec::new();
+        for &index in &created {
+            if let Some(ours) = self.reusable(index) {
+                self.targets[index] = Some(ours);
+                reused.push(index);
+            }
+        }
+        let mut fresh = created
+            .into_iter()
+            .filter(|index| !reused.contains(index))
+            .collect::<Vec<_>>();
+        fresh.sort_by_key(|&index| Reverse(self.held(index)));
+        let mut waiting = Vec::new();
+        for index in fresh {
+            let wanted = Value::Reference { index, offset: 0 };
+            let dest = self.pick(|goal| goal == wanted)?;
+            self.make_free(dest)?;
+            let size = self.goal.allocations[index].len();
+            self.push(Transition::Alloc { dest, size })?;
+            self.targets[index] = Some(self.state.allocations.len() - 1);
+            waiting.push(index);
+            while let Some(position) = waiting.iter().position(|&index| self.is_ready(index)) {
+                self.fill(waiting.remove(position))?;
+            }
+        }
+        for index in waiting.into_iter().chain(reused) {
+            self.fill(index)?;
+        }
+        Some(())
+    }
+
+    /// Number of words of goal allocation `index` a register holds the value
+    /// for.
+    fn held(&self, index: usize) -> usize {
+        self.goal.allocations[index]
+            .iter()
+            .filter(|&&goal| self.value(goal).and_then(|value| self.holder(value)).is_some())
+            .count()
+    }
+
+    /// Whether every allocation goal allocation `index` refers to is there.
+    fn is_ready(&self, index: usize) -> bool {
+        self.goal.allocations[index]
+            .iter()
+            .all(|&goal| self.value(goal).is_some())
+    }
+
+    /// Write the values of goal allocation `index` into its target, those a
+    /// register holds first.
+    fn fill(&mut self, index: usize) -> Option<()> {
+        let mut words = (0..self.goal.allocations[index].len()).collect::<Vec<_>>();
+        words.sort_by_key(|&word| {
+            let goal = self.goal.allocations[index].values[word];
+            self.value(goal).and_then(|value| self.holder(value)).is_none()
+        });
+        for word in words {
+            let goal = self.goal.allocations[index].values[word];
+            let value = self.value(goal)?;
+            let old = self.state.allocations[self.targets[index]?].values[word];
+            if !goal.is_specified() || old == value {
+                continue;
+            }
+            // Keep what a reused allocation holds if it is still needed
+            if self.holder(old).is_none() && self.pending().contains(&old) {
+                let _ = self.fetch(old)?;
+            }
+            let source = self.fetch(value)?;
+            let target = self.targets[index]?;
+            let (dest, base) = (0..=15).map(Register).find_map(|register| {
+                match self.state.get_register(register) {
+                    Value::Reference { index, offset } if index == target => {
+                        Some((register, offset))
+                    }
+                    _ => None,
+                }
+            })?;
+            self.push(Transition::Write {
+                dest,
+                offset: word as isize - base,
+                source,
+            })?;
+        }
+        Some(())
+    }
+
+    /// Give every register its goal value.
+    fn place(&mut self) -> Option<()> {
+        loop {
+            let unsatisfied = self
+                .order
+                .iter()
+                .copied()
+                .filter(|&register| !self.satisfied(register))
+                .collect::<Vec<_>>();
+            if unsatisfied.is_empty() {
+                return Some(());
+            }
+            if let Some(&dest) = unsatisfied
+                .iter()
+                .find(|&&register| self.freeable(register).is_some())
+            {
+                self.make_free(dest)?;
+                let value = self.value(self.goal.get_register(dest))?;
+                self.fetch_into(dest, value)?;
+                continue;
+            }
+
+            // The registers wait on each other. Swap one with a register
+            // holding its value, or move one out of the way.
+            let cycle = unsatisfied.iter().find_map(|&dest| {
+                let value = self.value(self.goal.get_register(dest))?;
+                unsatisfied
+                    .iter()
+                    .copied()
+                    .find(|&source| self.state.get_register(source) == value)
+                    .map(|source| Transition::Swap { dest, source })
+            });
+            if self.swaps && cycle.is_some() {
+                self.push(cycle?)?;
+            } else if let Some(temporary) = self.pick(|_| false) {
+                self.make_free(temporary)?;
+                self.push(Transition::Copy {
+                    dest:   temporary,
+                    source: unsatisfied[0],
+                })?;
+            } else {
+                // No register is free, swapping is the only way
+                self.push(cycle?)?;
+            }
+        }
+    }
+
+    /// Compare the goal's value of the flags with zero.
+    fn test(&mut self) -> Option<()> {
+        let flags = self.goal.flags;
+        if !flags.is_specified() || self.state.flags == flags {
+            return Some(());
+        }
+        let source = self.fetch(flags)?;
+        self.push(Transition::Test { source })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cost_model::{CostTable, Costs, Objective};
+    use Value::*;
+
+    const COSTS: &Costs = &Costs {
+        table:     CostTable::GENERIC,
+        objective: Objective::Size,
+    };
+
+    fn check(initial: &State, goal: &State, costs: &Costs) -> Vec<Transition> {
+        let path = initial.greedy_transition_to(goal, costs).unwrap();
+        let mut state = initial.clone();
+        for transition in &path {
+            assert!(transition.applies(&state), "{:?} does not apply", transition);
+            transition.apply(&mut state);
+        }
+        assert!(state.satisfies(goal));
+        path
+    }
+
+    #[test]
+    fn test_cycle() {
+        // Three registers rotated, with swaps or through a free register
+        let initial = State::builder()
+            .register(Register(0), Symbol(0))
+            .register(Register(1), Symbol(1))
+            .register(Register(2), Symbol(2))
+            .build()
+            .unwrap();
+        let goal = State::builder()
+            .register(Register(0), Symbol(1))
+            .register(Register(1), Symbol(2))
+            .register(Register(2), Symbol(0))
+            .build()
+            .unwrap();
+        let path = check(&initial, &goal, COSTS);
+        assert_eq!(path.len(), 2);
+        assert!(path.iter().all(|transition| matches!(transition, Transition::Swap { .. })));
+        let speed = Costs {
+            objective: Objective::Speed,
+            ..*COSTS
+        };
+        let path = check(&initial, &goal, &speed);
+        assert_eq!(path.len(), 4);
+        assert!(path.iter().all(|transition| matches!(transition, Transition::Copy { .. })));
+
+        // Without a free register there is no way around swapping
+        let mut initial = State::builder();
+        let mut goal = State::builder();
+        for register in 0..16 {
+            initial = initial.register(Register(register), Symbol(register as usize));
+            goal = goal.register(Register(register), Symbol((register as usize + 1) % 16));
+        }
+        check(&initial.build().unwrap(), &goal.build().unwrap(), &speed);
+    }
+
+    #[test]
+    fn test_closures() {
+        // The current closure has the size of the new one, which takes its
+        // place once the captures it still has to give are read out.
+        let closure = Reference {
+            index:  0,
+            offset: 0,
+        };
+        let initial = State::builder()
+            .register(Register(0), closure)
+            .register(Register(1), Symbol(1))
+            .allocation(vec![Literal(100), Symbol(2), Symbol(3)])
+            .build()
+            .unwrap();
+        let goal = State::builder()
+            .register(Register(0), Symbol(3))
+            .register(Register(1), closure)
+            .register(Register(2), Literal(7))
+            .allocation(vec![Literal(200), Symbol(1), Symbol(2)])
+            .build()
+            .unwrap();
+        let kinds = |path: Vec<Transition>| {
+            let count = |kind: fn(&Transition) -> bool| path.iter().filter(|t| kind(t)).count();
+            (
+                count(|t| matches!(t, Transition::Alloc { .. })),
+                count(|t| matches!(t, Transition::Drop { .. })),
+            )
+        };
+        assert_eq!(kinds(check(&initial, &goal, COSTS)), (0, 0));
+
+        // Otherwise it is dropped
+        let goal = State::builder()
+            .register(Register(0), Symbol(3))
+            .register(Register(1), closure)
+            .allocation(vec![Literal(200), Symbol(2)])
+            .build()
+            .unwrap();
+        assert_eq!(kinds(check(&initial, &goal, COSTS)), (1, 1));
+    }
+
+    #[test]
+    fn test_kept_allocation() {
+        // A loop keeps its closure in r0 and reads the captures out of it
+        let closure = Reference {
+            index:  0,
+            offset: 0,
+        };
+        let initial = State::builder()
+            .register(Register(0), closure)
+            .register(Register(1), Symbol(1))
+            .allocation(vec![Literal(100), Symbol(2)])
+            .build()
+            .unwrap();
+        let goal = State::builder()
+            .register(Register(0), closure)
+            .register(Register(1), Symbol(2))
+            .flags(Symbol(1))
+            .allocation(vec![Literal(100), Symbol(2)])
+            .build()
+            .unwrap();
+        assert_eq!(check(&initial, &goal, COSTS), vec![
+            Transition::Test {
+                source: Register(1),
+            },
+            Transition::Read {
+                dest:   Register(1),
+                source: Register(0),
+                offset: 1,
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_argument_block() {
+        // Every register is taken by an argument or the argument block. A new
+        // closure takes the arguments out of them and the block is reused
+        // for a smaller one.
+        let reference = |index| Reference { index, offset: 0 };
+        let mut initial = State::builder();
+        for register in 0..15 {
+            initial = initial.register(Register(register), Symbol(register as usize));
+        }
+        let initial = initial
+            .register(Register(15), reference(0))
+            .allocation(vec![Symbol(15), Symbol(16)])
+            .build()
+            .unwrap();
+        let goal = State::builder()
+            .register(Register(0), reference(0))
+            .register(Register(1), reference(1))
+            .allocation(vec![Literal(200), Symbol(2)])
+            .allocation(
+                std::iter::once(Literal(100))
+                    .chain((1..=16).map(Symbol))
+                    .collect(),
+            )
+            .build()
+            .unwrap();
+        let path = check(&initial, &goal, COSTS);
+        let allocs = path
+            .iter()
+            .filter(|transition| matches!(transition, Transition::Alloc { .. }))
+            .count();
+        assert_eq!(allocs, 1);
+    }
+}
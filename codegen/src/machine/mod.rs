@@ -1,9 +1,28 @@
+#[cfg(feature = "native")]
 mod assembler;
+#[cfg(feature = "native")]
+mod encode;
+mod greedy;
 mod optimizer;
 mod state;
 mod transition;
 mod value;
 
-pub(crate) use state::{Allocation, Flag, Register, State};
-pub(crate) use transition::Transition;
-pub(crate) use value::Value;
+// Scheduling values on a register machine.
+//
+// A `State` holds sixteen registers, the status flags and heap allocations.
+// `State::transition_to` searches for the cheapest sequence of
+// `Transition`s, single instructions, that puts the values a goal state
+// asks for in place, `State::greedy_transition_to` quickly picks one that
+// need not be the cheapest. The code generator uses them to set up the
+// registers for each call, but nothing here depends on the rest of the
+// compiler: other code generators can build states with `State::builder`
+// and weigh transitions with their own `TransitionCost`.
+
+#[cfg(feature = "native")]
+pub(crate) use encode::Instruction;
+#[cfg(feature = "native")]
+pub(crate) use state::Flag;
+pub use state::{Allocation, Register, State, StateBuilder, StateIterator};
+pub use transition::{Transition, TransitionCost};
+pub use value::Value;
@@ -1,15 +1,97 @@
-use super::{Register, State, Transition, Value};
+use super::{Register, State, Transition, TransitionCost, Value};
+use crate::Set;
 use itertools::Itertools;
+use log::debug;
+use parser::timings;
 use pathfinding::directed::astar::astar;
-use std::cmp::min;
+use std::{cmp::min, collections::VecDeque};
 
 // TODO: Caches results using normalized version of the problem.
 
+/// Mark a literal or symbol as constructed, returns whether it already was.
+/// References are not tracked, `register_set_cost` assumes they are available.
+fn is_constructed(constructed: &mut Vec<Value>, value: Value) -> bool {
+    match value {
+        Value::Literal(_) | Value::Symbol(_) => {
+            if constructed.contains(&value) {
+                true
+            } else {
+                constructed.push(value);
+                false
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Where the values a goal wants are in a state. `min_distance` looks up
+/// every goal value, so the state is scanned once up front instead of for
+/// each of them.
+struct Sources {
+    /// The specified values of the goal, sorted and without duplicates, with
+    /// the registers holding them as a bit set and the fewest reads it takes
+    /// to get them out of an allocation, see `read_depths`.
+    values: Vec<(Value, u16, Option<usize>)>,
+}
+
+impl Sources {
+    fn new(state: &State, goal: &State) -> Self {
+        let mut values = goal
+            .into_iter()
+            .filter(|value| value.is_specified())
+            .map(|value| (*value, 0, None))
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        values.dedup();
+        let mut sources = Self { values };
+        for (register, value) in state.registers.iter().enumerate() {
+            if let Some((_, registers, _)) = sources.get_mut(*value) {
+                *registers |= 1 << register;
+            }
+        }
+        for (allocation, depth) in state.allocations.iter().zip(state.read_depths()) {
+            for value in allocation {
+                if let Some((_, _, reads)) = sources.get_mut(*value) {
+                    *reads = Some(reads.map_or(depth, |reads| min(reads, depth)));
+                }
+            }
+        }
+        sources
+    }
+
+    fn get_mut(&mut self, value: Value) -> Option<&mut (Value, u16, Option<usize>)> {
+        let index = self.values.binary_search_by_key(&value, |entry| entry.0).ok()?;
+        Some(&mut self.values[index])
+    }
+
+    /// The registers holding goal value `value`, as a bit set, and the fewest
+    /// reads it takes to get it out of an allocation, if any holds it.
+    fn get(&self, value: Value) -> (u16, Option<usize>) {
+        self.values
+            .binary_search_by_key(&value, |entry| entry.0)
+            .map_or((0, None), |index| (self.values[index].1, self.values[index].2))
+    }
+}
+
 impl State {
-    pub(crate) fn transition_to(&self, goal: &Self) -> Vec<Transition> {
+    /// The cheapest sequence of transitions from this state to one that
+    /// satisfies `goal`, found with A*. It ends with `Drop`s of the
+    /// allocations the goal has no use for, see `drop_dead`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `goal` is not [reachable](Self::reachable) from this state.
+    #[must_use]
+    pub fn transition_to<C: TransitionCost + ?Sized>(
+        &self,
+        goal: &Self,
+        costs: &C,
+    ) -> Vec<Transition> {
         assert!(self.reachable(goal));
+        debug_assert!(self.is_counted());
 
-        // Find the optimal transition using pathfinder's A*
+        // Find the optimal transition using pathfinder's A*. The search is
+        // over canonical states, see `canonical`.
         let mut nodes_explored = 0;
         let (path, cost) = astar(
             self,
@@ -20,15 +102,14 @@ impl State {
                 //     n.min_distance(goal),
                 //     n
                 // );
-                n.useful_transitions(goal)
+                n.useful_transitions(goal, costs)
                     .into_iter()
                     .filter_map(|t| {
                         nodes_explored += 1;
                         // TODO: lazily compute next state?
-                        let mut new_state = n.clone();
-                        t.apply(&mut new_state);
+                        let new_state = t.after(n)?;
                         if new_state.is_valid() && new_state.reachable(goal) {
-                            Some((new_state, t.cost()))
+                            Some((new_state.canonical(goal, costs).0, t.cost(costs)))
                         } else {
                             None
                         }
@@ -36,39 +117,219 @@ impl State {
                     // TODO: Don't allocate
                     .collect::<Vec<_>>()
             },
-            |n| n.min_distance(goal),
+            |n| n.min_distance(goal, costs),
             |n| n.satisfies(goal),
         )
         .expect("Could not find valid transition path");
-        println!("Nodes explored: {}", nodes_explored);
-        println!("Cost: {}", cost);
+        debug!("Nodes explored: {}", nodes_explored);
+        timings::count("nodes", nodes_explored);
+        debug!("Cost: {}", cost);
 
         // Pathfinder gives a list of nodes visited, not the path taken.
         // So take all the pairs of nodes and find the best transition
-        // between them.
+        // between them. Of equally costly transitions, take the one that
+        // extends the critical path least. For example copying a value twice
+        // from the same register rather than in a chain. Transitions between
+        // canonical states are renamed to the registers of the actual state.
         let mut result = Vec::default();
+        let mut end = self.clone();
+        let mut registers = (0..=15).map(Register).collect::<Vec<_>>();
         for (from, to) in path.iter().tuple_windows() {
-            let mut cost = usize::max_value();
-            let mut best = None;
-            for transition in from.useful_transitions(goal) {
-                let mut dest = from.clone();
-                transition.apply(&mut dest);
-                if dest == *to && transition.cost() < cost {
-                    cost = transition.cost();
-                    best = Some(transition);
-                }
-            }
-            result.push(best.expect("Could not reproduce path"));
+            let best = from
+                .useful_transitions(goal, costs)
+                .into_iter()
+                .filter(|transition| {
+                    transition.after(from).map(|next| next.canonical(goal, costs).0).as_ref()
+                        == Some(to)
+                })
+                .map(|transition| {
+                    transition.rename(|register| registers[register.as_u8() as usize])
+                })
+                .min_by_key(|transition| {
+                    let mut extended = result.clone();
+                    extended.push(*transition);
+                    let latency = costs.latency(&extended);
+                    (transition.cost(costs), latency)
+                })
+                .expect("Could not reproduce path");
+            best.apply(&mut end);
+            result.push(best);
+            let (canonical, from) = end.canonical(goal, costs);
+            debug_assert_eq!(&canonical, to);
+            registers = from.to_vec();
         }
 
         // Test admisability criterion along path
         // #[cfg(debug)]
         // test::test_admisability(self, goal, &result);
 
+        result.extend(end.drop_dead(goal));
         result
     }
 
-    fn register_set_cost(&self, dest: Option<Register>, value: Value) -> usize {
+    /// This state with the values of the registers `goal` leaves unspecified
+    /// sorted within each class of registers, see
+    /// [`TransitionCost::register_class`], and for each register the one
+    /// its value comes from. Those registers are interchangeable on the way
+    /// to `goal`, so states that only differ in which of them holds what
+    /// have the same canonical state and are searched once.
+    fn canonical<C: TransitionCost + ?Sized>(
+        &self,
+        goal: &Self,
+        costs: &C,
+    ) -> (Self, [Register; 16]) {
+        let mut canonical = self.clone();
+        let mut from = [Register(0); 16];
+        let mut unspecified = (0..=15)
+            .map(Register)
+            .filter(|&register| !goal.get_register(register).is_specified())
+            .collect::<Vec<_>>();
+        unspecified.sort_by_key(|&register| costs.register_class(register));
+        for register in (0..=15).map(Register) {
+            from[register.as_u8() as usize] = register;
+        }
+        for (_, class) in &unspecified
+            .iter()
+            .group_by(|&&register| costs.register_class(register))
+        {
+            let class = class.copied().collect::<Vec<_>>();
+            let values = class
+                .iter()
+                .map(|&register| (self.get_register(register), register))
+                .sorted()
+                .collect::<Vec<_>>();
+            for (register, (value, source)) in class.into_iter().zip(values) {
+                canonical.registers[register.as_u8() as usize] = value;
+                from[register.as_u8() as usize] = source;
+            }
+        }
+        (canonical, from)
+    }
+
+    /// Which allocations the registers `goal` specifies reach, directly or
+    /// through other allocations. The others are dead once `goal` is reached.
+    fn live(&self, goal: &Self) -> Vec<bool> {
+        let mut live = vec![false; self.allocations.len()];
+        let mut todo = self
+            .registers
+            .iter()
+            .zip(goal.registers.iter())
+            .filter(|(_, goal)| goal.is_specified())
+            .map(|(value, _)| *value)
+            .collect::<Vec<_>>();
+        while let Some(value) = todo.pop() {
+            if let Value::Reference { index, .. } = value {
+                if !live[index] {
+                    live[index] = true;
+                    todo.extend(self.allocations[index].iter());
+                }
+            }
+        }
+        live
+    }
+
+    /// Drop the dead allocations, see `live`, that a register `goal` leaves
+    /// unspecified holds the last reference to. An allocation that holds the
+    /// last reference to another one is not dropped, as that would take
+    /// reading the reference out first.
+    pub(super) fn drop_dead(&mut self, goal: &Self) -> Vec<Transition> {
+        let mut drops = Vec::new();
+        loop {
+            let live = self.live(goal);
+            let drop = (0..=15).map(Register).find_map(|dest| {
+                let drop = Transition::Drop { dest };
+                match self.get_register(dest) {
+                    Value::Reference { index, .. }
+                        if !live[index]
+                            && !goal.get_register(dest).is_specified()
+                            && drop.applies(self) =>
+                    {
+                        Some(drop)
+                    }
+                    _ => None,
+                }
+            });
+            match drop {
+                Some(drop) => {
+                    drop.apply(self);
+                    drops.push(drop);
+                }
+                None => return drops,
+            }
+        }
+    }
+
+    /// The number of reads it takes to get a reference to each allocation into
+    /// a register, following references from the registers through other
+    /// allocations. Allocations that can not be reached this way count as
+    /// zero, the heuristic has to stay optimistic.
+    fn read_depths(&self) -> Vec<usize> {
+        let mut depths = vec![usize::max_value(); self.allocations.len()];
+        let mut queue = VecDeque::new();
+        for value in &self.registers {
+            if let Value::Reference { index, .. } = *value {
+                if depths[index] != 0 {
+                    depths[index] = 0;
+                    queue.push_back(index);
+                }
+            }
+        }
+        while let Some(index) = queue.pop_front() {
+            for value in &self.allocations[index] {
+                if let Value::Reference { index: next, .. } = *value {
+                    if depths[next] == usize::max_value() {
+                        depths[next] = depths[index] + 1;
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+        for depth in &mut depths {
+            if *depth == usize::max_value() {
+                *depth = 0;
+            }
+        }
+        depths
+    }
+
+    /// Which allocations hold, directly or through references to other
+    /// allocations, one of the `wanted` values. A chain that leads there
+    /// visits each allocation at most once, so this takes at most one round
+    /// per allocation.
+    pub(super) fn leads_to(&self, wanted: &Set<Value>) -> Vec<bool> {
+        let mut leads = self
+            .allocations
+            .iter()
+            .map(|allocation| allocation.iter().any(|value| wanted.contains(value)))
+            .collect::<Vec<_>>();
+        for _ in 0..self.allocations.len() {
+            let mut changed = false;
+            for (index, allocation) in self.allocations.iter().enumerate() {
+                if leads[index] {
+                    continue;
+                }
+                leads[index] = allocation.iter().any(|value| {
+                    match *value {
+                        Value::Reference { index: next, .. } => leads[next],
+                        _ => false,
+                    }
+                });
+                changed |= leads[index];
+            }
+            if !changed {
+                break;
+            }
+        }
+        leads
+    }
+
+    fn register_set_cost<C: TransitionCost + ?Sized>(
+        &self,
+        dest: Option<Register>,
+        value: Value,
+        sources: &Sources,
+        costs: &C,
+    ) -> usize {
         use Transition::*;
         use Value::*;
         // No goal
@@ -76,10 +337,11 @@ impl State {
             return 0;
         }
         // TODO: Copy does not take swaps into account
+        let (registers, reads) = sources.get(value);
 
         // Ignore References
         // TODO: Copy for references if not in place
-        if let Reference { .. } = value {
+        if let Reference { index, .. } = value {
             // Assume the reference is available somewhere. If wo do Alloc,
             // we need to subtract this cost.
             if let Some(dest) = dest {
@@ -95,26 +357,33 @@ impl State {
                             dest:   Register(0),
                             source: Register(0),
                         }
-                        .cost(),
+                        .cost(costs),
                         Swap {
                             dest:   Register(0),
                             source: Register(0),
                         }
-                        .cost(),
+                        .cost(costs),
                     );
                 }
             }
+            if registers != 0 || index >= self.allocations.len() {
+                // Already available to write from, or to an allocation that
+                // does not exist yet, which the Alloc creating it leaves in a
+                // register to write from. This includes allocations referring
+                // to themselves.
+                return 0;
+            }
             return min(
                 Copy {
                     dest:   Register(0),
                     source: Register(0),
                 }
-                .cost(),
+                .cost(costs),
                 Swap {
                     dest:   Register(0),
                     source: Register(0),
                 }
-                .cost(),
+                .cost(costs),
             );
         }
 
@@ -122,48 +391,55 @@ impl State {
         let mut cost = usize::max_value();
 
         // Try copy from registers
-        for source in (0..=15).map(Register) {
-            if value == self.get_register(source) {
-                cost = min(cost, match dest {
-                    None => 0,
-                    Some(dest) if dest == source => 0,
-                    Some(dest) => min(Copy { dest, source }.cost(), Swap { dest, source }.cost()),
-                });
-                if cost == 0 {
-                    return cost;
-                }
+        if registers != 0 {
+            let dest = match dest {
+                Some(dest) if registers & (1 << dest.as_u8()) == 0 => dest,
+                _ => return 0,
+            };
+            for source in (0..=15).filter(|source| registers & (1 << source) != 0) {
+                let source = Register(source);
+                cost = min(
+                    cost,
+                    min(
+                        Copy { dest, source }.cost(costs),
+                        Swap { dest, source }.cost(costs),
+                    ),
+                );
             }
         }
         let dest = dest.unwrap_or(Register(0));
 
         // Try literals
         if let Literal(value) = value {
-            cost = min(cost, Set { dest, value }.cost());
+            cost = min(cost, Set { dest, value }.cost(costs));
         }
 
-        // Try copy from allocations
+        // Try copy from allocations, reading the references that lead there
+        // first
         let read_cost = Read {
             dest,
             source: Register(0),
             offset: 0,
         }
-        .cost();
+        .cost(costs);
         if cost <= read_cost {
             return cost;
         }
-        for alloc in &self.allocations {
-            for alloc_val in alloc {
-                if *alloc_val == value {
-                    return read_cost;
-                }
-            }
+        let hop_cost = Read {
+            dest:   Register(0),
+            source: Register(0),
+            offset: 0,
+        }
+        .cost(costs);
+        if let Some(depth) = reads {
+            cost = min(cost, read_cost + depth * hop_cost);
         }
 
         assert_ne!(cost, usize::max_value());
         cost
     }
 
-    pub(crate) fn min_distance(&self, goal: &Self) -> usize {
+    pub(crate) fn min_distance<C: TransitionCost + ?Sized>(&self, goal: &Self, costs: &C) -> usize {
         use Transition::*;
         use Value::*;
         // Compute minimum distance by taking the sum of the minimum cost to set
@@ -178,29 +454,43 @@ impl State {
         }
 
         let mut cost = 0;
-        // let mut constructed: Set<Value> = Set::default();
 
-        // TODO: Values only have to be Set or Read once, after that they can be Copy'd
+        // Values only have to be Set or Read once, after that they can be
+        // Copy'd (or Swap'd) into another register or written from a register.
         // A Copy is not always better though.
-
-        // let get_cost = |dest, val| {
-        // let construct_cost = ;
-        // if constructed.contains(val) {
-        // if let Some(dest) = dest {
-        // min(construct_cost, Copy { dest, source }.cost())
-        // } else {
-        // 0
-        // }
-        // } else {
-        // self.register_set_cost(dest, *goal);
-        // }
-        // };
+        let move_cost = min(
+            Copy {
+                dest:   Register(0),
+                source: Register(1),
+            }
+            .cost(costs),
+            Swap {
+                dest:   Register(0),
+                source: Register(1),
+            }
+            .cost(costs),
+        );
+        let mut constructed: Vec<Value> = Vec::new();
+        let sources = Sources::new(self, goal);
 
         // Registers
-        for (i, (ours, goal)) in self.registers.iter().zip(goal.registers.iter()).enumerate() {
-            cost += self.register_set_cost(Some(Register(i as u8)), *goal);
+        for (i, goal) in goal.registers.iter().enumerate() {
+            let construct_cost =
+                self.register_set_cost(Some(Register(i as u8)), *goal, &sources, costs);
+            cost += if is_constructed(&mut constructed, *goal) {
+                min(construct_cost, move_cost)
+            } else {
+                construct_cost
+            };
+        }
+
+        // Flags need a `Test` of their value
+        if goal.flags.is_specified() && self.flags != goal.flags {
+            cost += Test {
+                source: Register(0),
+            }
+            .cost(costs);
         }
-        // TODO: Flags
 
         // Allocations
         let write_cost = Write {
@@ -208,103 +498,217 @@ impl State {
             offset: 0,
             source: Register(0),
         }
-        .cost();
+        .cost(costs);
+        let slot_cost = |constructed: &mut Vec<Value>, goal: Value| {
+            if is_constructed(constructed, goal) {
+                write_cost
+            } else {
+                write_cost + self.register_set_cost(None, goal, &sources, costs)
+            }
+        };
         let mut reused = 0;
         for goal in &goal.allocations {
             // Compute the cost of constructing it from scratch
+            let mut alloc_constructed = constructed.clone();
             let mut alloc_cost = Alloc {
                 dest: Register(0),
                 size: goal.len(),
             }
-            .cost();
+            .cost(costs);
             // Since Alloc is in place, we can undo one Copy
             alloc_cost -= min(
                 Copy {
                     dest:   Register(0),
                     source: Register(0),
                 }
-                .cost(),
+                .cost(costs),
                 Swap {
                     dest:   Register(0),
                     source: Register(0),
                 }
-                .cost(),
+                .cost(costs),
             );
             for goal in goal.iter() {
                 if goal.is_specified() {
-                    alloc_cost += write_cost + self.register_set_cost(None, *goal);
+                    alloc_cost += slot_cost(&mut alloc_constructed, *goal);
                 }
             }
 
             // See if we can change an existing allocation
             let mut reuse_cost = usize::max_value();
+            let mut reuse_constructed = Vec::new();
             for ours in &self.allocations {
                 if ours.len() != goal.len() {
                     continue;
                 }
+                let mut change_constructed = constructed.clone();
                 let mut change_cost = 0;
                 for (ours, goal) in ours.iter().zip(goal.iter()) {
                     if !goal.is_specified() || ours == goal {
                         // Good as is
                         continue;
                     }
-                    change_cost += write_cost + self.register_set_cost(None, *goal);
+                    change_cost += slot_cost(&mut change_constructed, *goal);
+                }
+                if change_cost < reuse_cost {
+                    reuse_cost = change_cost;
+                    reuse_constructed = change_constructed;
                 }
-                reuse_cost = min(reuse_cost, change_cost);
             }
 
             // Add best option to total cost
             if reuse_cost <= alloc_cost {
                 cost += reuse_cost;
+                constructed = reuse_constructed;
                 reused += 1;
             } else {
                 cost += alloc_cost;
+                constructed = alloc_constructed;
             }
         }
-        cost += (self.allocations.len() - reused) * Drop { dest: Register(0) }.cost();
+        // Several goal allocations may reuse the same existing allocation.
+        cost +=
+            self.allocations.len().saturating_sub(reused) * Drop { dest: Register(0) }.cost(costs);
 
         cost
     }
 
-    fn useful_transitions(&self, goal: &Self) -> Vec<Transition> {
+    /// The transitions worth trying from this state towards `goal`. Leaves out
+    /// those that change nothing, and of the free registers, which hold
+    /// nothing the goal needs and should hold nothing, tries only the first
+    /// of each class, see [`TransitionCost::register_class`]. Free registers
+    /// of a class are interchangeable: any path through one of them can swap
+    /// it for the first one at the same cost, so this does not lose
+    /// optimality.
+    fn useful_transitions<C: TransitionCost + ?Sized>(
+        &self,
+        goal: &Self,
+        costs: &C,
+    ) -> Vec<Transition> {
         let mut result = Vec::default();
-        // TODO: Filter out invalid transitions (which would lose references)
+        let swaps = costs.swaps();
         // TODO: No need to enumerate all cases of writing to an Unspecified, one
         // should be sufficient.
+        let wanted = goal
+            .into_iter()
+            .chain(Some(&goal.flags))
+            .filter(|value| matches!(value, Value::Literal(_) | Value::Symbol(_)))
+            .copied()
+            .collect::<Set<_>>();
+
+        // References are never free, they keep allocations alive
+        let free = |register: Register| {
+            let value = self.get_register(register);
+            !goal.get_register(register).is_specified()
+                && match value {
+                    Value::Unspecified => true,
+                    Value::Literal(_) | Value::Symbol(_) => !wanted.contains(&value),
+                    Value::Reference { .. } => false,
+                }
+        };
+        let first_of_class = |include: &dyn Fn(Register) -> bool| {
+            let mut first = Vec::new();
+            for register in (0..=15).map(Register).filter(|&register| include(register)) {
+                let class = costs.register_class(register);
+                if !first.iter().any(|(other, _)| *other == class) {
+                    first.push((class, register));
+                }
+            }
+            first
+        };
+        let first_free = first_of_class(&free);
+        let junk = |register: Register| {
+            free(register) && self.get_register(register).is_specified()
+        };
+        let first_junk = first_of_class(&junk);
+        let is_first = |first: &[(u8, Register)], register: Register| {
+            first.contains(&(costs.register_class(register), register))
+        };
+        let tried = |register: Register| !free(register) || is_first(&first_free, register);
+        // Values are only put in registers that do not hold what the goal
+        // wants already. Without swaps they are also moved through free
+        // registers.
+        let overwrite = |dest: Register| {
+            if junk(dest) {
+                is_first(&first_junk, dest)
+            } else {
+                self.get_register(dest) != goal.get_register(dest)
+            }
+        };
+        let temporary = |dest: Register| !swaps && free(dest) && is_first(&first_free, dest);
+        // With swaps the goal registers are hints: a value goes to a register
+        // the goal wants it in, or waits in a junk register. Only if its class
+        // has none left, a register the goal wants something else in holds it
+        // for a while. Without swaps values move through free registers and
+        // parking one in a goal register can give a shorter critical path, so
+        // there it is not pruned.
+        let hinted = |dest: Register, value: Value| {
+            let wants = goal.get_register(dest);
+            !swaps
+                || !wants.is_specified()
+                || wants == value
+                || matches!(
+                    (wants, value),
+                    (Value::Reference { .. }, Value::Reference { .. })
+                )
+                || !first_junk
+                    .iter()
+                    .any(|&(class, _)| class == costs.register_class(dest))
+        };
 
         // Generate Set transitions for each goal literal and register.
         for value in goal.literals().into_iter() {
-            for dest in (0..=15).map(Register) {
-                let dest_val = self.get_register(dest);
-                if dest_val == goal.get_register(dest) {
-                    // Don't overwrite already correct values
-                    continue;
+            for dest in (0..=15).map(Register).filter(|&dest| overwrite(dest)) {
+                if self.get_register(dest) != Value::Literal(value)
+                    && hinted(dest, Value::Literal(value))
+                {
+                    result.push(Transition::Set { dest, value });
                 }
-                result.push(Transition::Set { dest, value });
             }
         }
 
+        // Reading a reference is only useful to read through it, up to a value
+        // the goal wants, or to reuse its allocation for a goal one.
+        let leads_to = self.leads_to(&wanted);
+        let useful_read = |value: Value| {
+            match value {
+                Value::Unspecified => false,
+                Value::Reference { index, .. } => !goal.allocations.is_empty() || leads_to[index],
+                _ => true,
+            }
+        };
+
         // Copy and swap registers around
         for source in (0..=15).map(Register) {
             // No point in copying from unspecified regs
-            if !self.get_register(source).is_specified() {
+            let source_val = self.get_register(source);
+            if !source_val.is_specified() {
                 continue;
             }
 
-            // Generate moves and swaps between registers
-            for dest in (0..=15).map(Register) {
+            for dest in (0..=15)
+                .map(Register)
+                .filter(|&dest| overwrite(dest) || temporary(dest))
+            {
                 let dest_val = self.get_register(dest);
-                if dest_val == goal.get_register(dest) {
-                    // Don't overwrite already correct values
-                    continue;
-                }
 
                 // Copy to any reg
-                if source != dest {
+                if dest_val != source_val && hinted(dest, source_val) {
                     result.push(Transition::Copy { dest, source });
                 }
-                // Swap two regs
-                if source < dest && dest_val.is_specified() {
+            }
+
+            // Swap two regs. Without swaps values are moved through free
+            // registers.
+            for dest in (0..=15).map(Register) {
+                let dest_val = self.get_register(dest);
+                if swaps
+                    && source < dest
+                    && dest_val.is_specified()
+                    && dest_val != source_val
+                    && overwrite(dest)
+                    && (!junk(source) || is_first(&first_junk, source))
+                {
                     result.push(Transition::Swap { dest, source });
                 }
             }
@@ -313,17 +717,20 @@ impl State {
             if let Value::Reference {
                 index,
                 offset: base_offset,
-            } = self.get_register(source)
+            } = source_val
             {
                 let values = &self.allocations[index];
                 for offset in (0..values.len()).map(|n| (n as isize) - base_offset) {
                     // TODO: Check if goal is specified?
+                    let value = self.get_reference(source, offset).unwrap();
                     for dest in (0..=15).map(Register) {
                         let dest_val = self.get_register(dest);
 
-                        // Read if there is something there
-                        if dest_val != goal.get_register(dest)
-                            && self.get_reference(source, offset).unwrap().is_specified()
+                        // Read if there is something useful there
+                        if overwrite(dest)
+                            && dest_val != value
+                            && useful_read(value)
+                            && hinted(dest, value)
                         {
                             result.push(Transition::Read {
                                 dest,
@@ -333,8 +740,7 @@ impl State {
                         }
 
                         // Writes have source and dest flipped
-                        if dest_val.is_specified() {
-                            // TODO: Don't overwrite already correct values
+                        if dest_val.is_specified() && dest_val != value {
                             result.push(Transition::Write {
                                 dest: source,
                                 offset,
@@ -348,7 +754,7 @@ impl State {
 
         // Allocate for goal sizes
         for size in goal.alloc_sizes().into_iter() {
-            for dest in (0..=15).map(Register) {
+            for dest in (0..=15).map(Register).filter(|&dest| tried(dest)) {
                 result.push(Transition::Alloc { dest, size });
             }
         }
@@ -360,14 +766,59 @@ impl State {
             }
         }
 
+        // Test the goal flags value
+        if goal.flags.is_specified() {
+            for source in (0..=15).map(Register) {
+                if self.get_register(source) == goal.flags {
+                    result.push(Transition::Test { source });
+                }
+            }
+        }
+
+        // Filter out invalid transitions (which would lose references)
+        result.retain(|transition| transition.applies(self));
         result
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{super::Allocation, *};
-    use proptest::strategy::Strategy;
+    use super::{
+        super::{state::test::arb_state, transition::test::arb_simple_transition, Allocation},
+        *,
+    };
+    use crate::cost_model::{CostTable, Costs, Objective};
+    use proptest::{collection::vec, prop_assert, proptest};
+
+    const COSTS: &Costs = &Costs {
+        table:     CostTable::GENERIC,
+        objective: Objective::Size,
+    };
+
+    proptest! {
+        /// The cost of any path is an upper bound on the optimal cost, which
+        /// an admissible heuristic may not exceed.
+        ///
+        /// Paths do not `Swap` or `Drop`. A `Swap` places two values at once
+        /// while `min_distance` charges each register separately, and `Drop`
+        /// renumbers allocations while references are compared by index.
+        #[test]
+        fn test_min_distance_admissible(
+            initial in arb_state(),
+            transitions in vec(arb_simple_transition(), 0..8)
+        ) {
+            let mut goal = initial.clone();
+            let mut cost = 0;
+            for transition in &transitions {
+                if let Some(next) = transition.after(&goal) {
+                    goal = next;
+                    cost += transition.cost(COSTS);
+                }
+            }
+            let heuristic = initial.min_distance(&goal, COSTS);
+            prop_assert!(heuristic <= cost, "{} > {} for {:?}", heuristic, cost, transitions);
+        }
+    }
 
     #[test]
     fn test_min_distance() {
@@ -381,7 +832,7 @@ mod test {
             index:  0,
             offset: 0,
         };
-        goal.allocations.push(Allocation(vec![Symbol(5)]));
+        goal.allocations.push(Allocation::new(vec![Symbol(5)]));
         let optimal_path = vec![
             Alloc {
                 dest: Register(1),
@@ -397,13 +848,36 @@ mod test {
                 value: 3,
             },
         ];
-        let optimal_cost = optimal_path.iter().map(|t| t.cost()).sum::<usize>();
+        let optimal_cost = optimal_path.iter().map(|t| t.cost(COSTS)).sum::<usize>();
         test_admisability(&initial, &goal, &optimal_path);
-        let path = initial.transition_to(&goal);
-        let path_cost = optimal_path.iter().map(|t| t.cost()).sum::<usize>();
+        let path = initial.transition_to(&goal, COSTS);
+        let path_cost = optimal_path.iter().map(|t| t.cost(COSTS)).sum::<usize>();
         assert_eq!(optimal_cost, path_cost);
     }
 
+    #[test]
+    fn test_min_distance_self_reference() {
+        use Transition::*;
+        // The Alloc leaves the reference in the register it is written from
+        let initial = State::default();
+        let path = vec![
+            Alloc {
+                dest: Register(4),
+                size: 1,
+            },
+            Write {
+                dest:   Register(4),
+                offset: 0,
+                source: Register(4),
+            },
+        ];
+        let mut goal = initial.clone();
+        for transition in &path {
+            transition.apply(&mut goal);
+        }
+        test_admisability(&initial, &goal, &path);
+    }
+
     /// Provided a known best bath, test heuristic admisability.
     fn test_admisability(initial: &State, goal: &State, path: &[Transition]) {
         println!("Initial:\n{}", initial);
@@ -415,7 +889,7 @@ mod test {
             let mut next = states.last().unwrap().clone();
             ts.apply(&mut next);
             states.push(next);
-            println!(" {:7}: {:?}", ts.cost(), ts);
+            println!(" {:7}: {:?}", ts.cost(COSTS), ts);
         }
         assert!(states.last().unwrap().satisfies(&goal));
 
@@ -424,12 +898,12 @@ mod test {
         let mut overall_admisable = true;
         for start in (0..states.len()) {
             for end in (start..states.len()) {
-                let heuristic = states[start].min_distance(&states[end]);
+                let heuristic = states[start].min_distance(&states[end], COSTS);
                 let distance = path
                     .iter()
                     .skip(start)
                     .take(end - start)
-                    .map(|t| t.cost())
+                    .map(|t| t.cost(COSTS))
                     .sum::<usize>();
                 let admisable = heuristic <= distance;
                 println!(
@@ -440,8 +914,12 @@ mod test {
             }
 
             // Goal as target
-            let heuristic = states[start].min_distance(&goal);
-            let distance = path.iter().skip(start).map(|t| t.cost()).sum::<usize>();
+            let heuristic = states[start].min_distance(&goal, COSTS);
+            let distance = path
+                .iter()
+                .skip(start)
+                .map(|t| t.cost(COSTS))
+                .sum::<usize>();
             let admisable = heuristic <= distance;
             println!(
                 "{} - G: {:7} {:7} {:5}",
@@ -458,21 +936,457 @@ mod test {
     fn test_consistency(initial: &State, goal: &State) {
         println!("Initial:\n{}", initial);
         println!("Goal:\n{}", goal);
-        let mindist = initial.min_distance(goal);
+        let mindist = initial.min_distance(goal, COSTS);
         let mut overal_consistent = true;
         println!("Heuristic distance: {}", mindist);
-        for ts in initial.useful_transitions(goal) {
+        for ts in initial.useful_transitions(goal, COSTS) {
             let mut neighbor = initial.clone();
             ts.apply(&mut neighbor);
-            let cost = ts.cost();
-            let dist = neighbor.min_distance(goal);
+            let cost = ts.cost(COSTS);
+            let dist = neighbor.min_distance(goal, COSTS);
             let consistent = (cost + dist) >= mindist;
-            println!(" {:5} {:7} {:7}: {:?}", consistent, ts.cost(), dist, ts);
+            println!(
+                " {:5} {:7} {:7}: {:?}",
+                consistent,
+                ts.cost(COSTS),
+                dist,
+                ts
+            );
             overal_consistent &= consistent;
         }
         assert!(overal_consistent);
     }
 
+    #[test]
+    fn test_objective() {
+        use Transition::*;
+        use Value::*;
+        let mut initial = State::default();
+        initial.registers[0] = Symbol(0);
+        initial.registers[1] = Symbol(1);
+        let mut goal = State::default();
+        goal.registers[0] = Symbol(1);
+        goal.registers[1] = Symbol(0);
+
+        let swap = Swap {
+            dest:   Register(1),
+            source: Register(0),
+        };
+        assert_eq!(initial.transition_to(&goal, COSTS), vec![swap]);
+        let speed = Costs {
+            objective: Objective::Speed,
+            ..*COSTS
+        };
+        let path = initial.transition_to(&goal, &speed);
+        assert_eq!(path.len(), 3);
+        assert!(!path.contains(&swap));
+    }
+
+    #[test]
+    fn test_transition_cost() {
+        /// Copies are expensive, everything else is cheap.
+        struct NoCopies;
+        impl TransitionCost for NoCopies {
+            fn cost(&self, transition: &Transition) -> usize {
+                match transition {
+                    Transition::Copy { .. } => 100,
+                    _ => 1,
+                }
+            }
+        }
+
+        let literal = Value::Literal(0x1234_5678);
+        let initial = State::builder()
+            .register(Register::new(1), literal)
+            .build()
+            .unwrap();
+        let goal = State::builder()
+            .register(Register::new(1), literal)
+            .register(Register::new(2), literal)
+            .build()
+            .unwrap();
+        assert_eq!(initial.transition_to(&goal, COSTS), vec![Transition::Copy {
+            dest:   Register(2),
+            source: Register(1),
+        }]);
+        assert_eq!(initial.transition_to(&goal, &NoCopies), vec![Transition::Set {
+            dest:  Register(2),
+            value: 0x1234_5678,
+        }]);
+    }
+
+    /// A closure that captures a closure that captures a closure.
+    fn nested_closures() -> State {
+        use Value::*;
+        let mut state = State::default();
+        state.registers[0] = Reference {
+            index:  0,
+            offset: 0,
+        };
+        for (index, allocation) in [0, 1].iter().enumerate() {
+            state.allocations.push(Allocation::new(vec![
+                Literal(index as u64),
+                Reference {
+                    index:  allocation + 1,
+                    offset: 0,
+                },
+            ]));
+        }
+        state
+            .allocations
+            .push(Allocation::new(vec![Literal(2), Symbol(5)]));
+        state.count_references();
+        state
+    }
+
+    #[test]
+    fn test_chained_reads() {
+        use Transition::*;
+        use Value::*;
+        // All registers but one are taken, so the reads go through it
+        let mut initial = nested_closures();
+        let mut goal = State::default();
+        for i in 1..16 {
+            initial.registers[i] = Symbol(10 + i);
+            goal.registers[i] = Symbol(10 + i);
+        }
+        goal.registers[15] = Symbol(5);
+        let read = |source, offset| {
+            Read {
+                dest: Register(15),
+                source: Register(source),
+                offset,
+            }
+        };
+        assert_eq!(initial.transition_to(&goal, COSTS), vec![
+            read(0, 1),
+            read(15, 1),
+            read(15, 1)
+        ]);
+        assert_eq!(initial.read_depths(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_sources() {
+        use Value::*;
+        let closure = Reference {
+            index:  0,
+            offset: 0,
+        };
+        let mut state = nested_closures();
+        state.registers[3] = Literal(1);
+        state.registers[9] = Literal(1);
+        let goal = State::builder()
+            .register(Register(0), Symbol(5))
+            .register(Register(1), Literal(1))
+            .register(Register(2), Literal(7))
+            .register(Register(3), closure)
+            .allocation(vec![Literal(2), Literal(1)])
+            .build()
+            .unwrap();
+        let sources = Sources::new(&state, &goal);
+        assert_eq!(sources.get(closure), (1, None));
+        assert_eq!(sources.get(Symbol(5)), (0, Some(2)));
+        assert_eq!(sources.get(Literal(1)), (1 << 3 | 1 << 9, Some(1)));
+        assert_eq!(sources.get(Literal(2)), (0, Some(2)));
+        assert_eq!(sources.get(Literal(7)), (0, None));
+        // Only goal values are looked up
+        assert_eq!(sources.get(Literal(0)), (0, None));
+    }
+
+    #[test]
+    fn test_drop_dead() {
+        use Value::*;
+        let closure = Reference {
+            index:  0,
+            offset: 0,
+        };
+        let initial = State::builder()
+            .register(Register(0), closure)
+            .register(Register(1), Symbol(2))
+            .allocation(vec![Symbol(1)])
+            .build()
+            .unwrap();
+        let mut goal = State::default();
+        goal.registers[1] = Symbol(1);
+        assert_eq!(initial.transition_to(&goal, COSTS), vec![
+            Transition::Read {
+                dest:   Register(1),
+                source: Register(0),
+                offset: 0,
+            },
+            Transition::Drop { dest: Register(0) }
+        ]);
+        // Allocations the goal reaches stay
+        let kept = State::builder()
+            .register(Register(0), closure)
+            .register(Register(1), Symbol(1))
+            .allocation(vec![Unspecified])
+            .build()
+            .unwrap();
+        assert_eq!(initial.transition_to(&kept, COSTS).len(), 1);
+        // Dropping an allocation that holds the last reference to another
+        // would leak that one
+        goal.registers[1] = Symbol(5);
+        let path = nested_closures().transition_to(&goal, COSTS);
+        assert!(!path.contains(&Transition::Drop { dest: Register(0) }));
+    }
+
+    #[test]
+    fn test_useless_reads() {
+        use Value::*;
+        let initial = nested_closures();
+        let mut goal = State::default();
+        goal.registers[1] = Literal(7);
+        // Nothing the goal wants is behind the reference
+        let reads_reference = |transitions: Vec<Transition>| {
+            transitions
+                .iter()
+                .any(|transition| matches!(transition, Transition::Read { offset: 1, .. }))
+        };
+        assert!(!reads_reference(initial.useful_transitions(&goal, COSTS)));
+        goal.registers[1] = Symbol(5);
+        assert!(reads_reference(initial.useful_transitions(&goal, COSTS)));
+    }
+
+    #[test]
+    fn test_useless_transitions() {
+        use Transition::*;
+        use Value::*;
+        let initial = State::builder()
+            .register(Register(0), Symbol(1))
+            .register(Register(1), Symbol(1))
+            .register(Register(2), Symbol(2))
+            .build()
+            .unwrap();
+        let goal = State::builder()
+            .register(Register(0), Symbol(2))
+            .register(Register(2), Symbol(1))
+            .build()
+            .unwrap();
+        // Nothing that leaves the state as it is
+        let transitions = initial.useful_transitions(&goal, COSTS);
+        assert!(transitions
+            .iter()
+            .all(|transition| transition.after(&initial).unwrap() != initial));
+        assert!(!transitions.contains(&Copy {
+            dest:   Register(1),
+            source: Register(0),
+        }));
+        // Without swaps values move through the first free register of each
+        // class, r3 and r8
+        let speed = Costs {
+            objective: Objective::Speed,
+            ..*COSTS
+        };
+        let temporaries = initial
+            .useful_transitions(&goal, &speed)
+            .into_iter()
+            .filter_map(|transition| {
+                match transition {
+                    Copy { dest, .. } if dest.as_u8() > 2 => Some(dest),
+                    _ => None,
+                }
+            })
+            .unique()
+            .collect::<Vec<_>>();
+        assert_eq!(temporaries, vec![Register(3), Register(8)]);
+    }
+
+    /// Registers r1 to r4, r9 and r10 hold symbols the goal has no use for.
+    fn junk() -> (State, State) {
+        use Value::*;
+        let mut initial = State::builder().register(Register(7), Symbol(1));
+        for register in [1, 2, 3, 4, 9, 10].iter() {
+            initial = initial.register(Register(*register), Symbol(10 + *register as usize));
+        }
+        let goal = State::builder()
+            .register(Register(0), Literal(5))
+            .register(Register(6), Symbol(1))
+            .build()
+            .unwrap();
+        (initial.build().unwrap(), goal)
+    }
+
+    #[test]
+    fn test_free_registers() {
+        // Registers holding something useless are as good as empty ones, of
+        // those only the first of each class is overwritten. Others are if
+        // they do not hold their goal value, but not with a value the goal
+        // wants elsewhere while a free register is left.
+        let (initial, goal) = junk();
+        let sets = initial
+            .useful_transitions(&goal, COSTS)
+            .into_iter()
+            .filter_map(|transition| {
+                match transition {
+                    Transition::Set { dest, .. } => Some(dest.as_u8()),
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sets, vec![0, 1, 7, 9]);
+    }
+
+    #[test]
+    fn test_canonical() {
+        use Value::*;
+        // Registers the goal leaves unspecified are interchangeable within a
+        // class, so moving values between them gives the same canonical state.
+        let (initial, goal) = junk();
+        let mut permuted = initial.clone();
+        permuted.registers.swap(1, 7);
+        permuted.registers.swap(9, 10);
+        let (canonical, from) = initial.canonical(&goal, COSTS);
+        assert_eq!(permuted.canonical(&goal, COSTS).0, canonical);
+        for register in (0..=15).map(Register) {
+            let source = from[register.as_u8() as usize];
+            assert_eq!(canonical.get_register(register), initial.get_register(source));
+            assert_eq!(COSTS.register_class(register), COSTS.register_class(source));
+        }
+
+        // Registers the goal specifies stay where they are
+        let mut moved = initial.clone();
+        moved.registers[6] = Symbol(1);
+        moved.registers[7] = Unspecified;
+        assert_ne!(moved.canonical(&goal, COSTS).0, canonical);
+    }
+
+    /// Every transition that applies, with literals and sizes from `goal`.
+    fn every_transition(state: &State, goal: &State, swaps: bool) -> Vec<Transition> {
+        use Transition::*;
+        let mut result = Vec::new();
+        for dest in (0..=15).map(Register) {
+            result.extend(goal.literals().into_iter().map(|value| Set { dest, value }));
+            result.extend(goal.alloc_sizes().into_iter().map(|size| Alloc { dest, size }));
+            result.push(Drop { dest });
+            result.push(Test { source: dest });
+            for source in (0..=15).map(Register) {
+                result.push(Copy { dest, source });
+                if swaps {
+                    result.push(Swap { dest, source });
+                }
+                for offset in -4..4 {
+                    result.push(Read {
+                        dest,
+                        source,
+                        offset,
+                    });
+                    result.push(Write {
+                        dest,
+                        offset,
+                        source,
+                    });
+                }
+            }
+        }
+        result.retain(|transition| transition.applies(state));
+        result
+    }
+
+    /// The cost of the path found with the transitions `next` gives.
+    fn search<C, F>(initial: &State, goal: &State, costs: &C, next: F) -> usize
+    where
+        C: TransitionCost,
+        F: Fn(&State) -> Vec<Transition>,
+    {
+        astar(
+            initial,
+            |state| {
+                next(state)
+                    .into_iter()
+                    .filter_map(|transition| {
+                        let next = transition.after(state)?;
+                        next.reachable(goal).then(|| (next, transition.cost(costs)))
+                    })
+                    .collect::<Vec<_>>()
+            },
+            |state| state.min_distance(goal, costs),
+            |state| state.satisfies(goal),
+        )
+        .unwrap()
+        .1
+    }
+
+    #[test]
+    fn test_optimal() {
+        use Value::*;
+        // Leaving out useless transitions finds paths as cheap as trying them
+        // all, for the goals of the tests above. The heuristic is not always
+        // admissible, so fewer transitions can find a cheaper one.
+        let closure = Reference {
+            index:  0,
+            offset: 0,
+        };
+        let problems = vec![
+            junk(),
+            (
+                State::builder()
+                    .register(Register(0), Symbol(5))
+                    .build()
+                    .unwrap(),
+                State::builder()
+                    .register(Register(0), Literal(3))
+                    .register(Register(1), closure)
+                    .allocation(vec![Symbol(5)])
+                    .build()
+                    .unwrap(),
+            ),
+            (
+                State::builder()
+                    .register(Register(0), Symbol(0))
+                    .register(Register(1), Symbol(1))
+                    .build()
+                    .unwrap(),
+                State::builder()
+                    .register(Register(0), Symbol(1))
+                    .register(Register(1), Symbol(0))
+                    .build()
+                    .unwrap(),
+            ),
+            (
+                nested_closures(),
+                State::builder()
+                    .register(Register(1), Symbol(5))
+                    .build()
+                    .unwrap(),
+            ),
+            (
+                State::builder()
+                    .register(Register(0), Symbol(1))
+                    .register(Register(1), Symbol(2))
+                    .register(Register(2), Symbol(3))
+                    .build()
+                    .unwrap(),
+                State::builder()
+                    .register(Register(0), closure)
+                    .register(Register(1), Symbol(3))
+                    .register(Register(2), Literal(3))
+                    .allocation(vec![Symbol(1), Symbol(2)])
+                    .build()
+                    .unwrap(),
+            ),
+        ];
+        for objective in [Objective::Size, Objective::Speed, Objective::Balanced].iter() {
+            let costs = Costs {
+                objective: *objective,
+                ..*COSTS
+            };
+            for (initial, goal) in &problems {
+                let useful = |state: &State| state.useful_transitions(goal, &costs);
+                let every = |state: &State| every_transition(state, goal, costs.swaps());
+                let pruned = search(initial, goal, &costs, useful);
+                let exhaustive = search(initial, goal, &costs, every);
+                assert!(pruned <= exhaustive, "{} > {} for {:?}", pruned, exhaustive, objective);
+                let mut state = initial.clone();
+                for transition in initial.transition_to(goal, &costs) {
+                    transition.apply(&mut state);
+                }
+                assert!(state.satisfies(goal));
+            }
+        }
+    }
+
     #[test]
     fn test_basic() {
         use Transition::*;
@@ -489,9 +1403,9 @@ mod test {
         goal.registers[1] = Symbol(3);
         goal.registers[2] = Literal(3);
         goal.allocations
-            .push(Allocation(vec![Symbol(1), Symbol(2)]));
+            .push(Allocation::new(vec![Symbol(1), Symbol(2)]));
 
-        let path = initial.transition_to(&goal);
+        let path = initial.transition_to(&goal, COSTS);
         test_admisability(&initial, &goal, &path);
         test_consistency(&initial, &goal);
     }
@@ -515,13 +1429,13 @@ mod test {
             index:  0,
             offset: 0,
         };
-        goal.allocations.push(Allocation(vec![
+        goal.allocations.push(Allocation::new(vec![
             Literal(0x0000000000100058),
             Symbol(3),
             Symbol(4),
         ]));
 
-        let path = initial.transition_to(&goal);
+        let path = initial.transition_to(&goal, COSTS);
         test_admisability(&initial, &goal, &path);
         test_consistency(&initial, &goal);
     }
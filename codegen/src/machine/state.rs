@@ -7,20 +7,26 @@ use std::{
     slice::Iter as SliceIter,
 };
 
+/// One of the sixteen registers, `r0` to `r15`.
 // `u8` mostly for compatibility with dynasm
 #[derive(
     Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug, Default,
 )]
-pub(crate) struct Register(pub(crate) u8);
+pub struct Register(pub(crate) u8);
 
+/// The registers, the status flags and the heap allocations they reference.
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug, Default)]
-pub(crate) struct State {
+pub struct State {
     pub(crate) registers:   [Value; 16],
-    pub(crate) flags:       [Value; 7],
+    /// The value the status flags were last compared with zero for, see
+    /// `Transition::Test`, or `Unspecified` if they hold nothing useful.
+    pub(crate) flags:       Value,
     // TODO: Implement Eq to ignore permutation of allocations.
     pub(crate) allocations: Vec<Allocation>,
 }
 
+/// Status flags, used by intrinsics that branch on arithmetic results.
+#[cfg(feature = "native")]
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
 pub(crate) enum Flag {
     Carry     = 0,
@@ -32,11 +38,26 @@ pub(crate) enum Flag {
     Overflow  = 6,
 }
 
+/// The words of a heap allocation.
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug, Default)]
-pub(crate) struct Allocation(pub(crate) Vec<Value>);
+pub struct Allocation {
+    pub(crate) values:     Vec<Value>,
+    /// Number of references to the allocation from registers and
+    /// allocations, see `State::count_references`. Transitions keep it up to
+    /// date.
+    pub(crate) references: usize,
+}
+
+/// Builds a [`State`], see [`State::builder`].
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct StateBuilder {
+    state: State,
+}
 
+/// The values in registers and then in allocations, see [`State`]'s
+/// `IntoIterator`.
 #[derive(Clone, Debug)]
-pub(crate) struct StateIterator<'a> {
+pub struct StateIterator<'a> {
     state: &'a State,
     index: StateIteratorIndex<'a>,
 }
@@ -44,31 +65,107 @@ pub(crate) struct StateIterator<'a> {
 #[derive(Clone, Debug)]
 pub(crate) enum StateIteratorIndex<'a> {
     Register(SliceIter<'a, Value>),
-    Flags(SliceIter<'a, Value>),
     Allocations(SliceIter<'a, Allocation>),
     Allocation(SliceIter<'a, Allocation>, SliceIter<'a, Value>),
     Done,
 }
 
 impl Allocation {
-    pub(crate) fn len(&self) -> usize {
-        self.0.len()
+    /// An allocation holding `values`, not referenced yet.
+    pub(crate) fn new(values: Vec<Value>) -> Self {
+        Self {
+            values,
+            references: 0,
+        }
     }
 
-    pub(crate) fn iter(&self) -> <&Self as IntoIterator>::IntoIter {
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn iter(&self) -> <&Self as IntoIterator>::IntoIter {
         self.into_iter()
     }
 }
 
 impl Register {
-    pub(crate) fn as_u8(&self) -> u8 {
+    /// # Panics
+    ///
+    /// Panics if `index` is not below sixteen.
+    #[must_use]
+    pub fn new(index: u8) -> Self {
+        assert!(index < 16, "There is no register r{}", index);
+        Self(index)
+    }
+
+    #[must_use]
+    pub fn as_u8(&self) -> u8 {
         self.0
     }
 }
 
+impl StateBuilder {
+    #[must_use]
+    pub fn register(mut self, register: Register, value: Value) -> Self {
+        self.state.registers[register.as_u8() as usize] = value;
+        self
+    }
+
+    /// The value the status flags were compared with zero for.
+    #[must_use]
+    pub fn flags(mut self, value: Value) -> Self {
+        self.state.flags = value;
+        self
+    }
+
+    /// Add an allocation. References to it have the number of allocations
+    /// added before it as `index`.
+    #[must_use]
+    pub fn allocation(mut self, values: Vec<Value>) -> Self {
+        self.state.allocations.push(Allocation::new(values));
+        self
+    }
+
+    /// The state, or `None` if it is not [valid](State::is_valid).
+    #[must_use]
+    pub fn build(mut self) -> Option<State> {
+        self.state.count_references();
+        Some(self.state).filter(State::is_valid)
+    }
+}
+
 impl State {
+    /// A state with all registers and flags `Unspecified` and no allocations,
+    /// to fill in.
+    #[must_use]
+    pub fn builder() -> StateBuilder {
+        StateBuilder::default()
+    }
+
+    #[must_use]
+    pub fn flags(&self) -> Value {
+        self.flags
+    }
+
+    #[must_use]
+    pub fn allocations(&self) -> &[Allocation] {
+        &self.allocations
+    }
+
+    /// Every reference points to an existing allocation and every allocation
+    /// is referenced. The flags never hold a reference.
+    #[must_use]
     pub fn is_valid(&self) -> bool {
         use Value::*;
+        if let Reference { .. } = self.flags {
+            return false;
+        }
         // Make sure all references are N:1 to allocations
         let mut seen = BitVec::repeat(false, self.allocations.len());
         for val in &self.registers {
@@ -96,19 +193,45 @@ impl State {
             return false;
         }
 
-        // Flags can only hold symbol, unspecified or boolean 0 / 1
-        for flag in &self.flags {
-            match flag {
-                Unspecified | Symbol(_) => {}
-                Literal(n) if *n <= 1 => {}
-                _ => return false,
-            }
-        }
-
         // Otherwise it is valid
         true
     }
 
+    /// Number of references to allocation `index`, from registers and from
+    /// allocations.
+    pub(crate) fn reference_count(&self, index: usize) -> usize {
+        self.allocations[index].references
+    }
+
+    /// Count the references to each allocation, for states that are not the
+    /// result of transitions.
+    pub(crate) fn count_references(&mut self) {
+        let counts = (0..self.allocations.len())
+            .map(|index| self.into_iter().filter(|val| val.references(index)).count())
+            .collect::<Vec<_>>();
+        for (allocation, count) in self.allocations.iter_mut().zip(counts) {
+            allocation.references = count;
+        }
+    }
+
+    /// Whether the reference counts are up to date.
+    pub(crate) fn is_counted(&self) -> bool {
+        let mut counted = self.clone();
+        counted.count_references();
+        counted == *self
+    }
+
+    /// Replace a register or allocated word holding `old` by `new`, updating
+    /// the reference counts.
+    pub(crate) fn count_replacement(&mut self, old: Value, new: Value) {
+        if let Value::Reference { index, .. } = old {
+            self.allocations[index].references -= 1;
+        }
+        if let Value::Reference { index, .. } = new {
+            self.allocations[index].references += 1;
+        }
+    }
+
     pub(crate) fn symbols(&self) -> Set<usize> {
         self.into_iter()
             .filter_map(|val| {
@@ -132,20 +255,28 @@ impl State {
     }
 
     pub(crate) fn alloc_sizes(&self) -> Set<usize> {
-        self.allocations.iter().map(|a| a.0.len()).collect()
+        self.allocations.iter().map(Allocation::len).collect()
     }
 
-    /// A goal is reachable if it contains a subset of our symbols.
-    pub(crate) fn reachable(&self, goal: &Self) -> bool {
+    /// A goal is reachable if it contains a subset of our symbols. A symbol
+    /// the goal flags are compared for also counts if our flags already are.
+    #[must_use]
+    pub fn reachable(&self, goal: &Self) -> bool {
         debug_assert!(self.is_valid());
         debug_assert!(goal.is_valid());
 
         // Only Symbols matter, everything else can be constructed.
-        goal.symbols().is_subset(&self.symbols())
+        let symbols = self.symbols();
+        let flags = match goal.flags {
+            Value::Symbol(symbol) => self.flags == goal.flags || symbols.contains(&symbol),
+            _ => true,
+        };
+        flags && goal.symbols().is_subset(&symbols)
     }
 
     /// A goal is satisfied if all specified values are in place.
-    pub(crate) fn satisfies(&self, goal: &Self) -> bool {
+    #[must_use]
+    pub fn satisfies(&self, goal: &Self) -> bool {
         fn valsat(reference_checks: &mut Set<(usize, usize)>, ours: &Value, goal: &Value) -> bool {
             match goal {
                 Unspecified => true,
@@ -176,11 +307,16 @@ impl State {
         // references with the same offset and the allocations satisfy.
         let mut reference_checks: Set<(usize, usize)> = Set::default();
 
-        // Check registers and flags
-        let ours = self.registers.iter().chain(self.flags.iter());
-        let theirs = goal.registers.iter().chain(goal.flags.iter());
-        if !ours
-            .zip(theirs)
+        // Check flags
+        if goal.flags.is_specified() && self.flags != goal.flags {
+            return false;
+        }
+
+        // Check registers
+        if !self
+            .registers
+            .iter()
+            .zip(goal.registers.iter())
             .all(|(a, b)| valsat(&mut reference_checks, a, b))
         {
             return false;
@@ -222,16 +358,16 @@ impl State {
 }
 
 impl State {
-    pub(crate) fn get_register(&self, reg: Register) -> Value {
+    #[must_use]
+    pub fn get_register(&self, reg: Register) -> Value {
         // `Register` can only contain valid indices
         self.registers[reg.as_u8() as usize]
     }
 
-    pub(crate) fn get_flag(&self, flag: Flag) -> Value {
-        self.flags[flag as usize]
-    }
-
-    pub(crate) fn get_reference(&self, reg: Register, offset: isize) -> Option<Value> {
+    /// The word `offset` words into the allocation register `reg` references,
+    /// if it does and the word is in range.
+    #[must_use]
+    pub fn get_reference(&self, reg: Register, offset: isize) -> Option<Value> {
         match self.get_register(reg) {
             Value::Reference {
                 index,
@@ -239,7 +375,7 @@ impl State {
             } => {
                 let alloc = self.allocations.get(index)?;
                 let offset: usize = (offset + roffset).try_into().ok()?;
-                alloc.0.get(offset).map(|a| *a)
+                alloc.values.get(offset).map(|a| *a)
             }
             _ => None,
         }
@@ -253,7 +389,7 @@ impl State {
             } => {
                 let alloc = self.allocations.get_mut(index)?;
                 let offset: usize = (offset + roffset).try_into().ok()?;
-                alloc.0.get_mut(offset)
+                alloc.values.get_mut(offset)
             }
             _ => None,
         }
@@ -271,29 +407,17 @@ impl Display for State {
                 format!("{}", self.registers[i + 8])
             )?;
         }
-        writeln!(
-            f,
-            "    CF  = {:4}   PF  = {:4}   AF  = {:4}   ZF  = {:4}",
-            format!("{}", self.flags[0]),
-            format!("{}", self.flags[1]),
-            format!("{}", self.flags[2]),
-            format!("{}", self.flags[3]),
-        )?;
-        writeln!(
-            f,
-            "    SF  = {:4}   DF  = {:4}   OF  = {:4}",
-            format!("{}", self.flags[4]),
-            format!("{}", self.flags[5]),
-            format!("{}", self.flags[6]),
-        )?;
+        if self.flags.is_specified() {
+            writeln!(f, "    flags = {}", self.flags)?;
+        }
         for (i, alloc) in self.allocations.iter().enumerate() {
-            writeln!(f, "       {}: {:18}", i, format!("{}", alloc.0[0]));
+            writeln!(f, "       {}: {:18}", i, format!("{}", alloc.values[0]));
             for value in alloc.iter().skip(1) {
                 writeln!(f, "          {:18}", format!("{}", value));
             }
         }
 
-        // TODO: Flags + memory
+        // TODO: Memory
         Ok(())
     }
 }
@@ -302,7 +426,7 @@ impl<'a> IntoIterator for &'a Allocation {
     type Item = &'a Value;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.0.iter()
+        self.values.iter()
     }
 }
 
@@ -325,12 +449,6 @@ impl<'a> Iterator for StateIterator<'a> {
         use StateIteratorIndex::*;
         match &mut self.index {
             Register(iter) => {
-                iter.next().or_else(|| {
-                    self.index = Flags(self.state.flags.iter());
-                    self.next()
-                })
-            }
-            Flags(iter) => {
                 iter.next().or_else(|| {
                     self.index = Allocations(self.state.allocations.iter());
                     self.next()
@@ -360,3 +478,114 @@ impl<'a> Iterator for StateIterator<'a> {
         }
     }
 }
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::{super::transition::test::arb_transition, super::value::test::arb_value, *};
+    use proptest::{array::uniform16, collection::vec, prop_assert, proptest, strategy::Strategy};
+
+    /// Whether all allocations can be reached from the registers. This is
+    /// stricter than `is_valid`, which accepts unreachable cycles.
+    fn all_reachable(state: &State) -> bool {
+        let mut reached = vec![false; state.allocations.len()];
+        let mut todo = state.registers.to_vec();
+        while let Some(value) = todo.pop() {
+            if let Value::Reference { index, .. } = value {
+                if !reached[index] {
+                    reached[index] = true;
+                    todo.extend(state.allocations[index].iter());
+                }
+            }
+        }
+        reached.into_iter().all(|reached| reached)
+    }
+
+    /// Valid states with up to three allocations, all reachable.
+    pub(in crate::machine) fn arb_state() -> impl Strategy<Value = State> {
+        (0_usize..4)
+            .prop_flat_map(|num_allocations| {
+                (
+                    uniform16(arb_value(num_allocations)),
+                    arb_value(0),
+                    vec(vec(arb_value(num_allocations), 1..4), num_allocations),
+                )
+            })
+            .prop_map(|(registers, flags, allocations)| {
+                let mut state = State {
+                    registers,
+                    flags,
+                    allocations: allocations.into_iter().map(Allocation::new).collect(),
+                };
+                state.count_references();
+                state
+            })
+            .prop_filter("Unreachable allocation", all_reachable)
+    }
+
+    #[test]
+    fn test_is_valid() {
+        let reference = Value::Reference {
+            index:  0,
+            offset: 0,
+        };
+        let mut state = State::default();
+        assert!(state.is_valid());
+        // Dangling reference
+        state.registers[0] = reference;
+        assert!(!state.is_valid());
+        state.allocations.push(Allocation::new(vec![Value::Unspecified]));
+        assert!(state.is_valid());
+        // Unreferenced allocation
+        state.allocations.push(Allocation::new(vec![reference]));
+        assert!(!state.is_valid());
+        // Flags hold a reference
+        let mut state = State::default();
+        state.registers[0] = reference;
+        state.allocations.push(Allocation::new(vec![Value::Unspecified]));
+        state.flags = reference;
+        assert!(!state.is_valid());
+    }
+
+    #[test]
+    fn test_builder() {
+        let reference = Value::Reference {
+            index:  0,
+            offset: 1,
+        };
+        let state = State::builder()
+            .register(Register::new(3), reference)
+            .flags(Value::Symbol(2))
+            .allocation(vec![Value::Literal(1), Value::Symbol(2)])
+            .build()
+            .unwrap();
+        assert_eq!(state.get_register(Register::new(3)), reference);
+        assert_eq!(state.get_reference(Register::new(3), 0), Some(Value::Symbol(2)));
+        assert_eq!(state.get_reference(Register::new(3), 1), None);
+        assert_eq!(state.flags(), Value::Symbol(2));
+        assert_eq!(state.allocations().len(), 1);
+        // Dangling reference
+        let dangling = State::builder().register(Register::new(0), reference);
+        assert_eq!(dangling.build(), None);
+    }
+
+    proptest! {
+        #[test]
+        fn test_transitions_preserve_validity(
+            initial in arb_state(),
+            transitions in vec(arb_transition(), 0..32)
+        ) {
+            let mut state = initial;
+            for transition in &transitions {
+                if transition.applies(&state) {
+                    transition.apply(&mut state);
+                    prop_assert!(state.is_valid(), "{:?} made invalid:\n{}", transition, state);
+                }
+            }
+        }
+
+        #[test]
+        fn test_satisfies_reflexive(state in arb_state()) {
+            prop_assert!(state.satisfies(&state));
+        }
+    }
+}
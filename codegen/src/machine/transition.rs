@@ -1,7 +1,7 @@
 use super::{Allocation, Register, State, Value};
-use crate::OffsetAssembler;
-use dynasmrt::DynasmApi;
+use crate::cost_model::{CostTable, Costs, Objective, Timing};
 use serde::{Deserialize, Serialize};
+use std::{cmp::max, convert::TryFrom};
 
 // TODO: Explore exotic instructions that can potentially accomplish the same
 // in fewer bytes/cycles:
@@ -14,15 +14,15 @@ use serde::{Deserialize, Serialize};
 // * Mov8/16/32
 // * Add/Sub/Xor
 
-// TODO: Track flags, offer alternatives for XOR zeroing that do not clear
-// flags.
+// TODO: Offer alternatives for XOR zeroing that do not clear flags.
 
 // TODO:
 // Read constant from memory?
 
 /// Single instruction
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
-pub(crate) enum Transition {
+#[non_exhaustive]
+pub enum Transition {
     /// Set register `dest` to literal `value`
     Set { dest: Register, value: u64 },
     /// Copy register `source` into `dest`
@@ -47,18 +47,30 @@ pub(crate) enum Transition {
     Alloc { dest: Register, size: usize },
     /// Drop the allocation referenced to
     Drop { dest: Register },
+    /// Compare register `source` with the number zero, setting the Zero flag
+    /// if they are equal
+    Test { source: Register },
 }
 
 impl Transition {
-    pub(crate) fn applies(&self, state: &State) -> bool {
-        // TODO: Does not check if it overwrites a last Reference. We could do
-        // this quickly by tracking reference counts in Allocations. This is also
-        // a good foundation for deferred reference counting, once we implement that.
+    /// A transition applies if its operands are available and it does not
+    /// overwrite the last reference to an allocation. Allocations are only
+    /// dropped through their last reference, and not while they hold the
+    /// last reference to another one. Applying it to a valid state results in
+    /// a valid state.
+    #[must_use]
+    pub fn applies(&self, state: &State) -> bool {
         use Transition::*;
         use Value::*;
         match *self {
-            Set { dest, .. } => true,
-            Copy { dest, source } => state.get_register(source).is_specified(),
+            Set { dest, value } => {
+                !loses_last_reference(state, state.get_register(dest), Literal(value))
+            }
+            Copy { dest, source } => {
+                let value = state.get_register(source);
+                value.is_specified()
+                    && !loses_last_reference(state, state.get_register(dest), value)
+            }
             Swap { dest, source } => {
                 state.get_register(dest).is_specified() || state.get_register(source).is_specified()
             }
@@ -68,7 +80,10 @@ impl Transition {
                 offset,
             } => {
                 match state.get_reference(source, offset) {
-                    Some(val) => val.is_specified(),
+                    Some(val) => {
+                        val.is_specified()
+                            && !loses_last_reference(state, state.get_register(dest), val)
+                    }
                     None => false,
                 }
             }
@@ -77,28 +92,146 @@ impl Transition {
                 offset,
                 source,
             } => {
-                state.get_register(source).is_specified()
-                    && state.get_reference(dest, offset).is_some()
+                let value = state.get_register(source);
+                match state.get_reference(dest, offset) {
+                    Some(old) => value.is_specified() && !loses_last_reference(state, old, value),
+                    None => false,
+                }
+            }
+            Alloc { dest, size } => {
+                size > 0 && !loses_last_reference(state, state.get_register(dest), Unspecified)
             }
-            Alloc { dest, size } => size > 0,
             Drop { dest } => {
                 match state.get_register(dest) {
-                    Reference { .. } => true,
+                    Reference { index, .. } => {
+                        // References held by the dropped allocation disappear with it
+                        let contents = &state.allocations[index];
+                        let cycles = contents.iter().filter(|value| value.references(index));
+                        state.reference_count(index) == 1 + cycles.count()
+                            && contents.iter().all(|value| {
+                                match *value {
+                                    Reference { index: other, .. } if other != index => {
+                                        let inside = contents
+                                            .iter()
+                                            .filter(|value| value.references(other))
+                                            .count();
+                                        state.reference_count(other) > inside
+                                    }
+                                    _ => true,
+                                }
+                            })
+                    }
                     _ => false,
                 }
             }
+            Test { source } => {
+                match state.get_register(source) {
+                    Literal(_) | Symbol(_) => state.flags != state.get_register(source),
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    /// Whether the instruction changes the status flags, other than by
+    /// `Test`. These leave the flags `Unspecified`.
+    pub(crate) fn clobbers_flags(&self) -> bool {
+        use Transition::*;
+        match *self {
+            Set { value, .. } => value == 0,
+            Alloc { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// The same transition on the registers `rename` maps these to.
+    pub(crate) fn rename(&self, rename: impl Fn(Register) -> Register) -> Self {
+        use Transition::*;
+        match *self {
+            Set { dest, value } => {
+                Set {
+                    dest: rename(dest),
+                    value,
+                }
+            }
+            Copy { dest, source } => {
+                Copy {
+                    dest:   rename(dest),
+                    source: rename(source),
+                }
+            }
+            Swap { dest, source } => {
+                Swap {
+                    dest:   rename(dest),
+                    source: rename(source),
+                }
+            }
+            Read {
+                dest,
+                source,
+                offset,
+            } => {
+                Read {
+                    dest:   rename(dest),
+                    source: rename(source),
+                    offset,
+                }
+            }
+            Write {
+                dest,
+                offset,
+                source,
+            } => {
+                Write {
+                    dest:   rename(dest),
+                    offset,
+                    source: rename(source),
+                }
+            }
+            Alloc { dest, size } => {
+                Alloc {
+                    dest: rename(dest),
+                    size,
+                }
+            }
+            Drop { dest } => Drop { dest: rename(dest) },
+            Test { source } => {
+                Test {
+                    source: rename(source),
+                }
+            }
+        }
+    }
+
+    /// The state after applying the transition, or `None` if it does not
+    /// apply.
+    #[must_use]
+    pub fn after(&self, state: &State) -> Option<State> {
+        if self.applies(state) {
+            let mut next = state.clone();
+            self.apply(&mut next);
+            Some(next)
+        } else {
+            None
         }
     }
 
+    /// Apply the transition in place. It must apply, see
+    /// [`Transition::applies`].
     pub(crate) fn apply(&self, state: &mut State) {
         use Transition::*;
         use Value::*;
         debug_assert!(self.applies(state));
+        if self.clobbers_flags() {
+            state.flags = Unspecified;
+        }
+        let set_register = |state: &mut State, dest: Register, value: Value| {
+            state.count_replacement(state.get_register(dest), value);
+            state.registers[dest.as_u8() as usize] = value;
+        };
         match *self {
-            Set { dest, value } => state.registers[dest.as_u8() as usize] = Literal(value),
-            Copy { dest, source } => {
-                state.registers[dest.as_u8() as usize] = state.get_register(source)
-            }
+            Set { dest, value } => set_register(state, dest, Literal(value)),
+            Copy { dest, source } => set_register(state, dest, state.get_register(source)),
             Swap { dest, source } => {
                 state
                     .registers
@@ -109,51 +242,57 @@ impl Transition {
                 dest,
                 source,
                 offset,
-            } => {
-                state.registers[dest.as_u8() as usize] =
-                    state.get_reference(source, offset).unwrap()
-            }
+            } => set_register(state, dest, state.get_reference(source, offset).unwrap()),
             Write {
                 dest,
                 offset,
                 source,
-            } => *(state.get_mut_reference(dest, offset).unwrap()) = state.get_register(source),
+            } => {
+                let value = state.get_register(source);
+                state.count_replacement(state.get_reference(dest, offset).unwrap(), value);
+                *(state.get_mut_reference(dest, offset).unwrap()) = value;
+            }
             Alloc { dest, size } => {
+                set_register(state, dest, Unspecified);
                 state.registers[dest.as_u8() as usize] = Reference {
                     index:  state.allocations.len(),
                     offset: 0,
                 };
-                state.allocations.push(Allocation(vec![Unspecified; size]));
+                state.allocations.push(Allocation {
+                    values:     vec![Unspecified; size],
+                    references: 1,
+                });
             }
             Drop { dest } => {
-                // TODO: Make sure all references are gone and remaining references to other
-                // allocations have their indices correctly updated. Use swap_remove to make
-                // it easier.
                 if let Reference { index, .. } = state.get_register(dest) {
-                    // Remove Allocation and Reference
-                    state.allocations.swap_remove(index);
+                    // The register holds the last reference from outside, the
+                    // allocation takes its references to others along
+                    state.registers[dest.as_u8() as usize] = Unspecified;
+                    let removed = state.allocations.swap_remove(index);
                     let new = index;
                     let old = state.allocations.len();
-
-                    // Replace all indices `swap` with `index`
-                    // Any References to `index` become Unspecified
-                    for val in state.registers.iter_mut() {
-                        if let Reference { index, .. } = val {
-                            if *index == new {
-                                *val = Value::Unspecified
-                            } else if *index == old {
-                                *index = new;
+                    for value in removed.iter() {
+                        match *value {
+                            Reference { index: other, .. } if other == new => {}
+                            Reference { index: other, .. } if other == old => {
+                                state.allocations[new].references -= 1;
+                            }
+                            Reference { index: other, .. } => {
+                                state.allocations[other].references -= 1;
                             }
+                            _ => {}
                         }
                     }
-                    for alloc in state.allocations.iter_mut() {
-                        for val in alloc.0.iter_mut() {
-                            if let Reference { index, .. } = val {
-                                if *index == new {
-                                    *val = Value::Unspecified
-                                } else if *index == old {
-                                    *index = new;
-                                }
+
+                    // The last allocation moved to `index`
+                    let values = state
+                        .registers
+                        .iter_mut()
+                        .chain(state.allocations.iter_mut().flat_map(|a| a.values.iter_mut()));
+                    for value in values {
+                        if let Reference { index, .. } = value {
+                            if *index == old {
+                                *index = new;
                             }
                         }
                     }
@@ -161,58 +300,391 @@ impl Transition {
                     panic!("Can only Drop a Reference.")
                 }
             }
+            Test { source } => state.flags = state.get_register(source),
         }
+        debug_assert!(state.is_counted());
     }
 }
 
-// Costs
-impl Transition {
-    pub(crate) fn cost(&self) -> usize {
-        // TODO: In practice, we either want the absolute smallest or absolute
-        // fastest code. The middle ground doesn't really exist anymore. The only
-        // other trade-off is compile time, which we don't care about at the moment.
+/// Replacing `old` by `new` removes the last reference to an allocation.
+fn loses_last_reference(state: &State, old: Value, new: Value) -> bool {
+    match old {
+        Value::Reference { index, .. } => {
+            !new.references(index) && state.reference_count(index) == 1
+        }
+        _ => false,
+    }
+}
+
+/// What [`State::transition_to`] minimizes. [`Costs`] weighs transitions by
+/// the x86_64 encoding and a processor's [`CostTable`], other code generators
+/// can bring their own.
+pub trait TransitionCost {
+    /// The cost of a single transition. It has to be at least one, and may
+    /// only depend on the transition, not on the state it applies to.
+    fn cost(&self, transition: &Transition) -> usize;
+
+    /// Whether to use `Swap`. Without it, values move through free registers.
+    fn swaps(&self) -> bool {
+        true
+    }
+
+    /// Registers of the same class cost the same in every transition, so of
+    /// the free registers the optimizer only tries the first of each class.
+    /// By default every register is its own class.
+    fn register_class(&self, register: Register) -> u8 {
+        register.as_u8()
+    }
+
+    /// Breaks ties between equally costly paths, the lowest wins. Called with
+    /// each prefix of the path as it is reconstructed.
+    fn latency(&self, _path: &[Transition]) -> usize {
+        0
+    }
+}
 
-        // Optimize for size, with cycles as a potential tie-breaker
+impl TransitionCost for Costs {
+    fn cost(&self, transition: &Transition) -> usize {
+        // Latency depends on the whole sequence, see `critical_path`, and only
+        // breaks ties.
+        let size = transition.size();
+        let throughput = transition.timing(&self.table).throughput;
         // Add one to always have a non-zero cost
-        1 + self.size() * 10000 + self.cycles()
+        1 + match self.objective {
+            Objective::Size => size * 10000 + throughput,
+            // Throughput is in twelfths of a cycle
+            Objective::Balanced => size * 12 + throughput,
+            Objective::Speed => throughput * 10000 + size,
+        }
     }
 
-    /// Code size in bytes
-    pub(crate) fn size(&self) -> usize {
-        let mut asm = OffsetAssembler::default();
-        self.assemble(&mut asm);
-        asm.offset().0
+    // Optimizing for speed there are no swaps, they are slower than moves.
+    fn swaps(&self) -> bool {
+        self.objective != Objective::Speed
+    }
+
+    // Swaps with rax are shorter and r8 and up need a REX prefix, see `size`.
+    fn register_class(&self, register: Register) -> u8 {
+        match register.as_u8() {
+            0 => 0,
+            1..=7 => 1,
+            _ => 2,
+        }
+    }
+
+    fn latency(&self, path: &[Transition]) -> usize {
+        Transition::critical_path(path, &self.table)
+    }
+}
+
+// Costs
+impl Transition {
+    pub(crate) fn cost<C: TransitionCost + ?Sized>(&self, costs: &C) -> usize {
+        costs.cost(self)
     }
 
-    /// Run time in clock cycles ⨉ 12
+    /// Code size in bytes of the x86_64 instructions `assemble` emits. The
+    /// optimizer asks for it for every transition it considers, so it is
+    /// computed from the encoding rules instead of assembling.
+    #[must_use]
+    pub fn size(&self) -> usize {
+        use Transition::*;
+        // REX prefix to reach registers r8 and up
+        let rex = |register: Register| usize::from(register.as_u8() >= 8);
+        // ModRM, SIB and displacement. The base register is only known at run
+        // time, so a SIB byte is always emitted in case it is rsp or r12.
+        let memory = |offset: isize| {
+            if i8::try_from((8 * offset) as i32).is_ok() {
+                3
+            } else {
+                6
+            }
+        };
+        match *self {
+            Set { dest, value: 0 } => 2 + rex(dest),
+            Set { dest, value } if value <= u64::from(u32::max_value()) => 5 + rex(dest),
+            Set { .. } => 10,
+            Copy { dest, source } | Swap { dest, source } if dest == source => 0,
+            // REX.W, opcode and ModRM
+            Copy { .. } => 3,
+            // Exchanging with rax has a short form
+            Swap { dest, source } if dest.as_u8() == 0 || source.as_u8() == 0 => 2,
+            Swap { .. } => 3,
+            // REX.W and opcode
+            Read { offset, .. } | Write { offset, .. } => 2 + memory(offset),
+            // Load the free memory pointer at an absolute address with REX,
+            // opcode, ModRM, SIB and address, then add the size to it with a
+            // 32 bit immediate. The address is always 32 bits, its value does
+            // not affect size.
+            Alloc { .. } => 8 + 11,
+            Drop { .. } => 0,
+            // REX.W, opcode, ModRM and the 32 bit immediate dynasm emits
+            Test { .. } => 7,
+        }
+    }
+
+    /// Throughput and latency according to `costs`.
     /// Note: It's impossible to be perfectly accurate in time, because it
     /// ultimately depends on the non-disclosed internal details of the
     /// specific processor in use. Provided here is a very rough estimate.
-    /// See <https://www.agner.org/optimize/instruction_tables.pdf>
-    // TODO: Account for dependency chains
-    // TODO: Measure and calibrate these numbers
-    pub(crate) fn cycles(&self) -> usize {
+    #[must_use]
+    pub fn timing(&self, costs: &CostTable) -> Timing {
         use Transition::*;
-        // Timings are minimum (throughput) from Fog's Skylake table
         match *self {
-            Set { .. } => 3,
-            Copy { dest, source } if dest == source => 0,
-            Copy { .. } => 3,
+            Set { .. } => costs.set,
+            Copy { dest, source } if dest == source => Timing::default(),
+            Copy { .. } => costs.copy,
             // See https://stackoverflow.com/questions/26469196/swapping-2-registers-in-8086-assembly-language16-bits
-            // See https://stackoverflow.com/questions/45766444/why-is-xchg-reg-reg-a-3-micro-op-instruction-on-modern-intel-architectures
-            Swap { dest, source } if dest == source => 0,
-            Swap { .. } => 6,
-            Read { .. } => 6,
-            Write { .. } => 12,
-            Alloc { .. } => 24, // TODO: Better estimate
-            Drop { .. } => 24,  // TODO: Better estimate
+            Swap { dest, source } if dest == source => Timing::default(),
+            Swap { .. } => costs.swap,
+            Read { .. } => costs.read,
+            Write { .. } => costs.write,
+            Alloc { .. } => costs.alloc,
+            Drop { .. } => costs.drop,
+            Test { .. } => costs.test,
         }
     }
+
+    /// Latency of the longest chain of dependent transitions in `path`.
+    /// Transitions depend on earlier ones through registers, the flags and the
+    /// free memory pointer. Memory is not tracked: reads are from allocations
+    /// that were complete before the path.
+    #[must_use]
+    pub fn critical_path(path: &[Self], costs: &CostTable) -> usize {
+        use Transition::*;
+        const FLAGS: usize = 16;
+        const ALLOCATOR: usize = 17;
+        let register = |register: Register| register.as_u8() as usize;
+
+        // Time at which each register, the flags and the allocator are ready
+        let mut ready = [0; 18];
+        let mut end = 0;
+        for transition in path {
+            let (reads, mut writes) = match *transition {
+                Set { dest, .. } => (vec![], vec![register(dest)]),
+                Copy { dest, source } | Read { dest, source, .. } => {
+                    (vec![register(source)], vec![register(dest)])
+                }
+                Swap { dest, source } => {
+                    let both = vec![register(dest), register(source)];
+                    (both.clone(), both)
+                }
+                Write { dest, source, .. } => (vec![register(dest), register(source)], vec![]),
+                Alloc { dest, .. } => (vec![ALLOCATOR], vec![register(dest), ALLOCATOR]),
+                Drop { .. } => (vec![], vec![]),
+                Test { source } => (vec![register(source)], vec![FLAGS]),
+            };
+            if transition.clobbers_flags() {
+                writes.push(FLAGS);
+            }
+            let start = reads.iter().map(|&read| ready[read]).max().unwrap_or(0);
+            let finish = start + transition.timing(costs).latency;
+            for write in writes {
+                ready[write] = finish;
+            }
+            end = max(end, finish);
+        }
+        end
+    }
 }
 
 #[cfg(test)]
-mod test {
-    use super::*;
+pub(super) mod test {
+    use super::{super::state::test::arb_state, *};
+    #[cfg(feature = "native")]
+    use crate::OffsetAssembler;
+    #[cfg(feature = "native")]
+    use dynasmrt::DynasmApi;
+    use proptest::{arbitrary::any, prop_assert_eq, prop_oneof, proptest, strategy::Strategy};
+
+    pub(in crate::machine) fn arb_transition() -> impl Strategy<Value = Transition> {
+        use Transition::*;
+        let register = || (0_u8..16).prop_map(Register);
+        prop_oneof![
+            arb_simple_transition(),
+            (register(), register()).prop_map(|(dest, source)| Swap { dest, source }),
+            register().prop_map(|dest| Drop { dest }),
+        ]
+    }
+
+    /// Transitions that change a single value, except for `Alloc`.
+    pub(in crate::machine) fn arb_simple_transition() -> impl Strategy<Value = Transition> {
+        use Transition::*;
+        let register = || (0_u8..16).prop_map(Register);
+        prop_oneof![
+            (register(), any::<u64>()).prop_map(|(dest, value)| Set { dest, value }),
+            (register(), register()).prop_map(|(dest, source)| Copy { dest, source }),
+            (register(), register(), 0_isize..4).prop_map(|(dest, source, offset)| {
+                Read {
+                    dest,
+                    source,
+                    offset,
+                }
+            }),
+            (register(), 0_isize..4, register()).prop_map(|(dest, offset, source)| {
+                Write {
+                    dest,
+                    offset,
+                    source,
+                }
+            }),
+            (register(), 1_usize..4).prop_map(|(dest, size)| Alloc { dest, size }),
+            register().prop_map(|source| Test { source }),
+        ]
+    }
+
+    /// Registers r0 and r1 refer to the same allocation, r2 holds the only
+    /// reference to another one.
+    fn shared_state() -> State {
+        let mut state = State::default();
+        state.registers[0] = Value::Reference {
+            index:  0,
+            offset: 0,
+        };
+        state.registers[1] = state.registers[0];
+        state.registers[2] = Value::Reference {
+            index:  1,
+            offset: 0,
+        };
+        state.registers[3] = Value::Literal(3);
+        state.allocations = vec![
+            Allocation::new(vec![Value::Symbol(7), Value::Unspecified]),
+            Allocation::new(vec![Value::Literal(1)]),
+        ];
+        state.count_references();
+        assert!(state.is_valid());
+        state
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_applies() {
+        use Transition::*;
+        let state = shared_state();
+        let r = Register;
+        // Overwriting a shared reference is fine, the last one is not
+        assert!(Set { dest: r(1), value: 0 }.applies(&state));
+        assert!(!Set { dest: r(2), value: 0 }.applies(&state));
+        // Unspecified values can not be copied or read
+        assert!(!Copy { dest: r(4), source: r(5) }.applies(&state));
+        assert!(Copy { dest: r(4), source: r(3) }.applies(&state));
+        assert!(Read { dest: r(4), source: r(0), offset: 0 }.applies(&state));
+        assert!(!Read { dest: r(4), source: r(0), offset: 1 }.applies(&state));
+        // Reads and writes need to be in bounds
+        assert!(!Read { dest: r(4), source: r(2), offset: 1 }.applies(&state));
+        assert!(Write { dest: r(0), offset: 1, source: r(3) }.applies(&state));
+        assert!(!Write { dest: r(0), offset: 2, source: r(3) }.applies(&state));
+        assert!(!Write { dest: r(3), offset: 0, source: r(3) }.applies(&state));
+        // Swapping two unspecified registers is pointless
+        assert!(!Swap { dest: r(4), source: r(5) }.applies(&state));
+        assert!(!Alloc { dest: r(4), size: 0 }.applies(&state));
+        assert!(!Alloc { dest: r(2), size: 1 }.applies(&state));
+        assert!(Drop { dest: r(2) }.applies(&state));
+        assert!(!Drop { dest: r(3) }.applies(&state));
+        // Shared allocations are not dropped, the other reference would dangle
+        assert!(!Drop { dest: r(0) }.applies(&state));
+        // Only literals and symbols can be tested, once
+        assert!(Test { source: r(3) }.applies(&state));
+        assert!(!Test { source: r(0) }.applies(&state));
+        assert!(!Test { source: r(4) }.applies(&state));
+        let mut tested = state.clone();
+        tested.flags = Value::Literal(3);
+        assert!(!Test { source: r(3) }.applies(&tested));
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_apply() {
+        use Transition::*;
+        let r = Register;
+        let mut state = shared_state();
+        Read { dest: r(4), source: r(1), offset: 0 }.apply(&mut state);
+        assert_eq!(state.registers[4], Value::Symbol(7));
+        Write { dest: r(0), offset: 1, source: r(3) }.apply(&mut state);
+        assert_eq!(state.allocations[0].values[1], Value::Literal(3));
+        Swap { dest: r(3), source: r(4) }.apply(&mut state);
+        assert_eq!(state.registers[3], Value::Symbol(7));
+        Alloc { dest: r(5), size: 2 }.apply(&mut state);
+        assert_eq!(state.registers[5], Value::Reference { index: 2, offset: 0 });
+        assert_eq!(state.allocations[2], Allocation {
+            values:     vec![Value::Unspecified; 2],
+            references: 1,
+        });
+        // Dropping moves the last allocation into the freed slot
+        Set { dest: r(1), value: 0 }.apply(&mut state);
+        Drop { dest: r(0) }.apply(&mut state);
+        assert_eq!(state.registers[0], Value::Unspecified);
+        assert_eq!(state.registers[5], Value::Reference { index: 0, offset: 0 });
+        assert_eq!(state.allocations.len(), 2);
+        assert!(state.is_valid());
+        // Flags survive moves but not allocations
+        Test { source: r(3) }.apply(&mut state);
+        assert_eq!(state.flags, Value::Symbol(7));
+        Copy { dest: r(4), source: r(3) }.apply(&mut state);
+        assert_eq!(state.flags, Value::Symbol(7));
+        Alloc { dest: r(6), size: 1 }.apply(&mut state);
+        assert_eq!(state.flags, Value::Unspecified);
+    }
+
+    proptest! {
+        #[test]
+        fn test_after(state in arb_state(), transition in arb_transition()) {
+            let after = transition.after(&state);
+            prop_assert_eq!(after.is_some(), transition.applies(&state));
+            if let Some(after) = after {
+                let mut applied = state.clone();
+                transition.apply(&mut applied);
+                prop_assert_eq!(after, applied);
+            }
+        }
+    }
+
+    /// Size of the code the assembler emits.
+    #[cfg(feature = "native")]
+    fn assembled_size(transition: Transition) -> usize {
+        let mut asm = OffsetAssembler::default();
+        transition.assemble(&mut asm, 0);
+        asm.offset().0
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
+    fn test_size() {
+        use Transition::*;
+        let check = |t: Transition| assert_eq!(t.size(), assembled_size(t), "{:?}", t);
+        let registers = || (0_u8..16).map(Register);
+        for dest in registers() {
+            for source in registers() {
+                check(Copy { dest, source });
+                check(Swap { dest, source });
+                for offset in -20..20 {
+                    check(Read {
+                        dest,
+                        source,
+                        offset,
+                    });
+                    check(Write {
+                        dest,
+                        offset,
+                        source,
+                    });
+                }
+            }
+            for &size in &[1, 15, 16, 17, 1000] {
+                check(Alloc { dest, size });
+            }
+            check(Drop { dest });
+            check(Test { source: dest });
+        }
+    }
+
+    #[cfg(feature = "native")]
+    proptest! {
+        #[test]
+        fn test_size_matches_assembler(transition in arb_transition()) {
+            prop_assert_eq!(transition.size(), assembled_size(transition));
+        }
+    }
 
     #[test]
     fn test_set_size() {
@@ -274,4 +746,36 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn test_critical_path() {
+        use Transition::*;
+        let costs = CostTable::GENERIC;
+        let copy = |dest, source| {
+            Copy {
+                dest:   Register(dest),
+                source: Register(source),
+            }
+        };
+        // A chain of copies against copies from the same register
+        assert_eq!(
+            Transition::critical_path(&[copy(1, 0), copy(2, 1), copy(3, 2)], &costs),
+            3 * costs.copy.latency
+        );
+        assert_eq!(
+            Transition::critical_path(&[copy(1, 0), copy(2, 0), copy(3, 0)], &costs),
+            costs.copy.latency
+        );
+        // Allocations depend on each other through the free memory pointer
+        let alloc = |dest| {
+            Alloc {
+                dest: Register(dest),
+                size: 1,
+            }
+        };
+        assert_eq!(
+            Transition::critical_path(&[alloc(0), alloc(1)], &costs),
+            2 * costs.alloc.latency
+        );
+    }
 }
@@ -1,18 +1,62 @@
 use serde::{Deserialize, Serialize};
-use std::fmt::{self, Display};
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
 
+/// The contents of a register or an allocated word.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
-pub(crate) enum Value {
+#[non_exhaustive]
+pub enum Value {
+    /// Anything. In a goal, the value does not matter.
     Unspecified,
     Literal(u64),
+    /// A value only known at run time, which can be moved but not constructed.
     Symbol(usize),
+    /// A pointer `offset` words into allocation `index` of the state.
     Reference { index: usize, offset: isize },
 }
 
 impl Value {
-    pub(crate) fn is_specified(&self) -> bool {
+    #[must_use]
+    pub fn is_specified(&self) -> bool {
         *self != Value::Unspecified
     }
+
+    /// Whether this is a reference into allocation `index`.
+    pub(crate) fn references(&self, index: usize) -> bool {
+        match *self {
+            Value::Reference { index: i, .. } => i == index,
+            _ => false,
+        }
+    }
+}
+
+/// The form `Display` writes.
+impl FromStr for Value {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid value '{}'", s);
+        if s == "?" {
+            return Ok(Value::Unspecified);
+        }
+        if let Some(hex) = s.strip_prefix("0x") {
+            return u64::from_str_radix(hex, 16)
+                .map(Value::Literal)
+                .map_err(|_| invalid());
+        }
+        if let Some(symbol) = s.strip_prefix('#') {
+            return symbol.parse().map(Value::Symbol).map_err(|_| invalid());
+        }
+        let mut parts = s.strip_suffix(']').ok_or_else(invalid)?.splitn(2, '[');
+        let index = parts.next().unwrap().parse().map_err(|_| invalid())?;
+        let offset = parts.next().ok_or_else(invalid)?;
+        Ok(Value::Reference {
+            index,
+            offset: offset.parse().map_err(|_| invalid())?,
+        })
+    }
 }
 
 impl Default for Value {
@@ -34,21 +78,30 @@ impl Display for Value {
 }
 
 #[cfg(test)]
-mod test {
+pub(super) mod test {
     use super::*;
     use proptest::{
         arbitrary::any,
         prop_oneof,
-        strategy::{Just, LazyTupleUnion, Strategy},
+        strategy::{BoxedStrategy, Just, Strategy},
     };
 
-    fn arb_value(num_allocations: usize) -> impl Strategy<Value = Value> {
-        prop_oneof![
+    /// Values in a state with `num_allocations` allocations. Symbols and
+    /// offsets come from small ranges so that values repeat.
+    pub(in crate::machine) fn arb_value(num_allocations: usize) -> BoxedStrategy<Value> {
+        let scalar = prop_oneof![
             Just(Value::Unspecified),
             any::<u64>().prop_map(Value::Literal),
-            any::<usize>().prop_map(Value::Symbol),
-            (0..num_allocations, any::<isize>())
+            (0_usize..8).prop_map(Value::Symbol),
+        ];
+        if num_allocations == 0 {
+            return scalar.boxed();
+        }
+        prop_oneof![
+            scalar,
+            (0..num_allocations, 0_isize..2)
                 .prop_map(|(index, offset)| Value::Reference { index, offset }),
         ]
+        .boxed()
     }
 }
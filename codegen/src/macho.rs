@@ -1,46 +1,64 @@
+use crate::{
+    codesign,
+    error::{finalize, new_assembler, Error, Phase, Result},
+    memory_map::PAGE,
+    utils::Timer,
+    Arch, Memory, MemoryMap, SegmentOrder, Startup, Target,
+};
 use dynasm::dynasm;
 use dynasmrt::DynasmApi;
-use std::{error::Error, fs, fs::File, io::Write, os::unix::fs::PermissionsExt, path::PathBuf};
+use parser::timings;
+use std::{fs, fs::File, io::Write, os::unix::fs::PermissionsExt, path::PathBuf};
 
-// TODO: These are not constant
-pub(crate) const CODE_START: usize = 0x11f8;
-
-const PAGE: usize = 4096;
-const RAM_PAGES: usize = 1024; // 4MB RAM
+/// Size of the load commands, five segments and the code signature and
+/// either a thread or the commands for `dyld`.
+const THREAD_COMMANDS: usize = 5 * 72 + 184 + 16;
+const DYLD_COMMANDS: usize = 5 * 72 + 48 + 24 + 80 + 32 + 24 + 56 + 24 + 16;
 
-pub(crate) fn rom_start(code_size: usize) -> usize {
-    // Add offset and round to next page boundary
-    let mut code_end = CODE_START + code_size;
-    if code_end % PAGE != 0 {
-        code_end += PAGE;
-        code_end -= code_end % PAGE;
-    }
-    assert_eq!(code_end % PAGE, 0);
-    code_end
-}
+// TODO: These are not constant
+pub(crate) const CODE_START: usize = PAGE + 32 + THREAD_COMMANDS;
+pub(crate) const DYLD_CODE_START: usize = PAGE + 32 + DYLD_COMMANDS;
 
-pub(crate) fn ram_start(rom_start: usize, rom_size: usize) -> usize {
-    // Add offset and round to next page boundary
-    let mut rom_end = rom_start + rom_size;
-    if rom_end % PAGE != 0 {
-        rom_end += PAGE;
-        rom_end -= rom_end % PAGE;
+impl Startup {
+    /// Address of the first segment, following the headers.
+    pub(crate) const fn code_start(self) -> usize {
+        match self {
+            Startup::Thread => CODE_START,
+            Startup::Dyld => DYLD_CODE_START,
+        }
     }
-    assert_eq!(rom_end % PAGE, 0);
-    rom_end
 }
 
-/// The `code`, `rom` and `ram` segments will be extended to 4k page boundaries,
-/// concatenated and loaded at address 0x1000. Ram will be extended to 4MB.
-pub(crate) struct Assembly {
-    pub(crate) code: Vec<u8>,
-    pub(crate) rom:  Vec<u8>,
-    pub(crate) ram:  Vec<u8>,
+/// Compiled segments. The `code`, `rom` and `ram` segments will be extended to
+/// 4k page boundaries and loaded at the addresses in `map`. Ram will be
+/// extended to the size in `memory`. The code is for `arch`. Mach-O
+/// executables start as in `startup`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Assembly {
+    pub arch:    Arch,
+    pub map:     MemoryMap,
+    pub memory:  Memory,
+    pub startup: Startup,
+    pub code:    Vec<u8>,
+    pub rom:     Vec<u8>,
+    pub ram:     Vec<u8>,
 }
 
 impl Assembly {
-    pub(crate) fn save(&self, destination: &PathBuf) -> Result<(), Box<dyn Error>> {
-        let exe = self.to_macho();
+    /// The executable for `target`, which the assembly needs to be compiled
+    /// for.
+    pub fn to_executable(&self, target: Target) -> Result<Vec<u8>> {
+        match target {
+            Target::X86_64AppleDarwin => self.to_macho(),
+            Target::X86_64UnknownLinuxGnu | Target::Aarch64UnknownLinuxGnu => self.to_elf(),
+        }
+    }
+
+    /// Write an executable for `target`.
+    pub fn save(&self, target: Target, destination: &PathBuf) -> Result<()> {
+        let exe = self.to_executable(target)?;
+        let _timer = Timer::new("Write".to_string());
+        timings::count("bytes", exe.len() as u64);
         {
             let mut file = File::create(destination)?;
             file.write_all(&exe)?;
@@ -59,19 +77,42 @@ impl Assembly {
     // See <https://pewpewthespells.com/re/Mach-O_File_Format.pdf>
     // See <https://github.com/apple/darwin-xnu/blob/master/EXTERNAL_HEADERS/mach-o/loader.h>
     // See <https://github.com/apple/darwin-xnu/blob/master/bsd/kern/mach_loader.c>
-    pub(crate) fn to_macho(&self) -> Vec<u8> {
-        let num_segments = 4;
-        let header_size: usize = 32 + 72 * num_segments + 184;
-        let code_pages = (self.code.len() + header_size + PAGE - 1) / PAGE;
-        let rom_pages = (self.rom.len() + PAGE - 1) / PAGE;
+    pub fn to_macho(&self) -> Result<Vec<u8>> {
+        // Apple Silicon needs 16 KiB pages
+        if self.arch != Arch::X86_64 {
+            return Err(Error::Unsupported {
+                format: "Mach-O",
+                arch:   self.arch,
+            });
+        }
+        // The first segment follows the headers on the first page
+        if self.map.start != self.startup.code_start() {
+            return Err(Error::CodeStart(self.map.start));
+        }
+        let (num_commands, commands_size) = match self.startup {
+            Startup::Thread => (7, THREAD_COMMANDS),
+            Startup::Dyld => (13, DYLD_COMMANDS),
+        };
+        let header_size = 32 + commands_size;
+        let (first, second) = match self.map.order {
+            SegmentOrder::CodeFirst => (&self.code, &self.rom),
+            SegmentOrder::RomFirst => (&self.rom, &self.code),
+        };
+        let first_pages = (first.len() + header_size + PAGE - 1) / PAGE;
+        let second_pages = (second.len() + PAGE - 1) / PAGE;
         let ram_init_pages = (self.ram.len() + PAGE - 1) / PAGE;
-        let ram_pages = std::cmp::max(RAM_PAGES, ram_init_pages);
+        let ram_pages = std::cmp::max(self.memory.ram_pages, ram_init_pages);
+        // The signature covers the file up to it
+        let signature_start = (first_pages + second_pages + ram_init_pages) * PAGE;
+        let signature_size = codesign::size(signature_start);
+        let signature_pages = (signature_size + PAGE - 1) / PAGE;
 
-        let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+        let mut ops = new_assembler(Phase::Executable)?;
 
         // All offsets and sizes are in pages
         fn segment(
             ops: &mut dynasmrt::x64::Assembler,
+            name: &[u8],
             vm_start: usize,
             vm_size: usize,
             file_start: usize,
@@ -80,11 +121,14 @@ impl Assembly {
         ) {
             assert!(vm_size > 0);
             let file_start = if file_size > 0 { file_start } else { 0 };
+            let mut segment_name = [0_u8; 16];
+            segment_name[..name.len()].copy_from_slice(name);
             dynasm!(ops
                 ; .dword 0x19       // Segment command
                 ; .dword 72         // command size
-                ; .qword 0          // segment name
-                ; .qword 0          // segment name
+            );
+            ops.extend(segment_name.iter());
+            dynasm!(ops
                 ; .qword (vm_start * PAGE) as i64   // VM Address
                 ; .qword (vm_size * PAGE) as i64     // VM Size
                 ; .qword (file_start * PAGE) as i64  // File Offset
@@ -95,7 +139,7 @@ impl Assembly {
                 ; .dword 0          // Flags
             );
         }
-        let end_of_ram = code_pages + rom_pages + ram_pages;
+        let end_of_ram = first_pages + second_pages + ram_pages;
         let mut vm_offset = 0;
         let mut file_offset = 0;
 
@@ -105,36 +149,112 @@ impl Assembly {
             ; .dword 0x0100_0007_u32 as i32 // Cpu type x86_64
             ; .dword 0x8000_0003_u32 as i32 // Cpu subtype (i386)
             ; .dword 0x2        // Type: executable
-            ; .dword (num_segments + 1) as i32         // num_commands
-            ; .dword (num_segments * 72 + 184) as i32  // Size of commands
-            ; .dword 0x1        // Noun definitions
+            ; .dword num_commands as i32
+            ; .dword commands_size as i32
+        );
+        match self.startup {
+            Startup::Thread => dynasm!(ops; .dword 0x1), // No undefined symbols
+            // Also linked by dyld, with two level namespaces
+            Startup::Dyld => dynasm!(ops; .dword 0x85),
+        }
+        dynasm!(ops
             ; .dword 0          // Reserved
         );
         // Page zero (___)
         // This is required by XNU for the process to start.
-        segment(&mut ops, vm_offset, 1, 0, 0, 0);
+        segment(&mut ops, b"__PAGEZERO", vm_offset, 1, 0, 0, 0);
         vm_offset += 1;
-        // Code (R_X)
+        // Code or ROM (R_X)
         // XNU insists there is one R_X segment starting from the start of the file,
-        // even tough this includes the non-executable the Mach-O headers.
+        // even tough this includes the non-executable the Mach-O headers. When the
+        // ROM comes first it is therefore executable too.
         // See <https://github.com/apple/darwin-xnu/blob/a449c6a/bsd/kern/mach_loader.c#L985>
-        segment(&mut ops, vm_offset, code_pages, 0, code_pages, 5);
-        vm_offset += code_pages;
-        file_offset += code_pages;
-        // ROM (R__)
-        segment(&mut ops, vm_offset, rom_pages, file_offset, rom_pages, 1);
-        vm_offset += rom_pages;
-        file_offset += rom_pages;
+        segment(
+            &mut ops,
+            b"__TEXT",
+            vm_offset,
+            first_pages,
+            0,
+            first_pages,
+            5,
+        );
+        vm_offset += first_pages;
+        file_offset += first_pages;
+        // ROM (R__) or code (R_X)
+        let (name, protect): (&[u8], _) = match self.map.order {
+            SegmentOrder::CodeFirst => (b"__ROM", 1),
+            SegmentOrder::RomFirst => (b"__CODE", 5),
+        };
+        segment(
+            &mut ops,
+            name,
+            vm_offset,
+            second_pages,
+            file_offset,
+            second_pages,
+            protect,
+        );
+        vm_offset += second_pages;
+        file_offset += second_pages;
         // RAM (RW_)
         segment(
             &mut ops,
+            b"__RAM",
             vm_offset,
             ram_pages,
             file_offset,
             ram_init_pages,
             3,
         );
+        // Signature (R__)
+        // The code signing tools expect it last in the file, in a segment with
+        // this name.
+        segment(
+            &mut ops,
+            b"__LINKEDIT",
+            end_of_ram,
+            signature_pages,
+            signature_start / PAGE,
+            signature_pages,
+            1,
+        );
+
+        match self.startup {
+            Startup::Thread => self.thread_command(&mut ops, end_of_ram),
+            Startup::Dyld => dyld_commands(&mut ops, signature_start, self.map.code_start),
+        }
+
+        // Code signature command (16 bytes)
+        dynasm!(ops
+            ; .dword 0x1d       // Code signature command
+            ; .dword 16         // Command size
+            ; .dword signature_start as i32 // File offset
+            ; .dword signature_size as i32  // Size
+        );
+
+        // Concatenate all the pages
+        let mut result = finalize(ops, Phase::Executable)?;
+        assert_eq!(result.len(), header_size);
+        assert_eq!(result.len(), self.map.start - PAGE);
+        result.extend(first);
+        zero_pad_to_boundary(&mut result, PAGE);
+        assert_eq!(result.len(), first_pages * PAGE);
+        result.extend(second);
+        zero_pad_to_boundary(&mut result, PAGE);
+        assert_eq!(result.len(), (first_pages + second_pages) * PAGE);
+        result.extend(&self.ram);
+        zero_pad_to_boundary(&mut result, PAGE);
+        assert_eq!(result.len(), signature_start);
+        let signature = codesign::sign(&result, (0, first_pages * PAGE));
+        assert_eq!(signature.len(), signature_size);
+        result.extend(signature);
+        zero_pad_to_boundary(&mut result, PAGE);
+        assert_eq!(result.len(), signature_start + signature_pages * PAGE);
+        Ok(result)
+    }
 
+    /// The `LC_UNIXTHREAD` command, `end_of_ram` is in pages.
+    fn thread_command(&self, ops: &mut dynasmrt::x64::Assembler, end_of_ram: usize) {
         // Unix thread segment (184 bytes)
         // rip need to be initialized to the start of the program.
         // If rsp is zero, XNU will allocate a stack for the program. XNU requires
@@ -143,9 +263,14 @@ impl Assembly {
         // XNU from allocating an otherwise unecessary stack, but still keep the
         // variables, we set rsp to the top of the RAM. On start, variables will be
         // in [rsp ... end of ram - 8]. The last eight bytes are reserved to store
-        // rsp in on start.
+        // rsp in on start. With `os_stack` XNU allocates the stack after all.
         // This initial 'stack' looks like:
         // See <https://github.com/apple/darwin-xnu/blob/master/bsd/kern/kern_exec.c#L3821>
+        let stack = if self.memory.os_stack {
+            0
+        } else {
+            end_of_ram * PAGE - 8
+        };
         dynasm!(ops
             ; .dword 0x5        // Segment command
             ; .dword 184        // Command size
@@ -153,30 +278,72 @@ impl Assembly {
             ; .dword 42         // Thread state (needs to be 42)
             ; .qword 0, 0, 0, 0 // r0, r3, r1, r2 (rax, rbx, rcx, rdx)
             ; .qword 0, 0, 0    // r7, r6, r5 (rdi, rsi, rbp)
-            ; .qword (end_of_ram * PAGE - 8) as i64     // r4 (rsp)
+            ; .qword stack as i64     // r4 (rsp)
             ; .qword 0, 0, 0, 0, 0, 0, 0, 0 // r8..r15
-            ; .qword (PAGE + header_size) as i64 // rip
+            ; .qword self.map.code_start as i64 // rip
             ; .qword 0, 0, 0, 0 // rflags, cs, fs, gs
         );
+    }
+}
 
-        // Concatenate all the pages
-        let mut result = ops.finalize().unwrap()[..].to_owned();
-        assert_eq!(result.len(), header_size);
-        assert_eq!(result.len(), CODE_START - PAGE);
-        result.extend(&self.code);
-        zero_pad_to_boundary(&mut result, PAGE);
-        assert_eq!(result.len(), code_pages * PAGE);
-        result.extend(&self.rom);
-        zero_pad_to_boundary(&mut result, PAGE);
-        assert_eq!(result.len(), (code_pages + rom_pages) * PAGE);
-        result.extend(&self.ram);
-        zero_pad_to_boundary(&mut result, PAGE);
-        assert_eq!(
-            result.len(),
-            (code_pages + rom_pages + ram_init_pages) * PAGE
-        );
-        result
+/// The commands `dyld` needs to start an executable that imports nothing,
+/// like `ld` writes them. The empty symbol tables point to the start of
+/// `__LINKEDIT` at `linkedit`. `dyld` calls the code at `code_start` with
+/// `argc`, `argv` and `envp` in r7, r6 and r2 on a stack it allocated.
+// See <https://github.com/apple-oss-distributions/dyld/blob/main/mach_o/Header.cpp>
+fn dyld_commands(ops: &mut dynasmrt::x64::Assembler, linkedit: usize, code_start: usize) {
+    /// A null terminated string padded to `size` bytes.
+    fn string(ops: &mut dynasmrt::x64::Assembler, string: &[u8], size: usize) {
+        assert!(string.len() < size);
+        ops.extend(string.iter());
+        ops.extend(std::iter::repeat(&0).take(size - string.len()));
     }
+    dynasm!(ops
+        // Dyld info command (48 bytes), there is nothing to rebase or bind
+        ; .dword 0x8000_0022_u32 as i32
+        ; .dword 48
+        ; .dword 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+        // Symbol table command (24 bytes)
+        ; .dword 0x2
+        ; .dword 24
+        ; .dword linkedit as i32 // Symbols
+        ; .dword 0
+        ; .dword linkedit as i32 // Strings
+        ; .dword 0
+        // Dynamic symbol table command (80 bytes)
+        ; .dword 0xb
+        ; .dword 80
+        ; .dword 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+        // Dynamic linker command (32 bytes)
+        ; .dword 0xe
+        ; .dword 32
+        ; .dword 12         // Name offset
+    );
+    string(ops, b"/usr/lib/dyld", 20);
+    dynasm!(ops
+        // Entry point command (24 bytes)
+        ; .dword 0x8000_0028_u32 as i32
+        ; .dword 24
+        ; .qword (code_start - PAGE) as i64 // File offset of the entry point
+        ; .qword 0          // Default stack size
+        // Dynamic library command (56 bytes)
+        ; .dword 0xc
+        ; .dword 56
+        ; .dword 24         // Name offset
+        ; .dword 2          // Timestamp
+        ; .dword 0x0527_0000 // Current version 1319
+        ; .dword 0x0001_0000 // Compatibility version 1
+    );
+    string(ops, b"/usr/lib/libSystem.B.dylib", 32);
+    dynasm!(ops
+        // Build version command (24 bytes)
+        ; .dword 0x32
+        ; .dword 24
+        ; .dword 1          // Platform macOS
+        ; .dword 0x000a_0f00 // Minimum OS 10.15
+        ; .dword 0x000a_0f00 // SDK 10.15
+        ; .dword 0          // Tools
+    );
 }
 
 fn zero_pad_to_boundary(vec: &mut Vec<u8>, block_size: usize) {
@@ -186,3 +353,217 @@ fn zero_pad_to_boundary(vec: &mut Vec<u8>, block_size: usize) {
         vec.extend(std::iter::repeat(0_u8).take(padding));
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::{intrinsics::Syscall, Os};
+
+    /// Code that writes to `target` and then exits with status zero, with a
+    /// small ROM and RAM. The `map` is for one byte code and ROM segments.
+    pub(crate) fn write_and_exit(os: Os, map: MemoryMap, target: usize) -> Assembly {
+        let mut ops: dynasmrt::x64::Assembler = new_assembler(Phase::Code).unwrap();
+        dynasm!(ops
+            ; mov r0, QWORD target as i64
+            ; mov QWORD [r0], 1
+            ; mov r0d, DWORD Syscall::Exit.number(os)
+            ; xor r7d, r7d
+            ; syscall
+        );
+        let code = finalize(ops, Phase::Code).unwrap();
+        assert_eq!(MemoryMap::new(map.order, map.start, code.len(), 8), map);
+        Assembly {
+            arch: Arch::X86_64,
+            map,
+            memory: Memory::default(),
+            startup: Startup::Thread,
+            code,
+            rom: vec![0; 8],
+            ram: vec![0; 8],
+        }
+    }
+
+    /// Maximum and initial protection of the segment commands.
+    fn protections(exe: &[u8]) -> Vec<(u32, u32)> {
+        let word = |offset: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&exe[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        (0..4)
+            .map(|index| 32 + 72 * index)
+            .map(|command| (word(command + 56), word(command + 60)))
+            .collect()
+    }
+
+    #[test]
+    fn test_segment_protections() {
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, CODE_START, 1, 1);
+        let exe = write_and_exit(Os::Darwin, map, 0).to_macho().unwrap();
+        // Page zero ___, code R_X, ROM R__, RAM RW_
+        assert_eq!(protections(&exe), vec![(0, 0), (5, 5), (1, 1), (3, 3)]);
+
+        let map = MemoryMap::new(SegmentOrder::RomFirst, CODE_START, 1, 1);
+        let exe = write_and_exit(Os::Darwin, map, 0).to_macho().unwrap();
+        // Page zero ___, headers and ROM R_X, code R_X, RAM RW_
+        assert_eq!(protections(&exe), vec![(0, 0), (5, 5), (5, 5), (3, 3)]);
+    }
+
+    #[test]
+    fn test_os_stack() {
+        // Offset of rsp in the thread command following the segment commands
+        const RSP: usize = 32 + 5 * 72 + 16 + 7 * 8;
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, CODE_START, 1, 1);
+        let mut assembly = write_and_exit(Os::Darwin, map, 0);
+        let rsp = |exe: &[u8]| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&exe[RSP..RSP + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        // Code and ROM take a page each
+        let ram_end = (2 + assembly.memory.ram_pages) * PAGE;
+        assert_eq!(rsp(&assembly.to_macho().unwrap()), ram_end as u64 - 8);
+        assembly.memory.os_stack = true;
+        assert_eq!(rsp(&assembly.to_macho().unwrap()), 0);
+    }
+
+    #[test]
+    fn test_code_signature() {
+        let word = |exe: &[u8], offset: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&exe[offset..offset + 4]);
+            u32::from_le_bytes(bytes) as usize
+        };
+        for &order in &[SegmentOrder::CodeFirst, SegmentOrder::RomFirst] {
+            let map = MemoryMap::new(order, CODE_START, 1, 1);
+            let exe = write_and_exit(Os::Darwin, map, 0).to_macho().unwrap();
+            // The last command points to the signature of the file before it
+            let command = CODE_START - PAGE - 16;
+            assert_eq!(word(&exe, command), 0x1d);
+            let (start, size) = (word(&exe, command + 8), word(&exe, command + 12));
+            assert_eq!(start, 3 * PAGE);
+            assert_eq!(
+                &exe[start..start + size],
+                &codesign::sign(&exe[..start], (0, PAGE))[..]
+            );
+            // The last segment maps it
+            let linkedit = 32 + 4 * 72;
+            assert_eq!(&exe[linkedit + 8..linkedit + 18], b"__LINKEDIT");
+            assert_eq!(word(&exe, linkedit + 40), start);
+            assert!(start + size <= exe.len());
+        }
+    }
+
+    /// The load commands and their offsets.
+    fn commands(exe: &[u8]) -> Vec<(u32, usize)> {
+        let word = |offset: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&exe[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        let mut offset = 32;
+        let mut result = Vec::new();
+        for _ in 0..word(16) {
+            result.push((word(offset), offset));
+            offset += word(offset + 4) as usize;
+        }
+        assert_eq!(offset, 32 + word(20) as usize);
+        result
+    }
+
+    #[test]
+    fn test_dyld_commands() {
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, DYLD_CODE_START, 1, 1);
+        let mut assembly = write_and_exit(Os::Darwin, map, 0);
+        assert!(matches!(
+            assembly.to_macho(),
+            Err(Error::CodeStart(DYLD_CODE_START))
+        ));
+        assembly.startup = Startup::Dyld;
+        let exe = assembly.to_macho().unwrap();
+        let commands = commands(&exe);
+        let kinds = commands.iter().map(|(kind, _)| *kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                0x19,
+                0x19,
+                0x19,
+                0x19,
+                0x19,
+                0x8000_0022,
+                0x2,
+                0xb,
+                0xe,
+                0x8000_0028,
+                0xc,
+                0x32,
+                0x1d
+            ]
+        );
+        // The entry point is the code
+        let main = commands[9].1;
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&exe[main + 8..main + 16]);
+        let entry = u64::from_le_bytes(bytes) as usize;
+        assert_eq!(&exe[entry..entry + assembly.code.len()], &assembly.code[..]);
+        assert!(exe[commands[8].1..].starts_with(b"\x0e\0\0\0\x20\0\0\0\x0c\0\0\0/usr/lib/dyld\0"));
+    }
+
+    #[test]
+    fn test_start_address() {
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, 0x1000, 1, 1);
+        let result = write_and_exit(Os::Darwin, map, 0).to_macho();
+        assert!(matches!(result, Err(Error::CodeStart(0x1000))));
+    }
+
+    #[test]
+    fn test_unsupported_arch() {
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, CODE_START, 1, 1);
+        let mut assembly = write_and_exit(Os::Darwin, map, 0);
+        assembly.arch = Arch::Aarch64;
+        let result = assembly.to_macho();
+        assert!(matches!(result, Err(Error::Unsupported { .. })));
+    }
+
+    /// Run the executable through `dyld`.
+    #[test]
+    #[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+    fn test_dyld_startup() {
+        use std::{env, process::Command};
+
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, DYLD_CODE_START, 1, 1);
+        let executable = env::temp_dir().join("olus-dyld-startup");
+        let mut assembly = write_and_exit(Os::Darwin, map, map.ram_start);
+        assembly.startup = Startup::Dyld;
+        assembly
+            .save(Target::X86_64AppleDarwin, &executable)
+            .unwrap();
+        let status = Command::new(&executable).status().unwrap();
+        fs::remove_file(&executable).unwrap();
+        assert!(status.success());
+    }
+
+    /// Run the executable, writing to code and ROM must fault.
+    #[test]
+    #[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+    fn test_write_protection() {
+        use std::{env, os::unix::process::ExitStatusExt, process::Command};
+
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, CODE_START, 1, 1);
+        for &(case, target, faults) in &[
+            ("code", map.code_start, true),
+            ("rom", map.rom_start, true),
+            ("ram", map.ram_start, false),
+        ] {
+            let executable = env::temp_dir().join(format!("olus-protection-{}", case));
+            write_and_exit(Os::Darwin, map, target)
+                .save(Target::X86_64AppleDarwin, &executable)
+                .unwrap();
+            let status = Command::new(&executable).status().unwrap();
+            fs::remove_file(&executable).unwrap();
+            assert_eq!(status.signal().is_some(), faults, "writing to {}", case);
+            assert_eq!(status.success(), !faults, "writing to {}", case);
+        }
+    }
+}
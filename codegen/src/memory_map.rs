@@ -0,0 +1,180 @@
+// Placement of the segments in the address space. The first segment starts at
+// an arbitrary address, the others on the next page boundary after the
+// previous one, so each can get its own protection. RAM always comes last so
+// it can extend beyond its initialized part.
+
+pub(crate) const PAGE: usize = 4096;
+
+/// Order of the code and ROM segments.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SegmentOrder {
+    /// Code, ROM, RAM
+    CodeFirst,
+    /// ROM, code, RAM
+    RomFirst,
+}
+
+impl Default for SegmentOrder {
+    fn default() -> Self {
+        SegmentOrder::CodeFirst
+    }
+}
+
+/// How XNU starts Mach-O executables.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Startup {
+    /// An `LC_UNIXTHREAD` command with the initial registers. The code starts
+    /// right away, nothing else is loaded.
+    Thread,
+    /// An `LC_MAIN` command for `dyld`, which loads `libSystem` and calls the
+    /// code on a stack of its own. This is how `ld` links executables, OS
+    /// validation may expect it.
+    Dyld,
+}
+
+impl Default for Startup {
+    fn default() -> Self {
+        Startup::Thread
+    }
+}
+
+/// Bytes at the start of RAM taken by the allocator state, the return slot
+/// of outlined code and the declaration slot of the debug runtime.
+pub(crate) const RESERVED: usize = 24;
+
+/// Size and use of the RAM segment. The heap grows up from the start, the
+/// last quad word holds the stack pointer the program was started with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Memory {
+    /// Size of the RAM segment in pages, including the initialized part.
+    pub ram_pages:   usize,
+    /// Offset of the first heap allocation from the start of RAM. The
+    /// allocator state comes before it, instrumented code keeps its data
+    /// here and allocates after that.
+    pub heap_offset: usize,
+    /// Have the operating system allocate a separate stack for the command
+    /// line and environment. Otherwise these are placed at the end of RAM.
+    /// Only affects Mach-O executables started with [`Startup::Thread`],
+    /// Linux and `dyld` always allocate a stack.
+    ///
+    /// [`Startup::Thread`]: crate::Startup::Thread
+    pub os_stack:    bool,
+    /// Check that allocations fit in RAM and abort with a message if they do
+    /// not. Without the checks the heap overruns RAM and the program faults.
+    pub heap_checks: bool,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self {
+            ram_pages:   1024, // 4MB
+            heap_offset: RESERVED,
+            os_stack:    false,
+            heap_checks: true,
+        }
+    }
+}
+
+impl Memory {
+    /// Size of the RAM segment in bytes.
+    #[must_use]
+    pub const fn ram_size(&self) -> usize {
+        self.ram_pages * PAGE
+    }
+
+    /// Offset from the start of RAM where the heap ends, before the saved
+    /// stack pointer.
+    pub(crate) const fn heap_end(&self) -> usize {
+        self.ram_size() - 8
+    }
+
+    /// Whether the heap starts after the allocator state, is aligned and
+    /// leaves room for the saved stack pointer.
+    pub(crate) const fn is_valid(&self) -> bool {
+        self.heap_offset >= RESERVED
+            && self.heap_offset % 8 == 0
+            && self.heap_offset + 8 <= self.ram_size()
+    }
+}
+
+/// Start addresses of the segments.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MemoryMap {
+    pub order:      SegmentOrder,
+    /// Start of the first segment
+    pub start:      usize,
+    pub code_start: usize,
+    pub rom_start:  usize,
+    pub ram_start:  usize,
+}
+
+impl MemoryMap {
+    /// Place segments of the given sizes in `order` starting at `start`.
+    #[must_use]
+    pub fn new(order: SegmentOrder, start: usize, code_size: usize, rom_size: usize) -> Self {
+        let (code_start, rom_start) = match order {
+            SegmentOrder::CodeFirst => (start, page_align(start + code_size)),
+            SegmentOrder::RomFirst => (page_align(start + rom_size), start),
+        };
+        let ram_start = match order {
+            SegmentOrder::CodeFirst => page_align(rom_start + rom_size),
+            SegmentOrder::RomFirst => page_align(code_start + code_size),
+        };
+        Self {
+            order,
+            start,
+            code_start,
+            rom_start,
+            ram_start,
+        }
+    }
+}
+
+/// Round up to the next page boundary.
+pub(crate) const fn page_align(address: usize) -> usize {
+    (address + PAGE - 1) / PAGE * PAGE
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_code_first() {
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, 0x11f8, 0x10, 0x2000);
+        assert_eq!(map.code_start, 0x11f8);
+        assert_eq!(map.rom_start, 0x2000);
+        assert_eq!(map.ram_start, 0x4000);
+        // Segments ending on a page boundary are not padded
+        let map = MemoryMap::new(SegmentOrder::CodeFirst, 0x1000, 0x1000, 1);
+        assert_eq!(map.rom_start, 0x2000);
+        assert_eq!(map.ram_start, 0x3000);
+    }
+
+    #[test]
+    fn test_rom_first() {
+        let map = MemoryMap::new(SegmentOrder::RomFirst, 0x11f8, 0x2000, 0x10);
+        assert_eq!(map.rom_start, 0x11f8);
+        assert_eq!(map.code_start, 0x2000);
+        assert_eq!(map.ram_start, 0x4000);
+    }
+
+    #[test]
+    fn test_memory() {
+        assert!(Memory::default().is_valid());
+        let memory = |ram_pages, heap_offset| {
+            Memory {
+                ram_pages,
+                heap_offset,
+                ..Memory::default()
+            }
+            .is_valid()
+        };
+        assert!(memory(1, 64));
+        assert!(memory(1, PAGE - 8));
+        assert!(!memory(1, PAGE));
+        assert!(!memory(1, 8));
+        assert!(!memory(1, 20));
+        assert!(!memory(0, 16));
+    }
+}
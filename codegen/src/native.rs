@@ -0,0 +1,198 @@
+use crate::{
+    aarch64::A64,
+    allocator,
+    arch::Isa,
+    backend::Backend,
+    code::{self, Emitter},
+    error::{Phase, Result},
+    placement::Placement,
+    rom,
+    utils::Timer,
+    x64::X64,
+    Arch, Assembly, MemoryMap, Options,
+};
+use log::info;
+use parser::{mir::Module, timings};
+
+/// [`Emitter`] for any instruction set.
+trait Emit {
+    fn declaration(&mut self, index: usize);
+    fn intrinsic(&mut self, index: usize);
+    fn finish(self: Box<Self>) -> Result<(Vec<u8>, code::Layout)>;
+}
+
+impl<I: Isa> Emit for Emitter<'_, I> {
+    fn declaration(&mut self, index: usize) {
+        Emitter::declaration(self, index);
+    }
+
+    fn intrinsic(&mut self, index: usize) {
+        Emitter::intrinsic(self, index);
+    }
+
+    fn finish(self: Box<Self>) -> Result<(Vec<u8>, code::Layout)> {
+        Emitter::finish(*self)
+    }
+}
+
+/// Native code for the instruction set and operating system of the options,
+/// in code, ROM and initial RAM segments loaded from `start` on consecutive
+/// pages in the order given by the options. Executable formats wrap the
+/// segments, see [`Assembly::to_executable`]. Also returns where the code of
+/// each declaration and intrinsic is.
+///
+/// Code refers to ROM and ROM to code, so the code is assembled twice. The
+/// layout pass assembles it against dummy addresses to find where everything
+/// goes, after which the ROM is compiled and the declarations and intrinsics
+/// are emitted for their final addresses.
+pub(crate) struct Native<'a> {
+    module:  &'a Module,
+    options: &'a Options,
+    start:   usize,
+    pass:    Option<Pass<'a>>,
+}
+
+/// The final pass, once laid out.
+struct Pass<'a> {
+    map:     MemoryMap,
+    rom:     Vec<u8>,
+    /// Where the layout pass put the code, which the final pass should not
+    /// change
+    layout:  code::Layout,
+    emitter: Box<dyn Emit + 'a>,
+}
+
+impl<'a> Native<'a> {
+    pub(crate) const fn new(module: &'a Module, options: &'a Options, start: usize) -> Self {
+        Self {
+            module,
+            options,
+            start,
+            pass: None,
+        }
+    }
+
+    fn pass(&mut self) -> &mut Pass<'a> {
+        self.pass.as_mut().expect("Emitting before the layout")
+    }
+}
+
+impl<'a> Backend for Native<'a> {
+    type Output = (Assembly, code::Layout);
+
+    fn module(&self) -> &Module {
+        self.module
+    }
+
+    fn options(&self) -> &Options {
+        self.options
+    }
+
+    fn layout(&mut self) -> Result<Vec<usize>> {
+        let (module, options) = (self.module, self.options);
+        // The ROM size is known up front, so code placed after it can be
+        // compiled for its final address.
+        let rom_size = rom::Layout::size(module, options);
+        let code_start = MemoryMap::new(options.segment_order, self.start, 0, rom_size).code_start;
+        let dummy_code_layout = code::Layout::dummy(module, code_start);
+        let dummy_rom_layout = rom::Layout::dummy(module, options);
+        let placement = Placement::new(
+            module,
+            code::find_entry(module, options)?,
+            rom::canonical(module, options),
+        );
+
+        // First pass with dummy layout
+        let compile = match options.arch {
+            Arch::X86_64 => code::compile::<X64>,
+            Arch::Aarch64 => code::compile::<A64>,
+        };
+        let (code, code_layout) = compile(
+            module,
+            options,
+            code_start,
+            &dummy_code_layout,
+            &dummy_rom_layout,
+            0,
+            &placement,
+            Phase::Layout,
+        )?;
+
+        // Compile final rom
+        let map = MemoryMap::new(options.segment_order, self.start, code.len(), rom_size);
+        assert_eq!(map.code_start, code_start);
+        info!("ROM start: {:08x}", map.rom_start);
+        let (rom, rom_layout) = {
+            let _timer = Timer::new("ROM".to_string());
+            timings::count("bytes", rom_size as u64);
+            rom::compile(module, options, &code_layout, map.rom_start)
+        };
+        assert_eq!(rom.len(), rom_size);
+
+        // Second pass compile, planned here and emitted after
+        info!("RAM start: {:08x}", map.ram_start);
+        let _timer = Timer::new(format!("{:?} pass", Phase::Code));
+        let emitter: Box<dyn Emit + 'a> = match options.arch {
+            Arch::X86_64 => {
+                Box::new(Emitter::<X64>::new(
+                    module,
+                    options,
+                    code_start,
+                    &code_layout,
+                    &rom_layout,
+                    map.ram_start,
+                    &placement,
+                    Phase::Code,
+                )?)
+            }
+            Arch::Aarch64 => {
+                Box::new(Emitter::<A64>::new(
+                    module,
+                    options,
+                    code_start,
+                    &code_layout,
+                    &rom_layout,
+                    map.ram_start,
+                    &placement,
+                    Phase::Code,
+                )?)
+            }
+        };
+        self.pass = Some(Pass {
+            map,
+            rom,
+            layout: code_layout,
+            emitter,
+        });
+        Ok(placement.order)
+    }
+
+    fn emit_declaration(&mut self, index: usize) {
+        self.pass().emitter.declaration(index);
+    }
+
+    fn emit_intrinsic(&mut self, index: usize) {
+        self.pass().emitter.intrinsic(index);
+    }
+
+    fn finalize(self) -> Result<Self::Output> {
+        let pass = self.pass.expect("Finalizing before the layout");
+        let (code, layout) = pass.emitter.finish()?;
+        timings::count("bytes", code.len() as u64);
+        // Layout should not change between passes
+        assert_eq!(layout, pass.layout);
+
+        let options = self.options;
+        let ram = allocator::initial_ram(pass.map.ram_start, self.module, options)?;
+        let assembly = Assembly {
+            arch: options.arch,
+            map: pass.map,
+            memory: options.memory,
+            startup: options.startup,
+            code,
+            rom: pass.rom,
+            ram,
+        };
+        Ok((assembly, layout))
+    }
+}
@@ -0,0 +1,179 @@
+use crate::machine::Transition;
+use dynasm::dynasm;
+use dynasmrt::{x64::Assembler, DynamicLabel, DynasmApi, DynasmLabelApi};
+use std::collections::BTreeMap;
+
+// Outlining: transition sequences that occur in several declarations are
+// emitted once as a shared stub and replaced by calls.
+//
+// The stack can not be used for the linkage, `rsp` is an ordinary register in
+// the Oluś calling convention. Instead the caller stores its return address in
+// a fixed RAM slot and jumps to the stub, which returns with an indirect jump
+// through the slot. Stubs do not call other stubs, so one slot suffices.
+
+/// Offset of the return address slot from the start of RAM.
+pub(crate) const RETURN_SLOT: usize = 8;
+
+/// Size of `mov QWORD [slot], return; jmp stub`.
+pub(crate) const CALL_SIZE: usize = 17;
+
+/// Size of `jmp QWORD [slot]`.
+const RETURN_SIZE: usize = 7;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Step {
+    Transition(Transition),
+    /// Call to the stub with the given index
+    Stub(usize),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub(crate) struct Outlined {
+    pub(crate) paths: Vec<Vec<Step>>,
+    pub(crate) stubs: Vec<Vec<Transition>>,
+}
+
+impl Outlined {
+    /// Paths without any outlining.
+    pub(crate) fn new(paths: &[Vec<Transition>]) -> Self {
+        Self {
+            paths: paths
+                .iter()
+                .map(|path| path.iter().copied().map(Step::Transition).collect())
+                .collect(),
+            stubs: Vec::new(),
+        }
+    }
+}
+
+/// Greedily outline the sequence that saves the most bytes until no sequence
+/// saves anything.
+///
+/// The choices only depend on which transitions are equal and on their sizes,
+/// which are the same in both compilation passes.
+pub(crate) fn outline(paths: &[Vec<Transition>]) -> Outlined {
+    let mut result = Outlined::new(paths);
+    while let Some((sequence, occurrences)) = best_candidate(&result.paths) {
+        let stub = result.stubs.len();
+        // Replace back to front so earlier offsets stay valid
+        for &(path, start) in occurrences.iter().rev() {
+            let range = start..start + sequence.len();
+            let _ = result.paths[path].splice(range, Some(Step::Stub(stub)));
+        }
+        result.stubs.push(sequence);
+    }
+    result
+}
+
+/// Find the sequence with the largest saving, with its non-overlapping
+/// occurrences in order.
+fn best_candidate(paths: &[Vec<Step>]) -> Option<(Vec<Transition>, Vec<(usize, usize)>)> {
+    // BTreeMap for deterministic tie-breaking
+    let mut candidates: BTreeMap<Vec<Transition>, Vec<(usize, usize)>> = BTreeMap::new();
+    for (index, path) in paths.iter().enumerate() {
+        for start in 0..path.len() {
+            let run = path[start..]
+                .iter()
+                .take_while(|step| matches!(step, Step::Transition(_)))
+                .map(|step| {
+                    match step {
+                        Step::Transition(transition) => *transition,
+                        Step::Stub(_) => unreachable!(),
+                    }
+                })
+                .collect::<Vec<_>>();
+            for length in 2..=run.len() {
+                let occurrences = candidates.entry(run[..length].to_vec()).or_default();
+                // Skip occurrences overlapping the previous one
+                match occurrences.last() {
+                    Some(&(last_index, last_start))
+                        if last_index == index && last_start + length > start => {}
+                    _ => occurrences.push((index, start)),
+                }
+            }
+        }
+    }
+    candidates
+        .into_iter()
+        .map(|(sequence, occurrences)| {
+            let size: usize = sequence.iter().map(Transition::size).sum();
+            let saved = occurrences.len() * size;
+            let cost = occurrences.len() * CALL_SIZE + size + RETURN_SIZE;
+            (saved.saturating_sub(cost), sequence, occurrences)
+        })
+        .filter(|(saving, ..)| *saving > 0)
+        .max_by_key(|(saving, ..)| *saving)
+        .map(|(_, sequence, occurrences)| (sequence, occurrences))
+}
+
+/// Emit a call to `stub`, `return_address` is the address right after it.
+pub(crate) fn assemble_call(
+    asm: &mut Assembler,
+    ram_start: usize,
+    stub: DynamicLabel,
+    return_address: usize,
+) {
+    assert!(return_address <= i32::max_value() as usize);
+    let start = asm.offset().0;
+    dynasm!(asm
+        ; mov QWORD [(ram_start + RETURN_SLOT) as i32], DWORD return_address as i32
+        ; jmp =>stub
+    );
+    assert_eq!(asm.offset().0 - start, CALL_SIZE);
+}
+
+/// Emit the return at the end of a stub, through the slot.
+pub(crate) fn assemble_return(asm: &mut Assembler, ram_start: usize) {
+    let start = asm.offset().0;
+    dynasm!(asm
+        ; jmp QWORD [(ram_start + RETURN_SLOT) as i32]
+    );
+    assert_eq!(asm.offset().0 - start, RETURN_SIZE);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::machine::Register;
+
+    fn set(dest: u8, value: u64) -> Transition {
+        Transition::Set {
+            dest: Register(dest),
+            value,
+        }
+    }
+
+    #[test]
+    fn test_outline_shared_sequence() {
+        // Four 10 byte instructions
+        let shared = vec![
+            set(1, 0x1234_5678_9abc),
+            set(2, 0x2345_6789_abcd),
+            set(3, 0x3456_789a_bcde),
+            set(5, 0x4567_89ab_cdef),
+        ];
+        let mut first = shared.clone();
+        first.push(set(0, 1));
+        let mut second = vec![set(0, 2)];
+        second.extend(shared.iter().copied());
+        let third = shared.clone();
+        let outlined = outline(&[first, second, third]);
+        assert_eq!(outlined.stubs, vec![shared]);
+        assert_eq!(outlined.paths, vec![
+            vec![Step::Stub(0), Step::Transition(set(0, 1))],
+            vec![Step::Transition(set(0, 2)), Step::Stub(0)],
+            vec![Step::Stub(0)],
+        ]);
+    }
+
+    #[test]
+    fn test_outline_not_profitable() {
+        let path = vec![set(1, 1), set(2, 2)];
+        let outlined = outline(&[path.clone(), path.clone()]);
+        assert!(outlined.stubs.is_empty());
+        assert_eq!(outlined.paths[0], vec![
+            Step::Transition(set(1, 1)),
+            Step::Transition(set(2, 2))
+        ]);
+    }
+}
@@ -0,0 +1,162 @@
+use log::{debug, info};
+use parser::mir::{Expression, Module};
+use std::collections::HashMap;
+
+// Code placement. Every declaration ends in a tail call. When the callee is a
+// known declaration placed directly after the caller, the jump can be left
+// out and the caller falls through into it. Declarations are linked into
+// chains of such calls and the chains laid out one after the other, starting
+// with the one containing `main` so the prelude can fall through too.
+
+/// Order to emit the declarations in.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct Placement {
+    /// For every declaration, the one whose code it shares, see
+    /// [`crate::rom::canonical`]
+    pub(crate) shared:      Vec<usize>,
+    /// Indices of the declarations with their own code, in emission order
+    pub(crate) order:       Vec<usize>,
+    /// For every declaration, whether it falls through into the next one
+    pub(crate) fallthrough: Vec<bool>,
+}
+
+/// The declaration with its own code a declaration with its own code tail
+/// calls, if known statically.
+fn callees(module: &Module, shared: &[usize]) -> Vec<Option<usize>> {
+    let declarations = module
+        .declarations
+        .iter()
+        .enumerate()
+        .map(|(index, decl)| (decl.procedure[0], shared[index]))
+        .collect::<HashMap<_, _>>();
+    module
+        .declarations
+        .iter()
+        .enumerate()
+        .map(|(index, decl)| {
+            match decl.call.first() {
+                Some(Expression::Symbol(symbol)) if shared[index] == index => {
+                    declarations.get(symbol).copied()
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+impl Placement {
+    /// Place callees after their callers. A callee with several callers
+    /// follows the first one in source order. `main` always comes first, so
+    /// the prelude falls through into it. Declarations sharing the code of
+    /// another one are left out.
+    pub(crate) fn new(module: &Module, main: usize, shared: Vec<usize>) -> Self {
+        let count = module.declarations.len();
+        let callees = callees(module, &shared);
+
+        // Link chains, `next` and `previous` are the neighbours within a chain
+        let mut next = vec![None; count];
+        let mut previous = vec![None; count];
+        let head = |previous: &[Option<usize>], mut index: usize| {
+            while let Some(before) = previous[index] {
+                index = before;
+            }
+            index
+        };
+        for (caller, callee) in callees.iter().enumerate() {
+            let callee = match *callee {
+                Some(callee) if callee != main => callee,
+                _ => continue,
+            };
+            if previous[callee].is_none() && head(&previous, caller) != callee {
+                next[caller] = Some(callee);
+                previous[callee] = Some(caller);
+            }
+        }
+
+        // Chains in order of their heads, the one starting with main first
+        let heads = std::iter::once(main).chain((0..count).filter(|&index| {
+            previous[index].is_none() && index != main && shared[index] == index
+        }));
+        let mut order = Vec::with_capacity(count);
+        for mut index in heads {
+            order.push(index);
+            while let Some(after) = next[index] {
+                order.push(after);
+                index = after;
+            }
+        }
+        assert_eq!(
+            order.len(),
+            (0..count).filter(|&index| shared[index] == index).count()
+        );
+        let fallthrough = next.iter().map(Option::is_some).collect::<Vec<_>>();
+
+        let static_calls = callees.iter().filter(|callee| callee.is_some()).count();
+        let fallthroughs = fallthrough.iter().filter(|&&fall| fall).count();
+        info!(
+            "Code placement: {} of {} statically known calls fall through",
+            fallthroughs, static_calls
+        );
+        debug!("Declaration order: {:?}", order);
+        Self {
+            shared,
+            order,
+            fallthrough,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{code::find_entry, Options};
+    use parser::parse_mir;
+
+    fn place(source: &str) -> Placement {
+        let module = parse_mir(source).unwrap();
+        let main = find_entry(&module, &Options::default()).unwrap();
+        let shared = (0..module.declarations.len()).collect();
+        Placement::new(&module, main, shared)
+    }
+
+    #[test]
+    fn test_chain() {
+        let placement = place("c ↦ exit 0\nb ↦ c\nmain ↦ a\na ↦ b\n");
+        assert_eq!(placement.order, vec![2, 3, 1, 0]);
+        assert_eq!(placement.fallthrough, vec![false, true, true, true]);
+    }
+
+    #[test]
+    fn test_shared_callee() {
+        // `c` follows its first caller
+        let placement = place("b ↦ c\nmain ↦ x\nc ↦ exit 0\nx ↦ c\n");
+        assert_eq!(placement.order, vec![1, 3, 0, 2]);
+        assert_eq!(placement.fallthrough, vec![true, true, false, false]);
+        // Procedures passed as arguments are not called statically
+        let placement = place("main ↦ c b\nb ↦ c λ0\nc f ↦ f\nλ0 ↦ exit 0\n");
+        assert_eq!(placement.order, vec![0, 2, 1, 3]);
+        assert_eq!(placement.fallthrough, vec![true, false, false, false]);
+    }
+
+    #[test]
+    fn test_cycle() {
+        let placement = place("main ↦ a\na ↦ b\nb ↦ a\n");
+        assert_eq!(placement.order, vec![0, 1, 2]);
+        assert_eq!(placement.fallthrough, vec![true, true, false]);
+        // A loop through main is broken before main
+        let placement = place("a ↦ main\nmain ↦ a\n");
+        assert_eq!(placement.order, vec![1, 0]);
+        assert_eq!(placement.fallthrough, vec![false, true]);
+    }
+
+    #[test]
+    fn test_shared() {
+        let module = parse_mir("main ↦ a\na ↦ b\nb ↦ exit 0\nc ↦ exit 0\n").unwrap();
+        let placement = Placement::new(&module, 0, vec![0, 1, 2, 2]);
+        assert_eq!(placement.order, vec![0, 1, 2]);
+        // Calls to a shared declaration go to its code
+        let placement = Placement::new(&module, 0, vec![0, 1, 3, 3]);
+        assert_eq!(placement.order, vec![0, 1, 3]);
+        assert_eq!(placement.fallthrough, vec![true, true, false, false]);
+    }
+}
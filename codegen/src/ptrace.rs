@@ -0,0 +1,259 @@
+#![allow(unsafe_code)]
+use crate::{
+    assemble,
+    intrinsics::Syscall,
+    jit::{enter, LOAD_ADDRESS},
+    Options, Os,
+};
+use parser::mir::Module;
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, Read},
+    os::unix::{io::FromRawFd, process::ExitStatusExt},
+    process::{ExitStatus, Output},
+    ptr, thread,
+};
+
+// Run code compiled for Darwin on Linux, for smoke tests in CI.
+//
+// A forked child maps the segments like `crate::jit` does, the parent
+// traces it and rewrites the numbers of the Darwin `read`, `write` and `exit`
+// system calls to their Linux equivalents. Both pass arguments in the same
+// registers. Other Darwin system calls fail with `ENOSYS`. Errors are
+// reported the Darwin way, with the carry flag set and a positive error
+// number in r0.
+
+/// Darwin's BSD system calls are in this class.
+const DARWIN_CLASS: u64 = 0x0200_0000;
+
+/// Darwin `read`, not used by the intrinsics yet.
+const DARWIN_READ: u64 = 0x0200_0003;
+const LINUX_READ: u64 = 0;
+
+/// Carry flag in rflags.
+const CARRY: u64 = 1;
+
+/// The Linux system call implementing Darwin system call `number`.
+fn translate(number: u64) -> Option<u64> {
+    let darwin = |syscall: Syscall| syscall.number(Os::Darwin) as u64;
+    let linux = |syscall: Syscall| syscall.number(Os::Linux) as u64;
+    match number {
+        n if n == darwin(Syscall::Exit) => Some(linux(Syscall::Exit)),
+        n if n == darwin(Syscall::Write) => Some(linux(Syscall::Write)),
+        DARWIN_READ => Some(LINUX_READ),
+        _ => None,
+    }
+}
+
+/// Compile `module` for Darwin and run it with translated system calls.
+/// Returns the exit status and everything written to stdout and stderr.
+pub fn run(module: &Module, options: &Options) -> Result<Output, Box<dyn Error>> {
+    assert_eq!(options.os, Os::Darwin);
+    let assembly = assemble(module, options, LOAD_ADDRESS)?;
+    let (stdout_read, stdout_write) = pipe()?;
+    let (stderr_read, stderr_write) = pipe()?;
+    let pid = unsafe { libc::fork() };
+    if pid == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    if pid == 0 {
+        // Only async-signal-safe calls until the generated code takes over
+        unsafe {
+            libc::dup2(stdout_write, 1);
+            libc::dup2(stderr_write, 2);
+            libc::ptrace(libc::PTRACE_TRACEME, 0, ptr::null_mut::<libc::c_void>(), 0);
+            libc::raise(libc::SIGSTOP);
+            let _ = enter(&assembly);
+            libc::_exit(127);
+        }
+    }
+    unsafe {
+        libc::close(stdout_write);
+        libc::close(stderr_write);
+    }
+    // Drain the pipes while tracing, so a full pipe can not stall the child
+    let stdout = drain(stdout_read);
+    let stderr = drain(stderr_read);
+    let status = trace(pid)?;
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap()?,
+        stderr: stderr.join().unwrap()?,
+    })
+}
+
+fn pipe() -> io::Result<(libc::c_int, libc::c_int)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == 0 {
+        Ok((fds[0], fds[1]))
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn drain(fd: libc::c_int) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    thread::spawn(move || {
+        let mut result = Vec::new();
+        file.read_to_end(&mut result)?;
+        Ok(result)
+    })
+}
+
+fn check(result: libc::c_long) -> io::Result<()> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn wait(pid: libc::pid_t) -> io::Result<libc::c_int> {
+    let mut status = 0;
+    if unsafe { libc::waitpid(pid, &mut status, 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(status)
+}
+
+/// Resume the stopped child until it exits, translating system calls.
+fn trace(pid: libc::pid_t) -> io::Result<ExitStatus> {
+    let null = ptr::null_mut::<libc::c_void>();
+    // The child stops itself before mapping the segments
+    let _ = wait(pid)?;
+    let options = libc::PTRACE_O_TRACESYSGOOD | libc::PTRACE_O_EXITKILL;
+    check(unsafe { libc::ptrace(libc::PTRACE_SETOPTIONS, pid, null, options) })?;
+    let mut entering = true;
+    let mut translated = false;
+    let mut signal = 0;
+    loop {
+        check(unsafe { libc::ptrace(libc::PTRACE_SYSCALL, pid, null, signal) })?;
+        let status = wait(pid)?;
+        if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+            return Ok(ExitStatus::from_raw(status));
+        }
+        signal = 0;
+        if libc::WSTOPSIG(status) != libc::SIGTRAP | 0x80 {
+            // Deliver signals other than system call stops
+            signal = libc::WSTOPSIG(status);
+            continue;
+        }
+        let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        let regs_ptr = (&mut regs as *mut libc::user_regs_struct).cast::<libc::c_void>();
+        check(unsafe { libc::ptrace(libc::PTRACE_GETREGS, pid, null, regs_ptr) })?;
+        if entering {
+            // System calls of the Linux host code mapping the segments pass
+            translated = regs.orig_rax & DARWIN_CLASS != 0;
+            if translated {
+                regs.orig_rax = translate(regs.orig_rax).unwrap_or(u64::max_value());
+            }
+        } else if translated {
+            let result = regs.rax as i64;
+            if (-4095..0).contains(&result) {
+                regs.rax = (-result) as u64;
+                regs.eflags |= CARRY;
+            } else {
+                regs.eflags &= !CARRY;
+            }
+        }
+        if translated {
+            check(unsafe { libc::ptrace(libc::PTRACE_SETREGS, pid, null, regs_ptr) })?;
+        }
+        entering = !entering;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{runtime::PANIC_EXIT_CODE, Instrument, Memory};
+    use parser::{parse_mir, parse_str};
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(translate(0x0200_0001), Some(231));
+        assert_eq!(translate(0x0200_0004), Some(1));
+        assert_eq!(translate(0x0200_0003), Some(0));
+        assert_eq!(translate(0x0200_0005), None);
+    }
+
+    #[test]
+    fn test_run() {
+        let module = parse_str("main ↦\n    print “Hi” (↦ exit 3)\n").unwrap();
+        let output = run(&module, &Options::default()).unwrap();
+        assert_eq!(output.stdout, b"Hi");
+        assert_eq!(output.status.code(), Some(3));
+    }
+
+    #[test]
+    fn test_not_closure() {
+        let module = parse_mir("main ↦ 3\n").unwrap();
+        let options = Options {
+            debug_runtime: true,
+            ..Options::default()
+        };
+        let output = run(&module, &options).unwrap();
+        assert_eq!(output.stderr, b"Calling a value that is not a closure\n");
+        assert_eq!(output.status.code(), Some(i32::from(PANIC_EXIT_CODE)));
+    }
+
+    #[test]
+    fn test_trace() {
+        // The arguments survive the trace routine
+        let module = parse_str("f a b ↦ sub a b exit\nmain ↦ f 7 4\n").unwrap();
+        let options = Options {
+            instrument: Instrument::Trace,
+            ..Options::default()
+        };
+        let output = run(&module, &options).unwrap();
+        assert_eq!(output.stderr, b"main\nf\n");
+        assert_eq!(output.status.code(), Some(3));
+    }
+
+    #[test]
+    fn test_counts() {
+        let source = "count n ↦ isZero n (↦ exit 5) (↦ sub n 1 count)\nmain ↦ count 3\n";
+        let module = parse_str(source).unwrap();
+        let options = Options {
+            instrument: Instrument::Counts,
+            ..Options::default()
+        };
+        let output = run(&module, &options).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let lines = stderr.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), module.declarations.len());
+        assert!(lines.contains(&"count 4"));
+        assert!(lines.contains(&"main 1"));
+        assert_eq!(output.status.code(), Some(5));
+    }
+
+    #[test]
+    fn test_panic() {
+        let module = parse_str("main ↦\n    print “a” (↦ panic “boom”)\n").unwrap();
+        let output = run(&module, &Options::default()).unwrap();
+        assert_eq!(output.stdout, b"a");
+        assert_eq!(output.stderr, b"boom");
+        assert_eq!(output.status.code(), Some(i32::from(PANIC_EXIT_CODE)));
+        let module = parse_str("main ↦ abort\n").unwrap();
+        let output = run(&module, &Options::default()).unwrap();
+        assert_eq!(output.stderr, b"");
+        assert_eq!(output.status.code(), Some(i32::from(PANIC_EXIT_CODE)));
+    }
+
+    #[test]
+    fn test_heap_overflow() {
+        // Every call allocates a closure capturing the previous one
+        let module = parse_mir("main ↦ f 1\nf x ↦ f λ0\nλ0 ↦ exit x\n").unwrap();
+        let options = Options {
+            memory: Memory {
+                ram_pages: 1,
+                ..Memory::default()
+            },
+            ..Options::default()
+        };
+        let output = run(&module, &options).unwrap();
+        assert_eq!(output.stderr, b"Out of memory\n");
+        assert_eq!(output.status.code(), Some(i32::from(PANIC_EXIT_CODE)));
+    }
+}
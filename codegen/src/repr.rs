@@ -0,0 +1,135 @@
+use crate::Options;
+use parser::mir::NUMBER_MAX;
+
+// Run-time representation of values. A value is a 64 bit word whose low bits
+// tell its type:
+//
+// * `…1` a number `n` is stored as `2 n + 1`, see `number`. This leaves 63
+//   bits, arithmetic is modulo 2^63.
+// * `00` the address of a closure or tuple record, so calling `r0` is a plain
+//   `jmp QWORD [r0]`. Nothing tells them apart, calling a tuple is undefined.
+// * `10` the address of a string record plus `STRING_TAG`. The tag folds into
+//   the displacement of the loads.
+//
+// Records are:
+//
+// * Closures are a code pointer followed, in debug runtime mode, by an info
+//   word, see `closure_info`, and then the captured values in the order of
+//   `Declaration::closure`. Calling a closure jumps to its code pointer with
+//   the address of the record in r0. Constant closures live in ROM, closures
+//   with captures are allocated in RAM.
+// * Strings are a little endian 32 bit length in bytes followed by the UTF-8
+//   encoded bytes, without terminator. They are padded so the next record is
+//   aligned again. Literals live in ROM, `charAt` and `substring` allocate
+//   theirs in RAM.
+// * Tuples are their length, as a number value, followed by the elements.
+//   They are allocated in RAM by `newTuple` and changed in place.
+// * Argument blocks are the arguments of a call that do not fit in registers,
+//   see `abi`. They are allocated in RAM by the caller.
+//
+// All records start on a `STRING_ALIGN` boundary, provided the segment they
+// are in does, which leaves the low two bits of their address for the tag.
+
+/// Mask of the tag bits of a value.
+#[cfg(any(test, feature = "native"))]
+pub(crate) const TAG_MASK: u64 = 3;
+
+/// Tag of a string value, added to the address of the record.
+pub(crate) const STRING_TAG: i32 = 2;
+
+/// The value of the number `n`.
+pub(crate) fn number(n: u64) -> u64 {
+    assert!(n <= NUMBER_MAX);
+    (n << 1) | 1
+}
+
+/// The value of the string record at `address`.
+pub(crate) fn string(address: usize) -> u64 {
+    assert_eq!(address % STRING_ALIGN, 0);
+    address as u64 + STRING_TAG as u64
+}
+
+/// Offset of the info word in a closure record (debug runtime only).
+#[cfg(feature = "native")]
+pub(crate) const CLOSURE_INFO: i32 = 8;
+
+/// Size in bytes of the fixed part of a closure record, before the captures.
+/// Constant closures consist of just this.
+pub(crate) const fn closure_size(options: &Options) -> usize {
+    if options.debug_runtime {
+        16
+    } else {
+        8
+    }
+}
+
+/// The info word of a closure record in debug runtime mode: the arity in the
+/// low byte and the ROM address of the procedure name above it.
+pub(crate) fn closure_info(arity: usize, name: usize) -> u64 {
+    assert!(arity <= u8::max_value() as usize);
+    assert!(name <= (u32::max_value() as usize));
+    (arity as u64) | ((name as u64) << 8)
+}
+
+/// Offset of the length in a tuple record.
+pub(crate) const TUPLE_LENGTH: i32 = 0;
+
+/// Offset of the first element in a tuple record.
+pub(crate) const TUPLE_ELEMENTS: i32 = 8;
+
+/// Offset of the length in a string record.
+pub(crate) const STRING_LENGTH: i32 = 0;
+
+/// Offset of the first byte in a string record.
+pub(crate) const STRING_BYTES: i32 = 4;
+
+/// Alignment of string records, so the length can be loaded aligned.
+pub(crate) const STRING_ALIGN: usize = 4;
+
+/// Alignment of heap allocations, so closure records allocated after a string
+/// stay aligned.
+pub(crate) const HEAP_ALIGN: usize = 8;
+
+/// Size in bytes of the record of a string of `length` bytes, including
+/// padding.
+pub(crate) const fn string_size(length: usize) -> usize {
+    (STRING_BYTES as usize + length + STRING_ALIGN - 1) / STRING_ALIGN * STRING_ALIGN
+}
+
+/// The record of `string`.
+pub(crate) fn string_record(string: &str) -> Vec<u8> {
+    assert!(string.len() <= u32::max_value() as usize);
+    let mut record = Vec::with_capacity(string_size(string.len()));
+    record.extend_from_slice(&(string.len() as u32).to_le_bytes());
+    record.extend_from_slice(string.as_bytes());
+    record.resize(string_size(string.len()), 0);
+    record
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tags() {
+        assert_eq!(number(0), 1);
+        assert_eq!(number(3), 7);
+        assert_eq!(number(NUMBER_MAX), u64::max_value());
+        assert_eq!(string(0x1000) & TAG_MASK, STRING_TAG as u64);
+        assert_eq!(number(5) & 1, 1);
+    }
+
+    #[test]
+    fn test_string_size() {
+        assert_eq!(string_size(0), 4);
+        assert_eq!(string_size(1), 8);
+        assert_eq!(string_size(4), 8);
+        assert_eq!(string_size(5), 12);
+    }
+
+    #[test]
+    fn test_string_record() {
+        assert_eq!(string_record("Hi"), b"\x02\0\0\0Hi\0\0");
+        assert_eq!(string_record(""), b"\0\0\0\0");
+    }
+}
@@ -1,65 +1,317 @@
-use crate::code;
-use dynasm::dynasm;
-use dynasmrt::DynasmApi;
-use parser::mir::Module;
+use crate::{
+    code, intrinsics,
+    repr::{closure_info, closure_size, string_record, string_size},
+    runtime::{self, Message},
+    Options,
+};
+use parser::mir::{Expression, Module};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug, Default)]
 pub(crate) struct Layout {
     pub(crate) closures: Vec<usize>,
     pub(crate) imports:  Vec<usize>,
     pub(crate) strings:  Vec<usize>,
+    /// Procedure names for declarations then imports (debug runtime and
+    /// instrumentation only)
+    pub(crate) names:    Vec<usize>,
+    /// Table of the code start, code end and name of each declaration, in
+    /// quad words, right after the closure records (debug runtime and
+    /// instrumentation only)
+    pub(crate) ranges:   Option<usize>,
+    /// Runtime messages indexed by `Message` (only with run-time checks)
+    pub(crate) messages: Vec<usize>,
+    /// Compiler version string (only if `compilerVersion` is imported)
+    pub(crate) version:  Option<usize>,
+    /// Empty string terminated list of builtin names (only if `hasBuiltin`
+    /// is imported)
+    pub(crate) builtins: Option<usize>,
+    /// First address after the ROM
+    pub(crate) end:      usize,
 }
 
+#[cfg(feature = "native")]
 impl Layout {
-    pub(crate) fn dummy(module: &Module) -> Layout {
+    pub(crate) fn dummy(module: &Module, options: &Options) -> Layout {
         const DUMMY_ROM_START: usize = 1 << 20; // ~ 1MiB of code
-        layout(module, DUMMY_ROM_START)
+        layout(module, options, DUMMY_ROM_START)
     }
+
+    /// Size in bytes of the ROM for `module`, which does not depend on where
+    /// it is placed.
+    pub(crate) fn size(module: &Module, options: &Options) -> usize {
+        layout(module, options, 0).end
+    }
+}
+
+pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+fn imports(module: &Module, name: &str) -> bool {
+    module.imports.iter().any(|import| import == name)
+}
+
+/// Names of declarations followed by the names of imports.
+fn names(module: &Module) -> impl Iterator<Item = String> + '_ {
+    let declarations = module.declarations.iter().map(move |decl| {
+        let symbol = decl.procedure[0];
+        let name = &module.symbols[symbol];
+        if name.is_empty() {
+            format!("λ{}", symbol)
+        } else {
+            name.clone()
+        }
+    });
+    declarations.chain(module.imports.iter().cloned())
+}
+
+/// For every declaration, the declaration whose code and closure record it
+/// shares. Declarations with the same captures that only differ in the names
+/// of their parameters compile to the same code, and closures of them with the
+/// same captured values are the same closure. Closure records carry the name
+/// in debug runtime mode and instrumented code is per declaration, so nothing
+/// is shared then. The entry point is never shared.
+pub(crate) fn canonical(module: &Module, options: &Options) -> Vec<usize> {
+    #[derive(PartialEq, Eq, Hash)]
+    enum Key {
+        Parameter(usize),
+        Expression(Expression),
+    }
+    let mut seen = HashMap::new();
+    let mut result = (0..module.declarations.len()).collect::<Vec<_>>();
+    if runtime::has_declaration_table(options) {
+        return result;
+    }
+    let entry = code::find_entry(module, options).ok();
+    for (index, decl) in module.declarations.iter().enumerate() {
+        if Some(index) == entry {
+            continue;
+        }
+        let call = decl
+            .call
+            .iter()
+            .map(|expr| {
+                match *expr {
+                    Expression::Symbol(s) => {
+                        decl.procedure[1..]
+                            .iter()
+                            .position(|p| *p == s)
+                            .map_or(Key::Expression(expr.clone()), Key::Parameter)
+                    }
+                    _ => Key::Expression(expr.clone()),
+                }
+            })
+            .collect::<Vec<_>>();
+        // A declaration referring to itself is not the same as another one
+        // referring to itself.
+        if call.contains(&Key::Expression(Expression::Symbol(decl.procedure[0]))) {
+            continue;
+        }
+        let key = (decl.procedure.len(), decl.closure.clone(), call);
+        result[index] = *seen.entry(key).or_insert(index);
+    }
+    result
+}
+
+/// String records, each distinct string stored once.
+#[derive(Default)]
+struct Strings {
+    offsets: HashMap<String, usize>,
+    entries: Vec<String>,
+    offset:  usize,
+}
+
+impl Strings {
+    fn intern(&mut self, string: &str) -> usize {
+        if let Some(offset) = self.offsets.get(string) {
+            return *offset;
+        }
+        let offset = self.offset;
+        self.offsets.insert(string.to_string(), offset);
+        self.entries.push(string.to_string());
+        self.offset += string_size(string.len());
+        offset
+    }
+}
+
+#[cfg(any(test, feature = "native"))]
+pub(crate) fn layout(module: &Module, options: &Options, rom_start: usize) -> Layout {
+    layout_with_strings(module, options, rom_start).0
 }
 
-pub(crate) fn layout(module: &Module, rom_start: usize) -> Layout {
+/// The layout and the strings in the order they are stored.
+fn layout_with_strings(
+    module: &Module,
+    options: &Options,
+    rom_start: usize,
+) -> (Layout, Vec<String>) {
     let mut result = Layout::default();
     let mut offset = rom_start;
-    for _decl in &module.declarations {
-        result.closures.push(offset);
-        offset += 8;
+    let canonical = canonical(module, options);
+    for (index, &shared) in canonical.iter().enumerate() {
+        if shared == index {
+            result.closures.push(offset);
+            offset += closure_size(options);
+        } else {
+            result.closures.push(result.closures[shared]);
+        }
     }
     for _import in &module.imports {
         result.imports.push(offset);
-        offset += 8;
+        offset += closure_size(options);
+    }
+    if runtime::has_declaration_table(options) {
+        result.ranges = Some(offset);
+        offset += 24 * module.declarations.len();
+    }
+    // The builtin list has to be contiguous, so it goes first and the other
+    // strings can refer into it.
+    let mut strings = Strings {
+        offset,
+        ..Strings::default()
+    };
+    if imports(module, "hasBuiltin") {
+        result.builtins = Some(offset);
+        for name in &intrinsics::NAMES {
+            let _ = strings.intern(name);
+        }
+        let _ = strings.intern("");
     }
     for string in &module.strings {
-        result.strings.push(offset);
-        offset += 4 + string.len();
+        result.strings.push(strings.intern(string));
     }
-    result
+    if imports(module, "compilerVersion") {
+        result.version = Some(strings.intern(VERSION));
+    }
+    if runtime::has_declaration_table(options) {
+        for name in names(module) {
+            result.names.push(strings.intern(&name));
+        }
+    }
+    if runtime::is_needed(options) {
+        for message in &Message::ALL {
+            result.messages.push(strings.intern(message.text()));
+        }
+    }
+    result.end = strings.offset;
+    (result, strings.entries)
 }
 
 pub(crate) fn compile(
     module: &Module,
+    options: &Options,
     code_layout: &code::Layout,
     rom_start: usize,
 ) -> (Vec<u8>, Layout) {
     assert_eq!(module.declarations.len(), code_layout.declarations.len());
     assert_eq!(module.imports.len(), code_layout.imports.len());
-    let mut rom = dynasmrt::x64::Assembler::new().unwrap();
-    for offset in &code_layout.declarations {
-        dynasm!(rom
-            ; .qword *offset as i64
-        );
+    let (layout, strings) = layout_with_strings(module, options, rom_start);
+    let canonical = canonical(module, options);
+    let arities = module
+        .declarations
+        .iter()
+        .map(|decl| decl.procedure.len() - 1)
+        .chain(module.imports.iter().map(|name| intrinsics::arity(name)));
+    let offsets = code_layout
+        .declarations
+        .iter()
+        .chain(code_layout.imports.iter());
+    let mut rom = Vec::with_capacity(layout.end - rom_start);
+    let mut qword = |value: u64| rom.extend_from_slice(&value.to_le_bytes());
+    for (index, (offset, arity)) in offsets.zip(arities).enumerate() {
+        if canonical.get(index).map_or(false, |&shared| shared != index) {
+            continue;
+        }
+        qword(*offset as u64);
+        if options.debug_runtime {
+            qword(closure_info(arity, layout.names[index]));
+        }
     }
-    for offset in &code_layout.imports {
-        dynasm!(rom
-            ; .qword *offset as i64
-        );
+    if layout.ranges.is_some() {
+        let ends = &code_layout.declaration_ends;
+        for (index, (start, end)) in code_layout.declarations.iter().zip(ends).enumerate() {
+            qword(*start as u64);
+            qword(*end as u64);
+            qword(layout.names[index] as u64);
+        }
     }
-    for string in &module.strings {
-        dynasm!(rom
-            ; .dword string.len() as i32
-            ; .bytes string.bytes()
-        );
+    for string in &strings {
+        rom.extend(string_record(string));
+    }
+    assert_eq!(rom_start + rom.len(), layout.end);
+    (rom, layout)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parser::parse_mir;
+
+    #[test]
+    fn test_canonical() {
+        let module = parse_mir(
+            "main ↦ f λ0 λ1 λ2 λ3\nλ0 a ↦ exit a\nλ1 b ↦ exit b\nλ2 c ↦ exit 0\nλ3 ↦ λ3\n",
+        )
+        .unwrap();
+        assert_eq!(canonical(&module, &Options::default()), vec![0, 1, 1, 3, 4]);
+        let options = Options {
+            debug_runtime: true,
+            ..Options::default()
+        };
+        assert_eq!(canonical(&module, &options), vec![0, 1, 2, 3, 4]);
+
+        // Closures are shared only when they capture the same symbols
+        let module = parse_mir(
+            "main x y ↦ f λ0 λ1 λ2\nλ0 a ↦ exit x\nλ1 b ↦ exit x\nλ2 c ↦ exit y\n",
+        )
+        .unwrap();
+        assert_eq!(canonical(&module, &Options::default()), vec![0, 1, 1, 3]);
+    }
+
+    #[test]
+    fn test_shared_closures() {
+        let module = parse_mir("main ↦ f λ0 λ1\nλ0 ↦ exit 0\nλ1 ↦ exit 0\n").unwrap();
+        let layout = layout(&module, &Options::default(), 0);
+        assert_eq!(layout.closures, vec![0, 8, 8]);
+        assert_eq!(layout.imports, vec![16, 24]);
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
+    fn test_ranges() {
+        use crate::{compile, Target};
+        use std::convert::TryInto;
+        let module = parse_mir("main ↦ λ0 3\nλ0 a ↦ exit a\n").unwrap();
+        let options = Options {
+            debug_runtime: true,
+            ..Options::default()
+        };
+        let compiled = compile(&module, &options, Target::X86_64UnknownLinuxGnu).unwrap();
+        let rom_start = compiled.assembly.map.rom_start;
+        let layout = layout(&module, &options, rom_start);
+        // After the closure records of the two declarations and `exit`
+        let ranges = layout.ranges.unwrap();
+        assert_eq!(ranges, rom_start + 3 * closure_size(&options));
+        let rom = &compiled.assembly.rom[ranges - rom_start..];
+        let word = |index: usize| {
+            let bytes = rom[8 * index..8 * index + 8].try_into().unwrap();
+            u64::from_le_bytes(bytes) as usize
+        };
+        for index in 0..2 {
+            assert_eq!(word(3 * index), compiled.layout.declarations[index]);
+            assert_eq!(word(3 * index + 1), compiled.layout.declaration_ends[index]);
+            assert_eq!(word(3 * index + 2), layout.names[index]);
+        }
+        assert_eq!(Layout::dummy(&module, &Options::default()).ranges, None);
+    }
+
+    #[test]
+    fn test_interned_strings() {
+        let module = parse_mir("main ↦ hasBuiltin “exit” λ0 λ0\nλ0 ↦ exit 0\n").unwrap();
+        let layout = layout(&module, &Options::default(), 0);
+        let builtins = layout.builtins.unwrap();
+        // The string is the first entry of the builtin list
+        assert_eq!(intrinsics::NAMES[0], "exit");
+        assert_eq!(layout.strings, vec![builtins]);
     }
-    let rom = rom.finalize().expect("Finalize after commit.");
-    (rom.to_vec(), layout(module, rom_start))
 }
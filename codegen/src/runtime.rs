@@ -0,0 +1,86 @@
+use crate::{Instrument, Options};
+#[cfg(feature = "native")]
+use parser::mir::Module;
+
+// Run-time support routines. These are only emitted when run-time checks or
+// instrumentation are enabled and are reached through global labels, so their
+// address does not need to be part of the code layout. They are in assembly
+// for each instruction set, see `x64::runtime` and `aarch64::runtime`.
+
+/// Exit code used when the runtime aborts, same as a Rust panic.
+pub(crate) const PANIC_EXIT_CODE: u8 = 101;
+
+/// Offset from the start of RAM of the slot that holds the index of the
+/// running declaration with the debug runtime, so a core dump or debugger
+/// shows where the program was. Look the index up in the ROM table of code
+/// ranges, see `rom::Layout::ranges`.
+#[cfg(feature = "native")]
+pub(crate) const DECLARATION_SLOT: usize = 16;
+
+/// Number of registers the trace routine saves, those its system calls
+/// clobber. They are different ones on AArch64, but as many.
+#[cfg(feature = "native")]
+pub(crate) const TRACE_SAVED: usize = 6;
+
+/// Bytes after the counters to format a line of their dump in: a string
+/// record of a space, at most 20 digits and a newline.
+#[cfg(feature = "native")]
+pub(crate) const COUNTS_BUFFER: usize = 32;
+
+/// Messages the runtime support routines print, stored as ROM strings.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub(crate) enum Message {
+    ArityMismatch = 0,
+    Newline       = 1,
+    NotClosure    = 2,
+    HeapOverflow  = 3,
+}
+
+impl Message {
+    pub(crate) const ALL: [Message; 4] = [
+        Message::ArityMismatch,
+        Message::Newline,
+        Message::NotClosure,
+        Message::HeapOverflow,
+    ];
+
+    pub(crate) const fn text(self) -> &'static str {
+        match self {
+            Message::ArityMismatch => "Arity mismatch calling ",
+            Message::Newline => "\n",
+            Message::NotClosure => "Calling a value that is not a closure\n",
+            Message::HeapOverflow => "Out of memory\n",
+        }
+    }
+}
+
+/// Whether any run-time checks are emitted, and with them the support
+/// routines and their messages.
+pub(crate) const fn is_needed(options: &Options) -> bool {
+    options.debug_runtime
+        || options.memory.heap_checks
+        || !matches!(options.instrument, Instrument::None)
+}
+
+/// Whether declarations store their index in the declaration slot.
+#[cfg(feature = "native")]
+pub(crate) const fn records_declaration(options: &Options) -> bool {
+    options.debug_runtime || matches!(options.instrument, Instrument::Trace)
+}
+
+/// Whether the ROM has the table of code ranges and names of declarations.
+/// Declarations then each have code and a closure record of their own.
+pub(crate) const fn has_declaration_table(options: &Options) -> bool {
+    options.debug_runtime || !matches!(options.instrument, Instrument::None)
+}
+
+/// Bytes of RAM the instrumentation keeps its data in, at the heap offset
+/// before the first allocation.
+#[cfg(feature = "native")]
+pub(crate) fn instrumentation_size(module: &Module, options: &Options) -> usize {
+    match options.instrument {
+        Instrument::None => 0,
+        Instrument::Trace => 8 * TRACE_SAVED,
+        Instrument::Counts => 8 * module.declarations.len() + COUNTS_BUFFER,
+    }
+}
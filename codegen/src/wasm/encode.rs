@@ -0,0 +1,361 @@
+// Binary encoding of WebAssembly modules, just what the backend uses.
+// See <https://webassembly.github.io/spec/core/binary/index.html>
+
+/// Value types.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum ValType {
+    I32 = 0x7f,
+    I64 = 0x7e,
+}
+
+/// Instructions, encoded by `Instruction::encode`. Memory accesses take the
+/// static offset, the alignment is always the natural one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Instruction {
+    // Control
+    Block,
+    Loop,
+    If,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Return,
+    Call(u32),
+    CallIndirect(u32),
+    Select,
+
+    // Variables
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+
+    // Memory
+    I32Load(u32),
+    I64Load(u32),
+    I32Load8U(u32),
+    I32Store(u32),
+    I64Store(u32),
+    I32Store8(u32),
+
+    // Numeric
+    I32Const(i32),
+    I64Const(i64),
+    I32Eqz,
+    I32Ne,
+    I32GtU,
+    I32Add,
+    I32Sub,
+    I32And,
+    I64Eq,
+    I64Ne,
+    I64LtS,
+    I64LtU,
+    I64Add,
+    I64Sub,
+    I64Mul,
+    I64DivU,
+    I64RemU,
+    I64Or,
+    I64Shl,
+    I64ShrU,
+    I32WrapI64,
+    I64ExtendI32U,
+}
+
+impl Instruction {
+    pub(crate) fn encode(self, sink: &mut Vec<u8>) {
+        use Instruction::*;
+        let memarg = |sink: &mut Vec<u8>, opcode: u8, align: u32, offset: u32| {
+            sink.push(opcode);
+            unsigned(sink, align.into());
+            unsigned(sink, offset.into());
+        };
+        match self {
+            Block => sink.extend(&[0x02, 0x40]),
+            Loop => sink.extend(&[0x03, 0x40]),
+            If => sink.extend(&[0x04, 0x40]),
+            End => sink.push(0x0b),
+            Br(depth) => {
+                sink.push(0x0c);
+                unsigned(sink, depth.into());
+            }
+            BrIf(depth) => {
+                sink.push(0x0d);
+                unsigned(sink, depth.into());
+            }
+            Return => sink.push(0x0f),
+            Call(function) => {
+                sink.push(0x10);
+                unsigned(sink, function.into());
+            }
+            CallIndirect(ty) => {
+                sink.push(0x11);
+                unsigned(sink, ty.into());
+                sink.push(0x00); // Table
+            }
+            Select => sink.push(0x1b),
+            LocalGet(index) => {
+                sink.push(0x20);
+                unsigned(sink, index.into());
+            }
+            LocalSet(index) => {
+                sink.push(0x21);
+                unsigned(sink, index.into());
+            }
+            LocalTee(index) => {
+                sink.push(0x22);
+                unsigned(sink, index.into());
+            }
+            GlobalGet(index) => {
+                sink.push(0x23);
+                unsigned(sink, index.into());
+            }
+            GlobalSet(index) => {
+                sink.push(0x24);
+                unsigned(sink, index.into());
+            }
+            I32Load(offset) => memarg(sink, 0x28, 2, offset),
+            I64Load(offset) => memarg(sink, 0x29, 3, offset),
+            I32Load8U(offset) => memarg(sink, 0x2d, 0, offset),
+            I32Store(offset) => memarg(sink, 0x36, 2, offset),
+            I64Store(offset) => memarg(sink, 0x37, 3, offset),
+            I32Store8(offset) => memarg(sink, 0x3a, 0, offset),
+            I32Const(value) => {
+                sink.push(0x41);
+                signed(sink, value.into());
+            }
+            I64Const(value) => {
+                sink.push(0x42);
+                signed(sink, value);
+            }
+            I32Eqz => sink.push(0x45),
+            I32Ne => sink.push(0x47),
+            I32GtU => sink.push(0x4b),
+            I32Add => sink.push(0x6a),
+            I32Sub => sink.push(0x6b),
+            I32And => sink.push(0x71),
+            I64Eq => sink.push(0x51),
+            I64Ne => sink.push(0x52),
+            I64LtS => sink.push(0x53),
+            I64LtU => sink.push(0x54),
+            I64Add => sink.push(0x7c),
+            I64Sub => sink.push(0x7d),
+            I64Mul => sink.push(0x7e),
+            I64DivU => sink.push(0x80),
+            I64RemU => sink.push(0x82),
+            I64Or => sink.push(0x84),
+            I64Shl => sink.push(0x86),
+            I64ShrU => sink.push(0x88),
+            I32WrapI64 => sink.push(0xa7),
+            I64ExtendI32U => sink.push(0xad),
+        }
+    }
+}
+
+/// Unsigned LEB128.
+pub(crate) fn unsigned(sink: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            sink.push(byte);
+            return;
+        }
+        sink.push(byte | 0x80);
+    }
+}
+
+/// Signed LEB128.
+pub(crate) fn signed(sink: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            sink.push(byte);
+            return;
+        }
+        sink.push(byte | 0x80);
+    }
+}
+
+fn name(sink: &mut Vec<u8>, name: &str) {
+    unsigned(sink, name.len() as u64);
+    sink.extend(name.as_bytes());
+}
+
+/// A function body: its locals beyond the parameters and the instructions.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub(crate) struct Function {
+    pub(crate) locals: Vec<ValType>,
+    pub(crate) body:   Vec<Instruction>,
+}
+
+impl Function {
+    /// Add a local, returning its index given the number of parameters.
+    pub(crate) fn local(&mut self, params: usize, ty: ValType) -> u32 {
+        self.locals.push(ty);
+        (params + self.locals.len() - 1) as u32
+    }
+
+    pub(crate) fn push(&mut self, instruction: Instruction) {
+        self.body.push(instruction);
+    }
+
+    fn encode(&self, sink: &mut Vec<u8>) {
+        let mut bytes = Vec::new();
+        unsigned(&mut bytes, self.locals.len() as u64);
+        for &ty in &self.locals {
+            bytes.extend(&[1, ty as u8]);
+        }
+        for instruction in &self.body {
+            instruction.encode(&mut bytes);
+        }
+        Instruction::End.encode(&mut bytes);
+        unsigned(sink, bytes.len() as u64);
+        sink.extend(bytes);
+    }
+}
+
+/// A module with one table, one memory and function imports.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub(crate) struct Module {
+    /// Function types as parameters and results.
+    pub(crate) types:     Vec<(Vec<ValType>, Vec<ValType>)>,
+    /// Module, name and type of imported functions. These come first in the
+    /// function index space.
+    pub(crate) imports:   Vec<(&'static str, &'static str, u32)>,
+    /// Type and body of the defined functions.
+    pub(crate) functions: Vec<(u32, Function)>,
+    /// Function indices in the table, from table index zero.
+    pub(crate) table:     Vec<u32>,
+    /// Minimum size in 64 KiB pages.
+    pub(crate) memory:    u32,
+    /// Type and initial value of the mutable globals.
+    pub(crate) globals:   Vec<(ValType, i64)>,
+    /// Exported functions by name. The memory is exported as `memory`.
+    pub(crate) exports:   Vec<(&'static str, u32)>,
+    /// Initial memory contents and their address.
+    pub(crate) data:      (u32, Vec<u8>),
+}
+
+impl Module {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut result = b"\0asm\x01\0\0\0".to_vec();
+        let mut section = |id: u8, count: usize, contents: &dyn Fn(&mut Vec<u8>)| {
+            let mut bytes = Vec::new();
+            unsigned(&mut bytes, count as u64);
+            contents(&mut bytes);
+            result.push(id);
+            unsigned(&mut result, bytes.len() as u64);
+            result.extend(bytes);
+        };
+        let vector = |sink: &mut Vec<u8>, types: &[ValType]| {
+            unsigned(sink, types.len() as u64);
+            sink.extend(types.iter().map(|&ty| ty as u8));
+        };
+        section(1, self.types.len(), &|sink| {
+            for (params, results) in &self.types {
+                sink.push(0x60);
+                vector(sink, params);
+                vector(sink, results);
+            }
+        });
+        section(2, self.imports.len(), &|sink| {
+            for &(module, field, ty) in &self.imports {
+                name(sink, module);
+                name(sink, field);
+                sink.push(0x00);
+                unsigned(sink, ty.into());
+            }
+        });
+        section(3, self.functions.len(), &|sink| {
+            for &(ty, _) in &self.functions {
+                unsigned(sink, ty.into());
+            }
+        });
+        section(4, 1, &|sink| {
+            sink.extend(&[0x70, 0x00]);
+            unsigned(sink, self.table.len() as u64);
+        });
+        section(5, 1, &|sink| {
+            sink.push(0x00);
+            unsigned(sink, self.memory.into());
+        });
+        section(6, self.globals.len(), &|sink| {
+            for &(ty, value) in &self.globals {
+                sink.extend(&[ty as u8, 0x01]);
+                match ty {
+                    ValType::I32 => Instruction::I32Const(value as i32).encode(sink),
+                    ValType::I64 => Instruction::I64Const(value).encode(sink),
+                }
+                Instruction::End.encode(sink);
+            }
+        });
+        section(7, self.exports.len() + 1, &|sink| {
+            name(sink, "memory");
+            sink.extend(&[0x02, 0x00]);
+            for &(export, function) in &self.exports {
+                name(sink, export);
+                sink.push(0x00);
+                unsigned(sink, function.into());
+            }
+        });
+        section(9, 1, &|sink| {
+            sink.push(0x00);
+            Instruction::I32Const(0).encode(sink);
+            Instruction::End.encode(sink);
+            unsigned(sink, self.table.len() as u64);
+            for &function in &self.table {
+                unsigned(sink, function.into());
+            }
+        });
+        section(10, self.functions.len(), &|sink| {
+            for (_, function) in &self.functions {
+                function.encode(sink);
+            }
+        });
+        section(11, 1, &|sink| {
+            let (address, bytes) = &self.data;
+            sink.push(0x00);
+            Instruction::I32Const(*address as i32).encode(sink);
+            Instruction::End.encode(sink);
+            unsigned(sink, bytes.len() as u64);
+            sink.extend(bytes);
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_leb128() {
+        let encode = |f: &dyn Fn(&mut Vec<u8>)| {
+            let mut sink = Vec::new();
+            f(&mut sink);
+            sink
+        };
+        assert_eq!(encode(&|sink| unsigned(sink, 0)), vec![0x00]);
+        assert_eq!(
+            encode(&|sink| unsigned(sink, 624_485)),
+            vec![0xe5, 0x8e, 0x26]
+        );
+        assert_eq!(encode(&|sink| signed(sink, 63)), vec![0x3f]);
+        assert_eq!(encode(&|sink| signed(sink, 64)), vec![0xc0, 0x00]);
+        assert_eq!(encode(&|sink| signed(sink, -1)), vec![0x7f]);
+        assert_eq!(encode(&|sink| signed(sink, -123_456)), vec![0xc0, 0xbb, 0x78]);
+    }
+
+    #[test]
+    fn test_empty_module() {
+        let bytes = Module::default().encode();
+        assert_eq!(&bytes[..8], b"\0asm\x01\0\0\0");
+        // Type section without types
+        assert_eq!(&bytes[8..11], &[1, 1, 0]);
+    }
+}
@@ -0,0 +1,899 @@
+mod encode;
+
+use self::encode::{Function, Instruction, ValType};
+use crate::{
+    backend::{compile_with, Backend},
+    code, intrinsics,
+    repr::{
+        self, closure_size, string_size, HEAP_ALIGN, STRING_BYTES, STRING_LENGTH, STRING_TAG,
+        TUPLE_ELEMENTS, TUPLE_LENGTH,
+    },
+    rom,
+    runtime::{Message, PANIC_EXIT_CODE},
+    Instrument, Options, Result,
+};
+use parser::mir::{Declaration, Expression, Module};
+use std::collections::BTreeMap;
+
+// WebAssembly output, for running programs in browsers and other hosts.
+// See <https://webassembly.github.io/spec/core/>
+//
+// Wasm has no indirect jumps, so continuation passing is done with a
+// trampoline: every declaration and intrinsic is a function in the table that
+// sets up the registers for its call and returns, and the exported `run` calls
+// the closure in r0 until one returns an exit code instead of `CONTINUE`.
+// Nothing is ever returned to, so no shadow stack is needed.
+//
+// Registers r0..rN are mutable i64 globals, followed by the i32 allocation
+// pointer. Values are represented as in native code, see `repr`, with closure
+// records holding a table index instead of a code address. The ROM is placed
+// in linear memory at `ROM_START`, followed by the RAM.
+//
+// The module imports `env.write(fd, pointer, length)` for output and exports
+// its `memory` and `run`. Run-time checks other than heap checks are left to
+// the wasm engine, which traps on calls through a number.
+
+/// Address of the ROM in linear memory, leaving null unused.
+const ROM_START: usize = 16;
+
+/// Returned by a declaration to have `run` call the closure in r0.
+const CONTINUE: i32 = -1;
+
+/// Function types
+const STEP: u32 = 0; // () -> i32
+const WRITE_TYPE: u32 = 1; // (fd: i32, pointer: i32, length: i32) -> ()
+const STREQ_TYPE: u32 = 2; // (a: i32, b: i32) -> i32
+
+/// Function index of the imported `env.write`.
+const WRITE: u32 = 0;
+
+/// Size of a wasm page.
+const PAGE: usize = 1 << 16;
+
+struct Context<'a> {
+    module:    &'a Module,
+    options:   &'a Options,
+    rom:       &'a rom::Layout,
+    /// Number of register globals, the allocation pointer comes after them.
+    registers: u32,
+    /// Last address the heap can allocate up to.
+    heap_end:  usize,
+    /// Function index of the string comparison helper.
+    streq:     u32,
+}
+
+impl<'a> Context<'a> {
+    const fn free(&self) -> u32 {
+        self.registers
+    }
+
+    fn find_decl(&self, symbol: usize) -> Option<(usize, &'a Declaration)> {
+        self.module
+            .declarations
+            .iter()
+            .enumerate()
+            .find(|decl| decl.1.procedure[0] == symbol)
+    }
+}
+
+/// Compile to a WebAssembly module, see the comment at the top of `wasm`.
+pub fn compile_wasm(module: &Module, options: &Options) -> Result<Vec<u8>> {
+    let options = Options {
+        debug_runtime: false,
+        instrument: Instrument::None,
+        ..options.clone()
+    };
+    compile_with(Wasm {
+        module,
+        options: &options,
+        laid_out: None,
+        functions: Vec::new(),
+    })
+}
+
+/// The WebAssembly backend. Declarations and intrinsics are functions in the
+/// table, in the order of their indices.
+struct Wasm<'a> {
+    module:    &'a Module,
+    options:   &'a Options,
+    laid_out:  Option<LaidOut>,
+    functions: Vec<(u32, Function)>,
+}
+
+/// What the functions refer to, known once laid out.
+struct LaidOut {
+    main:      usize,
+    rom_bytes: Vec<u8>,
+    rom:       rom::Layout,
+    ram_start: usize,
+    registers: usize,
+    streq:     u32,
+}
+
+impl Wasm<'_> {
+    fn context(&self) -> Context<'_> {
+        let laid_out = self.laid_out.as_ref().expect("Emitting before the layout");
+        Context {
+            module:    self.module,
+            options:   self.options,
+            rom:       &laid_out.rom,
+            registers: laid_out.registers as u32,
+            heap_end:  laid_out.ram_start + self.options.memory.heap_end(),
+            streq:     laid_out.streq,
+        }
+    }
+}
+
+impl Backend for Wasm<'_> {
+    type Output = Vec<u8>;
+
+    fn module(&self) -> &Module {
+        self.module
+    }
+
+    fn options(&self) -> &Options {
+        self.options
+    }
+
+    fn layout(&mut self) -> Result<Vec<usize>> {
+        let (module, options) = (self.module, self.options);
+        let main = code::find_entry(module, options)?;
+        let declarations = module.declarations.len();
+
+        // Closure records refer to table indices, declarations then imports
+        let table = code::Layout {
+            declarations: (0..declarations).collect(),
+            imports: (declarations..declarations + module.imports.len()).collect(),
+            ..code::Layout::default()
+        };
+        let (rom_bytes, rom) = rom::compile(module, options, &table, ROM_START);
+        let ram_start = (rom.end + 7) & !7;
+
+        let registers = module
+            .declarations
+            .iter()
+            .map(|decl| decl.procedure.len().max(decl.call.len()))
+            .chain(
+                module
+                    .imports
+                    .iter()
+                    .map(|name| intrinsics::arity(name) + 1),
+            )
+            .max()
+            .unwrap_or(1);
+        let streq = function(declarations + module.imports.len());
+        self.laid_out = Some(LaidOut {
+            main,
+            rom_bytes,
+            rom,
+            ram_start,
+            registers,
+            streq,
+        });
+        Ok((0..declarations).collect())
+    }
+
+    fn emit_declaration(&mut self, index: usize) {
+        assert_eq!(self.functions.len(), index);
+        let function = declaration(&self.context(), &self.module.declarations[index]);
+        self.functions.push((STEP, function));
+    }
+
+    fn emit_intrinsic(&mut self, index: usize) {
+        assert_eq!(self.functions.len(), self.module.declarations.len() + index);
+        let function = intrinsic(&self.context(), &self.module.imports[index]);
+        self.functions.push((STEP, function));
+    }
+
+    fn finalize(mut self) -> Result<Vec<u8>> {
+        let laid_out = self.laid_out.expect("Finalizing before the layout");
+        let memory = &self.options.memory;
+        let streq = laid_out.streq;
+        self.functions.push((STREQ_TYPE, string_equal()));
+        self.functions
+            .push((STEP, run(laid_out.rom.closures[laid_out.main])));
+
+        let mut globals = vec![(ValType::I64, 0); laid_out.registers];
+        globals.push((ValType::I32, (laid_out.ram_start + memory.heap_offset) as i64));
+        let wasm = encode::Module {
+            types: vec![
+                (vec![], vec![ValType::I32]),
+                (vec![ValType::I32; 3], vec![]),
+                (vec![ValType::I32; 2], vec![ValType::I32]),
+            ],
+            imports: vec![("env", "write", WRITE_TYPE)],
+            table: (0..streq - 1).map(|index| index + 1).collect(),
+            memory: ((laid_out.ram_start + memory.ram_size() + PAGE - 1) / PAGE) as u32,
+            globals,
+            exports: vec![("run", streq + 1)],
+            data: (ROM_START as u32, laid_out.rom_bytes),
+            functions: self.functions,
+        };
+        Ok(wasm.encode())
+    }
+}
+
+/// Function index of the declaration or import at table position `index`,
+/// after the imported `env.write`.
+const fn function(index: usize) -> u32 {
+    (index + 1) as u32
+}
+
+/// Write the string record at the address in the i32 local `record` to `fd`.
+fn write(f: &mut Function, fd: i32, record: u32) {
+    use Instruction::*;
+    f.push(I32Const(fd));
+    f.push(LocalGet(record));
+    f.push(I32Const(STRING_BYTES));
+    f.push(I32Add);
+    f.push(LocalGet(record));
+    f.push(I32Load(STRING_LENGTH as u32));
+    f.push(Call(WRITE));
+}
+
+/// Write the runtime message to stderr and return `PANIC_EXIT_CODE`.
+fn abort_with(ctx: &Context<'_>, f: &mut Function, message: Message) {
+    use Instruction::*;
+    let record = f.local(0, ValType::I32);
+    f.push(I32Const(ctx.rom.messages[message as usize] as i32));
+    f.push(LocalSet(record));
+    write(f, 2, record);
+    f.push(I32Const(PANIC_EXIT_CODE.into()));
+    f.push(Return);
+}
+
+/// Value of `symbol` at the call, like `code::emit::goal_value`: a local if it
+/// is available or allocated, or the ROM record of a constant closure. New
+/// closures are added to `allocations` with their offset from the allocation
+/// base, each allocated once.
+fn symbol_value(
+    ctx: &Context<'_>,
+    f: &mut Function,
+    locals: &mut BTreeMap<usize, u32>,
+    allocations: &mut Vec<(usize, usize)>,
+    size: &mut usize,
+    symbol: usize,
+) -> Instruction {
+    if let Some(&local) = locals.get(&symbol) {
+        return Instruction::LocalGet(local);
+    }
+    let (index, decl) = ctx.find_decl(symbol).expect("Expected closure symbol");
+    if decl.closure.is_empty() {
+        return Instruction::I64Const(ctx.rom.closures[index] as i64);
+    }
+    // Captures are never declaration names, so they are locals already
+    let local = f.local(0, ValType::I64);
+    let _ = locals.insert(symbol, local);
+    allocations.push((symbol, *size));
+    *size += closure_size(ctx.options) + 8 * decl.closure.len();
+    Instruction::LocalGet(local)
+}
+
+fn declaration(ctx: &Context<'_>, decl: &Declaration) -> Function {
+    use Instruction::*;
+    let mut f = Function::default();
+
+    // Parameters and captures, including the closure itself in r0
+    let mut locals = BTreeMap::new();
+    for (i, &symbol) in decl.procedure.iter().enumerate() {
+        let local = f.local(0, ValType::I64);
+        f.push(GlobalGet(i as u32));
+        f.push(LocalSet(local));
+        let _ = locals.insert(symbol, local);
+    }
+    for (j, &symbol) in decl.closure.iter().enumerate() {
+        let local = f.local(0, ValType::I64);
+        f.push(GlobalGet(0));
+        f.push(I32WrapI64);
+        f.push(I64Load((closure_size(ctx.options) + 8 * j) as u32));
+        f.push(LocalSet(local));
+        let _ = locals.insert(symbol, local);
+    }
+
+    // Values of the call, collecting the closures to allocate
+    let mut allocations = Vec::new();
+    let mut size = 0;
+    let values = decl
+        .call
+        .iter()
+        .map(|expr| {
+            match *expr {
+                Expression::Literal(i) => I64Const(repr::string(ctx.rom.strings[i]) as i64),
+                Expression::Number(n) => I64Const(repr::number(ctx.module.numbers[n]) as i64),
+                Expression::Import(i) => I64Const(ctx.rom.imports[i] as i64),
+                Expression::Symbol(s) => {
+                    symbol_value(ctx, &mut f, &mut locals, &mut allocations, &mut size, s)
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Bump allocate all closures at once, checking the whole size up front
+    if size > 0 {
+        let base = f.local(0, ValType::I32);
+        f.push(GlobalGet(ctx.free()));
+        f.push(LocalTee(base));
+        f.push(I32Const(size as i32));
+        f.push(I32Add);
+        if ctx.options.memory.heap_checks {
+            f.push(I32Const(ctx.heap_end as i32));
+            f.push(I32GtU);
+            f.push(If);
+            abort_with(ctx, &mut f, Message::HeapOverflow);
+            f.push(End);
+            f.push(LocalGet(base));
+            f.push(I32Const(size as i32));
+            f.push(I32Add);
+        }
+        f.push(GlobalSet(ctx.free()));
+        for &(symbol, offset) in &allocations {
+            f.push(LocalGet(base));
+            f.push(I32Const(offset as i32));
+            f.push(I32Add);
+            f.push(I64ExtendI32U);
+            f.push(LocalSet(locals[&symbol]));
+        }
+        for &(symbol, offset) in &allocations {
+            let (index, closure) = ctx.find_decl(symbol).unwrap();
+            f.push(LocalGet(base));
+            f.push(I64Const(index as i64));
+            f.push(I64Store(offset as u32));
+            for (j, &capture) in closure.closure.iter().enumerate() {
+                let value =
+                    symbol_value(ctx, &mut f, &mut locals, &mut Vec::new(), &mut 0, capture);
+                f.push(LocalGet(base));
+                f.push(value);
+                f.push(I64Store(
+                    (offset + closure_size(ctx.options) + 8 * j) as u32,
+                ));
+            }
+        }
+    }
+
+    // The values only refer to locals, so registers can be set in any order
+    for (i, value) in values.into_iter().enumerate() {
+        f.push(value);
+        f.push(GlobalSet(i as u32));
+    }
+    f.push(I32Const(CONTINUE));
+    f
+}
+
+/// The exported entry point: call closures starting from `main` until one
+/// returns an exit code.
+fn run(main: usize) -> Function {
+    use Instruction::*;
+    let mut f = Function::default();
+    let result = f.local(0, ValType::I32);
+    f.push(I64Const(main as i64));
+    f.push(GlobalSet(0));
+    f.push(Block);
+    f.push(Loop);
+    f.push(GlobalGet(0));
+    f.push(I32WrapI64);
+    f.push(I64Load(0));
+    f.push(I32WrapI64);
+    f.push(CallIndirect(STEP));
+    f.push(LocalTee(result));
+    f.push(I32Const(CONTINUE));
+    f.push(I32Ne);
+    f.push(BrIf(1));
+    f.push(Br(0));
+    f.push(End);
+    f.push(End);
+    f.push(LocalGet(result));
+    f
+}
+
+/// Whether the string records at the two addresses have the same contents.
+fn string_equal() -> Function {
+    use Instruction::*;
+    let (a, b) = (0, 1);
+    let mut f = Function::default();
+    let length = f.local(2, ValType::I32);
+    f.push(LocalGet(a));
+    f.push(I32Load(STRING_LENGTH as u32));
+    f.push(LocalTee(length));
+    f.push(LocalGet(b));
+    f.push(I32Load(STRING_LENGTH as u32));
+    f.push(I32Ne);
+    f.push(If);
+    f.push(I32Const(0));
+    f.push(Return);
+    f.push(End);
+    f.push(Block);
+    f.push(Loop);
+    f.push(LocalGet(length));
+    f.push(I32Eqz);
+    f.push(BrIf(1));
+    f.push(LocalGet(a));
+    f.push(I32Load8U(STRING_BYTES as u32));
+    f.push(LocalGet(b));
+    f.push(I32Load8U(STRING_BYTES as u32));
+    f.push(I32Ne);
+    f.push(If);
+    f.push(I32Const(0));
+    f.push(Return);
+    f.push(End);
+    for (local, step) in &[(a, 1), (b, 1), (length, -1)] {
+        f.push(LocalGet(*local));
+        f.push(I32Const(*step));
+        f.push(I32Add);
+        f.push(LocalSet(*local));
+    }
+    f.push(Br(0));
+    f.push(End);
+    f.push(End);
+    f.push(I32Const(1));
+    f
+}
+
+/// The intrinsic `name`, with the same calling convention as the native ones
+/// in `x64::intrinsics`.
+fn intrinsic(ctx: &Context<'_>, name: &str) -> Function {
+    use Instruction::*;
+    let mut f = Function::default();
+    let r = GlobalGet;
+    let set = GlobalSet;
+    // Continue with the closure in register `ret`
+    let call = |f: &mut Function, ret: u32| {
+        f.push(r(ret));
+        f.push(set(0));
+        f.push(I32Const(CONTINUE));
+    };
+    // Untagged number in register `reg`
+    let untag = |f: &mut Function, reg: u32| {
+        f.push(r(reg));
+        f.push(I64Const(1));
+        f.push(I64ShrU);
+    };
+    // Tag the untagged number on the stack
+    let tag = |f: &mut Function| {
+        f.push(I64Const(1));
+        f.push(I64Shl);
+        f.push(I64Const(1));
+        f.push(I64Or);
+    };
+    // Record address of the string in register `reg`
+    let record = |f: &mut Function, reg: u32| {
+        let local = f.local(0, ValType::I32);
+        f.push(r(reg));
+        f.push(I32WrapI64);
+        f.push(I32Const(STRING_TAG));
+        f.push(I32Sub);
+        f.push(LocalSet(local));
+        local
+    };
+    // Address of the element at the index in r2 of the tuple in r1, or return
+    // `PANIC_EXIT_CODE` if it is out of bounds. Tagged indices compare like
+    // the indices.
+    let element = |f: &mut Function| {
+        let tuple = f.local(0, ValType::I32);
+        f.push(r(2));
+        f.push(r(1));
+        f.push(I32WrapI64);
+        f.push(LocalTee(tuple));
+        f.push(I64Load(TUPLE_LENGTH as u32));
+        f.push(I64LtU);
+        f.push(I32Eqz);
+        f.push(If);
+        f.push(I32Const(PANIC_EXIT_CODE.into()));
+        f.push(Return);
+        f.push(End);
+        f.push(r(2));
+        f.push(I64Const(1));
+        f.push(I64ShrU);
+        f.push(I64Const(3));
+        f.push(I64Shl);
+        f.push(I32WrapI64);
+        f.push(LocalGet(tuple));
+        f.push(I32Add);
+    };
+    // r0 = condition ? r(ok) : r(other), with the condition on the stack
+    let select = |f: &mut Function, ok: u32, other: u32| {
+        let condition = f.local(0, ValType::I32);
+        f.push(LocalSet(condition));
+        f.push(r(ok));
+        f.push(r(other));
+        f.push(LocalGet(condition));
+        f.push(Select);
+        f.push(set(0));
+        f.push(I32Const(CONTINUE));
+    };
+    match name {
+        "exit" => {
+            untag(&mut f, 1);
+            f.push(I32WrapI64);
+            f.push(I32Const(0xff));
+            f.push(I32And);
+        }
+        "print" => {
+            let string = record(&mut f, 1);
+            write(&mut f, 1, string);
+            call(&mut f, 2);
+        }
+        "add" => {
+            f.push(r(1));
+            f.push(r(2));
+            f.push(I64Add);
+            f.push(I64Const(1));
+            f.push(I64Sub);
+            f.push(set(1));
+            call(&mut f, 3);
+        }
+        "sub" => {
+            f.push(r(1));
+            f.push(r(2));
+            f.push(I64Sub);
+            f.push(I64Const(1));
+            f.push(I64Or);
+            f.push(set(1));
+            call(&mut f, 3);
+        }
+        "mul" => {
+            f.push(r(1));
+            f.push(I64Const(1));
+            f.push(I64Sub);
+            untag(&mut f, 2);
+            f.push(I64Mul);
+            f.push(I64Const(1));
+            f.push(I64Or);
+            f.push(set(1));
+            call(&mut f, 3);
+        }
+        "divmod" => {
+            // Like `div` on x86_64, division by zero traps.
+            let a = f.local(0, ValType::I64);
+            let b = f.local(0, ValType::I64);
+            untag(&mut f, 1);
+            f.push(LocalSet(a));
+            untag(&mut f, 2);
+            f.push(LocalSet(b));
+            f.push(LocalGet(a));
+            f.push(LocalGet(b));
+            f.push(I64DivU);
+            tag(&mut f);
+            f.push(set(1));
+            f.push(LocalGet(a));
+            f.push(LocalGet(b));
+            f.push(I64RemU);
+            tag(&mut f);
+            f.push(set(2));
+            call(&mut f, 3);
+        }
+        "isZero" | "input" | "parseInt" => {
+            // TODO: input and parseInt are placeholders, as in native code
+            f.push(r(1));
+            f.push(I64Const(1));
+            f.push(I64Eq);
+            select(&mut f, 2, 3);
+        }
+        "addChecked" => {
+            // The sum carries out of 64 bits exactly when it exceeds 63 bits,
+            // and it carries when it wraps around to below an addend.
+            f.push(r(1));
+            f.push(I64Const(1));
+            f.push(I64Sub);
+            f.push(r(2));
+            f.push(I64Add);
+            f.push(set(1));
+            f.push(r(1));
+            f.push(r(2));
+            f.push(I64LtU);
+            select(&mut f, 4, 3);
+        }
+        "subChecked" => {
+            let borrow = f.local(0, ValType::I32);
+            f.push(r(1));
+            f.push(r(2));
+            f.push(I64LtU);
+            f.push(LocalSet(borrow));
+            f.push(r(1));
+            f.push(r(2));
+            f.push(I64Sub);
+            f.push(I64Const(1));
+            f.push(I64Add);
+            f.push(set(1));
+            f.push(LocalGet(borrow));
+            select(&mut f, 4, 3);
+        }
+        "mulChecked" => {
+            // `a (2 b)` overflows 64 bits exactly when `a b` overflows 63
+            // bits, which is when dividing the wrapped product by `a` does not
+            // give back `2 b`. The divisor is made non-zero for `a = 0`.
+            let a = f.local(0, ValType::I64);
+            let product = f.local(0, ValType::I64);
+            untag(&mut f, 1);
+            f.push(LocalTee(a));
+            f.push(r(2));
+            f.push(I64Const(1));
+            f.push(I64Sub);
+            f.push(I64Mul);
+            f.push(LocalSet(product));
+            f.push(LocalGet(product));
+            f.push(LocalGet(a));
+            f.push(I64Const(1));
+            f.push(LocalGet(a));
+            f.push(I64Const(0));
+            f.push(I64Ne);
+            f.push(Select);
+            f.push(I64DivU);
+            f.push(r(2));
+            f.push(I64Const(1));
+            f.push(I64Sub);
+            f.push(I64Ne);
+            f.push(LocalGet(a));
+            f.push(I64Const(0));
+            f.push(I64Ne);
+            f.push(I32And);
+            f.push(LocalGet(product));
+            f.push(I64Const(1));
+            f.push(I64Add);
+            f.push(set(1));
+            select(&mut f, 4, 3);
+        }
+        "neg" => {
+            // `2 (-a) + 1 = 2 - (2 a + 1)`
+            f.push(I64Const(2));
+            f.push(r(1));
+            f.push(I64Sub);
+            f.push(set(1));
+            call(&mut f, 2);
+        }
+        "isNegative" => {
+            f.push(r(1));
+            f.push(I64Const(0));
+            f.push(I64LtS);
+            select(&mut f, 2, 3);
+        }
+        "lessSigned" => {
+            f.push(r(1));
+            f.push(r(2));
+            f.push(I64LtS);
+            select(&mut f, 3, 4);
+        }
+        "compilerVersion" => {
+            let version = ctx.rom.version.expect("Version string in ROM");
+            f.push(r(1));
+            f.push(set(0));
+            f.push(I64Const(repr::string(version) as i64));
+            f.push(set(1));
+            f.push(I32Const(CONTINUE));
+        }
+        "hasBuiltin" => {
+            let mut address = ctx.rom.builtins.expect("Builtin table in ROM");
+            let string = record(&mut f, 1);
+            for name in &intrinsics::NAMES {
+                f.push(LocalGet(string));
+                f.push(I32Const(address as i32));
+                f.push(Call(ctx.streq));
+                f.push(If);
+                call(&mut f, 2);
+                f.push(Return);
+                f.push(End);
+                address += string_size(name.len());
+            }
+            call(&mut f, 3);
+        }
+        "charAt" | "substring" => {
+            // Copy the bytes, clamped to the string, to a new record
+            let string = record(&mut f, 1);
+            let size = f.local(0, ValType::I64);
+            let start = f.local(0, ValType::I64);
+            let length = f.local(0, ValType::I64);
+            let clamp = |f: &mut Function, local: u32| {
+                f.push(LocalGet(local));
+                f.push(LocalGet(size));
+                f.push(LocalGet(local));
+                f.push(LocalGet(size));
+                f.push(I64LtU);
+                f.push(Select);
+                f.push(LocalSet(local));
+            };
+            f.push(LocalGet(string));
+            f.push(I32Load(STRING_LENGTH as u32));
+            f.push(I64ExtendI32U);
+            f.push(LocalSet(size));
+            untag(&mut f, 2);
+            f.push(LocalSet(start));
+            clamp(&mut f, start);
+            f.push(LocalGet(size));
+            f.push(LocalGet(start));
+            f.push(I64Sub);
+            f.push(LocalSet(size));
+            if name == "charAt" {
+                f.push(I64Const(1));
+            } else {
+                untag(&mut f, 3);
+            }
+            f.push(LocalSet(length));
+            clamp(&mut f, length);
+
+            // Allocate, keeping the heap aligned
+            let base = f.local(0, ValType::I32);
+            let next = f.local(0, ValType::I32);
+            f.push(GlobalGet(ctx.free()));
+            f.push(LocalTee(base));
+            f.push(LocalGet(length));
+            f.push(I32WrapI64);
+            f.push(I32Add);
+            f.push(I32Const(STRING_BYTES + HEAP_ALIGN as i32 - 1));
+            f.push(I32Add);
+            f.push(I32Const(-(HEAP_ALIGN as i32)));
+            f.push(I32And);
+            f.push(LocalSet(next));
+            if ctx.options.memory.heap_checks {
+                f.push(LocalGet(next));
+                f.push(I32Const(ctx.heap_end as i32));
+                f.push(I32GtU);
+                f.push(If);
+                abort_with(ctx, &mut f, Message::HeapOverflow);
+                f.push(End);
+            }
+            f.push(LocalGet(next));
+            f.push(GlobalSet(ctx.free()));
+            f.push(LocalGet(base));
+            f.push(LocalGet(length));
+            f.push(I32WrapI64);
+            f.push(I32Store(STRING_LENGTH as u32));
+
+            // Copy byte by byte, `source` is relative to the string bytes
+            let source = f.local(0, ValType::I32);
+            let index = f.local(0, ValType::I32);
+            f.push(LocalGet(string));
+            f.push(LocalGet(start));
+            f.push(I32WrapI64);
+            f.push(I32Add);
+            f.push(LocalSet(source));
+            f.push(Block);
+            f.push(Loop);
+            f.push(LocalGet(index));
+            f.push(LocalGet(length));
+            f.push(I32WrapI64);
+            f.push(I32Ne);
+            f.push(I32Eqz);
+            f.push(BrIf(1));
+            f.push(LocalGet(base));
+            f.push(LocalGet(index));
+            f.push(I32Add);
+            f.push(LocalGet(source));
+            f.push(LocalGet(index));
+            f.push(I32Add);
+            f.push(I32Load8U(STRING_BYTES as u32));
+            f.push(I32Store8(STRING_BYTES as u32));
+            f.push(LocalGet(index));
+            f.push(I32Const(1));
+            f.push(I32Add);
+            f.push(LocalSet(index));
+            f.push(Br(0));
+            f.push(End);
+            f.push(End);
+
+            f.push(LocalGet(base));
+            f.push(I32Const(STRING_TAG));
+            f.push(I32Add);
+            f.push(I64ExtendI32U);
+            f.push(set(1));
+            call(&mut f, if name == "charAt" { 3 } else { 4 });
+        }
+        "newTuple" => {
+            // A length beyond the heap would overflow the size
+            let base = f.local(0, ValType::I32);
+            let next = f.local(0, ValType::I32);
+            if ctx.options.memory.heap_checks {
+                f.push(I64Const(ctx.heap_end as i64));
+                f.push(r(1));
+                f.push(I64LtU);
+                f.push(If);
+                abort_with(ctx, &mut f, Message::HeapOverflow);
+                f.push(End);
+            }
+            f.push(GlobalGet(ctx.free()));
+            f.push(LocalTee(base));
+            f.push(r(1));
+            f.push(I64Const(2));
+            f.push(I64Shl);
+            f.push(I32WrapI64);
+            f.push(I32Add);
+            f.push(I32Const(TUPLE_ELEMENTS - 4));
+            f.push(I32Add);
+            f.push(LocalSet(next));
+            if ctx.options.memory.heap_checks {
+                f.push(LocalGet(next));
+                f.push(I32Const(ctx.heap_end as i32));
+                f.push(I32GtU);
+                f.push(If);
+                abort_with(ctx, &mut f, Message::HeapOverflow);
+                f.push(End);
+            }
+            f.push(LocalGet(next));
+            f.push(GlobalSet(ctx.free()));
+            f.push(LocalGet(base));
+            f.push(r(1));
+            f.push(I64Store(TUPLE_LENGTH as u32));
+
+            // Fill with zeros
+            let address = f.local(0, ValType::I32);
+            f.push(LocalGet(base));
+            f.push(I32Const(TUPLE_ELEMENTS));
+            f.push(I32Add);
+            f.push(LocalSet(address));
+            f.push(Block);
+            f.push(Loop);
+            f.push(LocalGet(address));
+            f.push(LocalGet(next));
+            f.push(I32Ne);
+            f.push(I32Eqz);
+            f.push(BrIf(1));
+            f.push(LocalGet(address));
+            f.push(I64Const(repr::number(0) as i64));
+            f.push(I64Store(0));
+            f.push(LocalGet(address));
+            f.push(I32Const(8));
+            f.push(I32Add);
+            f.push(LocalSet(address));
+            f.push(Br(0));
+            f.push(End);
+            f.push(End);
+
+            f.push(LocalGet(base));
+            f.push(I64ExtendI32U);
+            f.push(set(1));
+            call(&mut f, 2);
+        }
+        "tupleGet" => {
+            element(&mut f);
+            f.push(I64Load(TUPLE_ELEMENTS as u32));
+            f.push(set(1));
+            call(&mut f, 3);
+        }
+        "tupleSet" => {
+            element(&mut f);
+            f.push(r(3));
+            f.push(I64Store(TUPLE_ELEMENTS as u32));
+            call(&mut f, 4);
+        }
+        "abort" => f.push(I32Const(PANIC_EXIT_CODE.into())),
+        "panic" => {
+            let message = record(&mut f, 1);
+            write(&mut f, 2, message);
+            f.push(I32Const(PANIC_EXIT_CODE.into()));
+        }
+        _ => panic!("Unknown intrinsic {}", name),
+    }
+    f
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Error;
+    use parser::{parse_str, EntryError};
+
+    #[test]
+    fn test_module() {
+        let module = parse_str("main ↦\n    print “Hi” (↦ exit 0)\n").unwrap();
+        let bytes = compile_wasm(&module, &Options::default()).unwrap();
+        assert_eq!(&bytes[..8], b"\0asm\x01\0\0\0");
+        let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|w| w == needle);
+        // Imports `env.write` and exports `run`
+        assert!(contains(b"\x03env\x05write"));
+        assert!(contains(b"\x03run"));
+        // The string record in the data segment
+        assert!(contains(b"\x02\0\0\0Hi"));
+    }
+
+    #[test]
+    fn test_no_main() {
+        let module = parse_str("f ↦ exit 0\n").unwrap();
+        assert!(matches!(
+            compile_wasm(&module, &Options::default()),
+            Err(Error::Entry(EntryError::Missing(_)))
+        ));
+        let options = Options {
+            entry: Some("f".to_string()),
+            ..Options::default()
+        };
+        assert!(compile_wasm(&module, &options).is_ok());
+    }
+}
@@ -0,0 +1,474 @@
+use super::runtime;
+use crate::{
+    freestanding::{self, Port},
+    intrinsics::Syscall,
+    machine::Flag,
+    repr::{self, HEAP_ALIGN, STRING_ALIGN, STRING_BYTES, STRING_LENGTH, STRING_TAG},
+    rom, Features, Instrument, Memory, Options, Os,
+};
+use dynasm::dynasm;
+use dynasmrt::{x64::Assembler, DynasmApi, DynasmLabelApi};
+
+// The intrinsics of `crate::intrinsics` for x86_64.
+//
+// Syscalls are in r0, r7, r6, r2, r10, r8, r9, returns in r0, r1 clobbers r11
+// See <https://github.com/hjl-tools/x86-psABI/wiki/X86-psABI> A.2.1
+// See <https://github.com/apple/darwin-xnu/blob/master/bsd/kern/syscalls.master>
+
+// Arguments and results are tagged values, see `repr`. Numbers `2 n + 1` are
+// combined without untagging where the tag works out, e.g. `a + b - 1`.
+
+// TODO: These intrinsics don't need a closure to be passed. They can have a
+// more optimized calling convention.
+
+pub(crate) fn intrinsic(
+    ops: &mut Assembler,
+    options: &Options,
+    rom: &rom::Layout,
+    ram_start: usize,
+    name: &str,
+) {
+    match name {
+        "exit" if options.instrument == Instrument::Counts => {
+            dynasm!(ops
+                ; jmp ->exit_with_counts
+            );
+        }
+        "exit" => sys_exit(ops, options.os),
+        "print" => sys_print(ops, options.os),
+        "add" => add(ops),
+        "sub" => sub(ops),
+        "mul" => mul(ops),
+        "divmod" => divmod(ops),
+        "isZero" => is_zero(ops),
+        "addChecked" => add_checked(ops),
+        "subChecked" => sub_checked(ops),
+        "mulChecked" => mul_checked(ops, &options.features),
+        "neg" => neg(ops),
+        "isNegative" => is_negative(ops),
+        "lessSigned" => less_signed(ops),
+        "compilerVersion" => compiler_version(ops, rom),
+        "hasBuiltin" => has_builtin(ops, rom),
+        "abort" => runtime::abort(ops, options.os),
+        "panic" => panic(ops, options.os),
+        "charAt" => char_at(ops, ram_start, &options.memory),
+        "substring" => substring(ops, ram_start, &options.memory),
+        "newTuple" => new_tuple(ops, ram_start, &options.memory),
+        "tupleGet" => tuple_get(ops, options.os),
+        "tupleSet" => tuple_set(ops, options.os),
+        // TODO:
+        "input" => is_zero(ops),
+        "parseInt" => is_zero(ops),
+        _ => panic!("Unknown intrinsic {}", name),
+    }
+}
+
+/// Emit the exit builtin
+/// `exit code`
+pub(crate) fn sys_exit(ops: &mut Assembler, os: Os) {
+    if let Os::Freestanding { mmio } = os {
+        dynasm!(ops
+            ; mov r7, r1
+            ; shr r7, 1
+        );
+        freestanding::exit(ops, mmio);
+        return;
+    }
+    dynasm!(ops
+        // sys_exit(code)
+        ; mov r0d, DWORD Syscall::Exit.number(os)
+        ; mov r7, r1
+        ; shr r7, 1
+        ; syscall
+    );
+}
+
+/// Emit the print builtin
+/// `print str ret`
+fn sys_print(ops: &mut Assembler, os: Os) {
+    if let Os::Freestanding { mmio } = os {
+        dynasm!(ops
+            ; mov r15, r2
+            ; lea r6, [r1 - STRING_TAG]
+        );
+        freestanding::write(ops, mmio, Port::Output);
+        dynasm!(ops
+            ; mov r0, r15
+            ; jmp QWORD [r0]
+        );
+        return;
+    }
+    dynasm!(ops
+        // Back up ret to r15
+        ; mov r15, r2
+        // sys_write(fd, buffer, length)
+        ; mov r0d, DWORD Syscall::Write.number(os)
+        ; mov r7d, BYTE 1
+        ; lea r6, [r1 + STRING_BYTES - STRING_TAG]
+        ; mov r2d, [r1 + STRING_LENGTH - STRING_TAG]
+        ; syscall
+        // call ret from r15
+        ; mov r0, r15
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit the panic builtin, which writes the message to stderr and aborts
+/// `panic message`
+fn panic(ops: &mut Assembler, os: Os) {
+    dynasm!(ops
+        ; lea r12, [r1 - STRING_TAG]
+    );
+    runtime::print_reg(ops, os, 12);
+    runtime::abort(ops, os);
+}
+
+/// Emit the add builtin
+/// `add a b ret`
+fn add(ops: &mut Assembler) {
+    dynasm!(ops
+        ; lea r1, [r1 + r2 - 1]
+        ; mov r0, r3
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit the sub builtin
+/// `sub a b ret`
+fn sub(ops: &mut Assembler) {
+    // The difference of two numbers is even, setting the low bit adds one.
+    dynasm!(ops
+        ; sub r1, r2
+        ; or r1, 1
+        ; mov r0, r3
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit the mul builtin
+/// `mul a b ret`
+fn mul(ops: &mut Assembler) {
+    // `(2 a) b + 1` with `a` and `b` untagged. The low 64 bits of the product
+    // are the same for signed and unsigned multiplication, so the short
+    // two-operand `imul` suffices.
+    dynasm!(ops
+        ; dec r1
+        ; shr r2, 1
+        ; imul r1, r2
+        ; or r1, 1
+        ; mov r0, r3
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit the div builtin
+/// `divmod a b ret`
+fn divmod(ops: &mut Assembler) {
+    // TODO: Expose high bits
+    // See <https://www.felixcloutier.com/x86/div>
+    // TODO: Capture #DE event
+    dynasm!(ops
+        ; mov r4, r2
+        ; shr r4, 1
+        ; mov r0, r1
+        ; shr r0, 1
+        ; xor r2, r2
+        ; div r4  // r0 = r2:r0 / r4
+                  // r2 = r2:r0 % r4
+        ; lea r1, [r0 + r0 + 1]
+        ; lea r2, [r2 + r2 + 1]
+        ; mov r0, r3
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit the isZero builtin
+/// `isZero n true false`
+fn is_zero(ops: &mut Assembler) {
+    dynasm!(ops
+        ; cmp r1, 1
+        ; mov r0, r2
+        ; cmovnz r0, r3
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit the checked add builtin
+/// `addChecked a b ok overflow`
+fn add_checked(ops: &mut Assembler) {
+    // Carries out of 64 bits exactly when the sum exceeds 63 bits.
+    dynasm!(ops
+        ; dec r1
+        ; add r1, r2
+    );
+    continue_unless_flag(ops, Flag::Carry);
+}
+
+/// Emit the checked sub builtin
+/// `subChecked a b ok overflow`
+fn sub_checked(ops: &mut Assembler) {
+    // `lea` retags without touching the flags.
+    dynasm!(ops
+        ; sub r1, r2
+        ; lea r1, [r1 + 1]
+    );
+    continue_unless_flag(ops, Flag::Carry);
+}
+
+/// Emit the checked mul builtin
+/// `mulChecked a b ok overflow`
+fn mul_checked(ops: &mut Assembler, features: &Features) {
+    // `a (2 b)` with `a` and `b` untagged overflows 64 bits exactly when
+    // `a b` overflows 63 bits.
+    dynasm!(ops
+        ; shr r1, 1
+        ; dec r2
+    );
+    if features.bmi2 {
+        // See <https://www.felixcloutier.com/x86/mulx>
+        dynasm!(ops
+            ; mulx r0, r1, r1 // r0:r1 = r1 * r2
+            ; neg r0           // sets CF when r0 is non-zero
+        );
+    } else {
+        // See <https://www.felixcloutier.com/x86/mul>
+        dynasm!(ops
+            ; mov r0, r1
+            ; mul r2  // r2:r0 = r0 * r2, sets CF and OF when r2 is non-zero
+            ; mov r1, r0
+        );
+    }
+    dynasm!(ops
+        ; lea r1, [r1 + 1]
+    );
+    continue_unless_flag(ops, Flag::Carry);
+}
+
+/// Emit the neg builtin
+/// `neg a ret`
+fn neg(ops: &mut Assembler) {
+    // `2 (-a) + 1 = 2 - (2 a + 1)`
+    dynasm!(ops
+        ; neg r1
+        ; add r1, 2
+        ; mov r0, r2
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit the isNegative builtin
+/// `isNegative n true false`
+fn is_negative(ops: &mut Assembler) {
+    // The sign bit of the 63 bits is the sign bit of the tagged value.
+    dynasm!(ops
+        ; test r1, r1
+        ; mov r0, r2
+        ; cmovns r0, r3
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit the lessSigned builtin
+/// `lessSigned a b true false`
+fn less_signed(ops: &mut Assembler) {
+    // Tagging as `2 n + 1` keeps the signed order.
+    dynasm!(ops
+        ; cmp r1, r2
+        ; mov r0, r3
+        ; cmovge r0, r4
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Call `ok` in r3 with the result in r1, or `overflow` in r4 if `flag` is
+/// set. Only `mov` and `cmov` are used, so `flag` is preserved until the
+/// selection.
+fn continue_unless_flag(ops: &mut Assembler, flag: Flag) {
+    dynasm!(ops
+        ; mov r0, r3
+    );
+    match flag {
+        Flag::Carry => dynasm!(ops; cmovc r0, r4),
+        Flag::Parity => dynasm!(ops; cmovp r0, r4),
+        Flag::Zero => dynasm!(ops; cmovz r0, r4),
+        Flag::Sign => dynasm!(ops; cmovs r0, r4),
+        Flag::Overflow => dynasm!(ops; cmovo r0, r4),
+        Flag::Adjust | Flag::Direction => panic!("No conditional move on {:?}", flag),
+    }
+    dynasm!(ops
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit the compilerVersion builtin
+/// `compilerVersion ret`
+fn compiler_version(ops: &mut Assembler, rom: &rom::Layout) {
+    let version = rom.version.expect("Version string in ROM");
+    dynasm!(ops
+        ; mov r0, r1
+        ; mov r1d, DWORD repr::string(version) as i32
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit code setting `next` to the record following the string record at
+/// `string` of length `length`. Registers are given by number and hold record
+/// addresses, not string values.
+fn assemble_next_string(ops: &mut Assembler, next: u8, string: u8, length: u8) {
+    dynasm!(ops
+        ; lea Rq(next), [Rq(string) + Rq(length) + STRING_BYTES + STRING_ALIGN as i32 - 1]
+        ; and Rq(next), -(STRING_ALIGN as i32)
+    );
+}
+
+/// Emit the hasBuiltin builtin
+/// `hasBuiltin name true false`
+fn has_builtin(ops: &mut Assembler, rom: &rom::Layout) {
+    // The ROM table is a sequence of strings terminated by an empty one.
+    let table = rom.builtins.expect("Builtin table in ROM");
+    dynasm!(ops
+        ; lea r9, [r1 - STRING_TAG]
+        ; mov r10d, DWORD table as i32
+        ; next:
+        ; mov r1d, [r10 + STRING_LENGTH]
+        ; test r1d, r1d
+        ; jz >not_found
+    );
+    assemble_next_string(ops, 11, 10, 1);
+    dynasm!(ops
+        ; cmp r1d, [r9 + STRING_LENGTH]
+        ; jne >skip
+        ; lea r6, [r10 + STRING_BYTES]
+        ; lea r7, [r9 + STRING_BYTES]
+        ; repe cmpsb // Compare r1 bytes of [r6] and [r7]
+        ; je >found
+        ; skip:
+        ; mov r10, r11
+        ; jmp <next
+        ; found:
+        ; mov r0, r2
+        ; jmp QWORD [r0]
+        ; not_found:
+        ; mov r0, r3
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit the charAt builtin, the string of the byte at `index`, or the empty
+/// string past the end
+/// `charAt string index ret`
+fn char_at(ops: &mut Assembler, ram_start: usize, memory: &Memory) {
+    dynasm!(ops
+        ; mov r4, r3
+        ; mov r3d, DWORD repr::number(1) as i32
+    );
+    substring(ops, ram_start, memory);
+}
+
+/// Emit the substring builtin, which copies the bytes to a new string on the
+/// heap. Start and length are clamped to the string.
+/// `substring string start length ret`
+fn substring(ops: &mut Assembler, ram_start: usize, memory: &Memory) {
+    let heap_end = ram_start + memory.heap_end();
+    assert!(heap_end <= i32::max_value() as usize);
+    dynasm!(ops
+        ; mov r8d, [r1 + STRING_LENGTH - STRING_TAG]
+        ; shr r2, 1
+        ; shr r3, 1
+        ; cmp r2, r8
+        ; cmova r2, r8
+        ; sub r8, r2
+        ; cmp r3, r8
+        ; cmova r3, r8
+        ; lea r6, [r1 + r2 + STRING_BYTES - STRING_TAG]
+        // Allocate the record, keeping the heap aligned
+        ; mov r7, QWORD [ram_start as i32]
+        ; lea r10, [r7 + STRING_TAG]
+        ; lea r9, [r7 + r3 + STRING_BYTES + HEAP_ALIGN as i32 - 1]
+        ; and r9, -(HEAP_ALIGN as i32)
+    );
+    if memory.heap_checks {
+        dynasm!(ops
+            ; cmp r9, DWORD heap_end as i32
+            ; ja ->heap_overflow
+        );
+    }
+    dynasm!(ops
+        ; mov QWORD [ram_start as i32], r9
+        ; mov [r7 + STRING_LENGTH], r3d
+        ; add r7, STRING_BYTES
+        ; mov r1, r3
+        ; rep movsb // Copy r1 bytes from [r6] to [r7]
+        ; mov r1, r10
+        ; mov r0, r4
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit the newTuple builtin, which allocates a tuple of `length` zeros
+/// `newTuple length ret`
+fn new_tuple(ops: &mut Assembler, ram_start: usize, memory: &Memory) {
+    let heap_end = ram_start + memory.heap_end();
+    assert!(heap_end <= i32::max_value() as usize);
+    dynasm!(ops
+        ; mov r7, QWORD [ram_start as i32]
+    );
+    // A length beyond the heap would overflow the size
+    if memory.heap_checks {
+        dynasm!(ops
+            ; cmp r1, DWORD heap_end as i32
+            ; ja ->heap_overflow
+        );
+    }
+    dynasm!(ops
+        ; lea r9, [r7 + r1 * 4 + repr::TUPLE_ELEMENTS - 4]
+    );
+    if memory.heap_checks {
+        dynasm!(ops
+            ; cmp r9, DWORD heap_end as i32
+            ; ja ->heap_overflow
+        );
+    }
+    dynasm!(ops
+        ; mov QWORD [ram_start as i32], r9
+        ; mov [r7 + repr::TUPLE_LENGTH], r1
+        ; mov r10, r7
+        ; add r7, repr::TUPLE_ELEMENTS
+        ; shr r1, 1
+        ; mov r0d, DWORD repr::number(0) as i32
+        ; rep stosq // Store r1 times r0 to [r7]
+        ; mov r1, r10
+        ; mov r0, r2
+        ; jmp QWORD [r0]
+    );
+}
+
+/// Emit the tupleGet builtin
+/// `tupleGet tuple index ret`
+fn tuple_get(ops: &mut Assembler, os: Os) {
+    // Tagged numbers compare like the numbers, and the element of tagged
+    // index `2 i + 1` is at `4 (2 i + 1) + 4`.
+    dynasm!(ops
+        ; cmp r2, [r1 + repr::TUPLE_LENGTH]
+        ; jae >out_of_bounds
+        ; mov r1, [r1 + r2 * 4 + repr::TUPLE_ELEMENTS - 4]
+        ; mov r0, r3
+        ; jmp QWORD [r0]
+        ; out_of_bounds:
+    );
+    runtime::abort(ops, os);
+}
+
+/// Emit the tupleSet builtin, which changes the tuple in place
+/// `tupleSet tuple index value ret`
+fn tuple_set(ops: &mut Assembler, os: Os) {
+    dynasm!(ops
+        ; cmp r2, [r1 + repr::TUPLE_LENGTH]
+        ; jae >out_of_bounds
+        ; mov [r1 + r2 * 4 + repr::TUPLE_ELEMENTS - 4], r3
+        ; mov r0, r4
+        ; jmp QWORD [r0]
+        ; out_of_bounds:
+    );
+    runtime::abort(ops, os);
+}
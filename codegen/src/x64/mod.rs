@@ -0,0 +1,116 @@
+mod intrinsics;
+mod runtime;
+
+use crate::{
+    allocator::{Allocator, Bump},
+    arch::Isa,
+    machine::Transition,
+    outline, rom, Memory, Options,
+};
+use dynasm::dynasm;
+use dynasmrt::{
+    x64::{self, X64Relocation},
+    DynamicLabel, DynasmApi, DynasmLabelApi,
+};
+
+// x86_64, for macOS and Linux.
+// See <https://censoredusername.github.io/dynasm-rs/language/langref_x64.html#register>
+
+/// The x86_64 instruction set. The registers are numbered as in dynasm.
+pub(crate) struct X64;
+
+impl Isa for X64 {
+    type Relocation = X64Relocation;
+
+    const CALL_SIZE: usize = outline::CALL_SIZE;
+
+    fn prelude(asm: &mut x64::Assembler, ram_end: usize, main: usize, fallthrough: bool) {
+        dynasm!(asm
+            ; mov QWORD [(ram_end - 8) as i32], rsp
+            ; mov r0d, DWORD main as i32
+        );
+        if !fallthrough {
+            Self::call(asm);
+        }
+    }
+
+    fn transition(asm: &mut x64::Assembler, transition: &Transition, ram_start: usize) {
+        transition.assemble(asm, ram_start);
+    }
+
+    fn heap_check(asm: &mut x64::Assembler, ram_start: usize, memory: &Memory, size: usize) {
+        Bump::check(asm, ram_start, memory, size);
+    }
+
+    fn call_stub(
+        asm: &mut x64::Assembler,
+        ram_start: usize,
+        stub: DynamicLabel,
+        return_address: usize,
+    ) {
+        outline::assemble_call(asm, ram_start, stub, return_address);
+    }
+
+    fn stub_return(asm: &mut x64::Assembler, ram_start: usize) {
+        outline::assemble_return(asm, ram_start);
+    }
+
+    fn enter_declaration(asm: &mut x64::Assembler, ram_start: usize, index: usize) {
+        let slot = ram_start + crate::runtime::DECLARATION_SLOT;
+        assert!(slot <= i32::max_value() as usize && index <= i32::max_value() as usize);
+        dynasm!(asm
+            ; mov QWORD [slot as i32], DWORD index as i32
+        );
+    }
+
+    fn call_trace(asm: &mut x64::Assembler, ram_start: usize, return_address: usize) {
+        runtime::call_trace(asm, ram_start, return_address);
+    }
+
+    fn increment(asm: &mut x64::Assembler, address: usize) {
+        assert!(address <= i32::max_value() as usize);
+        dynasm!(asm
+            ; inc QWORD [address as i32]
+        );
+    }
+
+    fn select_on_zero(asm: &mut x64::Assembler) {
+        dynasm!(asm
+            ; cmovnz r0, r1
+        );
+    }
+
+    fn call(asm: &mut x64::Assembler) {
+        dynasm!(asm
+            ; jmp QWORD [r0]
+        );
+    }
+
+    fn jump(asm: &mut x64::Assembler, label: DynamicLabel) {
+        dynasm!(asm
+            ; jmp =>label
+        );
+    }
+
+    fn check_closure(asm: &mut x64::Assembler) {
+        runtime::check_closure(asm);
+    }
+
+    fn check_arity(asm: &mut x64::Assembler, argc: usize) {
+        runtime::check_arity(asm, argc);
+    }
+
+    fn intrinsic(
+        asm: &mut x64::Assembler,
+        options: &Options,
+        rom: &rom::Layout,
+        ram_start: usize,
+        name: &str,
+    ) {
+        intrinsics::intrinsic(asm, options, rom, ram_start, name);
+    }
+
+    fn runtime(asm: &mut x64::Assembler, options: &Options, rom: &rom::Layout, ram_start: usize) {
+        runtime::runtime(asm, options, rom, ram_start);
+    }
+}
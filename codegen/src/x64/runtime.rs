@@ -0,0 +1,240 @@
+use super::intrinsics;
+use crate::{
+    freestanding::{self, Port},
+    intrinsics::Syscall,
+    outline::{self, CALL_SIZE, RETURN_SLOT},
+    repr::{CLOSURE_INFO, STRING_BYTES, STRING_LENGTH, TAG_MASK},
+    rom,
+    runtime::{Message, COUNTS_BUFFER, DECLARATION_SLOT, PANIC_EXIT_CODE, TRACE_SAVED},
+    Instrument, Options, Os,
+};
+use dynasm::dynasm;
+use dynasmrt::{x64::Assembler, DynasmApi, DynasmLabelApi};
+
+// `crate::runtime` for x86_64.
+
+/// The registers saved by the trace routine.
+const TRACE_REGISTERS: [u8; TRACE_SAVED] = [0, 1, 2, 6, 7, 11];
+
+/// Emit the check that r0 holds a closure and not a number or string.
+pub(crate) fn check_closure(ops: &mut Assembler) {
+    dynasm!(ops
+        ; test r0b, TAG_MASK as i8
+        ; jnz ->not_closure
+    );
+}
+
+/// Emit the check that the closure in r0 takes `argc` arguments.
+pub(crate) fn check_arity(ops: &mut Assembler, argc: usize) {
+    assert!(argc <= i8::max_value() as usize);
+    dynasm!(ops
+        ; cmp BYTE [r0 + CLOSURE_INFO], argc as i8
+        ; jne ->arity_mismatch
+    );
+}
+
+/// Emit a call to the trace routine, `return_address` is the address right
+/// after it. It is linked like an outlined stub.
+pub(crate) fn call_trace(ops: &mut Assembler, ram_start: usize, return_address: usize) {
+    assert!(return_address <= i32::max_value() as usize);
+    let start = ops.offset().0;
+    dynasm!(ops
+        ; mov QWORD [(ram_start + RETURN_SLOT) as i32], DWORD return_address as i32
+        ; jmp ->trace
+    );
+    assert_eq!(ops.offset().0 - start, CALL_SIZE);
+}
+
+/// Emit the runtime support routines.
+pub(crate) fn runtime(ops: &mut Assembler, options: &Options, rom: &rom::Layout, ram_start: usize) {
+    let os = options.os;
+    // Arity mismatch: r0 holds the closure that was about to be called.
+    dynasm!(ops
+        ; ->arity_mismatch:
+        ; mov r12, QWORD [r0 + CLOSURE_INFO]
+        ; shr r12, 8
+    );
+    print_const(ops, os, rom.messages[Message::ArityMismatch as usize]);
+    print_reg(ops, os, 12);
+    print_const(ops, os, rom.messages[Message::Newline as usize]);
+    abort(ops, os);
+
+    // Not a closure: r0 holds a tagged number or string.
+    dynasm!(ops
+        ; ->not_closure:
+    );
+    print_const(ops, os, rom.messages[Message::NotClosure as usize]);
+    abort(ops, os);
+
+    // Heap overflow: the allocations of a declaration do not fit in RAM.
+    dynasm!(ops
+        ; ->heap_overflow:
+    );
+    print_const(ops, os, rom.messages[Message::HeapOverflow as usize]);
+    abort(ops, os);
+
+    match options.instrument {
+        Instrument::None => {}
+        Instrument::Trace => trace(ops, options, rom, ram_start),
+        Instrument::Counts => exit_with_counts(ops, options, rom, ram_start),
+    }
+}
+
+/// The trace routine: write the name of the declaration in the declaration
+/// slot to stderr. All registers are preserved, the ones the system calls
+/// clobber are saved at the heap offset.
+fn trace(ops: &mut Assembler, options: &Options, rom: &rom::Layout, ram_start: usize) {
+    let save = ram_start + options.memory.heap_offset;
+    let slot = ram_start + DECLARATION_SLOT;
+    let names = rom.ranges.expect("Tracing needs the table of code ranges") + 16;
+    assert!(save + 8 * TRACE_SAVED <= i32::max_value() as usize);
+    assert!(names <= i32::max_value() as usize);
+    dynasm!(ops
+        ; ->trace:
+    );
+    for (index, &reg) in TRACE_REGISTERS.iter().enumerate() {
+        dynasm!(ops
+            ; mov QWORD [(save + 8 * index) as i32], Rq(reg)
+        );
+    }
+    // The name is the third quad word of the declaration's entry in the table
+    dynasm!(ops
+        ; mov r6, QWORD [slot as i32]
+        ; imul r6, r6, 24
+        ; mov r6, QWORD [r6 + names as i32]
+    );
+    print_r6(ops, options.os);
+    print_const(ops, options.os, rom.messages[Message::Newline as usize]);
+    for (index, &reg) in TRACE_REGISTERS.iter().enumerate() {
+        dynasm!(ops
+            ; mov Rq(reg), QWORD [(save + 8 * index) as i32]
+        );
+    }
+    outline::assemble_return(ops, ram_start);
+}
+
+/// The routine the `exit` intrinsic jumps to when counting: write the name and
+/// count of every declaration to stderr, a line each, and exit with the code
+/// in r1.
+fn exit_with_counts(ops: &mut Assembler, options: &Options, rom: &rom::Layout, ram_start: usize) {
+    let declarations = rom.closures.len();
+    let counters = ram_start + options.memory.heap_offset;
+    let end = counters + 8 * declarations + COUNTS_BUFFER;
+    let names = rom.ranges.expect("Counting needs the table of code ranges") + 16;
+    assert!(end <= i32::max_value() as usize);
+    assert!(names <= i32::max_value() as usize);
+    // The exit code and the index survive the system calls in r15 and r12
+    dynasm!(ops
+        ; ->exit_with_counts:
+        ; mov r15, r1
+        ; xor r12d, r12d
+        ; counts_next:
+        ; cmp r12, DWORD declarations as i32
+        ; je >counts_done
+        ; imul r6, r12, 24
+        ; mov r6, QWORD [r6 + names as i32]
+    );
+    print_r6(ops, options.os);
+    // Format ` <count>\n` backwards from the end of the buffer, and prefix
+    // the length to make it a string record
+    dynasm!(ops
+        ; mov r0, QWORD [r12 * 8 + counters as i32]
+        ; mov r13d, DWORD end as i32
+        ; dec r13
+        ; mov BYTE [r13], b'\n' as i8
+        ; mov r14d, DWORD 10
+        ; counts_digit:
+        ; xor r2d, r2d
+        ; div r14
+        ; add r2b, b'0' as i8
+        ; dec r13
+        ; mov BYTE [r13], r2b
+        ; test r0, r0
+        ; jnz <counts_digit
+        ; dec r13
+        ; mov BYTE [r13], b' ' as i8
+        ; mov r6d, DWORD end as i32
+        ; sub r6, r13
+        ; mov DWORD [r13 - STRING_BYTES + STRING_LENGTH], r6d
+        ; lea r6, [r13 - STRING_BYTES]
+    );
+    print_r6(ops, options.os);
+    dynasm!(ops
+        ; inc r12
+        ; jmp <counts_next
+        ; counts_done:
+        ; mov r1, r15
+    );
+    intrinsics::sys_exit(ops, options.os);
+}
+
+/// Write the length-prefixed string at r6 to stderr. Clobbers r0, r1, r2, r6,
+/// r7 and r11.
+fn print_r6(ops: &mut Assembler, os: Os) {
+    if let Os::Freestanding { mmio } = os {
+        freestanding::write(ops, mmio, Port::Diagnostics);
+        return;
+    }
+    dynasm!(ops
+        ; mov r0d, DWORD Syscall::Write.number(os)
+        ; mov r7d, BYTE 2
+        ; mov r2d, [r6 + STRING_LENGTH]
+        ; add r6, STRING_BYTES
+        ; syscall
+    );
+}
+
+/// Write the length-prefixed string at `address` to stderr.
+fn print_const(ops: &mut Assembler, os: Os, address: usize) {
+    assert!(address <= (u32::max_value() as usize));
+    if let Os::Freestanding { mmio } = os {
+        dynasm!(ops
+            ; mov r6d, DWORD address as i32
+        );
+        freestanding::write(ops, mmio, Port::Diagnostics);
+        return;
+    }
+    dynasm!(ops
+        ; mov r0d, DWORD Syscall::Write.number(os)
+        ; mov r7d, BYTE 2
+        ; mov r6d, DWORD address as i32 + STRING_BYTES
+        ; mov r2d, DWORD [address as i32 + STRING_LENGTH]
+        ; syscall
+    );
+}
+
+/// Write the length-prefixed string pointed to by `reg` to stderr. The
+/// register must survive the syscall, so it can not be r0, r1, r2, r6, r7 or
+/// r11.
+pub(crate) fn print_reg(ops: &mut Assembler, os: Os, reg: u8) {
+    if let Os::Freestanding { mmio } = os {
+        dynasm!(ops
+            ; mov r6, Rq(reg)
+        );
+        freestanding::write(ops, mmio, Port::Diagnostics);
+        return;
+    }
+    dynasm!(ops
+        ; mov r0d, DWORD Syscall::Write.number(os)
+        ; mov r7d, BYTE 2
+        ; lea r6, [Rq(reg) + STRING_BYTES]
+        ; mov r2d, [Rq(reg) + STRING_LENGTH]
+        ; syscall
+    );
+}
+
+/// Exit with `PANIC_EXIT_CODE`.
+pub(crate) fn abort(ops: &mut Assembler, os: Os) {
+    if let Os::Freestanding { mmio } = os {
+        dynasm!(ops
+            ; mov r7d, BYTE PANIC_EXIT_CODE as i32
+        );
+        freestanding::exit(ops, mmio);
+        return;
+    }
+    dynasm!(ops
+        ; mov r0d, DWORD Syscall::Exit.number(os)
+        ; mov r7d, BYTE PANIC_EXIT_CODE as i32
+        ; syscall
+    );
+}
@@ -0,0 +1,173 @@
+#[cfg(feature = "native")]
+use codegen::{codegen, Features, Target};
+use codegen::{compile_c, compile_wasm, Options};
+use parser::{mir::Module, parse_file, parse_mir};
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+// End-to-end tests. Each `tests/e2e/<name>.olus` program, or textual MIR in
+// `<name>.mir`, is compiled to an executable, which is run and checked
+// against `<name>.stdout` and, if present, the expected exit code in
+// `<name>.status` (default zero). The transitions planned for each program
+// are snapshot in `<name>.transitions` by a unit test in `code/emit.rs`.
+//
+// Programs are compiled for every target, with greedily planned transitions
+// and with the search for the cheapest ones, and the executables for the host
+// are run. On other hosts the programs are still compiled. They are also
+// compiled to WebAssembly and run with `wasm-host.js` if `node` is available,
+// and to C and run if there is a `cc` to build it with. Without the `native`
+// feature only the latter two are run.
+
+/// Run the program as WebAssembly, if there is a `node` to run it with.
+fn run_wasm(name: &str, module: &Module) -> Option<Output> {
+    let wasm = env::temp_dir().join(format!("olus-e2e-{}.wasm", name));
+    fs::write(&wasm, compile_wasm(module, &Options::default()).unwrap()).unwrap();
+    let host = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/wasm-host.js");
+    let output = Command::new("node").arg(host).arg(&wasm).output().ok();
+    fs::remove_file(&wasm).unwrap();
+    output
+}
+
+/// Run the program through C, if there is a `cc` to compile it with.
+fn run_c(name: &str, module: &Module) -> Option<Output> {
+    let source = env::temp_dir().join(format!("olus-e2e-{}.c", name));
+    let executable = env::temp_dir().join(format!("olus-e2e-{}-c", name));
+    fs::write(&source, compile_c(module, &Options::default()).unwrap()).unwrap();
+    let cc = Command::new("cc")
+        .args(&["-std=c99", "-Wall", "-Werror", "-o"])
+        .arg(&executable)
+        .arg(&source)
+        .status()
+        .ok()?;
+    fs::remove_file(&source).unwrap();
+    assert!(cc.success());
+    let output = Command::new(&executable).output().unwrap();
+    fs::remove_file(&executable).unwrap();
+    Some(output)
+}
+
+fn e2e(name: &str) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/e2e");
+    let mir = dir.join(format!("{}.mir", name));
+    let module = if mir.exists() {
+        parse_mir(&fs::read_to_string(mir).unwrap()).unwrap()
+    } else {
+        parse_file(&dir.join(format!("{}.olus", name))).unwrap()
+    };
+    let mut outputs = Vec::new();
+    #[cfg(feature = "native")]
+    for &optimize in &[false, true] {
+        let options = Options {
+            features: Features::host(),
+            optimize,
+            ..Options::default()
+        };
+        for &target in &Target::ALL {
+            let executable = env::temp_dir().join(format!("olus-e2e-{}-{}", name, target));
+            codegen(&module, &options, target, &executable).unwrap();
+            if Target::host() == Some(target) {
+                outputs.push(Command::new(&executable).output().unwrap());
+            }
+            fs::remove_file(&executable).unwrap();
+        }
+    }
+    outputs.extend(run_wasm(name, &module));
+    outputs.extend(run_c(name, &module));
+
+    let expected_stdout = fs::read_to_string(dir.join(format!("{}.stdout", name))).unwrap();
+    let expected_status = fs::read_to_string(dir.join(format!("{}.status", name)))
+        .map_or(0, |status| status.trim().parse().unwrap());
+    for output in outputs {
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected_stdout);
+        assert_eq!(output.status.code(), Some(expected_status));
+    }
+}
+
+#[test]
+fn hello() {
+    e2e("hello");
+}
+
+#[test]
+fn exit_code() {
+    e2e("exit-code");
+}
+
+#[test]
+fn print_twice() {
+    e2e("print-twice");
+}
+
+/// Reads a string from ROM and captures it in a heap allocated closure.
+#[test]
+fn rom_heap() {
+    e2e("rom-heap");
+}
+
+/// Textual MIR input, with the argument names shadowing an import.
+#[test]
+fn mir() {
+    e2e("mir");
+}
+
+/// Arithmetic on tagged numbers wraps and overflows at 63 bits.
+#[test]
+fn arithmetic() {
+    e2e("arithmetic");
+}
+
+/// Negative literals and signed comparisons on the 63 bits.
+#[test]
+fn signed() {
+    e2e("signed");
+}
+
+/// Strings allocated by `substring` and `charAt`, with a closure allocated
+/// after them.
+#[test]
+fn strings() {
+    e2e("strings");
+}
+
+/// Tuples are changed in place, can hold themselves and abort on an index
+/// out of bounds.
+#[test]
+fn tuples() {
+    e2e("tuples");
+}
+
+/// Calls with more arguments than registers pass the rest in memory, to a
+/// loop, a closure with captures and its continuation.
+#[test]
+fn many_arguments() {
+    e2e("many-arguments");
+}
+
+/// The table of declarations of the debug runtime takes the ROM of bigger
+/// programs past a page. Searching for the transitions of this one takes too
+/// long, so it is not in `tests/e2e` and only compiled greedily.
+#[cfg(feature = "native")]
+#[test]
+fn debug_runtime() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let module = parse_file(&dir.join("loops.olus")).unwrap();
+    let options = Options {
+        features: Features::host(),
+        debug_runtime: true,
+        ..Options::default()
+    };
+    let expected_stdout = fs::read_to_string(dir.join("loops.stdout")).unwrap();
+    for &target in &Target::ALL {
+        let executable = env::temp_dir().join(format!("olus-e2e-loops-{}", target));
+        codegen(&module, &options, target, &executable).unwrap();
+        if Target::host() == Some(target) {
+            let output = Command::new(&executable).output().unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), expected_stdout);
+            assert_eq!(output.status.code(), Some(0));
+        }
+        fs::remove_file(&executable).unwrap();
+    }
+}
@@ -0,0 +1,15 @@
+// Runs a WebAssembly module compiled by Oluś: `node wasm-host.js <module>`.
+// Provides the `env.write` import and exits with the code `run` returns.
+const fs = require('fs');
+
+const bytes = fs.readFileSync(process.argv[2]);
+let memory;
+const env = {
+    write(fd, pointer, length) {
+        fs.writeSync(fd, new Uint8Array(memory.buffer, pointer, length));
+    },
+};
+WebAssembly.instantiate(bytes, { env }).then(({ instance }) => {
+    memory = instance.exports.memory;
+    process.exitCode = instance.exports.run();
+});
@@ -0,0 +1,74 @@
+use codegen::{compile_to_bytes, Options, Target};
+use olus::interpreter::{Interpeter, Value};
+use parser::{mir::Module, parse_str};
+use std::{env, error::Error, path::PathBuf};
+
+// Embedding the Oluś compiler as a library: parse a program from a string,
+// inspect its MIR, interpret it with a builtin provided by the host and
+// compile it to an executable.
+//
+// Run with `cargo run --example embed [output]`.
+
+/// Interpreted with the host builtin `greet`.
+const HOSTED: &str = "main ↦
+    greet “embedder” (↦)
+    exit 0
+";
+
+/// Compiled to an executable, so it only uses compiler intrinsics.
+const STANDALONE: &str = "main ↦
+    print “Hello, World!
+” (↦)
+    exit 0
+";
+
+/// `greet name return` prints a greeting and calls `return`.
+fn greet<'module>(arguments: &[Value<'module>]) -> Option<Vec<Value<'module>>> {
+    match arguments {
+        [Value::String(name), ret] => {
+            println!("Hello, {}!", name);
+            Some(vec![ret.clone()])
+        }
+        _ => None,
+    }
+}
+
+fn summarize(module: &Module) {
+    println!(
+        "{} symbols, {} imports: {}",
+        module.symbols.len(),
+        module.imports.len(),
+        module.imports.join(", ")
+    );
+    for declaration in &module.declarations {
+        let name = &module.symbols[declaration.procedure[0]];
+        println!(
+            "  {} takes {} arguments and captures {} values",
+            if name.is_empty() { "λ" } else { name },
+            declaration.procedure.len() - 1,
+            declaration.closure.len()
+        );
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let hosted = parse_str(HOSTED)?;
+    summarize(&hosted);
+    let mut interpreter = Interpeter::new(&hosted);
+    interpreter.register("greet", greet);
+    interpreter.eval_by_name("main", &[]);
+
+    let standalone = parse_str(STANDALONE)?;
+    summarize(&standalone);
+    let destination = env::args().nth(1).map_or_else(|| "a.out".into(), PathBuf::from);
+    let target = Target::host().unwrap_or_default();
+    let assembly = compile_to_bytes(&standalone, &Options::default(), target)?;
+    println!(
+        "Compiled {} bytes of code and {} bytes of ROM",
+        assembly.code.len(),
+        assembly.rom.len()
+    );
+    assembly.save(target, &destination)?;
+    println!("Wrote {} for {}", destination.display(), target);
+    Ok(())
+}
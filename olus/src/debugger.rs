@@ -0,0 +1,333 @@
+use crate::interpreter::{Interpeter, State, Value};
+use codegen::{
+    machine::{self, Register},
+    Goals,
+};
+use parser::mir::Module;
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+    ptr,
+};
+
+// An interactive debugger on top of the interpreter, reading one command per
+// line. It stops before calls to declarations with a breakpoint, where the
+// arguments and captures of the closure can be printed by name. The program
+// writes its output to stdout as usual. With the goal states of the compiled
+// program, stepping out of a closure also shows the registers its code should
+// end with, to compare with a debugger on the executable.
+
+const HELP: &str = "\
+break NAME     stop before calls to declaration NAME
+delete NAME    remove the breakpoint on NAME
+step [COUNT]   make the current call, or COUNT calls
+continue       run until a breakpoint or the end
+print NAME     show the value of NAME in the current closure
+call           show the current call
+quit           stop debugging
+";
+
+struct Debugger<'module> {
+    module:      &'module Module,
+    state:       State<'module>,
+    breakpoints: HashSet<String>,
+    goals:       Option<&'module Goals>,
+}
+
+/// Debug the program starting at entry point `entry`, taking commands from
+/// `input` until it ends or runs out and replying on `output`. Returns the
+/// exit code, or `None` if the program did not end. `goals` are those of
+/// `module` compiled, see [`codegen::Compiled::goals`].
+///
+/// # Panics
+///
+/// Panics if `entry` is not an entry point, see [`Module::entry`], or if
+/// there are not as many goals as declarations.
+pub fn debug<'module, R: BufRead, W: Write>(
+    module: &'module Module,
+    entry: &str,
+    goals: Option<&'module Goals>,
+    input: R,
+    mut output: W,
+) -> io::Result<Option<u64>> {
+    if let Some(goals) = goals {
+        assert_eq!(goals.states.len(), module.declarations.len());
+    }
+    let mut interpreter = Interpeter::new(module);
+    interpreter.trace_captures(true);
+    let mut debugger = Debugger {
+        module,
+        state: interpreter.start(entry, &[]),
+        breakpoints: HashSet::new(),
+        goals,
+    };
+    writeln!(output, "{}", debugger.call())?;
+    let mut lines = input.lines();
+    loop {
+        write!(output, "(olus) ")?;
+        output.flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let mut words = line.split_whitespace();
+        let reply = match (words.next(), words.next(), words.next()) {
+            (None, ..) => continue,
+            (Some("quit" | "q"), None, _) => break,
+            (Some("help" | "h"), None, _) => HELP.trim_end().to_string(),
+            (Some("break" | "b"), Some(name), None) => debugger.add_breakpoint(name),
+            (Some("delete" | "d"), Some(name), None) => {
+                if debugger.breakpoints.remove(name) {
+                    format!("Removed the breakpoint on {}", name)
+                } else {
+                    format!("No breakpoint on {}", name)
+                }
+            }
+            (Some("step" | "s"), count, None) => {
+                match count.map_or(Ok(1), str::parse) {
+                    Ok(count) => debugger.step(count),
+                    Err(_) => format!("Invalid count {}", count.unwrap()),
+                }
+            }
+            (Some("continue" | "c"), None, _) => debugger.resume(),
+            (Some("print" | "p"), Some(name), None) => debugger.print(name),
+            (Some("call"), None, _) => debugger.call(),
+            _ => format!("Unknown command {:?}, try help", line.trim()),
+        };
+        writeln!(output, "{}", reply)?;
+    }
+    Ok(debugger.state.status())
+}
+
+impl<'module> Debugger<'module> {
+    fn add_breakpoint(&mut self, name: &str) -> String {
+        let exists = self
+            .module
+            .declarations
+            .iter()
+            .any(|declaration| self.module.symbols[declaration.procedure[0]] == name);
+        if !exists {
+            return format!("No declaration {}", name);
+        }
+        let _ = self.breakpoints.insert(name.to_string());
+        format!("Breakpoint on {}", name)
+    }
+
+    /// Whether the current call is to a declaration with a breakpoint.
+    fn at_breakpoint(&self) -> bool {
+        match self.state.call().first() {
+            Some(Value::Closure(closure)) => {
+                let symbol = closure.declaration().procedure[0];
+                self.breakpoints.contains(&self.module.symbols[symbol])
+            }
+            _ => false,
+        }
+    }
+
+    /// Make `count` calls, showing the goal of the last one if it was to a
+    /// closure.
+    fn step(&mut self, count: usize) -> String {
+        let mut goal = None;
+        for _ in 0..count {
+            goal = self.goal();
+            if !self.state.step() {
+                goal = None;
+                break;
+            }
+        }
+        match goal {
+            Some(goal) => goal + &self.call(),
+            None => self.call(),
+        }
+    }
+
+    /// The goal state of the closure being called, with the values of the
+    /// symbols in it, one line per register and allocation.
+    fn goal(&self) -> Option<String> {
+        let closure = match self.state.call().first()? {
+            Value::Closure(closure) => closure,
+            _ => return None,
+        };
+        let index = self
+            .module
+            .declarations
+            .iter()
+            .position(|declaration| ptr::eq(declaration, closure.declaration()))?;
+        let goal = &self.goals?.states[index];
+        let mut out = String::new();
+        let symbol = closure.declaration().procedure[0];
+        writeln!(out, "Goal of {}", self.name(symbol)).unwrap();
+        for index in 0..16 {
+            let value = goal.get_register(Register::new(index));
+            if value.is_specified() {
+                writeln!(out, "    r{} = {}", index, self.describe(value)).unwrap();
+            }
+        }
+        if goal.flags().is_specified() {
+            writeln!(out, "    flags = {}", self.describe(goal.flags())).unwrap();
+        }
+        for (index, allocation) in goal.allocations().iter().enumerate() {
+            let values = allocation.iter().map(|value| self.describe(*value));
+            let values = values.collect::<Vec<_>>().join(", ");
+            writeln!(out, "    {}: {}", index, values).unwrap();
+        }
+        Some(out)
+    }
+
+    /// A value of a goal, with the value of the symbol if it is one.
+    fn describe(&self, value: machine::Value) -> String {
+        match value {
+            machine::Value::Symbol(symbol) => {
+                let resolved = self.state.resolve(symbol);
+                let resolved = resolved.map_or("?".to_string(), |value| self.state.show(&value));
+                format!("{} = {}", self.name(symbol), resolved)
+            }
+            _ => value.to_string(),
+        }
+    }
+
+    fn name(&self, symbol: usize) -> String {
+        let name = &self.module.symbols[symbol];
+        if name.is_empty() {
+            format!("λ{}", symbol)
+        } else {
+            name.clone()
+        }
+    }
+
+    fn resume(&mut self) -> String {
+        while self.state.step() && !self.at_breakpoint() {}
+        self.call()
+    }
+
+    /// The current call, or how the program ended.
+    fn call(&self) -> String {
+        if let Some(code) = self.state.status() {
+            return format!("Exited with code {}", code);
+        }
+        let values = self.state.call().iter().map(|value| self.state.show(value));
+        let call = values.collect::<Vec<_>>().join(" ");
+        if self.at_breakpoint() {
+            format!("Breakpoint ⇒ {}", call)
+        } else {
+            format!("⇒ {}", call)
+        }
+    }
+
+    /// The value of the argument, capture or declaration `name` is in the
+    /// closure being called. Nested scopes can reuse a name, so this tries
+    /// each symbol with it.
+    fn print(&self, name: &str) -> String {
+        let declaration = match self.state.call().first() {
+            Some(Value::Closure(closure)) => closure.declaration(),
+            _ => return "Not in a closure".to_string(),
+        };
+        let value = (0..self.module.symbols.len())
+            .filter(|&symbol| self.module.symbols[symbol] == name)
+            .filter(|symbol| {
+                declaration.procedure.contains(symbol)
+                    || declaration.closure.contains(symbol)
+                    || self.module.declaration(*symbol).is_some()
+            })
+            .find_map(|symbol| self.state.resolve(symbol));
+        match value {
+            Some(value) => format!("{} = {}", name, self.state.show(&value)),
+            None => format!("No {} in {}", name, self.state.show(&self.state.call()[0])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codegen::Target;
+    use parser::parse_str;
+
+    /// The replies to `commands`, one per line, and the exit code.
+    fn session(source: &str, commands: &str) -> (Vec<String>, Option<u64>) {
+        session_with_goals(source, false, commands)
+    }
+
+    /// Like `session`, with the goals of the program compiled for Linux if
+    /// `goals` is set.
+    fn session_with_goals(source: &str, goals: bool, commands: &str) -> (Vec<String>, Option<u64>) {
+        let module = parse_str(source).unwrap();
+        let goals = if goals {
+            let options = codegen::Options::default();
+            let compiled = codegen::compile(&module, &options, Target::X86_64UnknownLinuxGnu);
+            Some(compiled.unwrap().goals(&module, &options).unwrap())
+        } else {
+            None
+        };
+        let mut output = Vec::new();
+        let input = commands.as_bytes();
+        let status = debug(&module, "main", goals.as_ref(), input, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let replies = output.split("(olus) ").map(str::trim_end).map(String::from);
+        (replies.collect(), status)
+    }
+
+    #[test]
+    fn test_debug() {
+        let source = "f a k ↦ sub a 1 (b ↦ k a b)\nmain ↦ f 5 (_ r ↦ exit r)\n";
+        let (replies, status) = session(
+            source,
+            "break f\nbreak g\ncontinue\nprint a\nprint k\nprint x\nstep 2\nprint \
+             b\ndelete f\ncontinue\nstep\n",
+        );
+        assert_eq!(replies, vec![
+            "⇒ main",
+            "Breakpoint on f",
+            "No declaration g",
+            "Breakpoint ⇒ f 5 main.λ1",
+            "a = 5",
+            "k = main.λ1",
+            "No x in f",
+            "⇒ f.λ1{a = 5, k = main.λ1} 4",
+            "b = 4",
+            "Removed the breakpoint on f",
+            "Exited with code 4",
+            "Exited with code 4",
+            "",
+        ]);
+        assert_eq!(status, Some(4));
+    }
+
+    #[test]
+    fn test_commands() {
+        let source = "main ↦ exit 0\n";
+        let (replies, status) = session(source, "\nfrobnicate\nstep x\nprint main\nquit\nstep\n");
+        assert_eq!(replies, vec![
+            "⇒ main",
+            // Empty lines do nothing
+            "",
+            "Unknown command \"frobnicate\", try help",
+            "Invalid count x",
+            "main = main",
+            "",
+        ]);
+        // The program does not run past quitting
+        assert_eq!(status, None);
+    }
+
+    #[test]
+    fn test_goals() {
+        let source = "f a k ↦ sub a 1 (b ↦ k a b)\nmain ↦ f 5 (_ r ↦ exit r)\n";
+        let (replies, status) = session_with_goals(source, true, "break f\ncontinue\nstep\n");
+        assert_eq!(status, None);
+        let lines = replies[3].lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "Goal of f");
+        assert!(lines[1].starts_with("    r0 = 0x"));
+        assert_eq!(lines[2..5], [
+            "    r1 = a = 5",
+            "    r2 = 0x0000000000000003",
+            "    r3 = 0[0]",
+        ]);
+        assert!(lines[5].ends_with(", a = 5, k = main.λ1"));
+        assert_eq!(lines[6], "⇒ sub 5 1 f.λ1{a = 5, k = main.λ1}");
+        // Calls to builtins have no goal
+        let (replies, _) = session_with_goals(source, true, "step 2\nstep\n");
+        assert!(replies[2].starts_with("⇒ f.λ1"));
+    }
+}
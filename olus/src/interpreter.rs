@@ -1,14 +1,66 @@
-use std::unimplemented;
+use std::{cell::RefCell, collections::HashMap, convert::TryFrom, fmt::Write, rc::Rc, unimplemented};
 
-use parser::mir::{Declaration, Expression, Module};
+use log::{info, log_enabled, trace, warn, Level};
+use parser::mir::{Declaration, Expression, Module, NUMBER_MAX};
+
+/// Builtins implemented by the interpreter, as reported by `hasBuiltin`.
+pub(crate) const BUILTINS: [&str; 22] = [
+    "print",
+    "exit",
+    "isZero",
+    "sub",
+    "add",
+    "divmod",
+    "mul",
+    "addChecked",
+    "subChecked",
+    "mulChecked",
+    "neg",
+    "isNegative",
+    "lessSigned",
+    "compilerVersion",
+    "hasBuiltin",
+    "abort",
+    "panic",
+    "charAt",
+    "substring",
+    "newTuple",
+    "tupleGet",
+    "tupleSet",
+];
+
+/// Exit code of `abort` and `panic`, the same as generated code uses.
+const PANIC_EXIT_CODE: u64 = 101;
+
+/// A builtin provided by the embedding program. It receives the arguments of
+/// the call and returns the next call, or `None` if the arguments are invalid.
+pub type HostBuiltin<'module> = fn(&[Value<'module>]) -> Option<Vec<Value<'module>>>;
 
 pub struct Interpeter<'module> {
-    module: &'module Module,
+    module:   &'module Module,
+    host:     HashMap<String, HostBuiltin<'module>>,
+    captures: bool,
 }
 
 pub struct State<'module> {
-    module: &'module Module,
-    call:   Vec<Value<'module>>,
+    module:   &'module Module,
+    host:     HashMap<String, HostBuiltin<'module>>,
+    /// Show captured values and tuple elements when tracing.
+    captures: bool,
+    call:     Vec<Value<'module>>,
+    /// Collects the output instead of printing it.
+    capture:  Option<Output>,
+    /// The exit code, once the program ended.
+    status:   Option<u64>,
+}
+
+/// What a program wrote and how it ended, see `Interpeter::capture_by_name`.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Output {
+    pub stdout: String,
+    pub stderr: String,
+    /// The exit code, or `None` if the program got stuck.
+    pub status: Option<u64>,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -17,60 +69,134 @@ pub enum Value<'module> {
     Closure(Closure<'module>),
     String(String),
     Number(u64),
+    /// Shared, `tupleSet` changes it for every holder.
+    Tuple(Rc<RefCell<Vec<Value<'module>>>>),
 }
 
 #[derive(Clone, PartialEq, Debug)]
-struct Closure<'module> {
+pub struct Closure<'module> {
     declaration: &'module Declaration,
     closure:     Vec<Value<'module>>,
 }
 
+impl<'module> Closure<'module> {
+    #[must_use]
+    pub const fn declaration(&self) -> &'module Declaration {
+        self.declaration
+    }
+
+    /// Values of the captured symbols, in the order of
+    /// `Declaration::closure`.
+    #[must_use]
+    pub fn captures(&self) -> &[Value<'module>] {
+        &self.closure
+    }
+}
+
 impl<'module> Interpeter<'module> {
     pub fn new(module: &'module Module) -> Self {
-        dbg!(module);
-        Self { module }
+        trace!("Interpreting\n{}", module);
+        Self {
+            module,
+            host: HashMap::new(),
+            captures: false,
+        }
+    }
+
+    /// Have the trace show the values closures capture and the elements of
+    /// tuples, instead of just their names and lengths.
+    pub fn trace_captures(&mut self, captures: bool) {
+        self.captures = captures;
+    }
+
+    /// Make `builtin` available to the program under `name`. Host builtins
+    /// take precedence over the interpreter's own builtins.
+    pub fn register(&mut self, name: &str, builtin: HostBuiltin<'module>) {
+        self.host.insert(name.to_string(), builtin);
     }
 
-    pub fn eval_by_name(&self, name: &str, arguments: &[Value<'module>]) {
-        // Find name
+    /// Run, printing the output. Returns the exit code, or `None` if the
+    /// program got stuck. Every step is logged at trace level.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not an entry point, see [`Module::entry`].
+    pub fn eval_by_name(&self, name: &str, arguments: &[Value<'module>]) -> Option<u64> {
+        let mut state = self.start(name, arguments);
+        state.run();
+        state.status
+    }
+
+    /// Run like `eval_by_name`, but collect the output and exit code instead
+    /// of printing them.
+    pub fn capture_by_name(&self, name: &str, arguments: &[Value<'module>]) -> Output {
+        let mut state = self.start(name, arguments);
+        state.capture = Some(Output::default());
+        state.run();
+        Output {
+            status: state.status,
+            ..state.capture.unwrap()
+        }
+    }
+
+    /// The state calling entry point `name` with `arguments`, for running the
+    /// program step by step.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not an entry point, see [`Module::entry`].
+    #[must_use]
+    pub fn start(&self, name: &str, arguments: &[Value<'module>]) -> State<'module> {
         let index = self
             .module
-            .symbols
-            .iter()
-            .position(|item| item == name)
-            .expect("Function not found");
-        if !self.module.names[index] {
-            panic!("Symbol is not a proper name");
-        }
+            .entry(name)
+            .unwrap_or_else(|error| panic!("{}", error));
 
         // Set initial state
         let closure = Value::Closure(Closure {
-            declaration: self
-                .module
-                .declaration(index)
-                .expect("Symbol is not a proper name"),
+            declaration: &self.module.declarations[index],
             closure:     vec![],
         });
-        let mut state = State {
-            module: self.module,
-            call:   std::iter::once(closure)
+        State {
+            module:   self.module,
+            host:     self.host.clone(),
+            captures: self.captures,
+            call:     std::iter::once(closure)
                 .chain(arguments.iter().cloned())
                 .collect(),
-        };
-
-        // Run till completion
-        state.run();
+            capture:  None,
+            status:   None,
+        }
     }
 }
 
 impl<'module> State<'module> {
-    fn run(&mut self) {
+    /// Step until the program ends or gets stuck. Steps are a loop, not
+    /// recursion, so there is no limit on the depth of calls.
+    pub fn run(&mut self) {
         while self.step() {}
     }
 
-    fn step(&mut self) -> bool {
+    /// The current call, the closure or builtin to call and its arguments.
+    /// Empty once the program ended.
+    #[must_use]
+    pub fn call(&self) -> &[Value<'module>] {
+        &self.call
+    }
+
+    /// The exit code, once the program ended.
+    #[must_use]
+    pub const fn status(&self) -> Option<u64> {
+        self.status
+    }
+
+    /// Make the current call. Returns `false` if there is none because the
+    /// program ended, or if the builtin called can not take its arguments,
+    /// which leaves the program stuck with the call unchanged.
+    pub fn step(&mut self) -> bool {
         self.pretty_print();
         match self.call.first() {
+            Some(Value::Builtin(s)) if self.host.contains_key(s) => self.host_builtin().is_some(),
             Some(Value::Builtin(s)) => {
                 match s.as_ref() {
                     "print" => self.print().is_some(),
@@ -80,6 +206,21 @@ impl<'module> State<'module> {
                     "add" => self.add().is_some(),
                     "divmod" => self.divmod().is_some(),
                     "mul" => self.mul().is_some(),
+                    "addChecked" => self.add_checked().is_some(),
+                    "subChecked" => self.sub_checked().is_some(),
+                    "mulChecked" => self.mul_checked().is_some(),
+                    "neg" => self.neg().is_some(),
+                    "isNegative" => self.is_negative().is_some(),
+                    "lessSigned" => self.less_signed().is_some(),
+                    "compilerVersion" => self.compiler_version().is_some(),
+                    "hasBuiltin" => self.has_builtin().is_some(),
+                    "abort" => self.abort().is_some(),
+                    "panic" => self.panic().is_some(),
+                    "charAt" => self.char_at().is_some(),
+                    "substring" => self.substring().is_some(),
+                    "newTuple" => self.new_tuple().is_some(),
+                    "tupleGet" => self.tuple_get().is_some(),
+                    "tupleSet" => self.tuple_set().is_some(),
                     _ => unimplemented!(),
                 }
             }
@@ -110,7 +251,11 @@ impl<'module> State<'module> {
         }
     }
 
-    fn resolve(&self, symbol: usize) -> Option<Value<'module>> {
+    /// The value `symbol` has in the closure being called: an argument, a
+    /// capture or a new closure of a declaration. `None` if the current call
+    /// is not to a closure or the symbol is unbound there.
+    #[must_use]
+    pub fn resolve(&self, symbol: usize) -> Option<Value<'module>> {
         // Resolve only works in a closure
         let closure = match self.call.first()? {
             Value::Closure(closure) => Some(closure),
@@ -156,29 +301,103 @@ impl<'module> State<'module> {
         }
 
         // Builtin?
-        println!("Could not resolve symbol {}", self.module.symbols[symbol]);
+        warn!("Could not resolve symbol {}", self.module.symbols[symbol]);
         return None;
     }
 
+    /// Log the current call at trace level.
     pub fn pretty_print(&self) {
-        print!("\n⇒ ");
+        if self.call.is_empty() || !log_enabled!(Level::Trace) {
+            return;
+        }
+        let mut line = "⇒".to_string();
         for value in &self.call {
-            match value {
-                Value::Builtin(name) => print!("{} ", name),
-                Value::String(s) => print!("“{}” ", s),
-                Value::Number(n) => print!("{} ", n),
-                Value::Closure(c) => {
-                    let symbol = c.declaration.procedure[0];
-                    let name = &self.module.symbols[symbol];
-                    if name.is_empty() {
-                        print!("λ{} ", symbol);
-                    } else {
-                        print!("{} ", name);
+            line.push(' ');
+            line.push_str(&self.show(value));
+        }
+        trace!("{}", line);
+    }
+
+    /// `value` the way the trace shows it.
+    #[must_use]
+    pub fn show(&self, value: &Value<'module>) -> String {
+        let mut out = String::new();
+        self.render(&mut out, value, &mut Vec::new());
+        out
+    }
+
+    fn name(&self, symbol: usize) -> String {
+        let name = &self.module.symbols[symbol];
+        if name.is_empty() {
+            format!("λ{}", symbol)
+        } else {
+            name.clone()
+        }
+    }
+
+    /// Append `value` to `out`, with captures if enabled. `open` holds the
+    /// declarations and tuples being rendered. Meeting one again is a cycle,
+    /// or a recursion that could be as long as the program ran, and is cut
+    /// short with `…`.
+    fn render(&self, out: &mut String, value: &Value<'module>, open: &mut Vec<*const ()>) {
+        match value {
+            Value::Builtin(name) => out.push_str(name),
+            Value::String(s) => write!(out, "“{}”", s).unwrap(),
+            Value::Number(n) => write!(out, "{}", n).unwrap(),
+            Value::Closure(c) => {
+                out.push_str(&self.name(c.declaration.procedure[0]));
+                if !self.captures || c.closure.is_empty() {
+                    return;
+                }
+                let key = (c.declaration as *const Declaration).cast();
+                if open.contains(&key) {
+                    out.push_str("{…}");
+                    return;
+                }
+                open.push(key);
+                out.push('{');
+                let captures = c.declaration.closure.iter().zip(&c.closure);
+                for (index, (&symbol, value)) in captures.enumerate() {
+                    if index > 0 {
+                        out.push_str(", ");
+                    }
+                    write!(out, "{} = ", self.name(symbol)).unwrap();
+                    self.render(out, value, open);
+                }
+                out.push('}');
+                let _ = open.pop();
+            }
+            Value::Tuple(t) => {
+                if !self.captures {
+                    write!(out, "⟨{} values⟩", t.borrow().len()).unwrap();
+                    return;
+                }
+                let key = Rc::as_ptr(t).cast();
+                if open.contains(&key) {
+                    out.push_str("⟨…⟩");
+                    return;
+                }
+                open.push(key);
+                out.push('⟨');
+                for (index, element) in t.borrow().iter().enumerate() {
+                    if index > 0 {
+                        out.push_str(", ");
                     }
+                    self.render(out, element, open);
                 }
+                out.push('⟩');
+                let _ = open.pop();
             }
         }
-        println!("");
+    }
+
+    fn host_builtin(&mut self) -> Option<()> {
+        let builtin = match self.call.first() {
+            Some(Value::Builtin(name)) => self.host.get(name).copied(),
+            _ => None,
+        }?;
+        self.call = builtin(&self.call[1..])?;
+        Some(())
     }
 
     fn print(&mut self) -> Option<()> {
@@ -191,7 +410,10 @@ impl<'module> State<'module> {
             Value::String(s) => Some(s),
             _ => None,
         }?;
-        print!("{}", string);
+        match &mut self.capture {
+            Some(output) => output.stdout.push_str(string),
+            None => print!("{}", string),
+        }
         self.call = vec![self.call[2].clone()];
         Some(())
     }
@@ -203,8 +425,37 @@ impl<'module> State<'module> {
             Value::Number(n) => Some(n),
             _ => None,
         }?;
-        println!("[EXIT] {}", code);
+        let code = *code;
+        self.end(code);
+        Some(())
+    }
+
+    /// End the program with exit code `code`.
+    fn end(&mut self, code: u64) {
+        info!("Exit code {}", code);
+        self.status = Some(code);
         self.call = vec![];
+    }
+
+    fn abort(&mut self) -> Option<()> {
+        assert_eq!(self.call.first(), Some(&Value::Builtin("abort".to_string())));
+        assert_eq!(self.call.len(), 1);
+        self.end(PANIC_EXIT_CODE);
+        Some(())
+    }
+
+    fn panic(&mut self) -> Option<()> {
+        assert_eq!(self.call.first(), Some(&Value::Builtin("panic".to_string())));
+        assert_eq!(self.call.len(), 2);
+        let message = match &self.call[1] {
+            Value::String(s) => Some(s),
+            _ => None,
+        }?;
+        match &mut self.capture {
+            Some(output) => output.stderr.push_str(message),
+            None => eprint!("{}", message),
+        }
+        self.end(PANIC_EXIT_CODE);
         Some(())
     }
 
@@ -233,7 +484,10 @@ impl<'module> State<'module> {
             Value::Number(n) => Some(n),
             _ => None,
         }?;
-        self.call = vec![self.call[3].clone(), Value::Number(a - b)];
+        self.call = vec![
+            self.call[3].clone(),
+            Value::Number(a.wrapping_sub(*b) & NUMBER_MAX),
+        ];
         Some(())
     }
 
@@ -248,7 +502,10 @@ impl<'module> State<'module> {
             Value::Number(n) => Some(n),
             _ => None,
         }?;
-        self.call = vec![self.call[3].clone(), Value::Number(a + b)];
+        self.call = vec![
+            self.call[3].clone(),
+            Value::Number(a.wrapping_add(*b) & NUMBER_MAX),
+        ];
         Some(())
     }
 
@@ -285,7 +542,414 @@ impl<'module> State<'module> {
             Value::Number(n) => Some(n),
             _ => None,
         }?;
-        self.call = vec![self.call[3].clone(), Value::Number(a * b)];
+        self.call = vec![
+            self.call[3].clone(),
+            Value::Number(a.wrapping_mul(*b) & NUMBER_MAX),
+        ];
+        Some(())
+    }
+
+    fn add_checked(&mut self) -> Option<()> {
+        self.checked_arithmetic("addChecked", u64::checked_add)
+    }
+
+    fn sub_checked(&mut self) -> Option<()> {
+        self.checked_arithmetic("subChecked", u64::checked_sub)
+    }
+
+    fn mul_checked(&mut self) -> Option<()> {
+        self.checked_arithmetic("mulChecked", u64::checked_mul)
+    }
+
+    /// `op a b ok overflow` calls `ok result` or, when the result exceeds
+    /// `NUMBER_MAX`, `overflow` without arguments.
+    fn checked_arithmetic(&mut self, name: &str, op: fn(u64, u64) -> Option<u64>) -> Option<()> {
+        assert_eq!(self.call.first(), Some(&Value::Builtin(name.to_string())));
+        assert_eq!(self.call.len(), 5);
+        let a = match &self.call[1] {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }?;
+        let b = match &self.call[2] {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }?;
+        self.call = match op(*a, *b).filter(|&result| result <= NUMBER_MAX) {
+            Some(result) => vec![self.call[3].clone(), Value::Number(result)],
+            None => vec![self.call[4].clone()],
+        };
+        Some(())
+    }
+
+    fn neg(&mut self) -> Option<()> {
+        assert_eq!(self.call.first(), Some(&Value::Builtin("neg".to_string())));
+        assert_eq!(self.call.len(), 3);
+        let a = match &self.call[1] {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }?;
+        self.call = vec![
+            self.call[2].clone(),
+            Value::Number(a.wrapping_neg() & NUMBER_MAX),
+        ];
+        Some(())
+    }
+
+    fn is_negative(&mut self) -> Option<()> {
+        assert_eq!(
+            self.call.first(),
+            Some(&Value::Builtin("isNegative".to_string()))
+        );
+        assert_eq!(self.call.len(), 4);
+        let n = match &self.call[1] {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }?;
+        self.call = vec![self.call[if signed(*n) < 0 { 2 } else { 3 }].clone()];
+        Some(())
+    }
+
+    fn less_signed(&mut self) -> Option<()> {
+        assert_eq!(
+            self.call.first(),
+            Some(&Value::Builtin("lessSigned".to_string()))
+        );
+        assert_eq!(self.call.len(), 5);
+        let a = match &self.call[1] {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }?;
+        let b = match &self.call[2] {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }?;
+        self.call = vec![self.call[if signed(*a) < signed(*b) { 3 } else { 4 }].clone()];
+        Some(())
+    }
+
+    fn compiler_version(&mut self) -> Option<()> {
+        assert_eq!(
+            self.call.first(),
+            Some(&Value::Builtin("compilerVersion".to_string()))
+        );
+        assert_eq!(self.call.len(), 2);
+        let version = env!("CARGO_PKG_VERSION").to_string();
+        self.call = vec![self.call[1].clone(), Value::String(version)];
+        Some(())
+    }
+
+    fn has_builtin(&mut self) -> Option<()> {
+        assert_eq!(
+            self.call.first(),
+            Some(&Value::Builtin("hasBuiltin".to_string()))
+        );
+        assert_eq!(self.call.len(), 4);
+        let name = match &self.call[1] {
+            Value::String(s) => Some(s),
+            _ => None,
+        }?;
+        let known = BUILTINS.contains(&name.as_str()) || self.host.contains_key(name);
+        self.call = vec![self.call[if known { 2 } else { 3 }].clone()];
+        Some(())
+    }
+
+    fn char_at(&mut self) -> Option<()> {
+        assert_eq!(
+            self.call.first(),
+            Some(&Value::Builtin("charAt".to_string()))
+        );
+        assert_eq!(self.call.len(), 4);
+        let string = match &self.call[1] {
+            Value::String(s) => Some(s),
+            _ => None,
+        }?;
+        let index = match &self.call[2] {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }?;
+        let result = substring(string, *index, 1);
+        self.call = vec![self.call[3].clone(), Value::String(result)];
+        Some(())
+    }
+
+    fn substring(&mut self) -> Option<()> {
+        assert_eq!(
+            self.call.first(),
+            Some(&Value::Builtin("substring".to_string()))
+        );
+        assert_eq!(self.call.len(), 5);
+        let string = match &self.call[1] {
+            Value::String(s) => Some(s),
+            _ => None,
+        }?;
+        let start = match &self.call[2] {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }?;
+        let length = match &self.call[3] {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }?;
+        let result = substring(string, *start, *length);
+        self.call = vec![self.call[4].clone(), Value::String(result)];
+        Some(())
+    }
+
+    fn new_tuple(&mut self) -> Option<()> {
+        assert_eq!(
+            self.call.first(),
+            Some(&Value::Builtin("newTuple".to_string()))
+        );
+        assert_eq!(self.call.len(), 3);
+        let length = match &self.call[1] {
+            Value::Number(n) => usize::try_from(*n).ok(),
+            _ => None,
+        }?;
+        let tuple = vec![Value::Number(0); length];
+        self.call = vec![
+            self.call[2].clone(),
+            Value::Tuple(Rc::new(RefCell::new(tuple))),
+        ];
+        Some(())
+    }
+
+    fn tuple_get(&mut self) -> Option<()> {
+        assert_eq!(
+            self.call.first(),
+            Some(&Value::Builtin("tupleGet".to_string()))
+        );
+        assert_eq!(self.call.len(), 4);
+        let tuple = match &self.call[1] {
+            Value::Tuple(t) => Some(t),
+            _ => None,
+        }?;
+        let index = match &self.call[2] {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }?;
+        // Out of bounds aborts, like compiled code
+        let element = usize::try_from(*index)
+            .ok()
+            .and_then(|index| tuple.borrow().get(index).cloned());
+        match element {
+            Some(element) => self.call = vec![self.call[3].clone(), element],
+            None => self.end(PANIC_EXIT_CODE),
+        }
         Some(())
     }
+
+    fn tuple_set(&mut self) -> Option<()> {
+        assert_eq!(
+            self.call.first(),
+            Some(&Value::Builtin("tupleSet".to_string()))
+        );
+        assert_eq!(self.call.len(), 5);
+        let tuple = match &self.call[1] {
+            Value::Tuple(t) => Some(t),
+            _ => None,
+        }?;
+        let index = match &self.call[2] {
+            Value::Number(n) => Some(n),
+            _ => None,
+        }?;
+        let value = self.call[3].clone();
+        let updated = usize::try_from(*index).ok().and_then(|index| {
+            let mut tuple = tuple.borrow_mut();
+            let element = tuple.get_mut(index)?;
+            *element = value;
+            Some(())
+        });
+        match updated {
+            Some(()) => self.call = vec![self.call[4].clone()],
+            None => self.end(PANIC_EXIT_CODE),
+        }
+        Some(())
+    }
+}
+
+/// The bytes `start..start + length` of `string`, clamped to it. Bytes that
+/// are not whole characters are replaced, compiled code keeps them.
+fn substring(string: &str, start: u64, length: u64) -> String {
+    let bytes = string.as_bytes();
+    let clamp = |n: u64, limit: usize| usize::try_from(n).map_or(limit, |n| n.min(limit));
+    let start = clamp(start, bytes.len());
+    let end = start + clamp(length, bytes.len() - start);
+    String::from_utf8_lossy(&bytes[start..end]).into_owned()
+}
+
+/// The number `n` read as signed, see `NUMBER_MIN`.
+#[allow(clippy::cast_possible_wrap)]
+const fn signed(n: u64) -> i64 {
+    // Move the sign bit of the 63 bits to the top and extend it back down
+    ((n << 1) as i64) >> 1
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parser::{mir::NUMBER_MIN, parse_str};
+
+    #[test]
+    fn test_capture() {
+        let module = parse_str("main ↦\n    print “Hi\n” (↦)\n    exit 3\n").unwrap();
+        let output = Interpeter::new(&module).capture_by_name("main", &[]);
+        assert_eq!(output, Output {
+            stdout: "Hi\n".to_string(),
+            stderr: String::new(),
+            status: Some(3),
+        });
+    }
+
+    #[test]
+    fn test_capture_panic() {
+        let module = parse_str("main ↦\n    panic “Oops”\n").unwrap();
+        let output = Interpeter::new(&module).capture_by_name("main", &[]);
+        assert_eq!(output.stderr, "Oops");
+        assert_eq!(output.status, Some(PANIC_EXIT_CODE));
+    }
+
+    #[test]
+    fn test_entry() {
+        let module = parse_str("main ↦ exit 1
+test ↦ exit 2
+").unwrap();
+        let output = Interpeter::new(&module).capture_by_name("test", &[]);
+        assert_eq!(output.status, Some(2));
+
+        // Entry points that only forward are removed by eta reduction
+        let module = parse_str("main ↦ exit 1\ntest ↦ exit 2\nstart ↦ test\n").unwrap();
+        let output = Interpeter::new(&module).capture_by_name("start", &[]);
+        assert_eq!(output.status, Some(2));
+    }
+
+    #[test]
+    fn test_many_arguments() {
+        // Calls have no limit on arguments, unlike registers in native code
+        let module = parse_str(
+            "last a b c d e f g h i j k l m n o p q r ↦ exit r
+main ↦ last 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18
+",
+        )
+        .unwrap();
+        let output = Interpeter::new(&module).capture_by_name("main", &[]);
+        assert_eq!(output.status, Some(18));
+    }
+
+    #[test]
+    fn test_signed() {
+        assert_eq!(signed(NUMBER_MAX), -1);
+        assert_eq!(signed(NUMBER_MAX / 2), i64::max_value() >> 1);
+        assert_eq!(signed(NUMBER_MAX / 2 + 1), NUMBER_MIN);
+        let module = parse_str(concat!(
+            "main ↦\n",
+            "    neg 5 (a ↦)\n",
+            "    isNegative a (↦) (↦ exit 1)\n",
+            "    sub 0 1 (b ↦)\n",
+            "    lessSigned b a (↦ exit 2) (↦)\n",
+            "    lessSigned -4611686018427387904 b (↦) (↦ exit 3)\n",
+            "    add 4611686018427387903 1 (c ↦)\n",
+            "    isNegative c (↦) (↦ exit 4)\n",
+            "    exit -2\n",
+        ))
+        .unwrap();
+        let output = Interpeter::new(&module).capture_by_name("main", &[]);
+        assert_eq!(output.status, Some(NUMBER_MAX - 1));
+    }
+
+    #[test]
+    fn test_substring() {
+        assert_eq!(substring("Hello", 1, 3), "ell");
+        assert_eq!(substring("Hello", 3, 10), "lo");
+        assert_eq!(substring("Hello", NUMBER_MAX, 1), "");
+        assert_eq!(substring("é", 0, 1), "\u{fffd}");
+        let module = parse_str(concat!(
+            "main ↦\n",
+            "    substring “Hello, world!\n” 7 10 (s ↦)\n",
+            "    charAt s 0 (c ↦)\n",
+            "    print c (↦)\n",
+            "    print s (↦)\n",
+            "    exit 0\n",
+        ))
+        .unwrap();
+        let output = Interpeter::new(&module).capture_by_name("main", &[]);
+        assert_eq!(output.stdout, "wworld!\n");
+    }
+
+    #[test]
+    fn test_step() {
+        let module = parse_str("main a ↦ sub a 1 (_ ↦ exit a)\n").unwrap();
+        let interpreter = Interpeter::new(&module);
+        let mut state = interpreter.start("main", &[Value::Number(4)]);
+        let a = module.declarations[0].procedure[1];
+        assert_eq!(state.resolve(a), Some(Value::Number(4)));
+        assert!(state.step());
+        assert_eq!(state.call()[0], Value::Builtin("sub".to_string()));
+        // Builtins have no symbols
+        assert_eq!(state.resolve(a), None);
+        assert!(state.step());
+        match &state.call()[0] {
+            Value::Closure(closure) => assert_eq!(closure.captures(), &[Value::Number(4)]),
+            value => panic!("Expected a closure, got {:?}", value),
+        }
+        assert!(state.step());
+        assert!(state.step());
+        assert_eq!(state.call(), &[]);
+        assert_eq!(state.status(), Some(4));
+        assert!(!state.step());
+
+        // Stuck on invalid arguments
+        let mut state = interpreter.start("main", &[Value::String("4".to_string())]);
+        assert!(state.step());
+        assert!(!state.step());
+        assert_eq!(state.call().len(), 4);
+        assert_eq!(state.status(), None);
+    }
+
+    #[test]
+    fn test_render() {
+        fn render<'m>(state: &State<'m>, value: &Value<'m>) -> String {
+            let mut out = String::new();
+            state.render(&mut out, value, &mut Vec::new());
+            out
+        }
+        fn closure<'m>(declaration: &'m Declaration, captured: Value<'m>) -> Value<'m> {
+            Value::Closure(Closure {
+                declaration,
+                closure: vec![captured],
+            })
+        }
+        let module = parse_str("main a ↦ exit 0 (b ↦ exit a)\n").unwrap();
+        let lambda = &module.declarations[1];
+        let mut interpreter = Interpeter::new(&module);
+        let state = interpreter.start("main", &[]);
+        assert_eq!(render(&state, &closure(lambda, Value::Number(5))), "main.λ1");
+
+        interpreter.trace_captures(true);
+        let state = interpreter.start("main", &[]);
+        let five = closure(lambda, Value::Number(5));
+        assert_eq!(render(&state, &five), "main.λ1{a = 5}");
+        // Recursion and cycles are cut short
+        assert_eq!(render(&state, &closure(lambda, five)), "main.λ1{a = main.λ1{…}}");
+        let tuple = Rc::new(RefCell::new(vec![Value::String("x".to_string())]));
+        tuple.borrow_mut().push(Value::Tuple(tuple.clone()));
+        assert_eq!(render(&state, &Value::Tuple(tuple.clone())), "⟨“x”, ⟨…⟩⟩");
+        tuple.borrow_mut().clear();
+    }
+
+    #[test]
+    fn test_tuple() {
+        let module = parse_str(concat!(
+            "main ↦\n",
+            "    newTuple 2 (t ↦)\n",
+            "    tupleSet t 1 “b” (↦)\n",
+            "    tupleGet t 1 (b ↦)\n",
+            "    print b (↦)\n",
+            "    tupleGet t 0 (z ↦)\n",
+            "    tupleGet t 2 (x ↦ exit z)\n",
+        ))
+        .unwrap();
+        let output = Interpeter::new(&module).capture_by_name("main", &[]);
+        assert_eq!(output.stdout, "b");
+        assert_eq!(output.status, Some(PANIC_EXIT_CODE));
+    }
 }
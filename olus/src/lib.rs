@@ -0,0 +1,6 @@
+#![forbid(unsafe_code)]
+#![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
+
+pub mod debugger;
+pub mod interpreter;
+pub mod lsp;
@@ -0,0 +1,439 @@
+use crate::interpreter::BUILTINS;
+use log::{debug, warn};
+use lsp_server::{ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{self, Notification as _},
+    request::{self, Request as _},
+    Diagnostic, DiagnosticSeverity, GotoDefinitionParams, GotoDefinitionResponse, Hover,
+    HoverContents, HoverParams, HoverProviderCapability, InitializeResult, Location,
+    MarkupContent, MarkupKind, OneOf, Position, PublishDiagnosticsParams, Range,
+    ServerCapabilities, ServerInfo, TextDocumentPositionParams, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Url,
+};
+use parser::{parse_ast, parse_str, resolve::occurrences, Error, Span};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    io::{self, BufRead, Write},
+};
+
+// A language server for editors, speaking JSON-RPC on stdin and stdout. It
+// keeps the open documents, which editors send whole on every change, and
+// reports syntax errors and unknown names, goes to the binder of a name and
+// shows what a closure captures on hover.
+// See <https://microsoft.github.io/language-server-protocol/specifications/specification-current/>
+
+/// Serve requests from `input` until the client exits or closes it.
+pub fn serve<R: BufRead, W: Write>(mut input: R, mut output: W) -> io::Result<()> {
+    let mut server = Server::default();
+    while let Some(message) = Message::read(&mut input)? {
+        let replies = match message {
+            Message::Request(request) => vec![server.request(request).into()],
+            Message::Notification(notification)
+                if notification.method == notification::Exit::METHOD =>
+            {
+                break;
+            }
+            Message::Notification(notification) => server.notify(notification),
+            Message::Response(_) => vec![],
+        };
+        for reply in replies {
+            reply.write(&mut output)?;
+        }
+    }
+    Ok(())
+}
+
+/// The response to a `request` of kind `R`, or an error if its parameters are
+/// invalid.
+fn respond<R: request::Request>(
+    request: Request,
+    handler: impl FnOnce(R::Params) -> R::Result,
+) -> Response {
+    let id = request.id.clone();
+    match request.extract(R::METHOD) {
+        Ok((id, params)) => Response::new_ok(id, handler(params)),
+        Err(error) => Response::new_err(id, ErrorCode::InvalidParams as i32, error.to_string()),
+    }
+}
+
+/// The parameters of a `notification` of kind `N`, if they are valid.
+fn params<N: notification::Notification>(notification: Notification) -> Option<N::Params> {
+    notification
+        .extract(N::METHOD)
+        .map_err(|error| warn!("Ignoring notification: {}", error))
+        .ok()
+}
+
+#[derive(Debug, Default)]
+struct Server {
+    /// Text of the open documents by URI
+    documents: HashMap<Url, String>,
+}
+
+impl Server {
+    fn request(&self, request: Request) -> Response {
+        debug!("Received {}", request.method);
+        match request.method.as_str() {
+            request::Initialize::METHOD => {
+                let capabilities = ServerCapabilities {
+                    text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                        TextDocumentSyncKind::FULL,
+                    )),
+                    definition_provider: Some(OneOf::Left(true)),
+                    hover_provider: Some(HoverProviderCapability::Simple(true)),
+                    ..ServerCapabilities::default()
+                };
+                let server_info = ServerInfo {
+                    name:    "olus".to_string(),
+                    version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                };
+                Response::new_ok(request.id, InitializeResult {
+                    capabilities,
+                    server_info: Some(server_info),
+                })
+            }
+            request::Shutdown::METHOD => Response::new_ok(request.id, ()),
+            request::GotoDefinition::METHOD => {
+                respond::<request::GotoDefinition>(request, |params: GotoDefinitionParams| {
+                    let (uri, text, offset) = self.locate(params.text_document_position_params);
+                    definition(text, offset).map(|span| {
+                        GotoDefinitionResponse::Scalar(Location::new(uri, range(text, &span)))
+                    })
+                })
+            }
+            request::HoverRequest::METHOD => {
+                respond::<request::HoverRequest>(request, |params: HoverParams| {
+                    let (_, text, offset) = self.locate(params.text_document_position_params);
+                    hover(text, offset).map(|(span, value)| {
+                        Hover {
+                            contents: HoverContents::Markup(MarkupContent {
+                                kind: MarkupKind::Markdown,
+                                value,
+                            }),
+                            range:    Some(range(text, &span)),
+                        }
+                    })
+                })
+            }
+            _ => {
+                Response::new_err(
+                    request.id,
+                    ErrorCode::MethodNotFound as i32,
+                    "Method not found".to_string(),
+                )
+            }
+        }
+    }
+
+    /// The replies to `notification`.
+    fn notify(&mut self, notification: Notification) -> Vec<Message> {
+        debug!("Received {}", notification.method);
+        match notification.method.as_str() {
+            notification::DidOpenTextDocument::METHOD => {
+                params::<notification::DidOpenTextDocument>(notification).map_or_else(
+                    Vec::new,
+                    |params| self.update(params.text_document.uri, params.text_document.text),
+                )
+            }
+            notification::DidChangeTextDocument::METHOD => {
+                // With full sync the last change has the whole text
+                params::<notification::DidChangeTextDocument>(notification).map_or_else(
+                    Vec::new,
+                    |mut params| {
+                        match params.content_changes.pop() {
+                            Some(change) => self.update(params.text_document.uri, change.text),
+                            None => vec![],
+                        }
+                    },
+                )
+            }
+            notification::DidCloseTextDocument::METHOD => {
+                params::<notification::DidCloseTextDocument>(notification).map_or_else(
+                    Vec::new,
+                    |params| {
+                        let uri = params.text_document.uri;
+                        let _ = self.documents.remove(&uri);
+                        vec![publish_diagnostics(uri, Vec::new())]
+                    },
+                )
+            }
+            // Other notifications need no reply
+            _ => vec![],
+        }
+    }
+
+    /// The document, its text and the byte offset a request is about.
+    fn locate(&self, params: TextDocumentPositionParams) -> (Url, &str, usize) {
+        let uri = params.text_document.uri;
+        let text = self.documents.get(&uri).map_or("", String::as_str);
+        let position = params.position;
+        let offset = offset(text, position.line as usize, position.character as usize);
+        (uri, text, offset)
+    }
+
+    fn update(&mut self, uri: Url, text: String) -> Vec<Message> {
+        let diagnostics = diagnostics(&text)
+            .into_iter()
+            .map(|(span, message)| {
+                Diagnostic {
+                    range: range(&text, &span),
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    source: Some("olus".to_string()),
+                    message,
+                    ..Diagnostic::default()
+                }
+            })
+            .collect();
+        let _ = self.documents.insert(uri.clone(), text);
+        vec![publish_diagnostics(uri, diagnostics)]
+    }
+}
+
+fn publish_diagnostics(uri: Url, diagnostics: Vec<Diagnostic>) -> Message {
+    Notification::new(
+        notification::PublishDiagnostics::METHOD.to_string(),
+        PublishDiagnosticsParams::new(uri, diagnostics, None),
+    )
+    .into()
+}
+
+/// Syntax errors and names that are neither bound nor builtins. Once those are
+/// fixed, errors lowering the source.
+fn diagnostics(text: &str) -> Vec<(Span, String)> {
+    let (_, errors) = parse_ast(text);
+    let mut result = errors
+        .iter()
+        .map(|(error, span)| (span.clone(), error.to_string()))
+        .collect::<Vec<_>>();
+    for occurrence in occurrences(text) {
+        if occurrence.binder.is_none() && !BUILTINS.contains(&occurrence.name.as_str()) {
+            result.push((occurrence.span, format!("Unknown name {}", occurrence.name)));
+        }
+    }
+    if result.is_empty() {
+        match parse_str(text) {
+            Ok(_) => {}
+            Err(Error::Duplicate(duplicates)) => {
+                for duplicate in duplicates {
+                    result.push((duplicate.second.clone(), duplicate.to_string()));
+                }
+            }
+            Err(error) => result.push((0..0, error.to_string())),
+        }
+    }
+    result
+}
+
+/// The span of the binder of the name at `offset`.
+fn definition(text: &str, offset: usize) -> Option<Span> {
+    occurrences(text)
+        .into_iter()
+        .find(|occurrence| occurrence.span.start <= offset && offset <= occurrence.span.end)?
+        .binder
+}
+
+/// The span of the name at `offset` and a description of the closure it names
+/// or is a parameter of, with what the closure captures.
+fn hover(text: &str, offset: usize) -> Option<(Span, String)> {
+    let occurrence = occurrences(text)
+        .into_iter()
+        .find(|occurrence| occurrence.span.start <= offset && offset <= occurrence.span.end)?;
+    let binder = match &occurrence.binder {
+        Some(binder) => binder,
+        None if BUILTINS.contains(&occurrence.name.as_str()) => {
+            return Some((
+                occurrence.span,
+                format!("`{}` is a builtin", occurrence.name),
+            ));
+        }
+        None => return None,
+    };
+    let module = parse_str(text).ok()?;
+    let symbol = (0..module.symbols.len()).find(|&symbol| {
+        module.symbols[symbol] == occurrence.name && module.span(symbol).as_ref() == Some(binder)
+    })?;
+    let name = |symbol: usize| {
+        let name = &module.symbols[symbol];
+        if name.is_empty() {
+            format!("λ{}", symbol)
+        } else {
+            name.clone()
+        }
+    };
+    let (decl, kind) = match module.declaration(symbol) {
+        Some(decl) => (decl, "captures"),
+        None => {
+            let decl = module
+                .declarations
+                .iter()
+                .find(|decl| decl.procedure[1..].contains(&symbol))?;
+            (decl, "parameter, the closure captures")
+        }
+    };
+    let procedure = decl
+        .procedure
+        .iter()
+        .map(|&symbol| name(symbol))
+        .collect::<Vec<_>>();
+    let closure = decl
+        .closure
+        .iter()
+        .map(|&symbol| format!("`{}`", name(symbol)))
+        .collect::<Vec<_>>();
+    let closure = if closure.is_empty() {
+        "nothing".to_string()
+    } else {
+        closure.join(", ")
+    };
+    let value = format!(
+        "```olus\n{} ↦\n```\n{} {}",
+        procedure.join(" "),
+        kind,
+        closure
+    );
+    Some((occurrence.span, value))
+}
+
+/// Line and character of a byte offset. Characters are counted in UTF-16 code
+/// units, and lines end at `\n`, `\r\n` or `\r`, as in the protocol.
+fn position(text: &str, offset: usize) -> Position {
+    let mut line = 0;
+    let mut character = 0;
+    let mut chars = text[..offset].chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                line += 1;
+                character = 0;
+            }
+            c => character += c.len_utf16(),
+        }
+    }
+    let clamp = |value: usize| u32::try_from(value).unwrap_or(u32::MAX);
+    Position::new(clamp(line), clamp(character))
+}
+
+/// Byte offset of a line and character, clamped to the line.
+fn offset(text: &str, line: usize, character: usize) -> usize {
+    let mut current = (0, 0);
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        if current.0 > line || (current.0 == line && current.1 >= character) {
+            return index;
+        }
+        match c {
+            '\n' | '\r' if current.0 == line => return index,
+            '\r' if chars.peek().map(|&(_, next)| next) == Some('\n') => {}
+            '\n' | '\r' => current = (current.0 + 1, 0),
+            c => current.1 += c.len_utf16(),
+        }
+    }
+    text.len()
+}
+
+fn range(text: &str, span: &Span) -> Range {
+    Range::new(position(text, span.start), position(text, span.end))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_position() {
+        let text = "a ↦ b\r\n“😀” c\rd";
+        let c = text.find('c').unwrap();
+        assert_eq!(position(text, c), Position::new(1, 5));
+        assert_eq!(offset(text, 1, 5), c);
+        assert_eq!(position(text, text.len()), Position::new(2, 1));
+        assert_eq!(offset(text, 2, 1), text.len());
+        // Past the end of a line is its end
+        assert_eq!(offset(text, 0, 100), text.find('\r').unwrap());
+        assert_eq!(offset(text, 0, 4), text.find('b').unwrap());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        assert_eq!(diagnostics("main ↦ exit 0\n"), vec![]);
+        assert_eq!(diagnostics("main ↦ exit (f 0\n"), vec![
+            (14..15, "Unclosed parenthesis".to_string()),
+            (15..16, "Unknown name f".to_string()),
+        ]);
+        assert_eq!(diagnostics("f ↦ exit 0\nf ↦ exit 1\n"), vec![(
+            13..14,
+            "f is already declared".to_string()
+        )]);
+    }
+
+    #[test]
+    fn test_hover() {
+        let text = "f a k ↦ add a a (b ↦ k a b)\nmain ↦ f 1 (r ↦ exit r)\n";
+        let hover_at = |name: &str| hover(text, text.find(name).unwrap()).map(|(_, value)| value);
+        assert_eq!(
+            hover_at("f a"),
+            Some("```olus\nf a k ↦\n```\ncaptures nothing".to_string())
+        );
+        assert_eq!(
+            hover_at("b ↦"),
+            Some("```olus\nf.λ1 b ↦\n```\nparameter, the closure captures `a`, `k`".to_string())
+        );
+        assert_eq!(hover_at("exit"), Some("`exit` is a builtin".to_string()));
+        assert_eq!(hover_at("↦ k"), None);
+        assert_eq!(definition(text, text.find("f 1").unwrap()), Some(0..1));
+        assert_eq!(definition(text, text.find("k a b").unwrap()), Some(4..5));
+    }
+
+    #[test]
+    fn test_serve() {
+        let messages = [
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+            r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.olus","languageId":"olus","version":1,"text":"main ↦ exit x\n"}}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/definition","params":{"textDocument":{"uri":"file:///a.olus"},"position":{"line":0,"character":0}}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"method":"unknown"}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+            r#"{"jsonrpc":"2.0","id":4,"method":"shutdown"}"#,
+        ];
+        let input = messages
+            .iter()
+            .map(|body| format!("Content-Length: {}\r\n\r\n{}", body.len(), body))
+            .collect::<String>();
+        let mut output = Vec::new();
+        serve(input.as_bytes(), &mut output).unwrap();
+        let mut output = &output[..];
+        let mut replies = Vec::new();
+        while let Some(message) = Message::read(&mut output).unwrap() {
+            replies.push(serde_json::to_value(message).unwrap());
+        }
+
+        // Nothing is read after exit
+        assert_eq!(replies.len(), 4);
+        let capabilities = &replies[0]["result"]["capabilities"];
+        assert_eq!(capabilities["hoverProvider"], Value::Bool(true));
+        let range = |start, end| {
+            json!({
+                "start": {"line": 0, "character": start},
+                "end": {"line": 0, "character": end},
+            })
+        };
+        assert_eq!(
+            replies[1]["params"]["diagnostics"],
+            json!([{
+                "range": range(12, 13),
+                "severity": 1,
+                "source": "olus",
+                "message": "Unknown name x",
+            }])
+        );
+        assert_eq!(
+            replies[2]["result"],
+            json!({"uri": "file:///a.olus", "range": range(0, 4)})
+        );
+        assert_eq!(
+            replies[3]["error"]["code"],
+            json!(ErrorCode::MethodNotFound as i32)
+        );
+    }
+}
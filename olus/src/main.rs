@@ -1,18 +1,36 @@
 #![forbid(unsafe_code)]
 #![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
 
-mod interpreter;
-
-use codegen::codegen;
-use interpreter::Interpeter;
-use parser::parse_file;
-use std::{error::Error, path::PathBuf};
-use structopt::StructOpt;
+use codegen::{codegen, CostModel, Goals, Instrument, Objective, Startup, Target};
+use olus::interpreter::{Interpeter, Output};
+use parser::{
+    format_str, graph, lint, lint_captures,
+    minimize::minimize,
+    mir::{Module, DEFAULT_ENTRY},
+    parse_reader, parse_str, timings, Diagnostics, Level, Levels, Sink,
+};
+use std::{
+    any::Any,
+    convert::TryFrom,
+    env,
+    error::Error,
+    fs,
+    fs::File,
+    io,
+    io::{BufReader, Write},
+    panic,
+    path::PathBuf,
+    process,
+    str::FromStr,
+};
+use structopt::{clap::AppSettings, StructOpt};
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "Oluś")]
+#[structopt(name = "Oluś", setting = AppSettings::ArgsNegateSubcommands)]
 struct Options {
-    /// Verbose mode (-v, -vv, -vvv, etc.)
+    /// Verbose mode: -v for warnings, -vv for progress, -vvv for details and
+    /// timings of code generation, -vvvv to trace interpretation, -vvvvv with
+    /// the captured values
     #[structopt(short, long, parse(from_occurrences))]
     verbose: usize,
 
@@ -20,16 +38,586 @@ struct Options {
     #[structopt(short, long)]
     quiet: bool,
 
+    /// Report the wall time and counters of each phase on stderr when done:
+    /// lexing, parsing, lowering, planning the transitions of each
+    /// declaration, with the nodes the search explored for -O2, assembly and
+    /// writing files.
+    /// Programs run with '--run' end the process, so nothing is reported.
+    #[structopt(long)]
+    timings: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
     /// Source file
     #[structopt(parse(from_os_str))]
-    input: PathBuf,
+    input: Option<PathBuf>,
 
-    /// Output file, defaults to 'a.out'
+    /// Output file, defaults to 'a.out' for executables
     #[structopt(parse(from_os_str))]
     output: Option<PathBuf>,
+
+    /// Parse one top level declaration at a time, for very large sources
+    #[structopt(long)]
+    stream: bool,
+
+    /// Write an intermediate representation instead of running the program:
+    /// 'graph' for the call graph in Graphviz DOT format, 'c' for portable C
+    /// source, 'wasm' for a WebAssembly module, defaulting to 'a.wasm',
+    /// 'listing' for the machine code for the target under each source line,
+    /// 'captures' for the symbols each closure captures, 'mir' for textual
+    /// MIR, 'exe' for an executable for the target, defaulting to 'a.out',
+    /// 'map' for the address of the code of each declaration, or 'goals' for
+    /// the registers each declaration's code ends with, for 'olus debug
+    /// --goals'. Several kinds
+    /// separated by commas are written next to the output file or the source,
+    /// with an extension for each kind.
+    #[structopt(long, use_delimiter = true, multiple = false)]
+    emit: Vec<Emit>,
+
+    /// Declaration to start the program at
+    #[structopt(long, default_value = DEFAULT_ENTRY)]
+    entry: String,
+
+    /// Warn about closures capturing more than this many symbols
+    #[structopt(long)]
+    max_captures: Option<usize>,
+
+    /// Report a kind of warning: 'confusable', 'unused', 'large-capture',
+    /// 'shadowed', 'arity', 'undeclared', or 'unreachable' for declarations
+    /// the entry point never gets to, which is not reported by default
+    #[structopt(short = "W", long = "warn", number_of_values = 1)]
+    warn: Vec<String>,
+
+    /// Do not report a kind of warning
+    #[structopt(short = "A", long = "allow", number_of_values = 1)]
+    allow: Vec<String>,
+
+    /// Report a kind of warning as an error and fail. Denying wins over
+    /// warning, which wins over allowing.
+    #[structopt(short = "D", long = "deny", number_of_values = 1)]
+    deny: Vec<String>,
+
+    /// Fail on every kind of warning that is not allowed, for continuous
+    /// integration
+    #[structopt(long)]
+    deny_warnings: bool,
+
+    /// Compile to an executable for the target instead of running the
+    /// program: 'x86_64-apple-darwin', 'x86_64-unknown-linux-gnu' or
+    /// 'aarch64-unknown-linux-gnu'
+    #[structopt(long)]
+    target: Option<Target>,
+
+    /// Start Mach-O executables through dyld with an LC_MAIN command instead
+    /// of setting the registers with LC_UNIXTHREAD
+    #[structopt(long)]
+    dyld: bool,
+
+    /// Directory to keep compiled executables in, to skip compiling unchanged
+    /// programs again
+    #[structopt(long, parse(from_os_str))]
+    cache: Option<PathBuf>,
+
+    /// Processor to estimate run time of generated code for: 'generic',
+    /// 'skylake', 'zen' or a table written by 'olus calibrate'
+    #[structopt(long, default_value = "generic", parse(try_from_str = cost_model))]
+    cost_model: CostModel,
+
+    /// Optimize generated code for 'size', 'speed' or a 'balanced' sum of both
+    #[structopt(long, default_value = "size")]
+    objective: Objective,
+
+    /// Optimization level: 1 picks the instructions setting up each call
+    /// greedily, 2 searches for the cheapest ones, which takes much longer
+    #[structopt(short = "O", default_value = "1", possible_values = &["1", "2"])]
+    opt_level: u8,
+
+    /// Same as -O2
+    #[structopt(long)]
+    optimize: bool,
+
+    /// Check arities of calls at run time, and keep the index of the running
+    /// declaration in RAM for debuggers, see the book
+    #[structopt(long)]
+    debug_runtime: bool,
+
+    /// Add code to every declaration of executables: 'trace' writes the name
+    /// of each declaration to stderr as it starts, 'counts' writes how often
+    /// each one started when the program exits
+    #[structopt(long, default_value = "none")]
+    instrument: Instrument,
+
+    /// Compile and run the generated code in-process instead of interpreting
+    #[cfg(feature = "jit")]
+    #[structopt(long)]
+    run: bool,
+
+    /// Size of the RAM of generated code in 4 KiB pages
+    #[structopt(long)]
+    ram_pages: Option<usize>,
+
+    /// Offset from the start of RAM where the heap begins, by default right
+    /// after the allocator state
+    #[structopt(long)]
+    heap_offset: Option<usize>,
+
+    /// Let the operating system allocate the stack instead of using the end of
+    /// RAM
+    #[structopt(long)]
+    os_stack: bool,
+
+    /// Leave out the checks that allocations fit in RAM
+    #[structopt(long)]
+    no_heap_checks: bool,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Reformat a source file into canonical syntax
+    Fmt {
+        /// Source file
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        /// Overwrite the source file instead of printing to stdout
+        #[structopt(short, long)]
+        write: bool,
+    },
+    /// Report the errors and warnings of a program without running or
+    /// compiling it: syntax, names, calls with the wrong number of arguments
+    /// and the entry point
+    Check {
+        /// Source file
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+    },
+    /// Shrink a source the compiler fails on to a small reproducer with the
+    /// same panic message or first diagnostic. Panics are only caught in
+    /// builds that unwind, like the default debug build.
+    Minimize {
+        /// Source file
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        /// Also generate code, not only parse and lower
+        #[structopt(long)]
+        compile: bool,
+
+        /// Output file, defaults to stdout
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Run a program both in the interpreter and as an executable for the
+    /// host, and report where their output or exit code differ
+    TestCompare {
+        /// Source file
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+    },
+    /// Run a program in the interpreter under an interactive debugger, with
+    /// breakpoints on declarations. Type 'help' for the commands.
+    Debug {
+        /// Source file
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        /// Goal states written by '--emit goals' for the same source, to show
+        /// the registers the compiled code should have after each step
+        #[structopt(long, parse(from_os_str))]
+        goals: Option<PathBuf>,
+    },
+    /// Run a language server for editors on stdin and stdout
+    Lsp,
+    /// Time the instructions the optimizer chooses between on this processor
+    /// and write a table for '--cost-model'
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    Calibrate {
+        /// Output file, defaults to stdout
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Emit {
+    Graph,
+    C,
+    Wasm,
+    Listing,
+    Captures,
+    Mir,
+    Exe,
+    Map,
+    Goals,
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "graph" => Ok(Self::Graph),
+            "c" => Ok(Self::C),
+            "wasm" => Ok(Self::Wasm),
+            "listing" => Ok(Self::Listing),
+            "captures" => Ok(Self::Captures),
+            "mir" => Ok(Self::Mir),
+            "exe" => Ok(Self::Exe),
+            "map" => Ok(Self::Map),
+            "goals" => Ok(Self::Goals),
+            _ => {
+                Err(format!(
+                    "Unknown emit kind '{}', expected 'graph', 'c', 'wasm', 'listing', \
+                     'captures', 'mir', 'exe', 'map' or 'goals'",
+                    s
+                ))
+            }
+        }
+    }
+}
+
+impl Emit {
+    /// Extension of the file written when several kinds are emitted at once.
+    const fn extension(self) -> &'static str {
+        match self {
+            Self::Graph => "dot",
+            Self::C => "c",
+            Self::Wasm => "wasm",
+            Self::Listing => "lst",
+            Self::Captures => "captures",
+            Self::Mir => "mir",
+            Self::Exe => "",
+            Self::Map => "map",
+            Self::Goals => "goals",
+        }
+    }
+
+    /// Binary kinds are written to a file even if no output is given.
+    const fn default_output(self) -> Option<&'static str> {
+        match self {
+            Self::Wasm => Some("a.wasm"),
+            Self::Exe => Some("a.out"),
+            _ => None,
+        }
+    }
+
+    /// Whether the kind is made from code compiled for the target.
+    const fn compiles(self) -> bool {
+        matches!(self, Self::Listing | Self::Exe | Self::Map | Self::Goals)
+    }
+
+    /// Whether the kind needs the program to start somewhere.
+    const fn needs_entry(self) -> bool {
+        !matches!(self, Self::Graph | Self::Captures | Self::Mir)
+    }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+/// A named cost model, or else a calibrated table read from the file `s`.
+fn cost_model(s: &str) -> Result<CostModel, String> {
+    s.parse().or_else(|error| {
+        let contents = fs::read_to_string(s).map_err(|_| error)?;
+        Ok(CostModel::Calibrated(contents.parse()?))
+    })
+}
+
+/// Code generation options given on the command line.
+fn codegen_options(options: &Options) -> codegen::Options {
+    let default = codegen::Memory::default();
+    let memory = codegen::Memory {
+        ram_pages:   options.ram_pages.unwrap_or(default.ram_pages),
+        heap_offset: options.heap_offset.unwrap_or(default.heap_offset),
+        os_stack:    options.os_stack,
+        heap_checks: !options.no_heap_checks,
+    };
+    codegen::Options {
+        cost_model: options.cost_model,
+        objective: options.objective,
+        optimize: options.optimize || options.opt_level >= 2,
+        memory,
+        startup: if options.dyld {
+            Startup::Dyld
+        } else {
+            Startup::Thread
+        },
+        cache: options.cache.clone(),
+        entry: Some(options.entry.clone()),
+        debug_runtime: options.debug_runtime,
+        instrument: options.instrument,
+        ..codegen::Options::default()
+    }
+}
+
+/// Report syntax errors with the offending source lines, returning only a
+/// summary of them.
+fn report<T>(
+    input: &PathBuf,
+    contents: &str,
+    result: parser::Result<T>,
+) -> Result<T, Box<dyn Error>> {
+    result.map_err(|error| {
+        error.print_diagnostics(&input.display().to_string(), contents);
+        match error {
+            parser::Error::Syntax(_) | parser::Error::Duplicate(_) => {
+                format!("{} has errors, see above", input.display()).into()
+            }
+            error => error.into(),
+        }
+    })
+}
+
+/// The levels of warnings given on the command line.
+fn warning_levels(options: &Options) -> Result<Levels, String> {
+    let mut levels = Levels::default();
+    for &(names, level) in &[
+        (&options.allow, Level::Allow),
+        (&options.warn, Level::Warn),
+        (&options.deny, Level::Deny),
+    ] {
+        for name in names {
+            levels.set(name, level)?;
+        }
+    }
+    if options.deny_warnings {
+        levels.deny_warnings();
+    }
+    Ok(levels)
+}
+
+/// Parse the source and report the warnings about it, failing if any are
+/// denied. Streamed sources are only checked after lowering.
+fn parse(input: &PathBuf, options: &Options, stream: bool) -> Result<Module, Box<dyn Error>> {
+    let mut diagnostics = Diagnostics::new(warning_levels(options)?);
+    let (module, contents) = if stream {
+        let result = parse_reader(BufReader::new(File::open(input)?));
+        if result.is_err() {
+            // Only read the whole source to report the errors
+            let contents = fs::read_to_string(input)?;
+            return report(input, &contents, result);
+        }
+        (result?, None)
+    } else {
+        let contents = fs::read_to_string(input)?;
+        let module = report(input, &contents, parse_str(&contents))?;
+        (module, Some(contents))
+    };
+    let timer = timings::Timer::start("Lint");
+    if let Some(contents) = &contents {
+        for (warning, span) in lint(contents) {
+            diagnostics.warn(warning, span);
+        }
+    }
+    if let Some(limit) = options.max_captures {
+        for (warning, span) in lint_captures(&module, limit) {
+            diagnostics.warn(warning, span);
+        }
+    }
+    codegen::lint(&module, &codegen_options(options), &mut diagnostics);
+    drop(timer);
+    if !diagnostics.reported().is_empty() {
+        let contents = match contents {
+            Some(contents) => contents,
+            None => fs::read_to_string(input)?,
+        };
+        diagnostics.print(&input.display().to_string(), &contents);
+    }
+    if diagnostics.denied() > 0 {
+        return Err(format!("Denied warnings: {}", diagnostics.denied()).into());
+    }
+    Ok(module)
+}
+
+/// Fail unless the program can start at `entry`.
+fn check_entry(module: &Module, entry: &str) -> Result<(), String> {
+    module.entry(entry).map(|_| ()).map_err(|error| error.to_string())
+}
+
+/// Write each kind of `--emit`. A single one goes to the output file or
+/// stdout, several go to files named after the output file or the source.
+/// Executables, listings, maps and goals share one compile for the target.
+fn emit(module: &Module, options: &Options, input: &PathBuf) -> Result<(), Box<dyn Error>> {
+    let target = options.target.or_else(Target::host).unwrap_or_default();
+    let compiled = if options.emit.iter().any(|kind| kind.compiles()) {
+        Some(codegen::compile(module, &codegen_options(options), target)?)
+    } else {
+        None
+    };
+    let compiled = || compiled.as_ref().unwrap();
+    let base = options
+        .output
+        .clone()
+        .unwrap_or_else(|| input.clone())
+        .with_extension("");
+    for &kind in &options.emit {
+        let output = if options.emit.len() == 1 {
+            options
+                .output
+                .clone()
+                .or_else(|| kind.default_output().map(PathBuf::from))
+        } else {
+            Some(base.with_extension(kind.extension()))
+        };
+        let contents = match kind {
+            Emit::Graph => graph::dot(module).into_bytes(),
+            Emit::C => codegen::compile_c(module, &codegen_options(options))?.into_bytes(),
+            Emit::Wasm => codegen::compile_wasm(module, &codegen_options(options))?,
+            Emit::Listing => {
+                let source = fs::read_to_string(input)?;
+                compiled().listing(module, &source).into_bytes()
+            }
+            Emit::Captures => graph::captures(module).into_bytes(),
+            Emit::Mir => module.to_string().into_bytes(),
+            Emit::Exe => {
+                let output = output.ok_or("No output file for the executable")?;
+                compiled().save(&output)?;
+                continue;
+            }
+            Emit::Map => compiled().linker_map(module).into_bytes(),
+            Emit::Goals => {
+                let goals = compiled().goals(module, &codegen_options(options))?;
+                let text = goals.display(module).to_string();
+                text.into_bytes()
+            }
+        };
+        let _timer = timings::Timer::start("Write");
+        timings::count("bytes", contents.len() as u64);
+        match output {
+            Some(path) => fs::write(path, contents)?,
+            None => io::stdout().write_all(&contents)?,
+        }
+    }
+    Ok(())
+}
+
+fn format(input: &PathBuf, write: bool) -> Result<(), Box<dyn Error>> {
+    let contents = fs::read_to_string(input)?;
+    let formatted = report(input, &contents, format_str(&contents))?;
+    if write {
+        if formatted != contents {
+            fs::write(input, formatted)?;
+        }
+    } else {
+        print!("{}", formatted);
+    }
+    Ok(())
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| (*message).to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+/// How the compiler fails on `source`, if it does: the panic message or the
+/// first diagnostic.
+fn failure(source: &str, compile: bool) -> Option<String> {
+    let result = panic::catch_unwind(|| {
+        let module = match parse_str(source) {
+            Ok(module) => module,
+            Err(parser::Error::Syntax(errors)) => return Some(errors[0].0.to_string()),
+            Err(error) => return Some(error.to_string()),
+        };
+        if compile {
+            let options = codegen::Options::default();
+            if let Err(error) = codegen::compile_to_bytes(&module, &options, Target::default()) {
+                return Some(error.to_string());
+            }
+        }
+        None
+    });
+    result.unwrap_or_else(|payload| Some(format!("panic: {}", panic_message(&*payload))))
+}
+
+fn minimize_file(
+    input: &PathBuf,
+    compile: bool,
+    output: &Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let contents = fs::read_to_string(input)?;
+    // Candidates are expected to panic, keep that quiet
+    panic::set_hook(Box::new(|_| {}));
+    let expected = failure(&contents, compile).ok_or("The source does not fail")?;
+    eprintln!("Minimizing failure: {}", expected);
+    let reduced = minimize(&contents, |candidate| {
+        failure(candidate, compile).as_ref() == Some(&expected)
+    });
+    let _ = panic::take_hook();
+    match output {
+        Some(path) => fs::write(path, reduced)?,
+        None => print!("{}", reduced),
+    }
+    Ok(())
+}
+
+/// Whether the interpreted and compiled `stream` agree, reporting them if not.
+fn same(stream: &str, interpreted: &str, compiled: &str) -> bool {
+    if interpreted != compiled {
+        eprintln!("{} differs", stream);
+        eprintln!("  interpreter: {:?}", interpreted);
+        eprintln!("  compiled:    {:?}", compiled);
+    }
+    interpreted == compiled
+}
+
+/// Compare the interpreter with the executable compiled for the host.
+fn test_compare(input: &PathBuf, options: &Options) -> Result<(), Box<dyn Error>> {
+    let module = parse(input, options, false)?;
+    let target = Target::host().ok_or("No target for the host to compare against")?;
+    check_entry(&module, &options.entry)?;
+
+    let expected = Interpeter::new(&module).capture_by_name(&options.entry, &[]);
+
+    let executable = env::temp_dir().join(format!("olus-test-compare-{}", process::id()));
+    codegen(&module, &codegen_options(options), target, &executable)?;
+    let output = process::Command::new(&executable).output();
+    fs::remove_file(&executable)?;
+    let output = output?;
+    let actual = Output {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        status: output
+            .status
+            .code()
+            .and_then(|code| u64::try_from(code).ok()),
+    };
+
+    // Exit codes are truncated to a byte by the operating system
+    let status = |status: Option<u64>| {
+        status.map_or("none".to_string(), |code| (code & 0xff).to_string())
+    };
+    let agree = [
+        same("stdout", &expected.stdout, &actual.stdout),
+        same("stderr", &expected.stderr, &actual.stderr),
+        same(
+            "exit code",
+            &status(expected.status),
+            &status(actual.status),
+        ),
+    ];
+    if agree.contains(&false) {
+        return Err("The interpreter and the compiled program diverge".into());
+    }
+    eprintln!("The interpreter and the compiled program agree");
+    Ok(())
+}
+
+/// Report the phases recorded for `--timings`, if any.
+fn print_timings() {
+    let phases = timings::take();
+    if !phases.is_empty() {
+        eprint!("{}", timings::Report(&phases));
+    }
+}
+
+/// Exit with the exit code of a program, after reporting timings.
+fn exit(code: u64) -> ! {
+    print_timings();
+    process::exit(i32::try_from(code & 0xff).unwrap());
+}
+
+fn main() {
     // Parse commandline options using structopt
     let options = Options::from_args();
     // TODO: Print unicode version in version info
@@ -41,15 +629,114 @@ fn main() -> Result<(), Box<dyn Error>> {
         .init()
         .unwrap();
 
+    if options.timings {
+        timings::enable();
+    }
+    let result = run(&options);
+    print_timings();
+    if let Err(error) = result {
+        eprintln!("Error: {}", error);
+        process::exit(1);
+    }
+}
+
+fn run(options: &Options) -> Result<(), Box<dyn Error>> {
+    match &options.command {
+        Some(Command::Fmt { input, write }) => return format(input, *write),
+        Some(Command::Check { input }) => {
+            let module = parse(input, &options, options.stream)?;
+            check_entry(&module, &options.entry)?;
+            return Ok(());
+        }
+        Some(Command::Minimize {
+            input,
+            compile,
+            output,
+        }) => return minimize_file(input, *compile, output),
+        Some(Command::TestCompare { input }) => return test_compare(input, &options),
+        Some(Command::Debug { input, goals }) => {
+            let module = parse(input, &options, false)?;
+            check_entry(&module, &options.entry)?;
+            let goals = match goals {
+                Some(path) => Some(fs::read_to_string(path)?.parse::<Goals>()?),
+                None => None,
+            };
+            if goals.as_ref().map_or(false, |goals| {
+                goals.states.len() != module.declarations.len()
+            }) {
+                return Err("The goals are for a different program".into());
+            }
+            let stdin = io::stdin();
+            let status = olus::debugger::debug(
+                &module,
+                &options.entry,
+                goals.as_ref(),
+                stdin.lock(),
+                io::stdout(),
+            )?;
+            if let Some(code) = status {
+                exit(code);
+            }
+            return Ok(());
+        }
+        Some(Command::Lsp) => {
+            let stdin = io::stdin();
+            return Ok(olus::lsp::serve(stdin.lock(), io::stdout())?);
+        }
+        #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+        Some(Command::Calibrate { output }) => {
+            let table = codegen::calibrate::measure()?.to_string();
+            match output {
+                Some(path) => fs::write(path, table)?,
+                None => print!("{}", table),
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+    let input = options.input.as_ref().ok_or("No source file given")?;
+
     // Compile
-    let module = parse_file(&options.input)?;
+    let module = parse(input, &options, options.stream)?;
+
+    if options.emit.is_empty() || options.emit.iter().any(|kind| kind.needs_entry()) {
+        check_entry(&module, &options.entry)?;
+    }
+    if !options.emit.is_empty() {
+        return emit(&module, &options, input);
+    }
 
-    // Interpret
-    let interpreter = Interpeter::new(&module);
-    interpreter.eval_by_name("main", &[]);
+    // Write an executable. The target need not be the host, so only baseline
+    // processor features are used.
+    if let Some(target) = options.target {
+        let output = options.output.clone().unwrap_or_else(|| "a.out".into());
+        codegen(&module, &codegen_options(&options), target, &output)?;
+        return Ok(());
+    }
 
-    // Codegen
-    // codegen(&olus, &options.output.unwrap_or("a.out".into()))?;
+    // Run generated code
+    #[cfg(feature = "jit")]
+    {
+        if options.run {
+            let os = codegen::Os::host().ok_or("Unsupported host operating system")?;
+            let arch = codegen::Arch::host().ok_or("Unsupported host architecture")?;
+            let codegen_options = codegen::Options {
+                arch,
+                features: codegen::Features::host(),
+                os,
+                ..codegen_options(&options)
+            };
+            return codegen::jit::run(&module, &codegen_options);
+        }
+    }
 
+    // Interpret, exiting like the compiled program would
+    let mut interpreter = Interpeter::new(&module);
+    interpreter.trace_captures(options.verbose >= 5);
+    let timer = timings::Timer::start("Interpret");
+    if let Some(code) = interpreter.eval_by_name(&options.entry, &[]) {
+        drop(timer);
+        exit(code);
+    }
     Ok(())
 }
@@ -1,14 +1,20 @@
+use crate::lexer::Span;
 use serde::{Deserialize, Serialize};
 
-// An identifier occupies a binder spot.
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug, Default)]
-pub struct Binder(pub Option<usize>, pub String);
+/// Name of binders that ignore their argument. They do not bind anything, so
+/// the name can not be referenced.
+pub const IGNORED: &str = "_";
+
+// An identifier occupies a binder spot. The span locates it in the source,
+// binders introduced by desugaring take the one of the closure they are in.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug, Default)]
+pub struct Binder(pub Option<usize>, pub String, pub Option<Span>);
 
 // An expression occupies a reference spot.
 // Fructose is an inline declaration in parenthesis. It occupies one reference
 // spot which is linked to one implicit binding spot.
 // Galactose is a call statement in parenthesis.
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
 #[allow(clippy::use_self)] // 'Self' confuses Serde
 pub enum Expression {
     Reference(Option<usize>, String),
@@ -19,7 +25,7 @@ pub enum Expression {
 }
 
 // Glucose is a closure with an empty Call followed by a Call on the next line.
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
 #[allow(clippy::clippy::use_self)] // 'Self' confuses Serde
 pub enum Statement {
     Closure(Vec<Binder>, Vec<Expression>),
@@ -1,22 +1,48 @@
-use crate::ast;
+use crate::{
+    ast,
+    lexer::Span,
+    timings::{self, Timer},
+};
 use bitvec;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use thiserror::Error;
 
 type BitVec = bitvec::vec::BitVec<bitvec::order::Lsb0, u64>;
 
+/// Largest number a value can hold. Values carry a type tag at run time, which
+/// takes one bit, so numbers are 63 bits and arithmetic wraps around modulo
+/// 2^63.
+pub const NUMBER_MAX: u64 = u64::max_value() >> 1;
+
+/// Smallest number when read as signed. Signed numbers are two's complement in
+/// the 63 bits, so numbers above `NUMBER_MAX / 2` are negative.
+pub const NUMBER_MIN: i64 = -(1 << 62);
+
+/// Declaration programs start at unless another entry point is given.
+pub const DEFAULT_ENTRY: &str = "main";
+
 // TODO: Use entity-component system like the specs crate?
 // TODO:
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
 pub struct Module {
     pub symbols: Vec<String>,
 
     /// Bitvector of which symbols are names and not arguments
     pub names: BitVec,
 
+    /// Where each symbol is bound in the source, if the module was parsed from
+    /// source. May be shorter than `symbols`.
+    pub spans: Vec<Option<Span>>,
+
     pub imports:      Vec<String>,
     pub strings:      Vec<String>,
     pub numbers:      Vec<u64>,
     pub declarations: Vec<Declaration>,
+
+    /// Declarations removed by eta reduction and what references to them
+    /// refer to instead, so they can still be entry points.
+    pub forwarded: Vec<(usize, Expression)>,
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug, Default)]
@@ -26,6 +52,19 @@ pub struct Declaration {
     pub closure:   Vec<usize>, // TODO: BitVec
 }
 
+/// Why a name can not be used as an entry point, see [`Module::entry`].
+#[derive(Clone, PartialEq, Eq, Debug, Error)]
+pub enum EntryError {
+    #[error("No declaration named '{0}'")]
+    Missing(String),
+    #[error("'{0}' is a parameter, not a declaration")]
+    NotAName(String),
+    #[error("'{0}' captures parameters of other declarations, so it can not be an entry point")]
+    Captures(String),
+    #[error("'{0}' only forwards to the builtin '{1}', so it can not be an entry point")]
+    Import(String, String),
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
 pub enum Expression {
     Symbol(usize),
@@ -45,6 +84,19 @@ impl Module {
         n
     }
 
+    /// Where `symbol` is bound in the source, if known.
+    #[must_use]
+    pub fn span(&self, symbol: usize) -> Option<Span> {
+        self.spans.get(symbol).cloned().flatten()
+    }
+
+    fn set_span(&mut self, symbol: usize, span: Option<Span>) {
+        if self.spans.len() <= symbol {
+            self.spans.resize(symbol + 1, None);
+        }
+        self.spans[symbol] = span;
+    }
+
     pub fn provided_mask(&self, decl: &Declaration) -> BitVec {
         let mut mask = BitVec::repeat(false, self.symbols.len());
         for i in &decl.procedure {
@@ -101,6 +153,48 @@ impl Module {
             .find(|decl| decl.procedure[0] == name)
     }
 
+    fn declaration_index(&self, name: usize) -> Option<usize> {
+        self.declarations
+            .iter()
+            .position(|decl| decl.procedure[0] == name)
+    }
+
+    /// Index of the declaration named `name`, for starting the program there.
+    /// Entry points are called without a closure, so they can not capture
+    /// anything. A declaration removed by eta reduction starts at the
+    /// declaration it forwarded to.
+    pub fn entry(&self, name: &str) -> Result<usize, EntryError> {
+        let forwarded = self
+            .forwarded
+            .iter()
+            .find(|(symbol, _)| self.symbols[*symbol] == name);
+        let index = match forwarded {
+            Some((_, Expression::Symbol(target))) => self.declaration_index(*target),
+            Some((_, Expression::Import(import))) => {
+                return Err(EntryError::Import(
+                    name.to_string(),
+                    self.imports[*import].clone(),
+                ));
+            }
+            _ => {
+                self.declarations
+                    .iter()
+                    .position(|decl| self.symbols[decl.procedure[0]] == name)
+            }
+        };
+        let index = index.ok_or_else(|| {
+            if self.symbols.iter().any(|symbol| symbol == name) {
+                EntryError::NotAName(name.to_string())
+            } else {
+                EntryError::Missing(name.to_string())
+            }
+        })?;
+        if !self.declarations[index].closure.is_empty() {
+            return Err(EntryError::Captures(name.to_string()));
+        }
+        Ok(index)
+    }
+
     pub fn find_names(&mut self) {
         self.names = BitVec::repeat(false, self.symbols.len());
         for decl in &self.declarations {
@@ -108,38 +202,146 @@ impl Module {
         }
     }
 
-    fn closure_rec(&self, decl: &Declaration, provided: &BitVec) -> BitVec {
-        // TODO: Reformulate as a linear problem over GF(2)^{N x M} and
-        // solve using (sparse) matrices.
-        let context = self.provided_mask(decl) | provided.clone();
-        let required = self.required_mask(decl);
-        let mut closure = required & !context.clone();
-        let names = closure.clone() & self.names.clone();
-        // If a closure element is a name, it will be recursively replaced
-        // by the associated closure. But note that we still filter out
-        // procedure.
-        for name in (0..self.symbols.len()).filter(|i| names[*i]) {
-            closure.set(name, false);
-            closure |= self.closure_rec(self.declaration(name).unwrap(), &context);
+    /// The passes after lowering: eta reduction, hoisting and validation.
+    pub(crate) fn optimize(&mut self) -> crate::Result<()> {
+        {
+            let _timer = Timer::start("eta reduction");
+            self.eta_reduce();
+        }
+        {
+            let _timer = Timer::start("hoisting");
+            self.hoist_invariant_closures();
         }
+        let _timer = Timer::start("validation");
+        timings::count("declarations", self.declarations.len() as u64);
+        timings::count("symbols", self.symbols.len() as u64);
+        self.validate()?;
+        Ok(())
+    }
 
-        // Can not have any names in the closure.
-        assert!((closure.clone() & self.names.clone()).not_any());
-        closure
+    /// Remove forwarding declarations `f a b ↦ g a b` by referring to `g`
+    /// directly. Requires names and closures to be computed and keeps them up
+    /// to date.
+    pub fn eta_reduce(&mut self) {
+        while let Some((index, target)) = self.find_forwarding() {
+            timings::count("removed", 1);
+            let name = self.declarations.remove(index).procedure[0];
+            let calls = self.declarations.iter_mut().flat_map(|decl| &mut decl.call);
+            let forwarded = self.forwarded.iter_mut().map(|(_, target)| target);
+            for expr in calls.chain(forwarded) {
+                if *expr == Expression::Symbol(name) {
+                    *expr = target.clone();
+                }
+            }
+            self.forwarded.push((name, target));
+            self.find_names();
+            self.compute_closures();
+        }
     }
 
+    fn find_forwarding(&self) -> Option<(usize, Expression)> {
+        self.declarations
+            .iter()
+            .enumerate()
+            .find_map(|(index, decl)| {
+                let name = decl.procedure[0];
+                let (target, arguments) = decl.call.split_first()?;
+                let parameters = &decl.procedure[1..];
+                if self.symbols[name] == DEFAULT_ENTRY
+                    || arguments.len() != parameters.len()
+                    || !arguments
+                        .iter()
+                        .zip(parameters.iter())
+                        .all(|(argument, parameter)| *argument == Expression::Symbol(*parameter))
+                {
+                    return None;
+                }
+                match *target {
+                    Expression::Import(_) => Some((index, target.clone())),
+                    Expression::Symbol(s) if decl.procedure.contains(&s) => None,
+                    Expression::Symbol(s) => {
+                        // The target can only replace us where it can be
+                        // constructed, so it can not capture more than we do.
+                        let captures_more = self.declaration(s).map_or(false, |target| {
+                            target.closure.iter().any(|c| !decl.closure.contains(c))
+                        });
+                        if captures_more {
+                            None
+                        } else {
+                            Some((index, target.clone()))
+                        }
+                    }
+                    _ => None,
+                }
+            })
+    }
+
+    /// Compute the symbols each declaration captures: the symbols its call
+    /// requires that it does not provide itself, where names are replaced by
+    /// what their declaration captures. This is a least fixpoint, found with a
+    /// worklist over sparse sets so mutually recursive and deeply nested
+    /// declarations need no recursion.
     pub fn compute_closures(&mut self) {
         assert_eq!(self.names.len(), self.symbols.len());
-        let empty = BitVec::repeat(false, self.symbols.len());
-        let closures = self
-            .declarations
-            .iter()
-            .map(|decl| self.closure_rec(decl, &empty))
-            .collect::<Vec<_>>();
-        for (decl, closure) in self.declarations.iter_mut().zip(closures.into_iter()) {
-            decl.closure = (0..self.symbols.len())
-                .filter(|i| closure[*i])
+        let count = self.declarations.len();
+        let mut index = vec![None; self.symbols.len()];
+        for (i, decl) in self.declarations.iter().enumerate() {
+            index[decl.procedure[0]] = Some(i);
+        }
+
+        // Direct captures and the declarations whose captures are inherited
+        let mut closures = vec![BTreeSet::new(); count];
+        let mut sources = vec![BTreeSet::new(); count];
+        let mut users = vec![Vec::new(); count];
+        for (i, decl) in self.declarations.iter().enumerate() {
+            for expr in &decl.call {
+                match *expr {
+                    Expression::Symbol(s) if decl.procedure.contains(&s) => {}
+                    Expression::Symbol(s) if self.names[s] => {
+                        let source = index[s].expect("Name without declaration");
+                        if sources[i].insert(source) {
+                            users[source].push(i);
+                        }
+                    }
+                    Expression::Symbol(s) => {
+                        closures[i].insert(s);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Propagate captures to the declarations referring to them. Nested
+        // declarations tend to come later, so start from the end.
+        let mut pending = vec![true; count];
+        let mut worklist = (0..count).collect::<Vec<_>>();
+        while let Some(i) = worklist.pop() {
+            pending[i] = false;
+            let procedure = &self.declarations[i].procedure;
+            let inherited = sources[i]
+                .iter()
+                .flat_map(|&source| closures[source].iter().copied())
+                .filter(|symbol| !procedure.contains(symbol))
                 .collect::<Vec<_>>();
+            let mut changed = false;
+            for symbol in inherited {
+                changed |= closures[i].insert(symbol);
+            }
+            if changed {
+                for &user in &users[i] {
+                    if !pending[user] {
+                        pending[user] = true;
+                        worklist.push(user);
+                    }
+                }
+            }
+        }
+
+        let names = &self.names;
+        for (decl, closure) in self.declarations.iter_mut().zip(closures) {
+            // Can not have any names in the closure.
+            assert!(closure.iter().all(|symbol| !names[*symbol]));
+            decl.closure = closure.into_iter().collect();
         }
     }
 }
@@ -148,38 +350,117 @@ impl From<&ast::Statement> for Module {
     /// Requires the block to be desugared
     fn from(block: &ast::Statement) -> Self {
         let mut module = Module::default();
+        {
+            let _timer = Timer::start("MIR");
+            module.extend(block, 0);
+            module.find_names();
+            timings::count("declarations", module.declarations.len() as u64);
+        }
+        let _timer = Timer::start("closures");
+        module.compute_closures();
+        let captures = module.declarations.iter().map(|decl| decl.closure.len());
+        timings::count("captures", captures.sum::<usize>() as u64);
+        module
+    }
+}
+
+impl Module {
+    /// Add the declarations of a desugared block, whose source starts at
+    /// `offset`. Names and closures need to be recomputed afterwards.
+    pub(crate) fn extend(&mut self, block: &ast::Statement, offset: usize) {
         if let ast::Statement::Block(statements) = block {
-            module.declarations = statements
-                .iter()
-                .map(|statement| {
-                    match statement {
-                        ast::Statement::Closure(a, b) => {
-                            Declaration {
-                                procedure: a
-                                    .iter()
-                                    .map(|binder| {
-                                        module.symbol(
-                                            binder.0.expect("Must be bound"),
-                                            binder.1.clone(),
-                                        )
-                                    })
-                                    .collect::<Vec<_>>(),
-                                call:      b
-                                    .iter()
-                                    .map(|expr| module.convert(expr.clone()))
-                                    .collect::<Vec<_>>(),
-                                closure:   Vec::new(),
-                            }
+            for statement in statements {
+                let declaration = match statement {
+                    ast::Statement::Closure(a, b) => {
+                        Declaration {
+                            procedure: a
+                                .iter()
+                                .map(|binder| {
+                                    let symbol = self
+                                        .symbol(binder.0.expect("Must be bound"), binder.1.clone());
+                                    let span = binder
+                                        .2
+                                        .as_ref()
+                                        .map(|span| span.start + offset..span.end + offset);
+                                    self.set_span(symbol, span);
+                                    symbol
+                                })
+                                .collect::<Vec<_>>(),
+                            call:      b
+                                .iter()
+                                .map(|expr| self.convert(expr.clone()))
+                                .collect::<Vec<_>>(),
+                            closure:   Vec::new(),
                         }
-                        _ => panic!("Expected closure"),
                     }
-                })
-                .collect::<Vec<_>>();
+                    _ => panic!("Expected closure"),
+                };
+                self.declarations.push(declaration);
+            }
         } else {
             panic!("Expected block")
         }
-        module.find_names();
-        module.compute_closures();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EntryError;
+    use crate::{parse_mir, parse_str};
+
+    fn closures(source: &str) -> Vec<Vec<String>> {
+        let module = parse_mir(source).unwrap();
         module
+            .declarations
+            .iter()
+            .map(|decl| {
+                decl.closure
+                    .iter()
+                    .map(|symbol| module.symbols[*symbol].clone())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_entry() {
+        let module = parse_mir("main x ↦ a\na ↦ exit x\nb ↦ exit 0\n").unwrap();
+        assert_eq!(module.entry("main"), Ok(0));
+        assert_eq!(module.entry("b"), Ok(2));
+        assert_eq!(module.entry("c"), Err(EntryError::Missing("c".to_string())));
+        assert_eq!(module.entry("x"), Err(EntryError::NotAName("x".to_string())));
+        assert_eq!(module.entry("a"), Err(EntryError::Captures("a".to_string())));
+    }
+
+    #[test]
+    fn test_entry_forwarded() {
+        // Eta reduction removes `start` and `stop`, but not the entry points
+        let module = parse_str("other ↦ exit 0\nstart ↦ other\nstop ↦ exit\n").unwrap();
+        assert_eq!(module.declarations.len(), 1);
+        assert_eq!(module.entry("start"), module.entry("other"));
+        assert_eq!(
+            module.entry("stop"),
+            Err(EntryError::Import("stop".to_string(), "exit".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_mutual_recursion() {
+        let closures = closures("main x y ↦ a\na ↦ b x\nb ↦ a y\n");
+        assert_eq!(closures, vec![vec![], vec!["x", "y"], vec!["x", "y"]]);
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        // Each declaration refers to the next, the last one to `x`
+        const DEPTH: usize = 20_000;
+        let mut source = "main x ↦ f0\n".to_string();
+        for i in 0..DEPTH {
+            source.push_str(&format!("f{} ↦ f{}\n", i, i + 1));
+        }
+        source.push_str(&format!("f{} ↦ exit x\n", DEPTH));
+        let closures = closures(&source);
+        assert_eq!(closures[0], Vec::<String>::new());
+        assert!(closures[1..].iter().all(|closure| closure == &["x"]));
     }
 }
@@ -0,0 +1,125 @@
+use crate::{
+    ast::{Statement, IGNORED},
+    error::Error,
+    lexer::Span,
+    parser::emit,
+};
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+use std::collections::{hash_map::Entry, HashMap};
+use thiserror::Error;
+
+// Top level names are how declarations are found from outside, as entry
+// points and by the interpreter, so each can be declared only once. References
+// bind to the last binder before them, which would make a second declaration
+// silently take over the name for the source after it.
+
+/// A top level name declared a second time.
+#[derive(Clone, PartialEq, Eq, Debug, Error)]
+#[error("{name} is already declared")]
+pub struct Duplicate {
+    pub name:   String,
+    /// Span of the first declaration's name
+    pub first:  Span,
+    /// Span of the name declared again
+    pub second: Span,
+}
+
+/// The top level names of a source, added block by block.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Declarations {
+    spans:      HashMap<String, Span>,
+    duplicates: Vec<Duplicate>,
+}
+
+impl Declarations {
+    /// Add the names of the closures of the top level `block`, whose source
+    /// starts at `offset`.
+    pub(crate) fn add(&mut self, block: &Statement, offset: usize) {
+        let statements = match block {
+            Statement::Block(statements) => statements,
+            _ => return,
+        };
+        let names = statements.iter().filter_map(|statement| {
+            match statement {
+                Statement::Closure(binders, _) => binders.first(),
+                _ => None,
+            }
+        });
+        for binder in names {
+            let span = match (&binder.2, binder.1.as_str()) {
+                (Some(span), name) if name != IGNORED => span.start + offset..span.end + offset,
+                _ => continue,
+            };
+            match self.spans.entry(binder.1.clone()) {
+                Entry::Vacant(entry) => {
+                    let _ = entry.insert(span);
+                }
+                Entry::Occupied(entry) => {
+                    self.duplicates.push(Duplicate {
+                        name:   binder.1.clone(),
+                        first:  entry.get().clone(),
+                        second: span,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Fail if a name was declared more than once.
+    pub(crate) fn check(self) -> Result<(), Error> {
+        if self.duplicates.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Duplicate(self.duplicates))
+        }
+    }
+}
+
+/// Report `duplicates` on stderr with both declarations.
+pub(crate) fn print_duplicates(name: &str, source: &str, duplicates: &[Duplicate]) {
+    let diagnostics = duplicates.iter().map(|duplicate| {
+        Diagnostic::error()
+            .with_message(duplicate.to_string())
+            .with_labels(vec![
+                Label::primary((), duplicate.second.clone()).with_message("declared again here"),
+                Label::secondary((), duplicate.first.clone()).with_message("first declared here"),
+            ])
+    });
+    emit(name, source, diagnostics);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parse_reader, parse_str};
+
+    #[test]
+    fn test_duplicates() {
+        let source = "f ↦ exit 1\nmain ↦ f\nf ↦ exit 2\n";
+        let duplicate = Duplicate {
+            name:   "f".to_string(),
+            first:  0..1,
+            second: 24..25,
+        };
+        match parse_str(source) {
+            Err(Error::Duplicate(duplicates)) => assert_eq!(duplicates, vec![duplicate.clone()]),
+            result => panic!("Expected duplicate, got {:?}", result),
+        }
+        // Streaming finds them across chunks, at the same spans
+        match parse_reader(source.as_bytes()) {
+            Err(Error::Duplicate(duplicates)) => assert_eq!(duplicates, vec![duplicate]),
+            result => panic!("Expected duplicate, got {:?}", result),
+        }
+        assert_eq!(
+            parse_str(source).unwrap_err().to_string(),
+            "1 top level name(s) declared more than once, the first is f"
+        );
+    }
+
+    #[test]
+    fn test_local_names() {
+        // Parameters and closures in different declarations are not top level
+        assert!(parse_str("f a ↦ g (b ↦ exit a)\ng a ↦ exit (b ↦ a)\nmain ↦ f 1\n").is_ok());
+        assert!(parse_str("a ↦ exit 0\nmain a ↦ exit a\n").is_ok());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::ast::*;
+use crate::{ast::*, lexer::Span, parser::Error};
 use std::collections::HashMap;
 
 pub(crate) trait Visitor {
@@ -86,58 +86,123 @@ impl Host for Statement {
     }
 }
 
-/// Bind References to their Binders and flattens Blocks.
-pub(crate) fn bind(block: &mut Statement) -> usize {
-    // Number binders starting from zero
-    struct NumberBinders(usize);
-    impl Visitor for NumberBinders {
-        fn visit_binder(&mut self, n: &mut Option<usize>, _: &mut String) {
-            *n = Some(self.0);
-            self.0 += 1;
-        }
+/// State carried from one top level block to the next, so a source can be
+/// desugared a block at a time.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Desugarer {
+    /// Binder each name currently refers to
+    bindings:     HashMap<String, usize>,
+    binder_count: usize,
+    lambda_names: LambdaNames,
+}
+
+/// Names for the closures desugaring introduces, after the closure statement
+/// they are in: `f.λ1`, `f.λ2` and so on for those in `f`. Counting per name
+/// keeps the names in one declaration the same when others change.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LambdaNames(HashMap<String, usize>);
+
+impl LambdaNames {
+    fn next(&mut self, parent: &Parent) -> String {
+        let count = self.0.entry(parent.name.clone()).or_insert(0);
+        *count += 1;
+        format!("{}.λ{}", parent.name, count)
+    }
+}
+
+/// The closure statement being desugared. Closures introduced in it are named
+/// after it and have the span of its name.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Parent {
+    name: String,
+    span: Option<Span>,
+}
+
+impl Parent {
+    fn new(binders: &[Binder]) -> Self {
+        binders
+            .first()
+            .map(|binder| {
+                Self {
+                    name: binder.1.clone(),
+                    span: binder.2.clone(),
+                }
+            })
+            .unwrap_or_default()
     }
-    let mut number_binders = NumberBinders(0);
-    block.visit(&mut number_binders);
-    let num_binders = number_binders.0;
-
-    // Bind references
-    struct BindReferences(HashMap<String, usize>);
-    impl Visitor for BindReferences {
-        fn visit_binder(&mut self, n: &mut Option<usize>, s: &mut String) {
-            // TODO: Scoping.
-            // TODO: Forward looking.
-            let _ = self.0.insert((*s).to_string(), n.unwrap());
+}
+
+impl Desugarer {
+    /// Bind References to their Binders and flattens Blocks. Binders are
+    /// numbered after the ones in previous blocks.
+    pub(crate) fn bind(&mut self, block: &mut Statement) {
+        // Number binders continuing from previous blocks
+        struct NumberBinders(usize);
+        impl Visitor for NumberBinders {
+            fn visit_binder(&mut self, n: &mut Option<usize>, _: &mut String) {
+                *n = Some(self.0);
+                self.0 += 1;
+            }
         }
+        let mut number_binders = NumberBinders(self.binder_count);
+        block.visit(&mut number_binders);
+        self.binder_count = number_binders.0;
 
-        fn visit_reference(&mut self, n: &mut Option<usize>, s: &mut String) {
-            *n = self.0.get(s).cloned();
+        // Bind references
+        struct BindReferences<'a>(&'a mut HashMap<String, usize>);
+        impl Visitor for BindReferences<'_> {
+            fn visit_binder(&mut self, n: &mut Option<usize>, s: &mut String) {
+                // TODO: Scoping.
+                // TODO: Forward looking.
+                if s != IGNORED {
+                    let _ = self.0.insert((*s).to_string(), n.unwrap());
+                }
+            }
+
+            fn visit_reference(&mut self, n: &mut Option<usize>, s: &mut String) {
+                *n = self.0.get(s).cloned();
+            }
         }
-    }
-    let mut bind_references = BindReferences(HashMap::new());
-    block.visit(&mut bind_references);
-
-    // Flatten blocks
-    struct Flatten(Vec<Statement>);
-    impl Visitor for Flatten {
-        fn visit_statement(&mut self, s: &mut Statement) {
-            match s {
-                Statement::Block(_) => {}
-                _ => self.0.push(s.clone()),
+        let mut bind_references = BindReferences(&mut self.bindings);
+        block.visit(&mut bind_references);
+
+        // Flatten blocks
+        struct Flatten(Vec<Statement>);
+        impl Visitor for Flatten {
+            fn visit_statement(&mut self, s: &mut Statement) {
+                match s {
+                    Statement::Block(_) => {}
+                    _ => self.0.push(s.clone()),
+                }
             }
         }
+        let mut flatten = Flatten(Vec::new());
+        block.visit(&mut flatten);
+        *block = Statement::Block(flatten.0);
     }
-    let mut flatten = Flatten(Vec::new());
-    block.visit(&mut flatten);
-    *block = Statement::Block(flatten.0);
 
-    num_binders
+    /// Desugar a parsed `block`, whose call statements are at `calls`, see
+    /// [`crate::parser::Parser::call_spans`]. Fails with the calls that do not
+    /// continue a closure before them.
+    pub(crate) fn desugar(
+        &mut self,
+        block: &mut Statement,
+        calls: &[Span],
+    ) -> Result<(), Vec<(Error, Span)>> {
+        self.bind(block);
+        glucase_wrap(block, calls)?;
+        galactase(block, &mut self.binder_count, &mut self.lambda_names);
+        fructase(block, &mut self.binder_count, &mut self.lambda_names);
+        Ok(())
+    }
 }
 
-fn merge(target: &mut Vec<Expression>, call: Vec<Expression>) {
+/// Put `call` in the first empty call of `target`, if there is one.
+fn merge(target: &mut Vec<Expression>, call: Vec<Expression>) -> bool {
     // Empty expressions get replaces in entirety
     if target.is_empty() {
         *target = call;
-        return;
+        return true;
     }
 
     // Visit expression
@@ -158,19 +223,25 @@ fn merge(target: &mut Vec<Expression>, call: Vec<Expression>) {
     for expr in target {
         expr.visit(&mut state);
         if state.0 {
-            return;
+            return true;
         }
     }
-    panic!("Can not digest glucose.");
+    false
 }
 
-/// Fill empty calls with following statement
-pub(crate) fn glucase(statements: &[Statement]) -> Vec<Statement> {
+/// Fill empty calls with following statement. The call statements are at
+/// `calls`, in order.
+pub(crate) fn glucase(
+    statements: &[Statement],
+    calls: &[Span],
+) -> Result<Vec<Statement>, Vec<(Error, Span)>> {
     let mut result = Vec::new();
+    let mut errors = Vec::new();
     let mut closure: Option<(Vec<Binder>, Vec<Expression>)> = None;
+    let mut calls = calls.iter();
     for statement in statements {
         match statement {
-            Statement::Block(_) => panic!("Blocks not allowed here."),
+            Statement::Block(_) => unreachable!("Blocks are flattened when binding"),
             Statement::Closure(a, b) => {
                 if let Some((c, d)) = closure {
                     // TODO: Assert that result has no empty calls
@@ -179,10 +250,14 @@ pub(crate) fn glucase(statements: &[Statement]) -> Vec<Statement> {
                 closure = Some((a.clone(), b.clone()));
             }
             Statement::Call(a) => {
-                if let Some((_, d)) = &mut closure {
-                    merge(d, a.clone());
-                } else {
-                    panic!("Call without preceding closure.")
+                let span = calls.next().cloned().unwrap_or_default();
+                match &mut closure {
+                    Some((_, d)) => {
+                        if !merge(d, a.clone()) {
+                            errors.push((Error::NothingToContinue, span));
+                        }
+                    }
+                    None => errors.push((Error::CallWithoutClosure, span)),
                 }
             }
         }
@@ -190,27 +265,40 @@ pub(crate) fn glucase(statements: &[Statement]) -> Vec<Statement> {
     if let Some((c, d)) = closure {
         result.push(Statement::Closure(c, d));
     }
-    result
+    if errors.is_empty() {
+        Ok(result)
+    } else {
+        Err(errors)
+    }
 }
 
-pub(crate) fn glucase_wrap(block: &mut Statement) {
+pub(crate) fn glucase_wrap(
+    block: &mut Statement,
+    calls: &[Span],
+) -> Result<(), Vec<(Error, Span)>> {
     if let Statement::Block(statements) = block {
-        *statements = glucase(&statements);
+        *statements = glucase(&statements, calls)?;
     }
+    Ok(())
 }
 
 /// Converts all Fructose to Closures.
-pub(crate) fn fructase(block: &mut Statement, binder_id: &mut usize) {
-    struct State(usize, Vec<Statement>);
-    impl Visitor for State {
+pub(crate) fn fructase(block: &mut Statement, binder_id: &mut usize, names: &mut LambdaNames) {
+    struct State<'a>(usize, Vec<Statement>, Parent, &'a mut LambdaNames);
+    impl Visitor for State<'_> {
+        fn visit_closure(&mut self, binders: &mut Vec<Binder>, _: &mut Vec<Expression>) {
+            self.2 = Parent::new(binders);
+        }
+
         fn leave_expression(&mut self, e: &mut Expression) {
             *e = if let Expression::Fructose(p, c) = e {
-                let replacement = Expression::Reference(Some(self.0), String::default());
+                let name = self.3.next(&self.2);
+                let replacement = Expression::Reference(Some(self.0), name.clone());
                 let mut procedure = Vec::new();
                 std::mem::swap(p, &mut procedure);
                 let mut call = Vec::new();
                 std::mem::swap(c, &mut call);
-                procedure.insert(0, Binder(Some(self.0), String::default()));
+                procedure.insert(0, Binder(Some(self.0), name, self.2.span.clone()));
                 self.0 += 1;
                 // TODO: For glucase may need merge with sibling
                 self.1.push(Statement::Closure(procedure, call));
@@ -221,17 +309,22 @@ pub(crate) fn fructase(block: &mut Statement, binder_id: &mut usize) {
             }
         }
     }
-    let mut state = State(*binder_id, Vec::new());
+    let mut state = State(*binder_id, Vec::new(), Parent::default(), names);
     block.visit(&mut state);
     *binder_id = state.0;
     if let Statement::Block(statements) = block {
         statements.extend(state.1);
     } else {
-        panic!("Statement must be a block.")
+        unreachable!("Desugaring starts from a block")
     }
 }
 
-pub(crate) fn galac_vec(exprs: &mut Vec<Expression>, binder_id: &mut usize) {
+pub(crate) fn galac_vec(
+    exprs: &mut Vec<Expression>,
+    binder_id: &mut usize,
+    names: &mut LambdaNames,
+    parent: &Parent,
+) {
     // Find first Galactose or return
     if let Some(index) = exprs.iter().position(|e| {
         match e {
@@ -242,11 +335,12 @@ pub(crate) fn galac_vec(exprs: &mut Vec<Expression>, binder_id: &mut usize) {
         // Invert Galactose into Fructose
 
         // Replace galactose by a reference and fetch the call vec
-        let mut temp = Expression::Reference(Some(*binder_id), String::default());
+        let name = names.next(parent);
+        let mut temp = Expression::Reference(Some(*binder_id), name.clone());
         std::mem::swap(&mut exprs[index], &mut temp);
         let mut call = match temp {
             Expression::Galactose(c) => c,
-            _ => panic!("No Galactose at index."),
+            _ => unreachable!("The expression at `index` is a galactose"),
         };
 
         // Swap expression and call
@@ -254,7 +348,7 @@ pub(crate) fn galac_vec(exprs: &mut Vec<Expression>, binder_id: &mut usize) {
 
         // Append new fructose to the expression in the last position
         exprs.push(Expression::Fructose(
-            vec![Binder(Some(*binder_id), String::default())],
+            vec![Binder(Some(*binder_id), name, parent.span.clone())],
             call,
         ));
 
@@ -263,33 +357,31 @@ pub(crate) fn galac_vec(exprs: &mut Vec<Expression>, binder_id: &mut usize) {
 
         // Iterate till fix-point
         // TODO: What about iterating on `call`?
-        galac_vec(exprs, binder_id)
+        galac_vec(exprs, binder_id, names, parent)
     }
 }
 
-pub(crate) fn galactase(block: &mut Statement, binder_id: &mut usize) {
-    struct State(usize);
-    impl Visitor for State {
-        fn visit_closure(&mut self, _: &mut Vec<Binder>, exprs: &mut Vec<Expression>) {
-            galac_vec(exprs, &mut self.0);
+pub(crate) fn galactase(block: &mut Statement, binder_id: &mut usize, names: &mut LambdaNames) {
+    struct State<'a>(usize, Parent, &'a mut LambdaNames);
+    impl Visitor for State<'_> {
+        fn visit_closure(&mut self, binders: &mut Vec<Binder>, exprs: &mut Vec<Expression>) {
+            self.1 = Parent::new(binders);
+            galac_vec(exprs, &mut self.0, self.2, &self.1);
         }
 
         fn visit_fructose(&mut self, _: &mut Vec<Binder>, exprs: &mut Vec<Expression>) {
-            galac_vec(exprs, &mut self.0);
+            galac_vec(exprs, &mut self.0, self.2, &self.1);
         }
 
         fn visit_galactose(&mut self, exprs: &mut Vec<Expression>) {
-            galac_vec(exprs, &mut self.0);
+            galac_vec(exprs, &mut self.0, self.2, &self.1);
         }
     }
-    let mut state = State(*binder_id);
+    let mut state = State(*binder_id, Parent::default(), names);
     block.visit(&mut state);
     *binder_id = state.0;
 }
 
-pub(crate) fn desugar(block: &mut Statement) {
-    let mut binder_count = bind(block);
-    glucase_wrap(block);
-    galactase(block, &mut binder_count);
-    fructase(block, &mut binder_count);
+pub(crate) fn desugar(block: &mut Statement, calls: &[Span]) -> Result<(), Vec<(Error, Span)>> {
+    Desugarer::default().desugar(block, calls)
 }
@@ -0,0 +1,172 @@
+use crate::{lexer::Span, lint::Warning, parser::emit_diagnostics};
+use codespan_reporting::diagnostic::Severity;
+use std::collections::HashMap;
+
+// Where the parser and code generation report warnings to. Checks write into a
+// `Sink` instead of printing, so the caller decides what is reported and how:
+// the command line filters them by level, tests collect them in a `Vec`.
+
+/// Receives warnings as checks find them.
+pub trait Sink {
+    fn warn(&mut self, warning: Warning, span: Span);
+}
+
+impl Sink for Vec<(Warning, Span)> {
+    fn warn(&mut self, warning: Warning, span: Span) {
+        self.push((warning, span));
+    }
+}
+
+/// What to do with a kind of warning.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// The level of each kind of warning, by [`Warning::name`]. Kinds that are
+/// not set have their [`Warning::default_level`].
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Levels {
+    levels:        HashMap<&'static str, Level>,
+    deny_warnings: bool,
+}
+
+impl Levels {
+    /// Set the level of the kind of warning `name`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there is no kind of warning named `name`.
+    pub fn set(&mut self, name: &str, level: Level) -> Result<(), String> {
+        let name = Warning::NAMES
+            .iter()
+            .find(|known| **known == name)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown warning '{}', expected one of {}",
+                    name,
+                    Warning::NAMES.join(", ")
+                )
+            })?;
+        let _ = self.levels.insert(name, level);
+        Ok(())
+    }
+
+    /// Deny every kind of warning that is not allowed, for builds that should
+    /// not pass with warnings.
+    pub fn deny_warnings(&mut self) {
+        self.deny_warnings = true;
+    }
+
+    #[must_use]
+    pub fn level(&self, warning: &Warning) -> Level {
+        let level = self
+            .levels
+            .get(warning.name())
+            .copied()
+            .unwrap_or_else(|| warning.default_level());
+        match level {
+            Level::Warn if self.deny_warnings => Level::Deny,
+            level => level,
+        }
+    }
+}
+
+/// Collects the warnings that are not allowed, to report them together.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Diagnostics {
+    levels:   Levels,
+    reported: Vec<(Warning, Span, Level)>,
+}
+
+impl Diagnostics {
+    #[must_use]
+    pub fn new(levels: Levels) -> Self {
+        Self {
+            levels,
+            reported: Vec::new(),
+        }
+    }
+
+    /// The warnings that are not allowed, with their level, in the order
+    /// they were reported.
+    #[must_use]
+    pub fn reported(&self) -> &[(Warning, Span, Level)] {
+        &self.reported
+    }
+
+    /// The number of denied warnings.
+    #[must_use]
+    pub fn denied(&self) -> usize {
+        self.reported
+            .iter()
+            .filter(|(_, _, level)| *level == Level::Deny)
+            .count()
+    }
+
+    /// Report the warnings on stderr in source order, like
+    /// [`crate::print_warnings`], and denied ones as errors. Each message
+    /// ends with the name of its kind, for `-A`.
+    pub fn print(&self, name: &str, source: &str) {
+        let mut reported = self.reported.iter().collect::<Vec<_>>();
+        reported.sort_by_key(|(_, span, _)| span.start);
+        for (warning, span, level) in reported {
+            let severity = match level {
+                Level::Deny => Severity::Error,
+                _ => Severity::Warning,
+            };
+            let message = format!("{} [{}]", warning, warning.name());
+            emit_diagnostics(name, source, severity, vec![(message, span.clone())]);
+        }
+    }
+}
+
+impl Sink for Diagnostics {
+    fn warn(&mut self, warning: Warning, span: Span) {
+        let level = self.levels.level(&warning);
+        if level != Level::Allow {
+            self.reported.push((warning, span, level));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let unused = Warning::Unused("a".to_string());
+        let unreachable = Warning::Unreachable("f".to_string());
+        let mut levels = Levels::default();
+        assert_eq!(levels.level(&unused), Level::Warn);
+        assert_eq!(levels.level(&unreachable), Level::Allow);
+        levels.set("unreachable", Level::Warn).unwrap();
+        levels.set("unused", Level::Allow).unwrap();
+        assert_eq!(levels.level(&unused), Level::Allow);
+        assert_eq!(levels.level(&unreachable), Level::Warn);
+        assert!(levels.set("unusd", Level::Deny).is_err());
+
+        // Denying warnings leaves allowed ones alone
+        levels.deny_warnings();
+        assert_eq!(levels.level(&unused), Level::Allow);
+        assert_eq!(levels.level(&unreachable), Level::Deny);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let mut levels = Levels::default();
+        levels.set("shadowed", Level::Deny).unwrap();
+        let mut diagnostics = Diagnostics::new(levels);
+        diagnostics.warn(Warning::Unused("a".to_string()), 2..3);
+        diagnostics.warn(Warning::Unreachable("f".to_string()), 0..1);
+        diagnostics.warn(Warning::Shadowed("b".to_string()), 5..6);
+        assert_eq!(diagnostics.reported(), &[
+            (Warning::Unused("a".to_string()), 2..3, Level::Warn),
+            (Warning::Shadowed("b".to_string()), 5..6, Level::Deny),
+        ]);
+        assert_eq!(diagnostics.denied(), 1);
+    }
+}
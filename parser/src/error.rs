@@ -0,0 +1,37 @@
+use crate::{declarations::print_duplicates, lexer::Span, parser};
+use std::io;
+use thiserror::Error;
+
+pub use crate::{
+    declarations::Duplicate,
+    mir::EntryError, mir_text::Error as MirError, parser::Error as SyntaxError,
+    validate::Error as ValidationError,
+};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("{} syntax error(s), the first is: {}", .0.len(), .0[0].0)]
+    Syntax(Vec<(SyntaxError, Span)>),
+    #[error("{} top level name(s) declared more than once, the first is {}", .0.len(), .0[0].name)]
+    Duplicate(Vec<Duplicate>),
+    #[error(transparent)]
+    Mir(#[from] MirError),
+    #[error("invalid MIR: {0}")]
+    Invalid(#[from] ValidationError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl Error {
+    /// Report syntax errors and duplicate names on stderr with the offending
+    /// source lines. `name` is used to refer to the source.
+    pub fn print_diagnostics(&self, name: &str, source: &str) {
+        match self {
+            Self::Syntax(errors) => parser::print_diagnostics(name, source, errors),
+            Self::Duplicate(duplicates) => print_duplicates(name, source, duplicates),
+            _ => {}
+        }
+    }
+}
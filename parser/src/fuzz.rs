@@ -0,0 +1,68 @@
+use crate::{lexer::Lexer, parser::Parser};
+
+// Entry points for fuzzing, see the `fuzz` directory for the `cargo fuzz`
+// targets that call these. None of them may panic on any input.
+
+/// Run the lexer to completion.
+pub fn lexer(data: &[u8]) {
+    if let Ok(source) = std::str::from_utf8(data) {
+        for _ in Lexer::new(source) {}
+    }
+}
+
+/// Run the parser to completion.
+///
+/// # Panics
+///
+/// Panics if an error span is not a valid range of the source, so broken
+/// diagnostics count as crashes too.
+pub fn parser(data: &[u8]) {
+    if let Ok(source) = std::str::from_utf8(data) {
+        let mut parser = Parser::new(source);
+        let _ = parser.parse();
+        for (_, span) in parser.errors() {
+            assert!(source.get(span.clone()).is_some());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::proptest;
+
+    #[test]
+    fn test_regressions() {
+        for source in &[
+            "↦ f",
+            "a ↦ b ↦ c",
+            "(a “b” ↦ c)",
+            "“unterminated",
+            "a “unterminated\n  b",
+            "“a”” ”",
+            "    a\n  b\nc",
+            "a\n \n \n \n b",
+            "((((",
+            "))))",
+            "_",
+            "99999999999999999999999",
+        ] {
+            lexer(source.as_bytes());
+            parser(source.as_bytes());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_no_panic(source in "\\PC*") {
+            lexer(source.as_bytes());
+            parser(source.as_bytes());
+        }
+
+        #[test]
+        fn test_no_panic_syntax(source in "[a1 \n\t↦()“”]*") {
+            lexer(source.as_bytes());
+            parser(source.as_bytes());
+        }
+    }
+}
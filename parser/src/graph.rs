@@ -0,0 +1,163 @@
+use crate::mir::{Expression, Module};
+use std::fmt::{Result, Write};
+
+// Graphviz rendering of a module. Declarations and imports are nodes, calls
+// are solid edges and captured symbols are dotted edges from the declaration
+// binding the symbol to the declaration capturing it, labeled with the symbol.
+// The captures can also be listed as text, one declaration per line.
+
+/// Render the call graph and closure captures of `module` in DOT format.
+pub fn dot(module: &Module) -> String {
+    let mut out = String::new();
+    write_dot(&mut out, module).unwrap();
+    out
+}
+
+fn write_dot<W: Write>(out: &mut W, module: &Module) -> Result {
+    // Declaration binding each symbol
+    let mut binders = vec![None; module.symbols.len()];
+    for (index, declaration) in module.declarations.iter().enumerate() {
+        for &symbol in &declaration.procedure {
+            binders[symbol] = Some(index);
+        }
+    }
+
+    writeln!(out, "digraph module {{")?;
+    writeln!(out, "    node [shape=box];")?;
+    for (index, import) in module.imports.iter().enumerate() {
+        writeln!(out, "    i{} [label=\"{}\", shape=ellipse];", index, escape(import))?;
+    }
+    for (index, declaration) in module.declarations.iter().enumerate() {
+        let mut label = String::new();
+        for &symbol in &declaration.procedure {
+            write!(label, "{} ", symbol_name(module, symbol))?;
+        }
+        write!(label, "↦")?;
+        for expression in &declaration.call {
+            write!(label, " {}", expression_name(module, expression))?;
+        }
+        writeln!(out, "    d{} [label=\"{}\"];", index, escape(&label))?;
+    }
+    for (index, declaration) in module.declarations.iter().enumerate() {
+        match declaration.call.first() {
+            Some(Expression::Import(import)) => writeln!(out, "    d{} -> i{};", index, import)?,
+            Some(Expression::Symbol(symbol)) if module.names[*symbol] => {
+                if let Some(callee) = binders[*symbol] {
+                    writeln!(out, "    d{} -> d{};", index, callee)?;
+                }
+            }
+            _ => {}
+        }
+        for &symbol in &declaration.closure {
+            if let Some(binder) = binders[symbol] {
+                writeln!(
+                    out,
+                    "    d{} -> d{} [style=dotted, label=\"{}\"];",
+                    binder,
+                    index,
+                    escape(&symbol_name(module, symbol))
+                )?;
+            }
+        }
+    }
+    writeln!(out, "}}")
+}
+
+/// List the symbols each declaration of `module` captures, in declaration
+/// order. Closures allocate room for their captures each time they are
+/// created.
+pub fn captures(module: &Module) -> String {
+    let mut out = String::new();
+    for declaration in &module.declarations {
+        let name = symbol_name(module, declaration.procedure[0]);
+        if declaration.closure.is_empty() {
+            writeln!(out, "{} captures nothing", name).unwrap();
+            continue;
+        }
+        write!(out, "{} captures {}:", name, declaration.closure.len()).unwrap();
+        for &symbol in &declaration.closure {
+            write!(out, " {}", symbol_name(module, symbol)).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+    out
+}
+
+/// Anonymous symbols are named after their index, like the interpreter does.
+fn symbol_name(module: &Module, symbol: usize) -> String {
+    let name = &module.symbols[symbol];
+    if name.is_empty() {
+        format!("λ{}", symbol)
+    } else {
+        name.clone()
+    }
+}
+
+fn expression_name(module: &Module, expression: &Expression) -> String {
+    match expression {
+        Expression::Symbol(symbol) => symbol_name(module, *symbol),
+        Expression::Import(import) => module.imports[*import].clone(),
+        Expression::Literal(string) => format!("“{}”", module.strings[*string]),
+        Expression::Number(number) => module.numbers[*number].to_string(),
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_str;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_dot() {
+        let module = parse_str(indoc!(
+            "
+            main ↦
+                f “a\"b” (↦ exit 0)
+            "
+        ))
+        .unwrap();
+        assert_eq!(
+            dot(&module),
+            indoc!(
+                r#"
+                digraph module {
+                    node [shape=box];
+                    i0 [label="f", shape=ellipse];
+                    i1 [label="exit", shape=ellipse];
+                    d0 [label="main ↦ f “a\"b” main.λ1"];
+                    d1 [label="main.λ1 ↦ exit 0"];
+                    d0 -> i0;
+                    d1 -> i1;
+                }
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn test_captures() {
+        let module = parse_str(indoc!(
+            "
+            f x ↦ g (↦ h x)
+            "
+        ))
+        .unwrap();
+        let dot = dot(&module);
+        assert!(dot.contains("d0 -> d1 [style=dotted, label=\"x\"];"), "{}", dot);
+        assert_eq!(
+            captures(&module),
+            indoc!(
+                "
+                f captures nothing
+                f.λ1 captures 1: x
+                "
+            )
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use crate::{
+    mir::{Declaration, Expression, Module},
+    timings,
+};
+use std::collections::BTreeSet;
+
+// Loop-invariant closure hoisting. A loop is a declaration whose body calls it
+// again with as many arguments as it takes, from the declaration itself or
+// from closures nested in it. A parameter every such call passes on unchanged
+// is invariant, and so is a closure of the body capturing only invariant
+// parameters: creating it again on every iteration gives the same closure.
+//
+// The loop is split in an entry taking the name and parameters of the loop,
+// which creates the invariant closures once, and the body `f.loop`, which
+// takes them as extra parameters and passes them on in its recursive calls.
+// The invariant closures move to the entry, capturing its parameters instead.
+//
+//     f n k ↦ isZero n (↦ k 0) (↦ sub n 1 (m ↦ f m k))
+//
+// becomes, with `f.λ1` the closure `↦ k 0` created once,
+//
+//     f n k ↦ f.loop n k f.λ1
+//     f.loop n k c ↦ isZero n c (↦ sub n 1 (m ↦ f.loop m k c))
+
+/// Calls pass this many values in registers, see `abi` in codegen. The body
+/// takes at most this many parameters, including its name: more would go in
+/// an argument block allocated on every iteration, which is what hoisting the
+/// closures saves.
+const MAX_CALL: usize = 16;
+
+/// What hoisting the invariant closures out of a loop changes.
+struct Hoisting {
+    /// Index of the loop
+    index:     usize,
+    /// Declarations calling the loop again with as many arguments as it takes
+    recursive: Vec<usize>,
+    /// Parameters passed on unchanged by every recursive call
+    invariant: Vec<usize>,
+    /// The invariant closures and the declarations only they refer to
+    moved:     BTreeSet<usize>,
+    /// Names of the moved declarations the body refers to, which it receives
+    /// as parameters
+    hoisted:   Vec<usize>,
+}
+
+impl Module {
+    /// Create closures that are the same on every iteration of a loop once,
+    /// before entering the loop. Requires names and closures to be computed
+    /// and keeps them up to date.
+    pub fn hoist_invariant_closures(&mut self) {
+        // Loops found while hoisting are entries or bodies already done
+        let loops = self
+            .declarations
+            .iter()
+            .map(|declaration| declaration.procedure[0])
+            .collect::<Vec<_>>();
+        for name in loops {
+            let index = self
+                .declarations
+                .iter()
+                .position(|declaration| declaration.procedure[0] == name)
+                .unwrap();
+            if let Some(hoisting) = self.find_hoisting(index) {
+                timings::count("loops", 1);
+                timings::count("closures", hoisting.hoisted.len() as u64);
+                self.hoist(&hoisting);
+                self.find_names();
+                self.compute_closures();
+            }
+        }
+    }
+
+    fn find_hoisting(&self, index: usize) -> Option<Hoisting> {
+        let procedure = &self.declarations[index].procedure;
+        let (name, parameters) = procedure.split_first()?;
+        let refers_to = |declaration: &Declaration, symbol: usize| {
+            declaration.call.contains(&Expression::Symbol(symbol))
+        };
+
+        // The declaration and the closures nested in it
+        let body = (0..self.declarations.len())
+            .filter(|&other| {
+                other == index
+                    || self.declarations[other]
+                        .closure
+                        .iter()
+                        .any(|symbol| parameters.contains(symbol))
+            })
+            .collect::<BTreeSet<_>>();
+        let recursive = body
+            .iter()
+            .copied()
+            .filter(|&other| {
+                let call = &self.declarations[other].call;
+                call.first() == Some(&Expression::Symbol(*name)) && call.len() == procedure.len()
+            })
+            .collect::<Vec<_>>();
+        if recursive.is_empty() {
+            return None;
+        }
+        let invariant = parameters
+            .iter()
+            .enumerate()
+            .filter(|(position, parameter)| {
+                recursive.iter().all(|&other| {
+                    self.declarations[other].call[position + 1] == Expression::Symbol(**parameter)
+                })
+            })
+            .map(|(_, parameter)| *parameter)
+            .collect::<Vec<_>>();
+
+        // Closures of the body capturing only invariant parameters that do
+        // not enter the loop again, directly or through the closures they
+        // refer to.
+        let mut moved = body
+            .iter()
+            .copied()
+            .filter(|&other| {
+                let declaration = &self.declarations[other];
+                other != index
+                    && !declaration.closure.is_empty()
+                    && declaration.closure.iter().all(|symbol| invariant.contains(symbol))
+                    && !refers_to(declaration, *name)
+            })
+            .collect::<BTreeSet<_>>();
+        loop {
+            let stays = moved.iter().copied().find(|&other| {
+                self.declarations[other].call.iter().any(|expression| {
+                    match expression {
+                        Expression::Symbol(symbol) if self.names[*symbol] => {
+                            let declaration = self.declaration(*symbol).unwrap();
+                            !declaration.closure.is_empty()
+                                && !moved
+                                    .iter()
+                                    .any(|&m| self.declarations[m].procedure[0] == *symbol)
+                        }
+                        _ => false,
+                    }
+                })
+            });
+            match stays {
+                Some(other) => {
+                    let _ = moved.remove(&other);
+                }
+                None => break,
+            }
+        }
+        let hoisted = moved
+            .iter()
+            .map(|&other| self.declarations[other].procedure[0])
+            .filter(|&symbol| {
+                body.iter()
+                    .any(|other| !moved.contains(other) && refers_to(&self.declarations[*other], symbol))
+            })
+            .collect::<Vec<_>>();
+        if hoisted.is_empty() || procedure.len() + hoisted.len() > MAX_CALL {
+            return None;
+        }
+        Some(Hoisting {
+            index,
+            recursive,
+            invariant,
+            moved,
+            hoisted,
+        })
+    }
+
+    /// A new symbol with the name and span of `symbol`.
+    fn fresh(&mut self, symbol: usize, name: String) -> usize {
+        let fresh = self.symbols.len();
+        self.symbols.push(name);
+        self.names.push(false);
+        if let Some(span) = self.span(symbol) {
+            self.spans.resize(fresh, None);
+            self.spans.push(Some(span));
+        }
+        fresh
+    }
+
+    fn hoist(&mut self, hoisting: &Hoisting) {
+        let loop_ = self.declarations[hoisting.index].procedure.clone();
+        let body = self.fresh(loop_[0], format!("{}.loop", self.symbols[loop_[0]]));
+        let entry = loop_[1..]
+            .iter()
+            .map(|&parameter| self.fresh(parameter, self.symbols[parameter].clone()))
+            .collect::<Vec<_>>();
+        let received = hoisting
+            .hoisted
+            .iter()
+            .map(|&closure| self.fresh(closure, self.symbols[closure].clone()))
+            .collect::<Vec<_>>();
+        let replace = |call: &mut Vec<Expression>, from: &[usize], to: &[usize]| {
+            for expression in call {
+                if let Expression::Symbol(symbol) = expression {
+                    if let Some(position) = from.iter().position(|s| s == symbol) {
+                        *symbol = to[position];
+                    }
+                }
+            }
+        };
+
+        // The moved closures capture the parameters of the entry
+        let invariant = hoisting
+            .invariant
+            .iter()
+            .map(|&parameter| entry[loop_[1..].iter().position(|p| *p == parameter).unwrap()])
+            .collect::<Vec<_>>();
+        for (index, declaration) in self.declarations.iter_mut().enumerate() {
+            if hoisting.moved.contains(&index) {
+                replace(&mut declaration.call, &hoisting.invariant, &invariant);
+            } else {
+                replace(&mut declaration.call, &hoisting.hoisted, &received);
+            }
+        }
+        for &index in &hoisting.recursive {
+            let call = &mut self.declarations[index].call;
+            call[0] = Expression::Symbol(body);
+            call.extend(received.iter().map(|&symbol| Expression::Symbol(symbol)));
+        }
+        let declaration = &mut self.declarations[hoisting.index];
+        declaration.procedure[0] = body;
+        declaration.procedure.extend(&received);
+
+        let call = std::iter::once(body)
+            .chain(entry.iter().copied())
+            .chain(hoisting.hoisted.iter().copied())
+            .map(Expression::Symbol)
+            .collect();
+        let procedure = std::iter::once(loop_[0]).chain(entry).collect();
+        self.declarations.insert(hoisting.index, Declaration {
+            procedure,
+            call,
+            closure: Vec::new(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        error::{Error, ValidationError},
+        parse_mir, parse_str,
+    };
+
+    fn hoisted(source: &str) -> String {
+        let mut module = parse_mir(source).unwrap();
+        module.hoist_invariant_closures();
+        module.validate().unwrap();
+        module.to_string()
+    }
+
+    #[test]
+    fn test_hoist() {
+        assert_eq!(
+            hoisted(
+                "main ↦ f 3 λ0\nλ0 r ↦ exit r\nf n k ↦ isZero n λ1 λ2\nλ1 ↦ k 0\nλ2 ↦ sub n 1 \
+                 λ3\nλ3 m ↦ f m k\n"
+            ),
+            "main ↦ f 3 λ0\nλ0 r ↦ exit r\nf n#0 k#0 ↦ f.loop n#0 k#0 λ1\nf.loop n#1 k#1 λ2 ↦ \
+             isZero n#1 λ2 λ3\nλ1 ↦ k#0 0\nλ3 ↦ sub n#1 1 λ4\nλ4 m ↦ f.loop m k#1 λ2\n"
+        );
+    }
+
+    #[test]
+    fn test_not_invariant() {
+        // `k` changes between iterations, and closures entering the loop again
+        // stay in it.
+        let source = "main ↦ f 3 λ0\nλ0 r ↦ exit r\nf n k ↦ isZero n λ1 λ2\nλ1 ↦ k 0\nλ2 ↦ sub \
+                      n 1 λ3\nλ3 m ↦ f m λ1\n";
+        assert_eq!(hoisted(source), parse_mir(source).unwrap().to_string());
+        let source = "main ↦ f 3 λ0\nλ0 r ↦ exit r\nf n k ↦ isZero n λ1 λ2\nλ1 ↦ f 0 k\nλ2 ↦ sub \
+                      n 1 λ3\nλ3 m ↦ f m k\n";
+        assert_eq!(hoisted(source), parse_mir(source).unwrap().to_string());
+    }
+
+    #[test]
+    fn test_parse() {
+        // Parsing hoists, keeping the name of the loop for callers
+        let source = "f n k ↦ isZero n (↦ k 0) (↦ sub n 1 (m ↦ f m k))\nmain ↦ f 3 (r ↦ exit r)\n";
+        let module = parse_str(source).unwrap();
+        let f = module.entry("f").unwrap();
+        assert_eq!(module.symbols[module.declarations[f + 1].procedure[0]], "f.loop");
+        assert_eq!(module.declarations[f + 1].procedure.len(), 4);
+    }
+
+    #[test]
+    fn test_empty_call() {
+        // Hoisting runs before validation, so it has to skip empty calls
+        let error = parse_str("main ↦ print “b” (↦)\n").unwrap_err();
+        assert!(matches!(error, Error::Invalid(ValidationError::EmptyCall(_))));
+    }
+}
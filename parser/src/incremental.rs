@@ -0,0 +1,290 @@
+use crate::{
+    ast::{Binder, Expression, Statement},
+    desugar::{Host, Visitor},
+    lexer::Span,
+    parser::Parser,
+    stream,
+    version::Version,
+    SyntaxError,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+};
+
+// Incremental parsing for editors. The source is split into top level chunks
+// like the streaming parser does, and each chunk is parsed on its own. After
+// an edit, chunks whose text is unchanged are looked up by their hash and
+// reused, wherever they moved to, so only the edited chunks are parsed again.
+// Chunks keep spans relative to their start, so reusing one is free.
+
+/// A replacement of `range` of the source by `text`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Edit {
+    pub range: Span,
+    pub text:  String,
+}
+
+/// A syntax tree with the source it is parsed from, see [`reparse`].
+#[derive(Clone, Debug)]
+pub struct IncrementalAst {
+    source: String,
+    pragma: Option<Version>,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Clone, Debug)]
+struct Chunk {
+    /// Range of whole lines in the source
+    range:  Span,
+    hash:   u64,
+    /// Block of the top level statements
+    block:  Statement,
+    errors: Vec<(SyntaxError, Span)>,
+}
+
+/// Adds an offset to binder spans.
+struct Shift(usize);
+
+impl Shift {
+    fn binders(&self, binders: &mut [Binder]) {
+        for binder in binders {
+            if let Some(span) = &mut binder.2 {
+                *span = span.start + self.0..span.end + self.0;
+            }
+        }
+    }
+}
+
+impl Visitor for Shift {
+    fn visit_closure(&mut self, binders: &mut Vec<Binder>, _: &mut Vec<Expression>) {
+        self.binders(binders);
+    }
+
+    fn visit_fructose(&mut self, binders: &mut Vec<Binder>, _: &mut Vec<Expression>) {
+        self.binders(binders);
+    }
+}
+
+fn hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl IncrementalAst {
+    /// Parse `contents`, recovering from syntax errors like [`crate::parse_ast`].
+    #[must_use]
+    pub fn new(contents: &str) -> Self {
+        build(contents.to_string(), None).0
+    }
+
+    #[must_use]
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The syntax tree, the same as [`crate::parse_ast`] gives for the source.
+    #[must_use]
+    pub fn ast(&self) -> Statement {
+        let mut statements = Vec::new();
+        for chunk in &self.chunks {
+            let mut block = chunk.block.clone();
+            block.visit(&mut Shift(chunk.range.start));
+            if let Statement::Block(block) = block {
+                statements.extend(block);
+            }
+        }
+        Statement::Block(statements)
+    }
+
+    /// The syntax errors, the tree is best-effort if there are any.
+    #[must_use]
+    pub fn errors(&self) -> Vec<(SyntaxError, Span)> {
+        self.chunks
+            .iter()
+            .flat_map(|chunk| {
+                let offset = chunk.range.start;
+                chunk.errors.iter().map(move |(error, span)| {
+                    (error.clone(), span.start + offset..span.end + offset)
+                })
+            })
+            .collect()
+    }
+
+    fn text(&self, chunk: &Chunk) -> &str {
+        &self.source[chunk.range.clone()]
+    }
+}
+
+/// Apply `edits` one after the other to the source of `old` and parse the
+/// result, reusing the chunks of `old` that did not change.
+///
+/// # Panics
+///
+/// Panics if an edit range is not a valid range of the source at that point.
+#[must_use]
+pub fn reparse(old: &IncrementalAst, edits: &[Edit]) -> IncrementalAst {
+    let mut source = old.source.clone();
+    for edit in edits {
+        source.replace_range(edit.range.clone(), &edit.text);
+    }
+    build(source, Some(old)).0
+}
+
+/// Parse `source`, reusing chunks of `old`. Also returns how many chunks were
+/// parsed.
+fn build(source: String, old: Option<&IncrementalAst>) -> (IncrementalAst, usize) {
+    // The first chunk is parsed differently, it selects the version
+    let mut cache = HashMap::new();
+    if let Some(old) = old {
+        for (index, chunk) in old.chunks.iter().enumerate() {
+            let _ = cache.insert((index == 0, chunk.hash), (old, chunk));
+        }
+    }
+    let mut pragma = None;
+    let mut chunks = Vec::new();
+    let mut parsed = 0;
+    for (index, range) in stream::chunks(&source).into_iter().enumerate() {
+        let text = &source[range.clone()];
+        let hash = hash(text);
+        let reusable = cache
+            .get(&(index == 0, hash))
+            .filter(|(old, chunk)| old.text(chunk) == text && (index == 0 || old.pragma == pragma));
+        let chunk = if let Some((old, chunk)) = reusable {
+            if index == 0 {
+                pragma = old.pragma;
+            }
+            Chunk {
+                range,
+                ..(*chunk).clone()
+            }
+        } else {
+            parsed += 1;
+            let mut parser = if index == 0 {
+                Parser::new(text)
+            } else {
+                Parser::continuation(text, pragma)
+            };
+            let block = parser.parse();
+            if index == 0 {
+                pragma = parser.pragma();
+            }
+            Chunk {
+                range,
+                hash,
+                block,
+                errors: parser.errors().to_vec(),
+            }
+        };
+        chunks.push(chunk);
+    }
+    let ast = IncrementalAst {
+        source,
+        pragma,
+        chunks,
+    };
+    (ast, parsed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_ast;
+    use pretty_assertions::assert_eq;
+    use std::{fs, path::Path};
+
+    fn check(incremental: &IncrementalAst) {
+        let (ast, errors) = parse_ast(incremental.source());
+        assert_eq!(incremental.ast(), ast);
+        assert_eq!(incremental.errors(), errors);
+    }
+
+    #[test]
+    fn test_reparse() {
+        let source = "f a ↦ g a\n\ng b ↦\n    exit b\n\nmain ↦ f 1\n";
+        let (ast, parsed) = build(source.to_string(), None);
+        assert_eq!(parsed, 3);
+        check(&ast);
+
+        // Only the edited chunk is parsed, the others move
+        let edit = Edit {
+            range: 0..1,
+            text:  "ff".to_string(),
+        };
+        let (ast, parsed) = build(reparse(&ast, &[edit]).source, Some(&ast));
+        assert_eq!(parsed, 1);
+        assert_eq!(
+            ast.source(),
+            "ff a ↦ g a\n\ng b ↦\n    exit b\n\nmain ↦ f 1\n"
+        );
+        check(&ast);
+
+        // Edits apply one after the other and can split chunks
+        let edits = [
+            Edit {
+                range: 3..3,
+                text:  "x ↦ exit 0\n".to_string(),
+            },
+            Edit {
+                range: 0..3,
+                text:  "f ".to_string(),
+            },
+        ];
+        let reparsed = reparse(&ast, &edits);
+        assert_eq!(
+            reparsed.source(),
+            "f x ↦ exit 0\na ↦ g a\n\ng b ↦\n    exit b\n\nmain ↦ f 1\n"
+        );
+        check(&reparsed);
+        assert_eq!(reparsed.chunks.len(), 4);
+
+        // Errors move along with their chunk
+        let ast = IncrementalAst::new("f ↦ (\n\ng ↦ )\n");
+        let edit = Edit {
+            range: 0..0,
+            text:  "\n".to_string(),
+        };
+        check(&reparse(&ast, &[edit]));
+    }
+
+    #[test]
+    fn test_pragma() {
+        let ast = IncrementalAst::new("#olus 0\nf ↦ g\n\nh ↦ k 1\n");
+        assert_eq!(ast.errors().len(), 1);
+        // The pragma is a chunk of its own, later ones lex differently with it
+        let edit = Edit {
+            range: 6..7,
+            text:  "1".to_string(),
+        };
+        let (reparsed, parsed) = build(reparse(&ast, &[edit]).source, Some(&ast));
+        assert_eq!(parsed, 3);
+        assert!(reparsed.errors().is_empty());
+        check(&reparsed);
+    }
+
+    /// Delete each line of the examples in turn, and compare with parsing from
+    /// scratch.
+    #[test]
+    fn test_same_as_parse_ast() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |ext| ext != "olus") {
+                continue;
+            }
+            let source = fs::read_to_string(&path).unwrap();
+            let ast = IncrementalAst::new(&source);
+            check(&ast);
+            let mut start = 0;
+            for line in source.split_inclusive('\n') {
+                let edit = Edit {
+                    range: start..start + line.len(),
+                    text:  String::new(),
+                };
+                check(&reparse(&ast, &[edit]));
+                start += line.len();
+            }
+        }
+    }
+}
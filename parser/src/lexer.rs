@@ -1,8 +1,14 @@
 #![allow(clippy::use_self)] // False positive from macro
 #![allow(clippy::non_ascii_literal)] // Syntax is non-ascii
 
+use crate::{
+    mir::{NUMBER_MAX, NUMBER_MIN},
+    version::{Feature, Version},
+};
 use logos::Logos;
-use std::{cmp::Ordering, str::FromStr};
+use std::{borrow::Cow, cmp::Ordering, num::IntErrorKind, str::FromStr};
+use thiserror::Error;
+use unic_normal::StrNormalForm;
 
 pub type Span = std::ops::Range<usize>;
 
@@ -13,26 +19,56 @@ pub enum Token<'source> {
     LineStart,
     LineEnd,
     Identifier(&'source str),
-    String(&'source str),
+    /// String literal, borrowed from the source unless it contains escapes
+    String(Cow<'source, str>),
     Number(u64),
     Error(Error, Span),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Error)]
 pub enum Error {
+    #[error("Invalid token")]
     TokenError,
+    #[error("Indentation does not match any enclosing block")]
     IndentationError,
+    #[error("Indentation mixes tabs and spaces inconsistently with the enclosing block")]
+    MixedIndentation,
+    #[error("Invalid string literal")]
     StringError,
+    #[error("Unterminated string literal")]
     StringUnterminated,
+    #[error("Invalid escape sequence")]
+    EscapeError,
+    #[error("Invalid number literal")]
     NumberError,
+    #[error("Number literal exceeds the largest number {}", NUMBER_MAX)]
+    NumberRange,
+    #[error("Number literal is below the smallest number {}", NUMBER_MIN)]
+    NegativeRange,
+    #[error("Unknown version, the latest is {}", Version::LATEST)]
+    VersionError,
+    #[error("Version pragma must be on the first line")]
+    PragmaError,
+    #[error("{0} require version {} or later", .0.introduced())]
+    Unsupported(Feature),
 }
 
+/// Columns a tab advances to the next multiple of, unless configured.
+pub const DEFAULT_TAB_WIDTH: usize = 4;
+
 pub struct Lexer<'source> {
     lexer:        logos::Lexer<'source, RawToken>,
     next_token:   Option<RawToken>,
     line_started: bool,
-    indent_stack: Vec<usize>,
-    next_indent:  usize,
+    /// Width and white space of the enclosing blocks' indentation
+    indent_stack: Vec<(usize, &'source str)>,
+    next_indent:  (usize, &'source str),
+    indent_span:  Span,
+    tab_width:    usize,
+    /// Token owed after an indentation error
+    pending:      Option<Token<'source>>,
+    pragma:       Option<Version>,
+    continuation: bool,
 }
 
 #[derive(Logos, Debug, Clone, Copy, PartialEq)]
@@ -44,8 +80,9 @@ enum RawToken {
     #[regex(r"[\p{Pattern_White_Space}--(?&newline)]+")]
     Whitespace,
 
-    // Line breaks
-    #[regex(r"[\p{Pattern_White_Space}&&(?&newline)]+")]
+    // A single line break, where CR LF counts as one. Blank lines produce a
+    // token each, so a span never covers more than one line break.
+    #[regex(r"\r\n|[\p{Pattern_White_Space}&&(?&newline)]")]
     Newline,
 
     // Identifiers and symbols
@@ -57,9 +94,29 @@ enum RawToken {
     #[token("“")]
     StringStart,
 
-    #[regex(r"[0-9]+")]
+    // Alternative to the typographic quotes that is easy to type, see
+    // [`unescape`]
+    #[token("\"")]
+    AsciiStringStart,
+
+    // Alternative to `↦`, lexed as the same identifier
+    #[token("|->")]
+    AsciiMaplet,
+
+    // The binder that ignores its argument, see [`crate::ast::IGNORED`]
+    #[token("_")]
+    Ignored,
+
+    // Decimal, hexadecimal or binary, with optional underscores between
+    // digits and an optional minus sign. Prefixes without digits and stray
+    // underscores are lexed too, to report them.
+    #[regex(r"-?([0-9][0-9_]*|0x[0-9A-Fa-f_]*|0b[01_]*)")]
     Number,
 
+    // Language version, see [`Version`]
+    #[regex(r"#olus[ \t]+[0-9]+")]
+    Pragma,
+
     #[error]
     Error,
 }
@@ -85,21 +142,65 @@ impl<'source> Lexer<'source> {
             lexer:        RawToken::lexer(source),
             line_started: false,
             indent_stack: vec![],
-            next_indent:  0,
+            next_indent:  (0, ""),
+            indent_span:  0..0,
+            tab_width:    DEFAULT_TAB_WIDTH,
+            pending:      None,
             next_token:   None,
+            pragma:       None,
+            continuation: false,
         }
     }
 
-    pub fn source(&self) -> &'source str {
-        self.lexer.source()
+    /// Lex a continuation of a source whose start selected `pragma`.
+    pub fn continuation(source: &'source str, pragma: Option<Version>) -> Self {
+        Lexer {
+            pragma,
+            continuation: true,
+            ..Self::new(source)
+        }
     }
 
-    const fn indentation_length(str: &str) -> usize {
-        // Indentation length currently equals number of characters
-        str.len()
+    /// Set the number of columns a tab in indentation advances to the next
+    /// multiple of.
+    pub fn tab_width(mut self, tab_width: usize) -> Self {
+        assert!(tab_width > 0);
+        self.tab_width = tab_width;
+        self
+    }
+
+    /// The version selected by the pragma, if any.
+    pub const fn pragma(&self) -> Option<Version> {
+        self.pragma
+    }
+
+    /// Span of the source consumed by the last token.
+    pub fn span(&self) -> Span {
+        self.lexer.span()
+    }
+
+    /// Width of indentation in columns. Tabs advance to the next multiple of
+    /// the tab width, other white space is one column.
+    fn indentation_length(&self, str: &str) -> usize {
+        str.chars().fold(0, |column, c| {
+            if c == '\t' {
+                (column / self.tab_width + 1) * self.tab_width
+            } else {
+                column + 1
+            }
+        })
+    }
+
+    /// Indentation is only consistent if it extends or equals that of the
+    /// enclosing block character for character, whatever the tab width.
+    fn mixed_indentation(&mut self, next: Token<'source>) -> Token<'source> {
+        self.pending = Some(next);
+        Token::Error(Error::MixedIndentation, self.indent_span.clone())
     }
 
     fn parse_string(&mut self) -> Token<'source> {
+        // Spans of the string lexer are relative to the start of the remainder
+        let offset = self.lexer.span().end;
         let mut lexer: logos::Lexer<_> = LiteralString::lexer(self.lexer.remainder());
         let mut nesting = 0_usize;
         loop {
@@ -111,21 +212,136 @@ impl<'source> Lexer<'source> {
                     } else {
                         let result = &self.lexer.remainder()[0..lexer.span().start];
                         self.lexer.bump(lexer.span().end);
-                        break Token::String(result);
+                        break Token::String(Cow::Borrowed(result));
                     }
                 }
                 Some(LiteralString::Characters) => {}
-                Some(LiteralString::Error) => break Token::Error(Error::StringError, lexer.span()),
-                None => break Token::Error(Error::StringUnterminated, 0..lexer.span().end),
+                Some(LiteralString::Error) => {
+                    let span = lexer.span();
+                    break Token::Error(Error::StringError, offset + span.start..offset + span.end);
+                }
+                None => {
+                    // Consume the rest of the source, it is all part of the string
+                    let end = self.lexer.remainder().len();
+                    self.lexer.bump(end);
+                    break Token::Error(Error::StringUnterminated, offset - "“".len()..offset + end);
+                }
+            }
+        }
+    }
+
+    fn parse_ascii_string(&mut self) -> Token<'source> {
+        let start = self.lexer.span().start;
+        let offset = self.lexer.span().end;
+        let remainder = self.lexer.remainder();
+        let mut chars = remainder.char_indices();
+        let end = loop {
+            match chars.next() {
+                Some((index, '"')) => break index,
+                Some((_, '\\')) => {
+                    let _ = chars.next();
+                }
+                Some(_) => {}
+                None => {
+                    // Consume the rest of the source, it is all part of the string
+                    self.lexer.bump(remainder.len());
+                    return Token::Error(Error::StringUnterminated, start..offset + remainder.len());
+                }
+            }
+        };
+        self.lexer.bump(end + 1);
+        if !self.pragma.unwrap_or_default().supports(Feature::AsciiStrings) {
+            return Token::Error(Error::Unsupported(Feature::AsciiStrings), start..offset + end + 1);
+        }
+        match unescape(&remainder[..end]) {
+            Ok(string) => Token::String(string),
+            Err(span) => Token::Error(Error::EscapeError, offset + span.start..offset + span.end),
+        }
+    }
+
+    fn parse_ascii_maplet(&mut self) -> Token<'source> {
+        if self.pragma.unwrap_or_default().supports(Feature::AsciiMaplet) {
+            Token::Identifier("↦")
+        } else {
+            Token::Error(Error::Unsupported(Feature::AsciiMaplet), self.lexer.span())
+        }
+    }
+
+    fn parse_ignored(&mut self) -> Token<'source> {
+        if self.pragma.unwrap_or_default().supports(Feature::IgnoredBinder) {
+            Token::Identifier("_")
+        } else {
+            Token::Error(Error::Unsupported(Feature::IgnoredBinder), self.lexer.span())
+        }
+    }
+
+    fn parse_pragma(&mut self) -> Option<Token<'source>> {
+        if self.continuation || self.lexer.span().start != 0 {
+            return Some(Token::Error(Error::PragmaError, self.lexer.span()));
+        }
+        let number = self.lexer.slice()["#olus".len()..].trim_start();
+        match u64::from_str(number).ok().and_then(Version::from_number) {
+            Some(version) => {
+                self.pragma = Some(version);
+                None
             }
+            None => Some(Token::Error(Error::VersionError, self.lexer.span())),
         }
     }
 
     fn parse_number(&mut self) -> Token<'source> {
-        u64::from_str(self.lexer.slice()).map_or_else(
-            |_| Token::Error(Error::NumberError, self.lexer.span()),
-            Token::Number,
-        )
+        let version = self.pragma.unwrap_or_default();
+        if !version.supports(Feature::NumberLiterals) {
+            return Token::Error(
+                Error::Unsupported(Feature::NumberLiterals),
+                self.lexer.span(),
+            );
+        }
+        let slice = self.lexer.slice();
+        let (negative, slice) = match slice.strip_prefix('-') {
+            Some(slice) => (true, slice),
+            None => (false, slice),
+        };
+        if negative && !version.supports(Feature::NegativeLiterals) {
+            return Token::Error(
+                Error::Unsupported(Feature::NegativeLiterals),
+                self.lexer.span(),
+            );
+        }
+        let (digits, radix) = if let Some(digits) = slice.strip_prefix("0x") {
+            (digits, 16)
+        } else if let Some(digits) = slice.strip_prefix("0b") {
+            (digits, 2)
+        } else {
+            (slice, 10)
+        };
+        if (radix != 10 || digits.contains('_')) && !version.supports(Feature::RadixLiterals) {
+            return Token::Error(
+                Error::Unsupported(Feature::RadixLiterals),
+                self.lexer.span(),
+            );
+        }
+        if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+            return Token::Error(Error::NumberError, self.lexer.span());
+        }
+        let digits = digits.replace('_', "");
+        let range = if negative {
+            Error::NegativeRange
+        } else {
+            Error::NumberRange
+        };
+        match u64::from_str_radix(&digits, radix) {
+            // Negative numbers wrap around to the top half, see `NUMBER_MIN`
+            Ok(number) if negative && number <= NUMBER_MIN.unsigned_abs() => {
+                Token::Number(number.wrapping_neg() & NUMBER_MAX)
+            }
+            Ok(number) if !negative && number <= NUMBER_MAX => Token::Number(number),
+            Ok(_) => Token::Error(range, self.lexer.span()),
+            Err(error) if *error.kind() == IntErrorKind::PosOverflow => {
+                Token::Error(range, self.lexer.span())
+            }
+            Err(_) => Token::Error(Error::NumberError, self.lexer.span()),
+        }
     }
 }
 
@@ -133,67 +349,177 @@ impl<'source> Iterator for Lexer<'source> {
     type Item = Token<'source>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.next_token.is_none() {
-            self.next_token = self.lexer.next();
-        }
-        match self.next_token {
-            None => None,
-            Some(RawToken::Newline) => {
-                self.next_indent = 0;
-                self.next_token = None;
-                if self.line_started {
-                    self.line_started = false;
-                    Some(Token::LineEnd)
-                } else {
-                    self.next()
-                }
+        // Loop instead of recursing over white space, so long runs of blank
+        // lines can not overflow the stack.
+        loop {
+            if let Some(token) = self.pending.take() {
+                return Some(token);
             }
-            Some(RawToken::Whitespace) => {
-                if !self.line_started {
-                    // TODO: Compute indent_size
-                    self.next_indent = Self::indentation_length(self.lexer.slice());
-                }
-                self.next_token = None;
-                self.next()
+            if self.next_token.is_none() {
+                self.next_token = self.lexer.next();
             }
-            Some(token) => {
-                if self.line_started {
+            match self.next_token? {
+                RawToken::Newline => {
+                    self.next_indent = (0, "");
+                    self.next_token = None;
+                    if self.line_started {
+                        self.line_started = false;
+                        return Some(Token::LineEnd);
+                    }
+                }
+                RawToken::Whitespace => {
+                    if !self.line_started {
+                        let slice = self.lexer.slice();
+                        self.next_indent = (self.indentation_length(slice), slice);
+                        self.indent_span = self.lexer.span();
+                    }
+                    self.next_token = None;
+                }
+                RawToken::Pragma => {
                     self.next_token = None;
-                    match token {
-                        RawToken::Identifier => Some(Token::Identifier(self.lexer.slice())),
-                        RawToken::Error => Some(Token::Error(Error::TokenError, self.lexer.span())),
-                        RawToken::StringStart => Some(self.parse_string()),
-                        RawToken::Number => Some(self.parse_number()),
-                        _ => unreachable!(),
+                    if let Some(error) = self.parse_pragma() {
+                        return Some(error);
                     }
-                } else {
-                    let mut last_indent = self.indent_stack.last().copied().unwrap_or_default();
-                    match self.next_indent.cmp(&last_indent) {
+                }
+                token if self.line_started => {
+                    self.next_token = None;
+                    return Some(match token {
+                        RawToken::Identifier => Token::Identifier(self.lexer.slice()),
+                        RawToken::StringStart => self.parse_string(),
+                        RawToken::AsciiStringStart => self.parse_ascii_string(),
+                        RawToken::AsciiMaplet => self.parse_ascii_maplet(),
+                        RawToken::Ignored => self.parse_ignored(),
+                        RawToken::Number => self.parse_number(),
+                        RawToken::Error
+                        | RawToken::Whitespace
+                        | RawToken::Newline
+                        | RawToken::Pragma => {
+                            Token::Error(Error::TokenError, self.lexer.span())
+                        }
+                    });
+                }
+                _ => {
+                    let (next_indent, next_white) = self.next_indent;
+                    let (last_indent, last_white) =
+                        self.indent_stack.last().copied().unwrap_or_default();
+                    return Some(match next_indent.cmp(&last_indent) {
                         Ordering::Greater => {
                             self.indent_stack.push(self.next_indent);
-                            Some(Token::BlockStart)
+                            if next_white.starts_with(last_white) {
+                                Token::BlockStart
+                            } else {
+                                self.mixed_indentation(Token::BlockStart)
+                            }
                         }
                         Ordering::Less => {
                             self.indent_stack.pop();
-                            last_indent = self.indent_stack.last().copied().unwrap_or_default();
-                            if self.next_indent > last_indent {
+                            let (last_indent, _) =
+                                self.indent_stack.last().copied().unwrap_or_default();
+                            if next_indent > last_indent {
                                 // We un-indented back to a level not seen before,
-                                // this is an error.
-                                // TODO: Ideally we recover with [Error, BlockEnd, BlockStart]
-                                // for consistency.
-                                Some(Token::Error(Error::IndentationError, self.lexer.span()))
+                                // this is an error. Recover with [Error, BlockEnd,
+                                // BlockStart] so blocks stay balanced, the line
+                                // starts a new block at its own level.
+                                self.pending = Some(Token::BlockEnd);
+                                Token::Error(Error::IndentationError, self.lexer.span())
                             } else {
-                                Some(Token::BlockEnd)
+                                Token::BlockEnd
                             }
                         }
                         Ordering::Equal => {
                             self.line_started = true;
-                            Some(Token::LineStart)
+                            if next_white == last_white {
+                                Token::LineStart
+                            } else {
+                                self.mixed_indentation(Token::LineStart)
+                            }
                         }
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Decode the contents of an ASCII string literal. The escapes are `\"`, `\\`,
+/// `\n`, `\r`, `\t`, `\0` and `\u{…}` with one to six hexadecimal digits.
+/// Returns the span of the first invalid escape on error.
+pub(crate) fn unescape(literal: &str) -> Result<Cow<'_, str>, Span> {
+    if !literal.contains('\\') {
+        return Ok(Cow::Borrowed(literal));
+    }
+    let mut result = String::with_capacity(literal.len());
+    let mut chars = literal.char_indices();
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let (end, decoded) = match chars.next() {
+            Some((index, 'u')) => {
+                let rest = &literal[index + 1..];
+                let digits = rest
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.split('}').next().filter(|_| rest.contains('}')))
+                    .filter(|digits| {
+                        (1..=6).contains(&digits.len())
+                            && digits.bytes().all(|b| b.is_ascii_hexdigit())
+                    });
+                match digits {
+                    Some(digits) => {
+                        // Skip the braces and digits, all single bytes
+                        let _ = chars.nth(digits.len() + 1);
+                        let decoded = u32::from_str_radix(digits, 16).ok().and_then(char::from_u32);
+                        (index + digits.len() + 3, decoded)
                     }
+                    None => (index + 1, None),
                 }
             }
+            Some((index, c)) => {
+                let decoded = match c {
+                    '"' => Some('"'),
+                    '\\' => Some('\\'),
+                    'n' => Some('\n'),
+                    'r' => Some('\r'),
+                    't' => Some('\t'),
+                    '0' => Some('\0'),
+                    _ => None,
+                };
+                (index + c.len_utf8(), decoded)
+            }
+            None => (literal.len(), None),
+        };
+        match decoded {
+            Some(c) => result.push(c),
+            None => return Err(start..end),
+        }
+    }
+    Ok(Cow::Owned(result))
+}
+
+/// The identifier `name` in Normalization Form C, so names that only differ in
+/// whether accents are separate characters are the same name.
+// See <https://www.unicode.org/reports/tr15>
+pub(crate) fn normalize(name: &str) -> String {
+    name.nfc().collect()
+}
+
+/// Source form of a string literal. Strings with balanced typographic quotes
+/// are written verbatim between them, others as escaped ASCII strings.
+pub(crate) fn quote(string: &str) -> String {
+    let mut nesting = 0_usize;
+    let balanced = string.chars().all(|c| {
+        match c {
+            '“' => nesting += 1,
+            '”' => return nesting.checked_sub(1).map(|n| nesting = n).is_some(),
+            _ => {}
         }
+        true
+    }) && nesting == 0;
+    if balanced {
+        format!("“{}”", string)
+    } else {
+        format!("\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\""))
     }
 }
 
@@ -222,6 +548,35 @@ mod test {
         assert_eq!(parse(" "), vec![(Whitespace, 0..1)]);
     }
 
+    #[test]
+    fn test_line_breaks() {
+        use RawToken::*;
+        assert_eq!(parse("\r\n"), vec![(Newline, 0..2)]);
+        assert_eq!(parse("\r\n\r\n"), vec![(Newline, 0..2), (Newline, 2..4)]);
+        assert_eq!(parse("\n\r"), vec![(Newline, 0..1), (Newline, 1..2)]);
+        assert_eq!(parse("\r\r\n"), vec![(Newline, 0..1), (Newline, 1..3)]);
+        assert_eq!(parse("a \r\n"), vec![(Identifier, 0..1), (Whitespace, 1..2), (
+            Newline,
+            2..4
+        )]);
+    }
+
+    /// Files authored on Windows or classic Mac OS lex the same as with line
+    /// feeds, with spans into the original source.
+    #[test]
+    fn test_line_endings() {
+        let source = "a\n    b\n\n        c\nd\n";
+        let tokens = Lexer::new(source).collect::<Vec<_>>();
+        for ending in &["\r\n", "\r"] {
+            let source = source.replace('\n', ending);
+            assert_eq!(Lexer::new(&source).collect::<Vec<_>>(), tokens);
+        }
+        let source = "a\r\n    b\r\n\r\n\tc\r\n";
+        let mut lexer = Lexer::new(source);
+        assert_eq!(lexer.nth(7), Some(Token::Error(Error::MixedIndentation, 12..13)));
+        assert_eq!(&source[lexer.span()], "c");
+    }
+
     #[test]
     fn test_identifier() {
         use RawToken::*;
@@ -246,7 +601,7 @@ mod test {
             (Identifier, 5..8),
             (Whitespace, 8..9)
         ]);
-        assert_eq!(parse("_hello"), vec![(Error, 0..1), (Identifier, 1..6)]);
+        assert_eq!(parse("_hello"), vec![(Ignored, 0..1), (Identifier, 1..6)]);
         // assert_eq!(parse("0123 a"), vec![(Identifier, 0..5)]);
         assert_eq!(parse("+-asd"), vec![
             (Identifier, 0..1),
@@ -289,32 +644,345 @@ mod test {
         );
     }
 
+    #[test]
+    #[rustfmt::skip]
+    fn test_indentation_error() {
+        use Token::*;
+        assert_eq!(
+            Lexer::new(indoc!(r#"
+                a
+                        b
+                    c
+                d
+            "#)).collect::<Vec<_>>(),
+            vec![
+                LineStart, Identifier("a"), LineEnd,
+                BlockStart,
+                    LineStart, Identifier("b"), LineEnd,
+                Error(super::Error::IndentationError, 16..17),
+                BlockEnd,
+                BlockStart,
+                    LineStart, Identifier("c"), LineEnd,
+                BlockEnd,
+                LineStart, Identifier("d"), LineEnd,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tab_width() {
+        let lexer = Lexer::new("");
+        assert_eq!(lexer.indentation_length("\t"), 4);
+        assert_eq!(lexer.indentation_length("  \t"), 4);
+        assert_eq!(lexer.indentation_length("\t  "), 6);
+        let lexer = lexer.tab_width(8);
+        assert_eq!(lexer.indentation_length("  \t"), 8);
+        assert_eq!(lexer.indentation_length("\t\t"), 16);
+    }
+
+    #[test]
+    fn test_mixed_indentation() {
+        use Token::*;
+        assert_eq!(Lexer::new("a\n    b\n\tc\n").collect::<Vec<_>>(), vec![
+            LineStart,
+            Identifier("a"),
+            LineEnd,
+            BlockStart,
+            LineStart,
+            Identifier("b"),
+            LineEnd,
+            Error(super::Error::MixedIndentation, 8..9),
+            LineStart,
+            Identifier("c"),
+            LineEnd,
+        ]);
+        // Extending the enclosing indentation is fine
+        assert!(Lexer::new("a\n\tb\n\t  c\n")
+            .all(|token| !matches!(token, Error(..))));
+        assert_eq!(
+            Lexer::new("a\n  b\n\t  c\n").tab_width(2).nth(7),
+            Some(Error(super::Error::MixedIndentation, 6..9))
+        );
+    }
+
     #[test]
     fn test_string() {
         use Token::*;
         assert_eq!(Lexer::new("“Hello”asd").collect::<Vec<_>>(), vec![
             LineStart,
-            String("Hello"),
+            String("Hello".into()),
             Identifier("asd")
         ]);
         assert_eq!(
             Lexer::new("“Outer “inner” quotation” trailing input").collect::<Vec<_>>(),
             vec![
                 LineStart,
-                String("Outer “inner” quotation"),
+                String("Outer “inner” quotation".into()),
                 Identifier("trailing"),
                 Identifier("input")
             ]
         );
         assert_eq!(Lexer::new("“Hello””asd").collect::<Vec<_>>(), vec![
             LineStart,
-            String("Hello"),
+            String("Hello".into()),
             Identifier("”"),
             Identifier("asd")
         ]);
         assert_eq!(
             Lexer::new("“1“2“3”2”“2“3““5”””2”1”a").collect::<Vec<_>>(),
-            vec![LineStart, String("1“2“3”2”“2“3““5”””2”1"), Identifier("a")]
+            vec![LineStart, String("1“2“3”2”“2“3““5”””2”1".into()), Identifier("a")]
+        );
+    }
+
+    #[test]
+    fn test_string_error_span() {
+        use Token::*;
+        assert_eq!(Lexer::new("a “bc").collect::<Vec<_>>(), vec![
+            LineStart,
+            Identifier("a"),
+            Error(super::Error::StringUnterminated, 2..7),
+        ]);
+    }
+
+    #[test]
+    fn test_ascii_string() {
+        use Token::*;
+        assert_eq!(Lexer::new(r#"a "Hello" "“”" "“""#).collect::<Vec<_>>(), vec![
+            LineStart,
+            Identifier("a"),
+            String("Hello".into()),
+            String("“”".into()),
+            String("“".into()),
+        ]);
+        // Quotes of the other kind are ordinary characters
+        assert_eq!(Lexer::new(r#"“a"b” "a”b""#).collect::<Vec<_>>(), vec![
+            LineStart,
+            String(r#"a"b"#.into()),
+            String("a”b".into()),
+        ]);
+        let tokens = Lexer::new(r#""a\"b\\c\n" x"#).collect::<Vec<_>>();
+        assert_eq!(tokens[1], String("a\"b\\c\n".into()));
+        assert_eq!(tokens[2], Identifier("x"));
+        assert_eq!(Lexer::new(r#"a "b\q" c"#).collect::<Vec<_>>(), vec![
+            LineStart,
+            Identifier("a"),
+            Error(super::Error::EscapeError, 4..6),
+            Identifier("c"),
+        ]);
+        assert_eq!(Lexer::new("a \"b\\\"").collect::<Vec<_>>(), vec![
+            LineStart,
+            Identifier("a"),
+            Error(super::Error::StringUnterminated, 2..6),
+        ]);
+    }
+
+    #[test]
+    fn test_ascii_maplet() {
+        use Token::*;
+        let mut lexer = Lexer::new("f a |-> g|->h | -> |-");
+        assert_eq!(lexer.by_ref().take(5).collect::<Vec<_>>(), vec![
+            LineStart,
+            Identifier("f"),
+            Identifier("a"),
+            Identifier("↦"),
+            Identifier("g"),
+        ]);
+        assert_eq!(lexer.span(), 8..9);
+        assert_eq!(lexer.next(), Some(Identifier("↦")));
+        assert_eq!(lexer.span(), 9..12);
+        assert_eq!(lexer.collect::<Vec<_>>(), vec![
+            Identifier("h"),
+            Identifier("|"),
+            Identifier("-"),
+            Identifier(">"),
+            Identifier("|"),
+            Identifier("-"),
+        ]);
+        assert_eq!(Lexer::new("#olus 1\nf |-> g").nth(2), Some(Error(
+            super::Error::Unsupported(Feature::AsciiMaplet),
+            10..13
+        )));
+    }
+
+    #[test]
+    fn test_ignored() {
+        use Token::*;
+        assert_eq!(Lexer::new("f _ a_ ↦").collect::<Vec<_>>(), vec![
+            LineStart,
+            Identifier("f"),
+            Identifier("_"),
+            Identifier("a_"),
+            Identifier("↦"),
+        ]);
+        assert_eq!(Lexer::new("#olus 4\nf _ ↦").nth(2), Some(Error(
+            super::Error::Unsupported(Feature::IgnoredBinder),
+            10..11
+        )));
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert!(matches!(unescape("abc"), Ok(Cow::Borrowed("abc"))));
+        assert_eq!(unescape(r#"\"\\\n\r\t\0"#).unwrap(), "\"\\\n\r\t\0");
+        assert_eq!(unescape(r"\u{41}\u{1F600}\u{0}").unwrap(), "A\u{1F600}\0");
+        assert_eq!(unescape(r"ab\x"), Err(2..4));
+        assert_eq!(unescape(r"\é"), Err(0..3));
+        assert_eq!(unescape(r"\u41"), Err(0..2));
+        assert_eq!(unescape(r"\u{}"), Err(0..2));
+        assert_eq!(unescape(r"\u{1234567}"), Err(0..2));
+        assert_eq!(unescape(r"\u{+41}"), Err(0..2));
+        assert_eq!(unescape(r"a\u{D800}"), Err(1..9));
+        assert_eq!(unescape("\\"), Err(0..1));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("a “b” c"), "“a “b” c”");
+        assert_eq!(quote(r#"a"b"#), r#"“a"b”"#);
+        assert_eq!(quote("a”b"), r#""a”b""#);
+        assert_eq!(quote(r#"”\"#), r#""”\\""#);
+        assert_eq!(quote(r#"“"#), r#""“""#);
+        assert_eq!(quote(r#"”“"#), r#""”“""#);
+        for string in &["a”b", r#"“\""#, "”\n“"] {
+            let quoted = quote(string);
+            let mut lexer = Lexer::new(&quoted);
+            assert_eq!(lexer.nth(1), Some(Token::String((*string).into())));
+        }
+    }
+
+    #[test]
+    fn test_pragma() {
+        use Token::*;
+        assert_eq!(Lexer::new("#olus 0\na").collect::<Vec<_>>(), vec![
+            LineStart,
+            Identifier("a")
+        ]);
+        assert_eq!(Lexer::new("#olus 1000").collect::<Vec<_>>(), vec![Error(
+            super::Error::VersionError,
+            0..10
+        )]);
+        assert_eq!(Lexer::new("a\n#olus 1").collect::<Vec<_>>(), vec![
+            LineStart,
+            Identifier("a"),
+            LineEnd,
+            Error(super::Error::PragmaError, 2..9),
+        ]);
+    }
+
+    #[test]
+    fn test_feature_gate() {
+        use Token::*;
+        assert_eq!(Lexer::new("#olus 1\nexit 0").collect::<Vec<_>>(), vec![
+            LineStart,
+            Identifier("exit"),
+            Number(0)
+        ]);
+        let tokens = Lexer::new("#olus 0\nexit 0").collect::<Vec<_>>();
+        assert_eq!(tokens, vec![
+            LineStart,
+            Identifier("exit"),
+            Error(super::Error::Unsupported(Feature::NumberLiterals), 13..14)
+        ]);
+        if let Error(error, _) = &tokens[2] {
+            assert_eq!(error.to_string(), "Number literals require version 1 or later");
+        }
+        assert_eq!(Lexer::new("#olus 1\nprint \"a\"").collect::<Vec<_>>(), vec![
+            LineStart,
+            Identifier("print"),
+            Error(super::Error::Unsupported(Feature::AsciiStrings), 14..17)
+        ]);
+    }
+
+    #[test]
+    fn test_number_range() {
+        use Token::*;
+        assert_eq!(
+            Lexer::new("#olus 1\nexit 9223372036854775807").collect::<Vec<_>>(),
+            vec![LineStart, Identifier("exit"), Number(NUMBER_MAX)]
         );
+        assert_eq!(
+            Lexer::new("#olus 1\nexit 9223372036854775808").collect::<Vec<_>>(),
+            vec![
+                LineStart,
+                Identifier("exit"),
+                Error(super::Error::NumberRange, 13..32)
+            ]
+        );
+        // Beyond u64 is a range error too
+        assert_eq!(
+            Lexer::new("exit 0x1_0000_0000_0000_0000").collect::<Vec<_>>(),
+            vec![
+                LineStart,
+                Identifier("exit"),
+                Error(super::Error::NumberRange, 5..28)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_radix_literals() {
+        use Token::*;
+        let numbers = |source| {
+            Lexer::new(source)
+                .filter(|token| !matches!(token, LineStart | LineEnd))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(numbers("0x1f 0xFF 0b101 1_000 0x7fff_ffff_ffff_ffff 0b1_1"), vec![
+            Number(0x1f),
+            Number(0xff),
+            Number(0b101),
+            Number(1000),
+            Number(NUMBER_MAX),
+            Number(0b11),
+        ]);
+        assert_eq!(numbers("0x 0b_ 0x8000_0000_0000_0000"), vec![
+            Error(super::Error::NumberError, 0..2),
+            Error(super::Error::NumberError, 3..6),
+            Error(super::Error::NumberRange, 7..28),
+        ]);
+        // Underscores only go between digits
+        assert_eq!(numbers("0b_1 1_ 1__0 0x_f"), vec![
+            Error(super::Error::NumberError, 0..4),
+            Error(super::Error::NumberError, 5..7),
+            Error(super::Error::NumberError, 8..12),
+            Error(super::Error::NumberError, 13..17),
+        ]);
+        assert_eq!(numbers("#olus 2\n0x10 1_0 10"), vec![
+            Error(super::Error::Unsupported(Feature::RadixLiterals), 8..12),
+            Error(super::Error::Unsupported(Feature::RadixLiterals), 13..16),
+            Number(10),
+        ]);
+    }
+
+    #[test]
+    fn test_negative_literals() {
+        use Token::*;
+        let numbers = |source| {
+            Lexer::new(source)
+                .filter(|token| !matches!(token, LineStart | LineEnd))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(numbers("-1 -0 -0x10 -4611686018427387904"), vec![
+            Number(NUMBER_MAX),
+            Number(0),
+            Number(NUMBER_MAX - 15),
+            Number(NUMBER_MAX / 2 + 1),
+        ]);
+        let tokens = numbers("-4611686018427387905 - 1");
+        assert_eq!(tokens, vec![
+            Error(super::Error::NegativeRange, 0..20),
+            Identifier("-"),
+            Number(1),
+        ]);
+        if let Error(error, _) = &tokens[0] {
+            assert_eq!(
+                error.to_string(),
+                "Number literal is below the smallest number -4611686018427387904"
+            );
+        }
+        assert_eq!(numbers("#olus 3\n-1"), vec![Error(
+            super::Error::Unsupported(Feature::NegativeLiterals),
+            8..10
+        )]);
     }
 }
@@ -1,29 +1,268 @@
 #![forbid(unsafe_code)]
 #![warn(clippy::all, clippy::pedantic, clippy::cargo, clippy::nursery)]
 
-mod ast;
+pub mod ast;
+mod declarations;
 mod desugar;
+mod diagnostics;
+mod error;
+pub mod fuzz;
+pub mod graph;
+mod hoist;
+mod incremental;
 mod lexer;
+mod lint;
+pub mod minimize;
 pub mod mir;
+mod mir_text;
 mod parser;
+mod printer;
+pub mod resolve;
+mod stream;
+pub mod timings;
+mod validate;
+mod version;
 
-use std::{fs::File, io, io::prelude::*, path::PathBuf};
+pub use crate::{
+    diagnostics::{Diagnostics, Level, Levels, Sink},
+    error::{Duplicate, EntryError, Error, MirError, Result, SyntaxError, ValidationError},
+    incremental::{reparse, Edit, IncrementalAst},
+    lexer::{Span, DEFAULT_TAB_WIDTH},
+    lint::{lint, lint_captures, print_warnings, Warning},
+    stream::parse_reader,
+};
+use crate::timings::Timer;
+use std::{fs, path::PathBuf};
 
-pub fn parse_file(name: &PathBuf) -> io::Result<mir::Module> {
-    // Read file contents
-    let mut file = File::open(name)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let contents = contents;
+pub fn parse_file(name: &PathBuf) -> Result<mir::Module> {
+    parse_str(&fs::read_to_string(name)?)
+}
+
+/// Parse source code into a syntax tree, recovering from syntax errors. The
+/// tree is best-effort if any errors are returned.
+pub fn parse_ast(contents: &str) -> (ast::Statement, Vec<(SyntaxError, Span)>) {
+    let mut parser = parser::Parser::new(contents);
+    let ast = parser.parse();
+    (ast, parser.errors().to_vec())
+}
+
+/// Parse, desugar and lower source code.
+pub fn parse_str(contents: &str) -> Result<mir::Module> {
+    parse_str_with_tab_width(contents, DEFAULT_TAB_WIDTH)
+}
 
-    // Parse
-    let mut ast = parser::parse(&contents);
-    desugar::desugar(&mut ast);
-    let module = mir::Module::from(&ast);
+/// Like [`parse_str`], with tabs in indentation advancing to the next multiple
+/// of `tab_width` columns.
+pub fn parse_str_with_tab_width(contents: &str, tab_width: usize) -> Result<mir::Module> {
+    let mut parser = parser::Parser::new(contents).tab_width(tab_width);
+    let mut ast = {
+        let _timer = Timer::start("parse");
+        timings::count("bytes", contents.len() as u64);
+        parser.parse()
+    };
+    if !parser.errors().is_empty() {
+        return Err(Error::Syntax(parser.errors().to_vec()));
+    }
+    let mut declarations = declarations::Declarations::default();
+    declarations.add(&ast, 0);
+    declarations.check()?;
+    {
+        let _timer = Timer::start("desugar");
+        desugar::desugar(&mut ast, parser.call_spans()).map_err(Error::Syntax)?;
+    }
+    let mut module = mir::Module::from(&ast);
+    module.optimize()?;
     Ok(module)
 }
 
+/// Parse textual MIR, one declaration per line. See `mir::Module`'s `Display`
+/// implementation for the inverse.
+pub fn parse_mir(contents: &str) -> Result<mir::Module> {
+    let module = mir_text::parse(contents)?;
+    module.validate()?;
+    Ok(module)
+}
+
+/// Reformat source code into canonical syntax.
+pub fn format_str(contents: &str) -> Result<String> {
+    let mut parser = parser::Parser::new(contents);
+    let ast = parser.parse();
+    if !parser.errors().is_empty() {
+        return Err(Error::Syntax(parser.errors().to_vec()));
+    }
+    Ok(printer::print(parser.pragma(), &ast))
+}
+
 #[cfg(test)]
 mod tests {
-    // TODO
+    use super::*;
+    use std::{env, fmt::Write, fs, path::Path};
+
+    #[test]
+    fn test_syntax_error() {
+        match parse_str("main ↦\n    ↦ f\n") {
+            Err(Error::Syntax(errors)) => {
+                assert_eq!(errors, vec![(SyntaxError::MissingName, 13..16)]);
+            }
+            result => panic!("Expected syntax error, got {:?}", result),
+        }
+        assert!(format_str("a “b").is_err());
+    }
+
+    #[test]
+    fn test_continuation_error() {
+        // Call lines continue in an empty call of the lines before them
+        match parse_str("main ↦ f\n  g\n") {
+            Err(Error::Syntax(errors)) => {
+                assert_eq!(errors, vec![(SyntaxError::NothingToContinue, 13..14)]);
+            }
+            result => panic!("Expected syntax error, got {:?}", result),
+        }
+        match parse_str("f x\nmain ↦ exit 0 (↦)\n  g\n") {
+            Err(Error::Syntax(errors)) => {
+                assert_eq!(errors, vec![(SyntaxError::CallWithoutClosure, 0..3)]);
+            }
+            result => panic!("Expected syntax error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_normalization() {
+        // Composed and decomposed é are the same name
+        let module = parse_str("caf\u{e9} ↦ exit 0\nmain ↦ cafe\u{301}\n").unwrap();
+        assert_eq!(module.imports, vec!["exit".to_string()]);
+        assert_eq!(
+            format_str("cafe\u{301} ↦ exit 0\n").unwrap(),
+            "caf\u{e9} ↦ exit 0\n"
+        );
+    }
+
+    #[test]
+    fn test_tab_width() {
+        let source = "main ↦\n\tprint “a” (↦)\n\texit 0\n";
+        let module = parse_str(source).unwrap();
+        assert_eq!(parse_str_with_tab_width(source, 8).unwrap(), module);
+        match parse_str("main ↦\n    print “a” (↦)\n\texit 0\n") {
+            Err(Error::Syntax(errors)) => {
+                assert_eq!(errors[0].0, SyntaxError::Lexer(lexer::Error::MixedIndentation));
+            }
+            result => panic!("Expected syntax error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_lambda_names() {
+        let names = |source: &str| {
+            let module = parse_str(source).unwrap();
+            let mut names = module
+                .symbols
+                .into_iter()
+                .filter(|name| name.contains('λ'))
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        let source = "f a k ↦ g a (b ↦ k b)\nmain ↦ f 1 (r ↦ exit r)\n";
+        assert_eq!(names(source), vec!["f.λ1", "main.λ1"]);
+        // Lambdas elsewhere leave the names in `main` alone
+        let source = "f a k ↦ g (c ↦ c) a (b ↦ k b)\nmain ↦ f 1 (r ↦ exit r)\n";
+        assert_eq!(names(source), vec!["f.λ1", "f.λ2", "main.λ1"]);
+    }
+
+    #[test]
+    fn test_parse_ast_recovers() {
+        let (ast, errors) = parse_ast("f ↦ g (a
+g ↦ h)
+");
+        assert_eq!(errors, vec![]);
+        assert!(matches!(ast, ast::Statement::Block(ref statements) if statements.len() == 1));
+        let (ast, errors) = parse_ast("main ↦
+    f (a
+h ↦ i
+");
+        assert_eq!(errors, vec![(SyntaxError::UnclosedParen, 15..16)]);
+        match ast {
+            ast::Statement::Block(statements) => assert_eq!(statements.len(), 3),
+            _ => panic!("Expected block"),
+        }
+    }
+
+    /// Deterministic rendering of a module, one item per line so changes give
+    /// readable diffs.
+    fn render_mir(module: &mir::Module) -> String {
+        let mut out = String::new();
+        for (index, symbol) in module.symbols.iter().enumerate() {
+            let kind = if module.names[index] { "name" } else { "argument" };
+            writeln!(out, "symbol {} {:?} {}", index, symbol, kind).unwrap();
+        }
+        for (index, import) in module.imports.iter().enumerate() {
+            writeln!(out, "import {} {:?}", index, import).unwrap();
+        }
+        for (index, string) in module.strings.iter().enumerate() {
+            writeln!(out, "string {} {:?}", index, string).unwrap();
+        }
+        for (index, number) in module.numbers.iter().enumerate() {
+            writeln!(out, "number {} {}", index, number).unwrap();
+        }
+        for declaration in &module.declarations {
+            writeln!(
+                out,
+                "declaration {:?} call {:?} closure {:?}",
+                declaration.procedure, declaration.call, declaration.closure
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// The token stream, one token per line with its span.
+    fn render_tokens(source: &str) -> String {
+        let mut out = String::new();
+        let mut lexer = lexer::Lexer::new(source);
+        while let Some(token) = lexer.next() {
+            writeln!(out, "{:?} {:?}", lexer.span(), token).unwrap();
+        }
+        out
+    }
+
+    /// Compare `actual` with the golden file, or overwrite the golden file if
+    /// `BLESS` is set in the environment.
+    fn check_golden(path: &Path, actual: &str) -> bool {
+        if env::var_os("BLESS").is_some() {
+            fs::write(path, actual).unwrap();
+            return true;
+        }
+        let expected = fs::read_to_string(path).unwrap_or_default();
+        if expected != actual {
+            eprintln!("Mismatch in {}:\n{}", path.display(), actual);
+            return false;
+        }
+        true
+    }
+
+    /// Parse every example in `tests/golden` and compare the tokens, desugared
+    /// AST and MIR with the checked in `.tokens`, `.ast` and `.mir` files. Run
+    /// with `BLESS=1` to update them.
+    #[test]
+    fn golden() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        let mut sources = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "olus"))
+            .collect::<Vec<_>>();
+        sources.sort();
+        assert!(!sources.is_empty());
+        let mut ok = true;
+        for source in sources {
+            let contents = fs::read_to_string(&source).unwrap();
+            ok &= check_golden(&source.with_extension("tokens"), &render_tokens(&contents));
+            let mut parser = parser::Parser::new(&contents);
+            let mut ast = parser.parse();
+            desugar::desugar(&mut ast, parser.call_spans()).unwrap();
+            ok &= check_golden(&source.with_extension("ast"), &format!("{:#?}\n", ast));
+            ok &= check_golden(&source.with_extension("mir"), &render_mir(&parse_str(&contents).unwrap()));
+        }
+        assert!(ok, "Golden files differ, rerun with BLESS=1 to update them.");
+    }
 }
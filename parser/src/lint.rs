@@ -0,0 +1,300 @@
+use crate::{
+    ast::{Binder, Expression, Statement, IGNORED},
+    diagnostics::Level,
+    lexer::Span,
+    mir::Module,
+    parse_ast,
+    parser::emit_diagnostics,
+    resolve::{occurrences, Occurrence},
+};
+use codespan_reporting::diagnostic::Severity;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use thiserror::Error;
+use unic_normal::StrNormalForm;
+
+// Warnings about programs that are valid, but likely not what was meant. They
+// are found on the source text, so they point at what the author wrote.
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum Warning {
+    #[error("Name {0} can be confused with {1}")]
+    Confusable(String, String),
+    #[error("{0} is never used")]
+    Unused(String),
+    #[error("{0} captures {1} symbols, more than the limit of {2}")]
+    LargeCapture(String, usize, usize),
+    #[error("{0} hides a parameter of the same name")]
+    Shadowed(String),
+    #[error("{0} is never reached from the entry point")]
+    Unreachable(String),
+    #[error("{0} takes {1} arguments, but is called with {2}")]
+    Arity(String, usize, usize),
+    #[error("{0} is not declared and is not a builtin")]
+    Undeclared(String),
+}
+
+impl Warning {
+    /// The names of the kinds of warnings, for setting their [`Level`].
+    pub const NAMES: [&'static str; 7] = [
+        "confusable",
+        "unused",
+        "large-capture",
+        "shadowed",
+        "unreachable",
+        "arity",
+        "undeclared",
+    ];
+
+    /// The name of the kind of warning, one of [`Self::NAMES`].
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Confusable(..) => "confusable",
+            Self::Unused(_) => "unused",
+            Self::LargeCapture(..) => "large-capture",
+            Self::Shadowed(_) => "shadowed",
+            Self::Unreachable(_) => "unreachable",
+            Self::Arity(..) => "arity",
+            Self::Undeclared(_) => "undeclared",
+        }
+    }
+
+    /// Unreachable declarations are allowed unless asked for, top level
+    /// declarations can be entry points of other builds.
+    #[must_use]
+    pub const fn default_level(&self) -> Level {
+        match self {
+            Self::Unreachable(_) => Level::Allow,
+            _ => Level::Warn,
+        }
+    }
+}
+
+/// The warnings for `contents`, in source order.
+pub fn lint(contents: &str) -> Vec<(Warning, Span)> {
+    let occurrences = occurrences(contents);
+    let mut warnings = Vec::new();
+    confusables(&occurrences, &mut warnings);
+    unused(contents, &occurrences, &mut warnings);
+    shadowed(&parse_ast(contents).0, &mut warnings);
+    warnings.sort_by_key(|(_, span)| span.start);
+    warnings
+}
+
+/// Warn about declarations of `module` that capture more than `limit`
+/// symbols. Their closures are allocated with room for every capture each
+/// time they are created. Declarations without a source location, like those
+/// of textual MIR, are not warned about.
+pub fn lint_captures(module: &Module, limit: usize) -> Vec<(Warning, Span)> {
+    let mut warnings = module
+        .declarations
+        .iter()
+        .filter(|declaration| declaration.closure.len() > limit)
+        .filter_map(|declaration| {
+            let symbol = declaration.procedure[0];
+            let span = module.span(symbol)?;
+            let name = match module.symbols[symbol].as_str() {
+                "" => "Anonymous closure".to_string(),
+                name => name.to_string(),
+            };
+            let warning = Warning::LargeCapture(name, declaration.closure.len(), limit);
+            Some((warning, span))
+        })
+        .collect::<Vec<_>>();
+    warnings.sort_by_key(|(_, span)| span.start);
+    warnings
+}
+
+/// Report `warnings` on stderr with the source lines they are about, like
+/// [`crate::Error::print_diagnostics`].
+pub fn print_warnings(name: &str, source: &str, warnings: &[(Warning, Span)]) {
+    let warnings = warnings
+        .iter()
+        .map(|(warning, span)| (warning.to_string(), span.clone()));
+    emit_diagnostics(name, source, Severity::Warning, warnings);
+}
+
+/// Warn about different names that are the same in Normalization Form KC,
+/// like `ﬁle` and `file` or full width `ｘ` and `x`. The warning is on the
+/// first occurrence of the later name.
+fn confusables(occurrences: &[Occurrence], warnings: &mut Vec<(Warning, Span)>) {
+    let mut seen = HashSet::new();
+    let mut skeletons = HashMap::new();
+    for occurrence in occurrences {
+        if !seen.insert(&occurrence.name) {
+            continue;
+        }
+        let skeleton = occurrence.name.nfkc().collect::<String>();
+        match skeletons.entry(skeleton) {
+            Entry::Vacant(entry) => {
+                let _ = entry.insert(&occurrence.name);
+            }
+            Entry::Occupied(entry) => {
+                let warning = Warning::Confusable(occurrence.name.clone(), (*entry.get()).clone());
+                warnings.push((warning, occurrence.span.clone()));
+            }
+        }
+    }
+}
+
+/// Warn about binders nothing refers to. Parameters that are not needed can
+/// be written as [`IGNORED`] instead. Names of top level closures are not
+/// warned about, they can be called from outside.
+fn unused(contents: &str, occurrences: &[Occurrence], warnings: &mut Vec<(Warning, Span)>) {
+    let mut exported = HashSet::new();
+    if let (Statement::Block(statements), _) = parse_ast(contents) {
+        for statement in statements {
+            if let Statement::Closure(binders, _) = statement {
+                exported.extend(binders.into_iter().next().and_then(|binder| binder.2));
+            }
+        }
+    }
+    let used = occurrences
+        .iter()
+        .filter(|occurrence| !occurrence.is_binder())
+        .filter_map(|occurrence| occurrence.binder.as_ref())
+        .collect::<HashSet<_>>();
+    for occurrence in occurrences {
+        if occurrence.is_binder()
+            && occurrence.name != IGNORED
+            && !used.contains(&occurrence.span)
+            && !exported.contains(&occurrence.span)
+        {
+            let warning = Warning::Unused(occurrence.name.clone());
+            warnings.push((warning, occurrence.span.clone()));
+        }
+    }
+}
+
+/// Warn about binders named like a parameter of a closure they are in. The
+/// parameter can not be referred to from there on.
+fn shadowed(ast: &Statement, warnings: &mut Vec<(Warning, Span)>) {
+    fn check(binders: &[Binder], scope: &[String], warnings: &mut Vec<(Warning, Span)>) {
+        for Binder(_, name, span) in binders {
+            if name != IGNORED && scope.contains(name) {
+                if let Some(span) = span {
+                    warnings.push((Warning::Shadowed(name.clone()), span.clone()));
+                }
+            }
+        }
+    }
+    fn expressions(
+        call: &[Expression],
+        scope: &mut Vec<String>,
+        warnings: &mut Vec<(Warning, Span)>,
+    ) {
+        for expression in call {
+            match expression {
+                Expression::Fructose(binders, inner) => {
+                    check(binders, scope, warnings);
+                    let outer = scope.len();
+                    scope.extend(binders.iter().map(|binder| binder.1.clone()));
+                    expressions(inner, scope, warnings);
+                    scope.truncate(outer);
+                }
+                Expression::Galactose(inner) => expressions(inner, scope, warnings),
+                _ => {}
+            }
+        }
+    }
+    /// A block is the body of the closure before it, so that closure's
+    /// binders are in scope for it.
+    fn statement(
+        ast: &Statement,
+        parameters: &[Binder],
+        scope: &mut Vec<String>,
+        warnings: &mut Vec<(Warning, Span)>,
+    ) {
+        match ast {
+            Statement::Closure(binders, call) => {
+                check(binders, scope, warnings);
+                let outer = scope.len();
+                scope.extend(binders.iter().map(|binder| binder.1.clone()));
+                expressions(call, scope, warnings);
+                scope.truncate(outer);
+            }
+            Statement::Call(call) => expressions(call, scope, warnings),
+            Statement::Block(statements) => {
+                let outer = scope.len();
+                scope.extend(parameters.iter().map(|binder| binder.1.clone()));
+                let mut parameters: &[Binder] = &[];
+                for inner in statements {
+                    statement(inner, parameters, scope, warnings);
+                    parameters = match inner {
+                        Statement::Closure(binders, _) => binders,
+                        _ => &[],
+                    };
+                }
+                scope.truncate(outer);
+            }
+        }
+    }
+    statement(ast, &[], &mut Vec::new(), warnings);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_confusables() {
+        let source = "ﬁle ↦ exit 0\nmain ↦ file\nfile ↦ ﬁle\n";
+        assert_eq!(lint(source), vec![(
+            Warning::Confusable("file".to_string(), "ﬁle".to_string()),
+            26..30
+        )]);
+        // Normalization Form C already makes these the same name
+        assert_eq!(lint("caf\u{e9} ↦ exit 0\nmain ↦ cafe\u{301}\n"), vec![]);
+        assert_eq!(
+            lint("ｘ ↦ exit x\n")[0].0.to_string(),
+            "Name x can be confused with ｘ"
+        );
+    }
+
+    #[test]
+    fn test_unused() {
+        let source = "f a b ↦ exit a\nmain ↦ f 1 2 (x _ ↦ exit 0)\n";
+        assert_eq!(lint(source), vec![
+            (Warning::Unused("b".to_string()), 4..5),
+            (Warning::Unused("x".to_string()), 33..34),
+        ]);
+        // Local closures are not exported
+        let source = "main ↦\n    g a ↦ exit a\n    exit 0\n";
+        assert_eq!(lint(source), vec![(Warning::Unused("g".to_string()), 13..14)]);
+        assert_eq!(lint(source)[0].0.to_string(), "g is never used");
+    }
+
+    #[test]
+    fn test_shadowed() {
+        let shadowed = |source: &str| {
+            let mut warnings = Vec::new();
+            super::shadowed(&parse_ast(source).0, &mut warnings);
+            warnings
+        };
+        let source = "main a ↦\n    g a ↦ exit (b ↦ f b) a\n    g 1 (a ↦ exit a)\nf a ↦ exit a\n";
+        assert_eq!(shadowed(source), vec![
+            (Warning::Shadowed("a".to_string()), 17..18),
+            (Warning::Shadowed("a".to_string()), 51..52),
+        ]);
+        assert_eq!(shadowed(source)[0].0.to_string(), "a hides a parameter of the same name");
+        // Siblings and top level names are not parameters
+        assert_eq!(shadowed("f ↦ g (a ↦ exit a) (a ↦ exit a)\na ↦ exit 0\ng a ↦ a\n"), vec![]);
+        let warning = (Warning::Shadowed("a".to_string()), 11..12);
+        assert!(lint("f a ↦ g (a ↦ exit a)\n").contains(&warning));
+    }
+
+    #[test]
+    fn test_large_capture() {
+        let source = "f a b ↦ run g\ng ↦ add a b h\nh ↦ exit a\n";
+        let module = crate::parse_str(source).unwrap();
+        assert_eq!(lint_captures(&module, 1), vec![(
+            Warning::LargeCapture("g".to_string(), 2, 1),
+            16..17
+        )]);
+        assert_eq!(lint_captures(&module, 2), vec![]);
+        assert_eq!(
+            lint_captures(&module, 1)[0].0.to_string(),
+            "g captures 2 symbols, more than the limit of 1"
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use crate::lexer::{quote, Lexer, Token};
+use std::collections::HashSet;
+
+// Shrink a source while it keeps failing, to turn a crashing input found by
+// fuzzing into a small reproducer.
+//
+// Reductions follow the syntax: statements are dropped together with their
+// blocks, blocks are inlined into their parent, parentheses are unwrapped,
+// tokens are dropped and string literals shortened. They work on the source
+// text, so sources with syntax errors can be reduced too. The first reduction
+// that still fails is kept, until none applies.
+
+/// Reduce `source` to a smaller one for which `fails` still holds. `fails` is
+/// expected to hold for `source` itself.
+pub fn minimize<F: FnMut(&str) -> bool>(source: &str, mut fails: F) -> String {
+    let mut current = source.to_string();
+    let mut tried = HashSet::new();
+    'reduce: loop {
+        for candidate in candidates(&current) {
+            // Only strictly smaller candidates, so this terminates
+            if candidate.len() < current.len()
+                && tried.insert(candidate.clone())
+                && fails(&candidate)
+            {
+                current = candidate;
+                continue 'reduce;
+            }
+        }
+        return current;
+    }
+}
+
+/// Lines including their line feed.
+fn lines(source: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    for (index, _) in source.match_indices('\n') {
+        result.push(&source[start..=index]);
+        start = index + 1;
+    }
+    if start < source.len() {
+        result.push(&source[start..]);
+    }
+    result
+}
+
+/// Leading white space in characters, `None` for blank lines.
+fn indentation(line: &str) -> Option<usize> {
+    let content = line.trim_start();
+    if content.is_empty() {
+        None
+    } else {
+        Some(line[..line.len() - content.len()].chars().count())
+    }
+}
+
+/// Remove up to `count` leading white space characters.
+fn dedent(line: &str, count: usize) -> &str {
+    let mut result = line;
+    for _ in 0..count {
+        match result.chars().next() {
+            Some(c) if c.is_whitespace() && c != '\n' => result = &result[c.len_utf8()..],
+            _ => break,
+        }
+    }
+    result
+}
+
+/// Smaller variants of `source`, roughly largest reduction first.
+fn candidates(source: &str) -> Vec<String> {
+    let lines = lines(source);
+    let join = |parts: &[&str]| parts.concat();
+    // The end of the block of every line, blank lines belong to any block
+    let block_ends = (0..lines.len())
+        .map(|index| {
+            let indent = indentation(lines[index]);
+            (index + 1..lines.len())
+                .find(|&next| {
+                    matches!((indent, indentation(lines[next])), (Some(a), Some(b)) if b <= a)
+                })
+                .unwrap_or(lines.len())
+        })
+        .collect::<Vec<_>>();
+
+    let mut result = Vec::new();
+    // Drop statements with their blocks
+    for (index, &end) in block_ends.iter().enumerate() {
+        if indentation(lines[index]).is_some() {
+            result.push(join(&[&lines[..index], &lines[end..]].concat()));
+        }
+    }
+    // Inline blocks, replacing the statement by its block
+    for (index, &end) in block_ends.iter().enumerate() {
+        let outer = indentation(lines[index]);
+        let inner = lines[index + 1..end]
+            .iter()
+            .find_map(|line| indentation(line));
+        if let (Some(outer), Some(inner)) = (outer, inner) {
+            let mut candidate = join(&lines[..index]);
+            for line in &lines[index + 1..end] {
+                candidate.push_str(dedent(line, inner - outer));
+            }
+            candidate.push_str(&join(&lines[end..]));
+            result.push(candidate);
+        }
+    }
+    // Drop single lines, blank ones and statements leaving their block behind
+    for index in 0..lines.len() {
+        result.push(join(&[&lines[..index], &lines[index + 1..]].concat()));
+    }
+    let mut tokens = Vec::new();
+    let mut lexer = Lexer::new(source);
+    while let Some(token) = lexer.next() {
+        match token {
+            Token::Error(_, ref span) => tokens.push((span.clone(), token)),
+            Token::Identifier(_) | Token::String(_) | Token::Number(_) => {
+                tokens.push((lexer.span(), token))
+            }
+            _ => {}
+        }
+    }
+    // Unwrap parentheses
+    let mut open = Vec::new();
+    for (span, token) in &tokens {
+        match token {
+            Token::Identifier("(") => open.push(span.clone()),
+            Token::Identifier(")") => {
+                if let Some(start) = open.pop() {
+                    result.push(format!(
+                        "{}{}{}",
+                        &source[..start.start],
+                        &source[start.end..span.start],
+                        &source[span.end..]
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+    // Drop tokens, then shorten strings
+    let mut strings = Vec::new();
+    for (span, token) in tokens {
+        // Take a trailing space along
+        let end = if source[span.end..].starts_with(' ') {
+            span.end + 1
+        } else {
+            span.end
+        };
+        result.push(format!("{}{}", &source[..span.start], &source[end..]));
+        if let Token::String(string) = token {
+            let length = string.chars().count();
+            for shortened in &[0, length / 2] {
+                if *shortened < length {
+                    let shortened = string.chars().take(*shortened).collect::<String>();
+                    let literal = quote(&shortened);
+                    strings.push(format!(
+                        "{}{}{}",
+                        &source[..span.start],
+                        literal,
+                        &source[span.end..]
+                    ));
+                }
+            }
+        }
+    }
+    result.extend(strings);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parse_ast, SyntaxError};
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_candidates() {
+        let candidates = candidates("a ↦\n    b\n        c\nd\n");
+        // Dropping statements with their blocks comes first
+        assert_eq!(
+            &candidates[..4],
+            &[
+                "d\n",
+                "a ↦\nd\n",
+                "a ↦\n    b\nd\n",
+                "a ↦\n    b\n        c\n"
+            ]
+        );
+        // Then inlining blocks
+        assert_eq!(&candidates[4..6], &["b\n    c\nd\n", "a ↦\n    c\nd\n"]);
+    }
+
+    #[test]
+    fn test_minimize() {
+        let source = indoc!(
+            "
+            main ↦
+                f “hello”
+                g (↦ h “boom” x)
+                k
+            other ↦ exit 0
+            "
+        );
+        let result = minimize(source, |source| {
+            source.contains("boom") && parse_ast(source).1.is_empty()
+        });
+        assert_eq!(result.trim(), "“boom”");
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let source = "main ↦\n    print “a” (f x\n    exit 0\n";
+        let result = minimize(source, |source| {
+            parse_ast(source)
+                .1
+                .iter()
+                .any(|(error, _)| *error == SyntaxError::UnclosedParen)
+        });
+        assert_eq!(result.trim(), "(");
+    }
+
+    #[test]
+    fn test_shorten_strings() {
+        let result = minimize("main ↦ print “abcdefgh” x\n", |source| {
+            source.contains("“a")
+        });
+        assert_eq!(result.trim(), "“a”");
+    }
+}
@@ -0,0 +1,368 @@
+use crate::{
+    lexer::{quote, unescape},
+    mir::{Declaration, Expression, Module},
+};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::{self, Display, Formatter, Write},
+    str::FromStr,
+};
+use thiserror::Error;
+
+// Textual MIR, one declaration per line:
+//
+//     main ↦ print “Hello” λ0
+//     λ0 ↦ exit 0
+//
+// Symbols bound by a procedure are referred to by name, any other name is an
+// import. Anonymous symbols are written `λk` and symbols whose name is not
+// unique `name#k`, numbered in order of appearance. Closures are not written,
+// they are recomputed when parsing. Literals are written like in source code,
+// so they can span lines.
+
+#[derive(Clone, PartialEq, Eq, Debug, Error)]
+pub enum Error {
+    #[error("line {0}: unterminated string")]
+    UnterminatedString(usize),
+    #[error("line {0}: invalid escape sequence")]
+    InvalidEscape(usize),
+    #[error("line {0}: expected ↦")]
+    MissingMaplet(usize),
+    #[error("line {0}: expected a symbol, found `{1}`")]
+    ExpectedSymbol(usize, String),
+    #[error("line {0}: symbol `{1}` is bound more than once")]
+    DuplicateBinder(usize, String),
+    #[error("line {0}: symbol `{1}` is not bound")]
+    UnboundSymbol(usize, String),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Token<'a> {
+    Maplet,
+    Word(&'a str),
+    String(Cow<'a, str>),
+}
+
+/// Split a source into lines of tokens, with the line number each starts on.
+/// Lines end in LF, CR LF or a lone CR.
+fn tokenize(source: &str) -> Result<Vec<(usize, Vec<Token<'_>>)>, Error> {
+    let mut lines = Vec::new();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut start_line = 1;
+    let mut chars = source.char_indices().peekable();
+    let line_break = |c: char, next: Option<&(usize, char)>| {
+        c == '\n' || (c == '\r' && next.map(|&(_, c)| c) != Some('\n'))
+    };
+    while let Some((start, c)) = chars.next() {
+        if tokens.is_empty() {
+            start_line = line;
+        }
+        match c {
+            c if line_break(c, chars.peek()) => {
+                if !tokens.is_empty() {
+                    lines.push((start_line, std::mem::take(&mut tokens)));
+                }
+                line += 1;
+            }
+            c if c.is_whitespace() => {}
+            '↦' => tokens.push(Token::Maplet),
+            '“' => {
+                let mut nesting = 0_usize;
+                let end = loop {
+                    match chars.next() {
+                        Some((_, '“')) => nesting += 1,
+                        Some((end, '”')) if nesting == 0 => break end,
+                        Some((_, '”')) => nesting -= 1,
+                        Some((_, c)) if line_break(c, chars.peek()) => line += 1,
+                        Some(_) => {}
+                        None => return Err(Error::UnterminatedString(start_line)),
+                    }
+                };
+                tokens.push(Token::String(Cow::Borrowed(&source[start + '“'.len_utf8()..end])));
+            }
+            '"' => {
+                let end = loop {
+                    match chars.next() {
+                        Some((end, '"')) => break end,
+                        Some((_, '\\')) => {
+                            if let Some((_, c)) = chars.next() {
+                                if line_break(c, chars.peek()) {
+                                    line += 1;
+                                }
+                            }
+                        }
+                        Some((_, c)) if line_break(c, chars.peek()) => line += 1,
+                        Some(_) => {}
+                        None => return Err(Error::UnterminatedString(start_line)),
+                    }
+                };
+                let string = unescape(&source[start + 1..end]).map_err(|_| Error::InvalidEscape(line))?;
+                tokens.push(Token::String(string));
+            }
+            _ => {
+                let mut end = source.len();
+                while let Some(&(index, c)) = chars.peek() {
+                    if c.is_whitespace() || c == '↦' || c == '“' || c == '"' {
+                        end = index;
+                        break;
+                    }
+                    let _ = chars.next();
+                }
+                tokens.push(Token::Word(&source[start..end]));
+            }
+        }
+    }
+    if !tokens.is_empty() {
+        lines.push((start_line, tokens));
+    }
+    Ok(lines)
+}
+
+/// The name a symbol written as `word` has in the module.
+fn symbol_name(word: &str) -> &str {
+    let numbered = |suffix: &str| !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit());
+    if word.strip_prefix('λ').map_or(false, numbered) {
+        return "";
+    }
+    match word.rfind('#') {
+        Some(index) if numbered(&word[index + 1..]) => &word[..index],
+        _ => word,
+    }
+}
+
+fn intern<T: PartialEq>(values: &mut Vec<T>, value: T) -> usize {
+    values.iter().position(|v| *v == value).unwrap_or_else(|| {
+        values.push(value);
+        values.len() - 1
+    })
+}
+
+/// Parse textual MIR. Names and closures are computed.
+pub fn parse(source: &str) -> Result<Module, Error> {
+    let lines = tokenize(source)?;
+    let mut module = Module::default();
+
+    // Symbols are bound in procedures, which may come after their use
+    let mut symbols = HashMap::new();
+    for (line, tokens) in &lines {
+        let maplet = tokens
+            .iter()
+            .position(|token| *token == Token::Maplet)
+            .ok_or(Error::MissingMaplet(*line))?;
+        if maplet == 0 {
+            return Err(Error::MissingMaplet(*line));
+        }
+        for token in &tokens[..maplet] {
+            match token {
+                Token::Word(word) if u64::from_str(word).is_err() => {
+                    let index = module.symbols.len();
+                    if symbols.insert(*word, index).is_some() {
+                        return Err(Error::DuplicateBinder(*line, (*word).to_string()));
+                    }
+                    module.symbols.push(symbol_name(word).to_string());
+                }
+                Token::Word(word) => return Err(Error::ExpectedSymbol(*line, (*word).to_string())),
+                Token::String(string) => return Err(Error::ExpectedSymbol(*line, quote(string))),
+                Token::Maplet => unreachable!(),
+            }
+        }
+    }
+
+    for (line, tokens) in &lines {
+        let maplet = tokens.iter().position(|token| *token == Token::Maplet).unwrap();
+        let procedure = tokens[..maplet]
+            .iter()
+            .map(|token| {
+                match token {
+                    Token::Word(word) => symbols[word],
+                    _ => unreachable!(),
+                }
+            })
+            .collect();
+        let mut call = Vec::new();
+        for token in &tokens[maplet + 1..] {
+            call.push(match token {
+                Token::Maplet => return Err(Error::ExpectedSymbol(*line, "↦".to_string())),
+                Token::String(string) => {
+                    Expression::Literal(intern(&mut module.strings, string.to_string()))
+                }
+                Token::Word(word) => {
+                    if let Some(&symbol) = symbols.get(word) {
+                        Expression::Symbol(symbol)
+                    } else if let Ok(number) = u64::from_str(word) {
+                        Expression::Number(intern(&mut module.numbers, number))
+                    } else if symbol_name(word) != *word {
+                        return Err(Error::UnboundSymbol(*line, (*word).to_string()));
+                    } else {
+                        Expression::Import(intern(&mut module.imports, (*word).to_string()))
+                    }
+                }
+            });
+        }
+        module.declarations.push(Declaration {
+            procedure,
+            call,
+            closure: Vec::new(),
+        });
+    }
+    module.find_names();
+    module.compute_closures();
+    Ok(module)
+}
+
+/// Print the declarations of `module` as textual MIR.
+fn write_module<W: Write>(out: &mut W, module: &Module) -> fmt::Result {
+    // Names that need a number to be unique. Only count what is written, the
+    // module may contain symbols and imports that are no longer referred to.
+    let mut counts = HashMap::new();
+    for declaration in &module.declarations {
+        for &symbol in &declaration.procedure {
+            *counts.entry(module.symbols[symbol].as_str()).or_insert(0) += 1;
+        }
+        for expression in &declaration.call {
+            if let Expression::Import(import) = expression {
+                *counts.entry(module.imports[*import].as_str()).or_insert(0) += 2;
+            }
+        }
+    }
+    let mut words = vec![None; module.symbols.len()];
+    let mut numbering = HashMap::new();
+    let mut word = |symbol: usize| -> String {
+        let name = module.symbols[symbol].as_str();
+        words[symbol]
+            .get_or_insert_with(|| {
+                let prefix = if name.is_empty() { "λ".to_string() } else { format!("{}#", name) };
+                if !name.is_empty() && counts.get(name) == Some(&1) && symbol_name(name) == name {
+                    return name.to_string();
+                }
+                let count = numbering.entry(prefix.clone()).or_insert(0);
+                *count += 1;
+                format!("{}{}", prefix, *count - 1)
+            })
+            .clone()
+    };
+    for declaration in &module.declarations {
+        for &symbol in &declaration.procedure {
+            write!(out, "{} ", word(symbol))?;
+        }
+        write!(out, "↦")?;
+        for expression in &declaration.call {
+            match expression {
+                Expression::Symbol(symbol) => write!(out, " {}", word(*symbol))?,
+                Expression::Import(import) => write!(out, " {}", module.imports[*import])?,
+                Expression::Literal(string) => write!(out, " {}", quote(&module.strings[*string]))?,
+                Expression::Number(number) => write!(out, " {}", module.numbers[*number])?,
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+impl Display for Module {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write_module(f, self)
+    }
+}
+
+impl FromStr for Module {
+    type Err = Error;
+
+    fn from_str(source: &str) -> Result<Self, Error> {
+        parse(source)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_str;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use std::{fs, path::Path};
+
+    #[test]
+    fn test_parse() {
+        let module = parse(indoc!(
+            "
+            main ↦ f “a b” λ0
+
+            λ0 ↦ exit 0
+            f s r ↦ print s r
+            "
+        ))
+        .unwrap();
+        assert_eq!(module.symbols, vec!["main", "", "f", "s", "r"]);
+        assert_eq!(module.imports, vec!["exit", "print"]);
+        assert_eq!(module.strings, vec!["a b"]);
+        assert_eq!(module.numbers, vec![0]);
+        assert_eq!(module.declarations[0].call, vec![
+            Expression::Symbol(2),
+            Expression::Literal(0),
+            Expression::Symbol(1)
+        ]);
+        assert!(module.names[1] && !module.names[3]);
+    }
+
+    #[test]
+    fn test_ascii_strings() {
+        let module = parse("f ↦ g \"a”b\" “c\"d”\n").unwrap();
+        assert_eq!(module.strings, vec!["a”b", "c\"d"]);
+        assert_eq!(module.to_string(), "f ↦ g \"a”b\" “c\"d”\n");
+    }
+
+    #[test]
+    fn test_disambiguation() {
+        let source = indoc!(
+            "
+            f x#0 ↦ g x#0 λ0
+            λ0 x#1 ↦ x x#1 λ1
+            λ1 ↦ x#0
+            "
+        );
+        let module = parse(source).unwrap();
+        assert_eq!(module.symbols, vec!["f", "x", "", "x", ""]);
+        assert_eq!(module.imports, vec!["g", "x"]);
+        assert_eq!(module.declarations[1].closure, vec![1]);
+        assert_eq!(module.to_string(), source);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse("f ↦\ng\n"), Err(Error::MissingMaplet(2)));
+        assert_eq!(parse("↦ f\n"), Err(Error::MissingMaplet(1)));
+        assert_eq!(parse("f ↦ “\n\n"), Err(Error::UnterminatedString(1)));
+        assert_eq!(parse("f “a” ↦\n"), Err(Error::ExpectedSymbol(1, "“a”".to_string())));
+        assert_eq!(parse("f 1 ↦\n"), Err(Error::ExpectedSymbol(1, "1".to_string())));
+        assert_eq!(parse("f ↦\nf ↦\n"), Err(Error::DuplicateBinder(2, "f".to_string())));
+        assert_eq!(parse("f ↦ “a\n” λ1\n"), Err(Error::UnboundSymbol(1, "λ1".to_string())));
+        assert_eq!(parse("f ↦\n\ng ↦ x#3\n"), Err(Error::UnboundSymbol(3, "x#3".to_string())));
+        assert_eq!(parse("f ↦\r\n\r\ng ↦ x#3\r\n"), Err(Error::UnboundSymbol(3, "x#3".to_string())));
+        assert_eq!(parse("f ↦ “\r”\rg ↦ x#3\r"), Err(Error::UnboundSymbol(3, "x#3".to_string())));
+        assert_eq!(parse("f ↦ \"\n\\q\"\n"), Err(Error::InvalidEscape(2)));
+        assert_eq!(parse("f ↦ \"a\\\"\n"), Err(Error::UnterminatedString(1)));
+    }
+
+    /// Printing and parsing the golden examples gives the same module, up to
+    /// the numbering of symbols.
+    #[test]
+    fn test_round_trip() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |ext| ext != "olus") {
+                continue;
+            }
+            let module = parse_str(&fs::read_to_string(&path).unwrap()).unwrap();
+            let text = module.to_string();
+            let parsed: Module = text.parse().unwrap();
+            assert_eq!(parsed.to_string(), text, "{}", path.display());
+            assert_eq!(parsed.declarations.len(), module.declarations.len());
+            for (parsed, original) in parsed.declarations.iter().zip(&module.declarations) {
+                assert_eq!(parsed.closure.len(), original.closure.len(), "{}", path.display());
+            }
+        }
+    }
+}
@@ -1,46 +1,125 @@
 use crate::{
-    ast::{Binder, Expression, Statement},
-    lexer::{Error, Lexer, Span, Token},
+    ast::{Binder, Expression, Statement, IGNORED},
+    lexer::{self, Lexer, Span, Token},
+    timings,
+    version::Version,
 };
+use codespan_reporting::diagnostic::{Diagnostic, Label, Severity};
+use std::{borrow::Cow, time::Instant};
+use thiserror::Error;
+
+#[derive(Clone, Debug, PartialEq, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Lexer(lexer::Error),
+    #[error("Unexpected token")]
+    UnexpectedToken,
+    #[error("Maplet already found")]
+    DuplicateMaplet,
+    #[error("Expected binder")]
+    ExpectedBinder,
+    #[error("Closure without a name")]
+    MissingName,
+    #[error("Unclosed parenthesis")]
+    UnclosedParen,
+    #[error("Closing parenthesis without an opening one")]
+    UnmatchedParen,
+    #[error("`_` ignores an argument, it can not be referenced")]
+    IgnoredReference,
+    #[error("Call without a closure before it to continue")]
+    CallWithoutClosure,
+    #[error("Call without an empty call `()` or `(↦)` before it to continue")]
+    NothingToContinue,
+}
 
 pub struct Parser<'source> {
-    lexer: Lexer<'source>,
+    lexer:    Lexer<'source>,
+    /// Token to be returned again, used to resynchronize after an error
+    peeked:   Option<Token<'source>>,
+    /// Blocks started inside parentheses that closed before the block ended
+    dangling: usize,
+    errors:   Vec<(Error, Span)>,
+    /// Where each call statement is, in order
+    calls:    Vec<Span>,
+    /// Whether to time lexing, see [`timings`]
+    timed:    bool,
 }
 
 impl<'source> Parser<'source> {
     pub fn new(source: &'source str) -> Self {
         Parser {
-            lexer: Lexer::new(source),
+            lexer:    Lexer::new(source),
+            peeked:   None,
+            dangling: 0,
+            errors:   vec![],
+            calls:    vec![],
+            timed:    timings::is_enabled(),
         }
     }
 
+    /// Parse a continuation of a source whose start selected `pragma`.
+    pub fn continuation(source: &'source str, pragma: Option<Version>) -> Self {
+        Parser {
+            lexer:    Lexer::continuation(source, pragma),
+            peeked:   None,
+            dangling: 0,
+            errors:   vec![],
+            calls:    vec![],
+            timed:    timings::is_enabled(),
+        }
+    }
+
+    /// Set the number of columns a tab in indentation advances to the next
+    /// multiple of, see [`lexer::DEFAULT_TAB_WIDTH`].
+    pub fn tab_width(mut self, tab_width: usize) -> Self {
+        self.lexer = self.lexer.tab_width(tab_width);
+        self
+    }
+
+    /// Parse the source. This never fails, problems are recorded in
+    /// [`Parser::errors`] and the offending tokens skipped. Parsing resumes at
+    /// the next line or, for unclosed parentheses, at the end of the block, so
+    /// the result is a best-effort syntax tree.
     pub fn parse(&mut self) -> Statement {
         self.parse_block()
     }
 
-    fn print_diagnostic(&self, error: Error, span: Span) {
-        use codespan_reporting::{
-            diagnostic::{Diagnostic, Label},
-            files::SimpleFile,
-            term::{
-                self,
-                termcolor::{ColorChoice, StandardStream},
-            },
-        };
-
-        let file = SimpleFile::new("source", self.lexer.source());
-        let diagnostic = Diagnostic::error()
-            .with_message(format!("Error {:?}", error))
-            .with_labels(vec![Label::primary((), span)]);
-
-        let writer = StandardStream::stderr(ColorChoice::Always);
-        let config = codespan_reporting::term::Config::default();
-        term::emit(&mut writer.lock(), &config, &file, &diagnostic).unwrap();
+    pub fn errors(&self) -> &[(Error, Span)] {
+        &self.errors
+    }
+
+    /// Where each call statement of the syntax tree is, in the order of the
+    /// source, for desugaring to report calls it can not continue.
+    pub(crate) fn call_spans(&self) -> &[Span] {
+        &self.calls
+    }
+
+    /// The version selected by the pragma, if any. Only known after parsing.
+    pub const fn pragma(&self) -> Option<Version> {
+        self.lexer.pragma()
+    }
+
+    fn error(&mut self, error: Error) {
+        self.errors.push((error, self.lexer.span()));
+    }
+
+    fn next(&mut self) -> Option<Token<'source>> {
+        if let Some(token) = self.peeked.take() {
+            return Some(token);
+        }
+        if !self.timed {
+            return self.lexer.next();
+        }
+        // Lexing is interleaved with parsing, so it is timed token by token
+        let start = Instant::now();
+        let token = self.lexer.next();
+        timings::add("lex", start.elapsed(), "tokens", 1);
+        token
     }
 
     fn parse_block(&mut self) -> Statement {
         let mut statements = vec![];
-        while let Some(token) = self.lexer.next() {
+        while let Some(token) = self.next() {
             match token {
                 Token::BlockStart => {
                     statements.push(self.parse_block());
@@ -48,10 +127,10 @@ impl<'source> Parser<'source> {
                 Token::LineStart => {
                     statements.push(self.parse_line());
                 }
+                Token::BlockEnd if self.dangling > 0 => self.dangling -= 1,
                 Token::BlockEnd => break,
-                _ => {
-                    println!("Unexpected block token {:?}", token);
-                }
+                Token::Error(error, span) => self.errors.push((Error::Lexer(error), span)),
+                _ => self.error(Error::UnexpectedToken),
             }
         }
         Statement::Block(statements)
@@ -59,113 +138,235 @@ impl<'source> Parser<'source> {
 
     fn parse_line(&mut self) -> Statement {
         let mut line = vec![];
+        let mut spans = vec![];
         let mut maplet_pos = None;
-        while let Some(token) = self.lexer.next() {
+        while let Some(token) = self.next() {
             match token {
                 Token::Identifier("↦") => {
                     if maplet_pos.is_some() {
-                        println!("Maplet already found.");
+                        self.error(Error::DuplicateMaplet);
                     } else {
-                        maplet_pos = Some(line.len());
+                        maplet_pos = Some((line.len(), self.lexer.span()));
                     }
                 }
-                Token::Identifier("(") => line.push(self.parse_paren()),
+                Token::Identifier("(") => {
+                    let open = self.lexer.span();
+                    spans.push(open.clone());
+                    line.push(self.parse_paren(open));
+                }
+                Token::Identifier(")") => self.error(Error::UnmatchedParen),
                 Token::Identifier(name) => {
-                    line.push(Expression::Reference(None, name.to_owned()));
+                    spans.push(self.lexer.span());
+                    line.push(Expression::Reference(None, lexer::normalize(name)));
                 }
                 Token::String(str) => {
-                    line.push(Expression::Literal(str.to_owned()));
+                    spans.push(self.lexer.span());
+                    line.push(Expression::Literal(str.into_owned()));
                 }
                 Token::Number(n) => {
+                    spans.push(self.lexer.span());
                     line.push(Expression::Number(n));
                 }
                 Token::LineEnd => break,
-                Token::Error(error, span) => self.print_diagnostic(error, span),
-                _ => {
-                    println!("Unexpected line token {:?}", token);
+                Token::BlockStart | Token::BlockEnd | Token::LineStart => {
+                    // The line ended inside an unclosed parenthesis
+                    self.peeked = Some(token);
+                    break;
                 }
+                Token::Error(error, span) => self.errors.push((Error::Lexer(error), span)),
             }
         }
-        if let Some(maplet_pos) = maplet_pos {
+        if let Some((maplet_pos, maplet_span)) = maplet_pos {
             let (left, right) = line.split_at(maplet_pos);
-            assert!(!left.is_empty());
-            let mut binders = Vec::with_capacity(left.len());
-            for exp in left {
-                match exp {
-                    Expression::Reference(_, name) => {
-                        binders.push(Binder(None, name.to_string()));
-                    }
-                    _ => {
-                        println!("Expected binder");
-                    }
-                }
+            self.check_references(right, &spans[maplet_pos..]);
+            if left.is_empty() {
+                // Keep the body as a call so it still gets checked
+                self.errors.push((Error::MissingName, maplet_span.clone()));
+                self.calls.push(maplet_span);
+                return Statement::Call(right.to_vec());
             }
+            let binders = self.parse_binders(left, &spans);
             Statement::Closure(binders, right.to_vec())
         } else {
+            self.check_references(&line, &spans);
+            let span = match (spans.first(), spans.last()) {
+                (Some(first), Some(last)) => first.start..last.end,
+                _ => self.lexer.span(),
+            };
+            self.calls.push(span);
             Statement::Call(line)
         }
     }
 
-    fn parse_paren(&mut self) -> Expression {
+    /// Parse the inside of a parenthesis opened at `open`.
+    fn parse_paren(&mut self, open: Span) -> Expression {
         let mut line = vec![];
+        let mut spans = vec![];
         let mut maplet_pos = None;
-        while let Some(token) = self.lexer.next() {
+        let mut depth = 0_usize;
+        loop {
+            let token = if let Some(token) = self.next() {
+                token
+            } else {
+                self.errors.push((Error::UnclosedParen, open));
+                break;
+            };
             match token {
                 Token::Identifier("↦") => {
                     if maplet_pos.is_some() {
-                        println!("Maplet already found.");
+                        self.error(Error::DuplicateMaplet);
                     } else {
                         maplet_pos = Some(line.len());
                     }
                 }
-                Token::Identifier("(") => line.push(self.parse_paren()),
-                Token::Identifier(")") => break,
+                Token::Identifier("(") => {
+                    let open = self.lexer.span();
+                    spans.push(open.clone());
+                    line.push(self.parse_paren(open));
+                }
+                Token::Identifier(")") => {
+                    self.dangling += depth;
+                    break;
+                }
                 Token::Identifier(name) => {
-                    line.push(Expression::Reference(None, name.to_owned()));
+                    spans.push(self.lexer.span());
+                    line.push(Expression::Reference(None, lexer::normalize(name)));
                 }
                 Token::String(str) => {
-                    line.push(Expression::Literal(str.to_owned()));
+                    spans.push(self.lexer.span());
+                    line.push(Expression::Literal(str.into_owned()));
                 }
                 Token::Number(n) => {
+                    spans.push(self.lexer.span());
                     line.push(Expression::Number(n));
                 }
-                Token::BlockStart | Token::BlockEnd | Token::LineStart | Token::LineEnd => {
-                    // Ignore lines.
-                    // TODO: Make sure they don't confuse indentation state
-                }
-                _ => {
-                    println!("Unexpected paren token {:?}", token);
+                // Parentheses can span lines, but not end the block they
+                // started in.
+                Token::BlockStart => depth += 1,
+                Token::BlockEnd if depth == 0 && self.dangling > 0 => self.dangling -= 1,
+                Token::BlockEnd if depth == 0 => {
+                    self.errors.push((Error::UnclosedParen, open));
+                    self.peeked = Some(token);
+                    break;
                 }
+                Token::BlockEnd => depth -= 1,
+                Token::LineStart | Token::LineEnd => {}
+                Token::Error(error, span) => self.errors.push((Error::Lexer(error), span)),
             }
         }
         if let Some(maplet_pos) = maplet_pos {
             let (left, right) = line.split_at(maplet_pos);
-            let mut binders = Vec::with_capacity(left.len());
-            for exp in left {
-                match exp {
-                    Expression::Reference(_, name) => {
-                        binders.push(Binder(None, name.to_string()));
-                    }
-                    _ => {
-                        println!("Expected binder");
-                    }
-                }
-            }
+            self.check_references(right, &spans[maplet_pos..]);
+            let binders = self.parse_binders(left, &spans);
             Expression::Fructose(binders, right.to_vec())
         } else {
+            self.check_references(&line, &spans);
             Expression::Galactose(line)
         }
     }
+
+    /// Report references to [`IGNORED`] among the `expressions` right of a
+    /// maplet or in a call, located at `spans`.
+    fn check_references(&mut self, expressions: &[Expression], spans: &[Span]) {
+        for (exp, span) in expressions.iter().zip(spans) {
+            if matches!(exp, Expression::Reference(_, name) if name == IGNORED) {
+                self.errors.push((Error::IgnoredReference, span.clone()));
+            }
+        }
+    }
+
+    /// Binders from the `expressions` left of a maplet, located at `spans`.
+    fn parse_binders(&mut self, expressions: &[Expression], spans: &[Span]) -> Vec<Binder> {
+        let mut binders = Vec::with_capacity(expressions.len());
+        for (exp, span) in expressions.iter().zip(spans) {
+            match exp {
+                Expression::Reference(_, name) => {
+                    binders.push(Binder(None, name.to_string(), Some(span.clone())));
+                }
+                _ => self.error(Error::ExpectedBinder),
+            }
+        }
+        binders
+    }
+}
+
+pub fn print_diagnostics(name: &str, source: &str, errors: &[(Error, Span)]) {
+    let errors = errors
+        .iter()
+        .map(|(error, span)| (error.to_string(), span.clone()));
+    emit_diagnostics(name, source, Severity::Error, errors);
 }
 
+/// Report `diagnostics`, messages with the span they are about, on stderr.
+pub(crate) fn emit_diagnostics(
+    name: &str,
+    source: &str,
+    severity: Severity,
+    diagnostics: impl IntoIterator<Item = (String, Span)>,
+) {
+    let diagnostics = diagnostics.into_iter().map(|(message, span)| {
+        Diagnostic::new(severity)
+            .with_message(message)
+            .with_labels(vec![Label::primary((), span)])
+    });
+    emit(name, source, diagnostics);
+}
+
+/// Report `diagnostics` on stderr with the source lines their labels are on.
+pub(crate) fn emit(
+    name: &str,
+    source: &str,
+    diagnostics: impl IntoIterator<Item = Diagnostic<()>>,
+) {
+    use codespan_reporting::{
+        files::SimpleFile,
+        term::{
+            self,
+            termcolor::{ColorChoice, StandardStream},
+        },
+    };
+
+    // Diagnostics only break lines at line feeds. A lone carriage return is
+    // the same length, so replacing it keeps the spans valid.
+    let source = normalize_line_breaks(source);
+    let file = SimpleFile::new(name, source.as_ref());
+    let writer = StandardStream::stderr(ColorChoice::Always);
+    let config = codespan_reporting::term::Config::default();
+    for diagnostic in diagnostics {
+        // Failing to report is not worth aborting the compilation for
+        if term::emit(&mut writer.lock(), &config, &file, &diagnostic).is_err() {
+            break;
+        }
+    }
+}
+
+/// Replace carriage returns that are not followed by a line feed with one.
+fn normalize_line_breaks(source: &str) -> Cow<'_, str> {
+    if !source.contains('\r') {
+        return Cow::Borrowed(source);
+    }
+    let mut result = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\r' && chars.peek() != Some(&'\n') {
+            result.push('\n');
+        } else {
+            result.push(c);
+        }
+    }
+    Cow::Owned(result)
+}
+
+/// Parse the source, ignoring errors.
+#[cfg(test)]
 pub fn parse(source: &str) -> Statement {
-    let mut parser = Parser::new(source);
-    parser.parse()
+    Parser::new(source).parse()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::printer::print;
     use pretty_assertions::assert_eq;
 
     fn wrap_expr(expr: Expression) -> Statement {
@@ -212,7 +413,10 @@ mod tests {
         assert_eq!(
             parse("(a b ↦ f)"),
             wrap_expr(Expression::Fructose(
-                vec![Binder(None, "a".to_string()), Binder(None, "b".to_string()),],
+                vec![
+                    Binder(None, "a".to_string(), Some(1..2)),
+                    Binder(None, "b".to_string(), Some(3..4)),
+                ],
                 vec![Expression::Reference(None, "f".to_string()),]
             ))
         );
@@ -225,9 +429,9 @@ mod tests {
             Statement::Block(vec![
                 Statement::Closure(
                     vec![
-                        Binder(None, "fact".to_string()),
-                        Binder(None, "m".to_string()),
-                        Binder(None, "n".to_string()),
+                        Binder(None, "fact".to_string(), Some(0..4)),
+                        Binder(None, "m".to_string(), Some(5..6)),
+                        Binder(None, "n".to_string(), Some(7..8)),
                     ],
                     vec![
                         Expression::Reference(None, "f".to_string()),
@@ -240,6 +444,145 @@ mod tests {
         );
     }
 
+    fn parse_errors(source: &str) -> (Statement, Vec<(Error, Span)>) {
+        let mut parser = Parser::new(source);
+        let ast = parser.parse();
+        (ast, parser.errors().to_vec())
+    }
+
+    fn call(name: &str) -> Statement {
+        Statement::Call(vec![Expression::Reference(None, name.to_string())])
+    }
+
+    #[test]
+    fn parse_unclosed_paren() {
+        let (ast, errors) = parse_errors("a (b
+");
+        assert_eq!(errors, vec![(Error::UnclosedParen, 2..3)]);
+        assert_eq!(
+            ast,
+            Statement::Block(vec![Statement::Call(vec![
+                Expression::Reference(None, "a".to_string()),
+                Expression::Galactose(vec![Expression::Reference(None, "b".to_string())]),
+            ])])
+        );
+        // Parsing resumes after the block the parenthesis was opened in
+        let (ast, errors) = parse_errors("a
+    b (c
+d
+");
+        assert_eq!(errors, vec![(Error::UnclosedParen, 8..9)]);
+        match ast {
+            Statement::Block(statements) => {
+                assert_eq!(statements[0], call("a"));
+                assert_eq!(statements[2], call("d"));
+            }
+            _ => panic!("Expected block"),
+        }
+    }
+
+    #[test]
+    fn parse_unmatched_paren() {
+        let (ast, errors) = parse_errors("a) b
+c
+");
+        assert_eq!(errors, vec![(Error::UnmatchedParen, 1..2)]);
+        assert_eq!(
+            ast,
+            Statement::Block(vec![
+                Statement::Call(vec![
+                    Expression::Reference(None, "a".to_string()),
+                    Expression::Reference(None, "b".to_string()),
+                ]),
+                call("c")
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_indented_paren() {
+        // The block started inside the parenthesis does not end the outer one
+        let (ast, errors) = parse_errors("a (b
+    c)
+d
+");
+        assert_eq!(errors, vec![]);
+        match ast {
+            Statement::Block(statements) => {
+                assert_eq!(statements.len(), 2);
+                assert_eq!(statements[1], call("d"));
+            }
+            _ => panic!("Expected block"),
+        }
+    }
+
+    #[test]
+    fn parse_indentation_error() {
+        let (ast, errors) = parse_errors("a\n        b\n    c\nd\n");
+        assert_eq!(errors, vec![(
+            Error::Lexer(lexer::Error::IndentationError),
+            16..17
+        )]);
+        assert_eq!(
+            ast,
+            Statement::Block(vec![
+                call("a"),
+                Statement::Block(vec![call("b")]),
+                Statement::Block(vec![call("c")]),
+                call("d"),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_line_endings() {
+        let source = "f a ↦\n    g (a\n\nh ↦ k\n";
+        let (ast, errors) = parse_errors(source);
+        for ending in &["\r\n", "\r"] {
+            let windows = source.replace('\n', ending);
+            let (windows_ast, windows_errors) = parse_errors(&windows);
+            // Same tree, with spans into the original source
+            assert_eq!(print(None, &windows_ast), print(None, &ast));
+            match &windows_ast {
+                Statement::Block(statements) => {
+                    match &statements[0] {
+                        Statement::Closure(binders, _) => {
+                            assert_eq!(&windows[binders[1].2.clone().unwrap()], "a");
+                        }
+                        _ => panic!("Expected closure"),
+                    }
+                }
+                _ => panic!("Expected block"),
+            }
+            assert_eq!(windows_errors.len(), errors.len());
+            for ((error, span), (expected, _)) in windows_errors.iter().zip(&errors) {
+                assert_eq!(error, expected);
+                assert_eq!(&windows[span.clone()], "(");
+            }
+        }
+    }
+
+    #[test]
+    fn parse_ignored() {
+        let (ast, errors) = parse_errors("f _ x ↦ g x (_ ↦ h)\n");
+        assert_eq!(errors, vec![]);
+        assert_eq!(print(None, &ast), "f _ x ↦ g x (_ ↦ h)\n");
+        let (_, errors) = parse_errors("f _ ↦ g _ (↦ _)\n_\n");
+        assert_eq!(errors, vec![
+            (Error::IgnoredReference, 17..18),
+            (Error::IgnoredReference, 10..11),
+            (Error::IgnoredReference, 20..21),
+        ]);
+    }
+
+    #[test]
+    fn test_normalize_line_breaks() {
+        assert!(matches!(normalize_line_breaks("a\nb\n"), Cow::Borrowed(_)));
+        let normalized = normalize_line_breaks("a\rb\r\nc\r");
+        assert_eq!(normalized, "a\nb\r\nc\n");
+        assert_eq!(normalized.len(), "a\rb\r\nc\r".len());
+    }
+
     // #[test]
     // fn parse_block() {
     //     fn call(a: &str) -> Statement {
@@ -0,0 +1,185 @@
+use crate::{
+    ast::{Binder, Expression, Statement},
+    lexer::quote,
+    version::Version,
+};
+use std::fmt::{Result, Write};
+
+// Pretty printer producing canonical Oluś source, the inverse of `parser.rs`.
+//
+// The canonical form indents blocks by four spaces, puts single spaces between
+// tokens and separates top level declarations by an empty line. Literals are
+// reproduced verbatim, so newlines inside strings are kept. They use the
+// typographic quotes unless their content has unbalanced ones.
+
+const INDENT: &str = "    ";
+
+/// Print a parsed source file, `pragma` is the version selected by the source.
+pub fn print(pragma: Option<Version>, statement: &Statement) -> String {
+    let mut out = String::new();
+    if let Some(version) = pragma {
+        writeln!(out, "#olus {}", version).unwrap();
+    }
+    match statement {
+        Statement::Block(statements) => write_block(&mut out, 0, statements).unwrap(),
+        statement => write_statement(&mut out, 0, statement).unwrap(),
+    }
+    out
+}
+
+fn write_block<W: Write>(out: &mut W, depth: usize, statements: &[Statement]) -> Result {
+    for (index, statement) in statements.iter().enumerate() {
+        write_statement(out, depth, statement)?;
+        let last = index + 1 == statements.len();
+        if depth == 0 && !last && matches!(statement, Statement::Block(_)) {
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_statement<W: Write>(out: &mut W, depth: usize, statement: &Statement) -> Result {
+    match statement {
+        Statement::Block(statements) => return write_block(out, depth + 1, statements),
+        Statement::Closure(binders, expressions) => {
+            write!(out, "{}", INDENT.repeat(depth))?;
+            write_closure(out, binders, expressions)?;
+        }
+        Statement::Call(expressions) => {
+            write!(out, "{}", INDENT.repeat(depth))?;
+            write_expressions(out, expressions)?;
+        }
+    }
+    writeln!(out)
+}
+
+fn write_closure<W: Write>(out: &mut W, binders: &[Binder], expressions: &[Expression]) -> Result {
+    for Binder(_, name, _) in binders {
+        write!(out, "{} ", name)?;
+    }
+    write!(out, "↦")?;
+    if !expressions.is_empty() {
+        write!(out, " ")?;
+        write_expressions(out, expressions)?;
+    }
+    Ok(())
+}
+
+fn write_expressions<W: Write>(out: &mut W, expressions: &[Expression]) -> Result {
+    for (index, expression) in expressions.iter().enumerate() {
+        if index > 0 {
+            write!(out, " ")?;
+        }
+        write_expression(out, expression)?;
+    }
+    Ok(())
+}
+
+fn write_expression<W: Write>(out: &mut W, expression: &Expression) -> Result {
+    match expression {
+        Expression::Reference(_, name) => write!(out, "{}", name),
+        Expression::Literal(string) => write!(out, "{}", quote(string)),
+        Expression::Number(number) => write!(out, "{}", number),
+        Expression::Galactose(expressions) => {
+            write!(out, "(")?;
+            write_expressions(out, expressions)?;
+            write!(out, ")")
+        }
+        Expression::Fructose(binders, expressions) => {
+            write!(out, "(")?;
+            write_closure(out, binders, expressions)?;
+            write!(out, ")")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use std::{fs, path::Path};
+
+    fn format(source: &str) -> String {
+        let mut parser = Parser::new(source);
+        let ast = parser.parse();
+        assert_eq!(parser.errors(), &[]);
+        print(parser.pragma(), &ast)
+    }
+
+    #[test]
+    fn test_canonical() {
+        assert_eq!(
+            format(indoc!(
+                "
+                #olus 1
+                f  a b↦
+                  g a(b↦) ( ↦ c “x”)
+                  (↦)
+                main↦
+                  f 1 2
+                "
+            )),
+            indoc!(
+                "
+                #olus 1
+                f a b ↦
+                    g a (b ↦) (↦ c “x”)
+                    (↦)
+
+                main ↦
+                    f 1 2
+                "
+            )
+        );
+    }
+
+    #[test]
+    fn test_nested_blocks() {
+        let source = indoc!(
+            "
+            a ↦
+                b ↦
+                    c
+                d
+            "
+        );
+        assert_eq!(format(source), source);
+    }
+
+    /// Formatting the examples must preserve their meaning and be idempotent.
+    #[test]
+    fn test_ascii_strings() {
+        // Typographic quotes are canonical, unless the content is unbalanced
+        assert_eq!(
+            format("f ↦ g \"a\\tb\" \"“c”\" \"d”\\\\\"\n"),
+            "f ↦ g “a\tb” ““c”” \"d”\\\\\"\n"
+        );
+    }
+
+    #[test]
+    fn test_ascii_maplet() {
+        assert_eq!(format("f a |-> g (|-> a)\n"), "f a ↦ g (↦ a)\n");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |ext| ext != "olus") {
+                continue;
+            }
+            let source = fs::read_to_string(&path).unwrap();
+            let formatted = format(&source);
+            assert_eq!(
+                Parser::new(&formatted).parse(),
+                Parser::new(&source).parse(),
+                "{}",
+                path.display()
+            );
+            assert_eq!(format(&formatted), formatted, "{}", path.display());
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{
+    ast::{Binder, Expression, IGNORED},
+    desugar::{Host, Visitor},
+    lexer::{normalize, Lexer, Span, Token},
+    parse_ast,
+};
+use std::collections::{HashMap, HashSet};
+
+// Name resolution on the source text, for editors. It follows the binding of
+// `Desugarer::bind`: a name refers to the last binder of that name before it,
+// wherever it is in the source. It works on sources with syntax errors too.
+// Names are compared normalized, like the parser does.
+
+/// A name in the source and the binder it refers to.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Occurrence {
+    /// The normalized name
+    pub name:   String,
+    pub span:   Span,
+    /// Span of the binder, the occurrence itself for binders. `None` for
+    /// names no binder precedes, which are imports, and for references to
+    /// [`IGNORED`].
+    pub binder: Option<Span>,
+}
+
+impl Occurrence {
+    pub fn is_binder(&self) -> bool {
+        self.binder.as_ref() == Some(&self.span)
+    }
+}
+
+/// Every name in `contents`, in source order.
+pub fn occurrences(contents: &str) -> Vec<Occurrence> {
+    struct BinderSpans(HashSet<Span>);
+    impl BinderSpans {
+        fn add(&mut self, binders: &[Binder]) {
+            self.0
+                .extend(binders.iter().filter_map(|binder| binder.2.clone()));
+        }
+    }
+    impl Visitor for BinderSpans {
+        fn visit_closure(&mut self, binders: &mut Vec<Binder>, _: &mut Vec<Expression>) {
+            self.add(binders);
+        }
+
+        fn visit_fructose(&mut self, binders: &mut Vec<Binder>, _: &mut Vec<Expression>) {
+            self.add(binders);
+        }
+    }
+    let (mut ast, _) = parse_ast(contents);
+    let mut binder_spans = BinderSpans(HashSet::new());
+    ast.visit(&mut binder_spans);
+
+    let mut bindings = HashMap::new();
+    let mut result = Vec::new();
+    let mut lexer = Lexer::new(contents);
+    while let Some(token) = lexer.next() {
+        let name = match token {
+            Token::Identifier("↦" | "(" | ")") => continue,
+            Token::Identifier(name) => normalize(name),
+            _ => continue,
+        };
+        let span = lexer.span();
+        let binder = if binder_spans.0.contains(&span) {
+            if name != IGNORED {
+                let _ = bindings.insert(name.clone(), span.clone());
+            }
+            Some(span.clone())
+        } else {
+            bindings.get(&name).cloned()
+        };
+        result.push(Occurrence { name, span, binder });
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Each name with the start of its binder.
+    fn resolve(contents: &str) -> Vec<(&str, Option<usize>)> {
+        occurrences(contents)
+            .into_iter()
+            .map(|occurrence| {
+                let name = &contents[occurrence.span];
+                (name, occurrence.binder.map(|binder| binder.start))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_occurrences() {
+        let source = "f a ↦ g a (b ↦ exit b)\ng x ↦ f x\n";
+        let occurrences = occurrences(source);
+        assert_eq!(occurrences[0], Occurrence {
+            name:   "f".to_string(),
+            span:   0..1,
+            binder: Some(0..1),
+        });
+        assert!(occurrences[0].is_binder());
+        assert!(!occurrences[3].is_binder());
+        assert_eq!(resolve(source), vec![
+            ("f", Some(0)),
+            ("a", Some(2)),
+            // Later binders are not visible yet
+            ("g", None),
+            ("a", Some(2)),
+            ("b", Some(13)),
+            ("exit", None),
+            ("b", Some(13)),
+            ("g", Some(27)),
+            ("x", Some(29)),
+            ("f", Some(0)),
+            ("x", Some(29)),
+        ]);
+    }
+
+    #[test]
+    fn test_shadowing() {
+        let source = "a ↦ exit 0\nf a ↦ exit a\n";
+        assert_eq!(resolve(source)[5], ("a", Some(15)));
+        // Syntax errors leave the other names resolved
+        assert_eq!(resolve("f a ↦ exit (a\n")[3], ("a", Some(2)));
+    }
+
+    #[test]
+    fn test_ignored() {
+        let source = "f _ a ↦ g _ a\n";
+        assert_eq!(resolve(source), vec![
+            ("f", Some(0)),
+            ("_", Some(2)),
+            ("a", Some(4)),
+            ("g", None),
+            ("_", None),
+            ("a", Some(4)),
+        ]);
+    }
+
+    #[test]
+    fn test_normalized() {
+        // Decomposed é refers to the composed one
+        let occurrences = occurrences("caf\u{e9} ↦ exit 0\nmain ↦ cafe\u{301}\n");
+        assert_eq!(occurrences[3].name, "caf\u{e9}");
+        assert_eq!(occurrences[3].binder, Some(0..5));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::{
+    declarations::Declarations,
+    desugar::Desugarer,
+    error::{Error, Result},
+    lexer::Span,
+    mir::Module,
+    parser::Parser,
+    timings::{self, Timer},
+    version::Version,
+};
+use std::{io::BufRead, time::Instant};
+
+// Streaming parser for large, typically machine generated, sources.
+//
+// The source is split into chunks that each start with a top level closure
+// line. Every chunk is parsed, desugared and lowered on its own, so only the
+// AST of one chunk is in memory at a time. Name binding and binder numbering
+// continue from chunk to chunk, so the result is the same module as parsing
+// everything at once, up to the numbering of symbols and the order of
+// declarations.
+
+/// Tracks string and parenthesis nesting over consecutive lines.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+struct Nesting {
+    strings: usize,
+    parens:  usize,
+    /// Inside an ASCII string, and whether the next character is escaped
+    ascii:   Option<bool>,
+}
+
+impl Nesting {
+    const fn is_top_level(self) -> bool {
+        self.strings == 0 && self.parens == 0 && self.ascii.is_none()
+    }
+
+    /// Update the nesting with `text`, returns whether a maplet occurs outside
+    /// of strings and parentheses.
+    fn scan(&mut self, text: &str) -> bool {
+        let mut maplet = false;
+        for (index, c) in text.char_indices() {
+            if let Some(escaped) = self.ascii {
+                self.ascii = match c {
+                    _ if escaped => Some(false),
+                    '\\' => Some(true),
+                    '"' => None,
+                    _ => Some(false),
+                };
+                continue;
+            }
+            match c {
+                '“' => self.strings += 1,
+                '”' if self.strings > 0 => self.strings -= 1,
+                _ if self.strings > 0 => {}
+                '"' => self.ascii = Some(false),
+                '(' => self.parens += 1,
+                ')' => self.parens = self.parens.saturating_sub(1),
+                '↦' if self.parens == 0 => maplet = true,
+                '|' if self.parens == 0 && text[index..].starts_with("|->") => maplet = true,
+                _ => {}
+            }
+        }
+        maplet
+    }
+
+    /// Whether `line` starts a new chunk: a top level closure line starting in
+    /// the first column.
+    fn starts_chunk(mut self, line: &str) -> bool {
+        self.is_top_level()
+            && line.chars().next().map_or(false, |c| !c.is_whitespace())
+            && self.scan(line)
+    }
+}
+
+#[derive(Default)]
+struct Streamer {
+    module:       Module,
+    desugarer:    Desugarer,
+    declarations: Declarations,
+    pragma:       Option<Version>,
+    errors:       Vec<(crate::SyntaxError, crate::Span)>,
+    /// Offset of the current chunk in the source
+    offset:       usize,
+}
+
+impl Streamer {
+    fn chunk(&mut self, chunk: &str) {
+        let mut parser = if self.offset == 0 {
+            Parser::new(chunk)
+        } else {
+            Parser::continuation(chunk, self.pragma)
+        };
+        let start = Instant::now();
+        let mut ast = parser.parse();
+        timings::add("parse", start.elapsed(), "chunks", 1);
+        if self.offset == 0 {
+            self.pragma = parser.pragma();
+        }
+        let offset = self.offset;
+        self.errors.extend(
+            parser
+                .errors()
+                .iter()
+                .map(|(error, span)| (error.clone(), span.start + offset..span.end + offset)),
+        );
+        self.offset += chunk.len();
+        // Stop lowering after the first error, but keep collecting errors
+        if self.errors.is_empty() {
+            self.declarations.add(&ast, offset);
+            let start = Instant::now();
+            let desugared = self.desugarer.desugar(&mut ast, parser.call_spans());
+            timings::add("desugar", start.elapsed(), "chunks", 1);
+            if let Err(errors) = desugared {
+                self.errors.extend(
+                    errors
+                        .into_iter()
+                        .map(|(error, span)| (error, span.start + offset..span.end + offset)),
+                );
+                return;
+            }
+            let start = Instant::now();
+            self.module.extend(&ast, offset);
+            timings::add("MIR", start.elapsed(), "chunks", 1);
+        }
+    }
+
+    fn finish(mut self) -> Result<Module> {
+        if !self.errors.is_empty() {
+            return Err(Error::Syntax(self.errors));
+        }
+        self.declarations.check()?;
+        self.module.find_names();
+        {
+            let _timer = Timer::start("closures");
+            self.module.compute_closures();
+        }
+        self.module.optimize()?;
+        Ok(self.module)
+    }
+}
+
+/// The ranges of the chunks of `source`, as `parse_reader` splits it.
+pub(crate) fn chunks(source: &str) -> Vec<Span> {
+    let mut result = Vec::new();
+    let mut nesting = Nesting::default();
+    let mut start = 0;
+    let mut end = 0;
+    for line in source.split_inclusive('\n') {
+        if nesting.starts_chunk(line) && end > start {
+            result.push(start..end);
+            start = end;
+        }
+        let _ = nesting.scan(line);
+        end += line.len();
+    }
+    if end > start {
+        result.push(start..end);
+    }
+    result
+}
+
+/// Parse, desugar and lower a source one top level declaration at a time.
+/// Memory use is proportional to the largest declaration and the resulting
+/// module, not to the size of the source.
+pub fn parse_reader<R: BufRead>(mut reader: R) -> Result<Module> {
+    let timer = Timer::start("stream");
+    let mut streamer = Streamer::default();
+    let mut nesting = Nesting::default();
+    let mut chunk = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let end = reader.read_line(&mut line)? == 0;
+        if (end || nesting.starts_chunk(&line)) && !chunk.is_empty() {
+            streamer.chunk(&chunk);
+            chunk.clear();
+        }
+        if end {
+            break;
+        }
+        let _ = nesting.scan(&line);
+        chunk.push_str(&line);
+    }
+    drop(timer);
+    streamer.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{mir::Expression, parse_str};
+    use pretty_assertions::assert_eq;
+    use std::{fs, path::Path};
+
+    #[test]
+    fn test_starts_chunk() {
+        let nesting = Nesting::default();
+        assert!(nesting.starts_chunk("f a ↦ g a\n"));
+        assert!(!nesting.starts_chunk("    f a ↦ g a\n"));
+        assert!(!nesting.starts_chunk("g (a ↦ b)\n"));
+        assert!(!nesting.starts_chunk("g “a ↦ b”\n"));
+        assert!(nesting.starts_chunk("f a |-> g a\n"));
+        assert!(!nesting.starts_chunk("f a | -> g a\n"));
+        let mut nesting = Nesting::default();
+        let _ = nesting.scan("    print “Hello\n");
+        assert!(!nesting.starts_chunk("” f ↦\n"));
+        let _ = nesting.scan("” f ↦\n");
+        assert!(nesting.is_top_level());
+        assert!(!nesting.starts_chunk("g \"a ↦ b\"\n"));
+        assert!(!nesting.starts_chunk("g \"“\" (a ↦ b)\n"));
+        let _ = nesting.scan("    print \"a\\\"\n");
+        assert!(!nesting.starts_chunk("f ↦\"\n"));
+        let _ = nesting.scan("f ↦\"\n");
+        assert!(nesting.is_top_level());
+    }
+
+    /// Declarations with symbols replaced by their names, sorted, to compare
+    /// modules with different numbering.
+    fn describe(module: &Module) -> Vec<String> {
+        let symbol = |s: usize| format!("{:?}", module.symbols[s]);
+        let expression = |e: &Expression| {
+            match e {
+                Expression::Symbol(s) => symbol(*s),
+                Expression::Import(i) => format!("import {}", module.imports[*i]),
+                Expression::Literal(l) => format!("{:?}", module.strings[*l]),
+                Expression::Number(n) => module.numbers[*n].to_string(),
+            }
+        };
+        let mut result = module
+            .declarations
+            .iter()
+            .map(|decl| {
+                format!(
+                    "{:?} ↦ {:?} closure {:?}",
+                    decl.procedure.iter().map(|s| symbol(*s)).collect::<Vec<_>>(),
+                    decl.call.iter().map(expression).collect::<Vec<_>>(),
+                    decl.closure.iter().map(|s| symbol(*s)).collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn test_same_as_parse_str() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |ext| ext != "olus") {
+                continue;
+            }
+            let source = fs::read_to_string(&path).unwrap();
+            let streamed = parse_reader(source.as_bytes()).unwrap();
+            let parsed = parse_str(&source).unwrap();
+            assert_eq!(describe(&streamed), describe(&parsed), "{}", path.display());
+            assert_eq!(streamed.symbols.len(), parsed.symbols.len());
+        }
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let source = "main ↦\n    print “a\nb” (↦ f)\n\nf ↦ exit 0\n";
+        let parsed = parse_str(source).unwrap();
+        for ending in &["\r\n", "\r"] {
+            let source = source.replace('\n', ending);
+            let streamed = parse_reader(source.as_bytes()).unwrap();
+            // Line breaks in literals are kept verbatim
+            assert_eq!(streamed.strings, vec![format!("a{}b", ending)]);
+            assert_eq!(streamed.declarations.len(), parsed.declarations.len());
+        }
+    }
+
+    #[test]
+    fn test_chunks() {
+        let source = "f ↦ g (a ↦\nb)\n    h\n\ng ↦\n    “\nk ↦”\nk ↦ f";
+        let chunks = chunks(source)
+            .into_iter()
+            .map(|range| &source[range])
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, vec![
+            "f ↦ g (a ↦\nb)\n    h\n\n",
+            "g ↦\n    “\nk ↦”\n",
+            "k ↦ f"
+        ]);
+        assert!(super::chunks("").is_empty());
+    }
+
+    #[test]
+    fn test_errors() {
+        let source = "#olus 0\nf ↦ g\n\nh ↦ “a”\n    k 1\n";
+        match parse_reader(source.as_bytes()) {
+            Err(Error::Syntax(errors)) => {
+                // The pragma applies to later chunks and spans are absolute
+                assert_eq!(errors.len(), 1);
+                assert_eq!(&source[errors[0].1.clone()], "1");
+            }
+            result => panic!("Expected syntax error, got {:?}", result),
+        }
+
+        // Calls that do not continue anything are found when desugaring
+        let source = "f ↦ exit 0\n\nmain ↦ f\n    g 1\n";
+        match parse_reader(source.as_bytes()) {
+            Err(Error::Syntax(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(&source[errors[0].1.clone()], "g 1");
+            }
+            result => panic!("Expected syntax error, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    cell::RefCell,
+    fmt::{self, Display, Formatter},
+    time::{Duration, Instant},
+};
+
+// Wall time and counters of the phases of a build, for `--timings`. Phases
+// record themselves in a recorder for the thread, which is off until enabled,
+// so nothing is passed through the passes and builds without timings only pay
+// for checking it. A phase started while another one runs is nested in it.
+
+/// A timed phase.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Phase {
+    pub name:     String,
+    /// Number of phases it is nested in
+    pub depth:    usize,
+    pub duration: Duration,
+    /// Counters in the order they were first added to
+    pub counters: Vec<(&'static str, u64)>,
+}
+
+#[derive(Default)]
+struct Recorder {
+    phases:  Vec<Phase>,
+    /// The running phases, innermost last
+    running: Vec<usize>,
+}
+
+thread_local! {
+    static RECORDER: RefCell<Option<Recorder>> = RefCell::new(None);
+}
+
+/// Start recording phases on this thread.
+pub fn enable() {
+    RECORDER.with(|recorder| *recorder.borrow_mut() = Some(Recorder::default()));
+}
+
+/// Stop recording and return the phases, in the order they started.
+#[must_use]
+pub fn take() -> Vec<Phase> {
+    RECORDER.with(|recorder| recorder.borrow_mut().take().unwrap_or_default().phases)
+}
+
+fn with<T>(f: impl FnOnce(&mut Recorder) -> T) -> Option<T> {
+    RECORDER.with(|recorder| recorder.borrow_mut().as_mut().map(f))
+}
+
+/// Add `count` to `counter` of the innermost running phase.
+pub fn count(counter: &'static str, count: u64) {
+    let _ = with(|recorder| {
+        if let Some(&index) = recorder.running.last() {
+            add_count(&mut recorder.phases[index], counter, count);
+        }
+    });
+}
+
+fn add_count(phase: &mut Phase, counter: &'static str, count: u64) {
+    match phase.counters.iter_mut().find(|(name, _)| *name == counter) {
+        Some((_, total)) => *total += count,
+        None => phase.counters.push((counter, count)),
+    }
+}
+
+/// Add `duration` to phase `name` in the innermost running phase, creating it
+/// the first time. For work that is interleaved with its parent, like lexing
+/// with parsing, and timed in pieces.
+pub fn add(name: &str, duration: Duration, counter: &'static str, count: u64) {
+    let _ = with(|recorder| {
+        let depth = recorder.running.len();
+        let first = recorder.running.last().map_or(0, |&index| index + 1);
+        let existing = (first..recorder.phases.len())
+            .find(|&index| recorder.phases[index].depth == depth && recorder.phases[index].name == name);
+        let index = existing.unwrap_or_else(|| {
+            recorder.phases.push(Phase {
+                name: name.to_string(),
+                depth,
+                duration: Duration::default(),
+                counters: Vec::new(),
+            });
+            recorder.phases.len() - 1
+        });
+        let phase = &mut recorder.phases[index];
+        phase.duration += duration;
+        add_count(phase, counter, count);
+    });
+}
+
+/// Whether phases are recorded, to skip measuring work timed in pieces.
+#[must_use]
+pub fn is_enabled() -> bool {
+    RECORDER.with(|recorder| recorder.borrow().is_some())
+}
+
+/// A running phase, which ends when dropped.
+#[must_use]
+pub struct Timer {
+    index: Option<usize>,
+    start: Instant,
+}
+
+impl Timer {
+    pub fn start(name: &str) -> Self {
+        let index = with(|recorder| {
+            recorder.phases.push(Phase {
+                name:     name.to_string(),
+                depth:    recorder.running.len(),
+                duration: Duration::default(),
+                counters: Vec::new(),
+            });
+            let index = recorder.phases.len() - 1;
+            recorder.running.push(index);
+            index
+        });
+        Self {
+            index,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(index) = self.index {
+            let duration = self.start.elapsed();
+            let _ = with(|recorder| {
+                recorder.phases[index].duration = duration;
+                recorder.running.retain(|&running| running != index);
+            });
+        }
+    }
+}
+
+/// The phases as a table, one line per phase indented by nesting.
+pub struct Report<'a>(pub &'a [Phase]);
+
+impl Display for Report<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for phase in self.0 {
+            let name = format!("{:indent$}{}", "", phase.name, indent = 2 * phase.depth);
+            write!(f, "{:>10.3}ms  {}", phase.duration.as_secs_f64() * 1e3, name)?;
+            for (counter, count) in &phase.counters {
+                write!(f, ", {} {}", count, counter)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timings() {
+        // Nothing is recorded until enabled
+        {
+            let _timer = Timer::start("ignored");
+            count("things", 1);
+        }
+        assert!(!is_enabled());
+        assert_eq!(take(), vec![]);
+
+        enable();
+        {
+            let _outer = Timer::start("outer");
+            count("things", 2);
+            for _ in 0..3 {
+                add("piece", Duration::from_millis(1), "pieces", 1);
+            }
+            let _inner = Timer::start("inner");
+            count("things", 5);
+        }
+        count("things", 7);
+        let phases = take();
+        let summary = phases
+            .iter()
+            .map(|phase| (phase.name.as_str(), phase.depth, phase.counters.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            ("outer", 0, vec![("things", 2)]),
+            ("piece", 1, vec![("pieces", 3)]),
+            ("inner", 1, vec![("things", 5)]),
+        ]);
+        assert_eq!(phases[1].duration, Duration::from_millis(3));
+        assert!(phases[0].duration >= phases[2].duration);
+        let report = Report(&phases).to_string();
+        assert!(report.lines().nth(1).unwrap().ends_with("ms    piece, 3 pieces"));
+        assert!(!is_enabled());
+    }
+}
@@ -0,0 +1,246 @@
+use crate::mir::{Expression, Module, NUMBER_MAX};
+use thiserror::Error;
+
+// Invariants of a module as produced by lowering, checked before it is handed
+// to code generation or interpretation. A violation is a bug in an earlier
+// pass, not in the source, so errors refer to declarations and symbols by
+// index.
+
+#[derive(Clone, PartialEq, Eq, Debug, Error)]
+pub enum Error {
+    #[error("{names} names for {symbols} symbols")]
+    NamesLength { names: usize, symbols: usize },
+    #[error("declaration {0} has no name")]
+    EmptyProcedure(usize),
+    #[error("declaration {0} has an empty call")]
+    EmptyCall(usize),
+    #[error("declaration {declaration}: symbol {symbol} is out of range")]
+    Symbol { declaration: usize, symbol: usize },
+    #[error("declaration {declaration}: import {import} is out of range")]
+    Import { declaration: usize, import: usize },
+    #[error("declaration {declaration}: string {string} is out of range")]
+    String { declaration: usize, string: usize },
+    #[error("declaration {declaration}: number {number} is out of range")]
+    Number { declaration: usize, number: usize },
+    #[error("number {0} does not fit in a value")]
+    NumberRange(usize),
+    #[error("symbol {0} is bound more than once")]
+    DuplicateBinder(usize),
+    #[error("declaration {declaration}: name {symbol} is not marked as a name")]
+    UnmarkedName { declaration: usize, symbol: usize },
+    #[error("declaration {declaration}: parameter {symbol} is marked as a name")]
+    ParameterName { declaration: usize, symbol: usize },
+    #[error("symbol {0} is marked as a name but has no declaration")]
+    StrayName(usize),
+    #[error("declaration {declaration}: closure contains name {symbol}")]
+    ClosureName { declaration: usize, symbol: usize },
+    #[error("declaration {declaration}: symbol {symbol} is not a parameter, captured or a name")]
+    Unbound { declaration: usize, symbol: usize },
+}
+
+impl Module {
+    /// Check the internal invariants: all indices are in range, numbers are at
+    /// most `NUMBER_MAX`, every symbol is bound once, exactly the declaration
+    /// names are marked in `names`, closures contain no names and every symbol
+    /// a call uses is available.
+    pub fn validate(&self) -> Result<(), Error> {
+        let symbols = self.symbols.len();
+        if self.names.len() != symbols {
+            return Err(Error::NamesLength {
+                names: self.names.len(),
+                symbols,
+            });
+        }
+        if let Some(number) = self.numbers.iter().position(|&n| n > NUMBER_MAX) {
+            return Err(Error::NumberRange(number));
+        }
+        let mut bound = vec![false; symbols];
+        let mut declared = vec![false; symbols];
+        for (declaration, decl) in self.declarations.iter().enumerate() {
+            let (&name, parameters) = decl
+                .procedure
+                .split_first()
+                .ok_or(Error::EmptyProcedure(declaration))?;
+            if decl.call.is_empty() {
+                return Err(Error::EmptyCall(declaration));
+            }
+            for &symbol in decl.procedure.iter().chain(&decl.closure) {
+                if symbol >= symbols {
+                    return Err(Error::Symbol {
+                        declaration,
+                        symbol,
+                    });
+                }
+            }
+            for &symbol in &decl.procedure {
+                if std::mem::replace(&mut bound[symbol], true) {
+                    return Err(Error::DuplicateBinder(symbol));
+                }
+            }
+            declared[name] = true;
+            if !self.names[name] {
+                return Err(Error::UnmarkedName {
+                    declaration,
+                    symbol: name,
+                });
+            }
+            if let Some(&symbol) = parameters.iter().find(|&&symbol| self.names[symbol]) {
+                return Err(Error::ParameterName {
+                    declaration,
+                    symbol,
+                });
+            }
+            if let Some(&symbol) = decl.closure.iter().find(|&&symbol| self.names[symbol]) {
+                return Err(Error::ClosureName {
+                    declaration,
+                    symbol,
+                });
+            }
+            for expr in &decl.call {
+                match *expr {
+                    Expression::Symbol(symbol) if symbol >= symbols => {
+                        return Err(Error::Symbol {
+                            declaration,
+                            symbol,
+                        })
+                    }
+                    Expression::Symbol(symbol)
+                        if !self.names[symbol]
+                            && !decl.procedure.contains(&symbol)
+                            && !decl.closure.contains(&symbol) =>
+                    {
+                        return Err(Error::Unbound {
+                            declaration,
+                            symbol,
+                        })
+                    }
+                    Expression::Import(import) if import >= self.imports.len() => {
+                        return Err(Error::Import {
+                            declaration,
+                            import,
+                        })
+                    }
+                    Expression::Literal(string) if string >= self.strings.len() => {
+                        return Err(Error::String {
+                            declaration,
+                            string,
+                        })
+                    }
+                    Expression::Number(number) if number >= self.numbers.len() => {
+                        return Err(Error::Number {
+                            declaration,
+                            number,
+                        })
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if let Some(symbol) = (0..symbols).find(|&symbol| self.names[symbol] && !declared[symbol]) {
+            return Err(Error::StrayName(symbol));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_mir;
+
+    fn module() -> Module {
+        parse_mir("main ↦ f “a” 1 λ0\nλ0 x ↦ exit x\n").unwrap()
+    }
+
+    #[test]
+    fn test_valid() {
+        assert_eq!(module().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let mut module = module();
+        module.declarations[0].call[2] = Expression::Number(1);
+        assert_eq!(
+            module.validate(),
+            Err(Error::Number {
+                declaration: 0,
+                number:      1,
+            })
+        );
+        let mut module = self::module();
+        module.declarations[0].call.push(Expression::Symbol(9));
+        assert_eq!(
+            module.validate(),
+            Err(Error::Symbol {
+                declaration: 0,
+                symbol:      9,
+            })
+        );
+        let mut module = self::module();
+        module.numbers[0] = NUMBER_MAX + 1;
+        assert_eq!(module.validate(), Err(Error::NumberRange(0)));
+        let mut module = self::module();
+        module.names.push(false);
+        assert_eq!(
+            module.validate(),
+            Err(Error::NamesLength {
+                names:   4,
+                symbols: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        let mut module = module();
+        let lambda = module.declarations[1].procedure[0];
+        module.names.set(lambda, false);
+        // The call in `main` is checked first
+        assert_eq!(
+            module.validate(),
+            Err(Error::Unbound {
+                declaration: 0,
+                symbol:      lambda,
+            })
+        );
+        module.declarations[0].call.pop();
+        assert_eq!(
+            module.validate(),
+            Err(Error::UnmarkedName {
+                declaration: 1,
+                symbol:      lambda,
+            })
+        );
+        let mut module = self::module();
+        module.declarations[0].closure.push(lambda);
+        assert_eq!(
+            module.validate(),
+            Err(Error::ClosureName {
+                declaration: 0,
+                symbol:      lambda,
+            })
+        );
+        let mut module = self::module();
+        module.declarations.pop();
+        assert_eq!(module.validate(), Err(Error::StrayName(lambda)));
+    }
+
+    #[test]
+    fn test_binding() {
+        let mut module = module();
+        let x = module.declarations[1].procedure[1];
+        module.declarations[0].call.push(Expression::Symbol(x));
+        assert_eq!(
+            module.validate(),
+            Err(Error::Unbound {
+                declaration: 0,
+                symbol:      x,
+            })
+        );
+        module.declarations[0].closure.push(x);
+        assert_eq!(module.validate(), Ok(()));
+        module.declarations[0].procedure.push(x);
+        assert_eq!(module.validate(), Err(Error::DuplicateBinder(x)));
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+
+/// Revision of the grammar a source file is written in.
+///
+/// A file selects its version with a pragma on the first line, for example
+/// `#olus 1`. Files without a pragma use [`Version::LATEST`], so old programs
+/// keep parsing by pinning the version they were written against.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Version {
+    /// The original grammar: identifiers, strings, maplets, parentheses and
+    /// indentation blocks.
+    V0 = 0,
+    /// Adds decimal number literals.
+    V1 = 1,
+    /// Adds ASCII alternatives: double quoted string literals with escapes and
+    /// the `|->` maplet.
+    V2 = 2,
+    /// Adds hexadecimal `0x` and binary `0b` number literals, and underscores
+    /// between digits.
+    V3 = 3,
+    /// Adds negative number literals.
+    V4 = 4,
+    /// Adds the `_` binder, which ignores its argument.
+    V5 = 5,
+}
+
+/// Syntax that is not available in every version.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Feature {
+    NumberLiterals,
+    AsciiStrings,
+    AsciiMaplet,
+    RadixLiterals,
+    NegativeLiterals,
+    IgnoredBinder,
+}
+
+impl Version {
+    pub const ALL: [Self; 6] = [
+        Self::V0,
+        Self::V1,
+        Self::V2,
+        Self::V3,
+        Self::V4,
+        Self::V5,
+    ];
+    pub const LATEST: Self = Self::V5;
+
+    pub fn from_number(number: u64) -> Option<Self> {
+        Self::ALL.iter().copied().find(|version| *version as u64 == number)
+    }
+
+    pub fn supports(self, feature: Feature) -> bool {
+        self >= feature.introduced()
+    }
+}
+
+impl Default for Version {
+    fn default() -> Self {
+        Self::LATEST
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", *self as u64)
+    }
+}
+
+impl Feature {
+    pub const fn introduced(self) -> Version {
+        match self {
+            Self::NumberLiterals => Version::V1,
+            Self::AsciiStrings | Self::AsciiMaplet => Version::V2,
+            Self::RadixLiterals => Version::V3,
+            Self::NegativeLiterals => Version::V4,
+            Self::IgnoredBinder => Version::V5,
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NumberLiterals => write!(f, "Number literals"),
+            Self::AsciiStrings => write!(f, "ASCII string literals"),
+            Self::AsciiMaplet => write!(f, "ASCII maplets"),
+            Self::RadixLiterals => write!(f, "Hexadecimal, binary and underscored number literals"),
+            Self::NegativeLiterals => write!(f, "Negative number literals"),
+            Self::IgnoredBinder => write!(f, "Underscore binders"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_number() {
+        for version in &Version::ALL {
+            assert_eq!(Version::from_number(*version as u64), Some(*version));
+        }
+        assert_eq!(Version::from_number(Version::LATEST as u64 + 1), None);
+    }
+
+    #[test]
+    fn test_supports() {
+        assert!(!Version::V0.supports(Feature::NumberLiterals));
+        assert!(Version::V1.supports(Feature::NumberLiterals));
+        assert!(Version::LATEST.supports(Feature::NumberLiterals));
+        assert!(!Version::V1.supports(Feature::AsciiStrings));
+        assert!(Version::V2.supports(Feature::AsciiStrings));
+        assert!(!Version::V1.supports(Feature::AsciiMaplet));
+        assert!(Version::V2.supports(Feature::AsciiMaplet));
+        assert!(!Version::V2.supports(Feature::RadixLiterals));
+        assert!(Version::V3.supports(Feature::RadixLiterals));
+        assert!(!Version::V3.supports(Feature::NegativeLiterals));
+        assert!(Version::V4.supports(Feature::NegativeLiterals));
+        assert!(!Version::V4.supports(Feature::IgnoredBinder));
+        assert!(Version::V5.supports(Feature::IgnoredBinder));
+    }
+}